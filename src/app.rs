@@ -0,0 +1,1911 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! The daemon: the devices it monitors, its refresh loop and the interfaces
+//! controlling it.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::audit::Audit;
+use crate::control;
+use crate::errors::{Context, ErrorKind, Result};
+use crate::eventloop::EventLoop;
+use crate::hooks;
+use crate::localtime::TimeZone;
+use crate::metrics::{self, DeviceMetrics};
+use crate::mounts::Mounts;
+use crate::notify::Notifier;
+use crate::openfiles::OpenDevices;
+use crate::options::{Options, parse_arguments, resolve_disks};
+use crate::pidfile::PidFile;
+use crate::policy::{
+    Action, DeviceConfig, DeviceState, IdleTimePolicy, MonitorPolicy, Policy, SYNC_GLOBAL,
+    SYNC_SPIN_DOWN,
+};
+use crate::schedule;
+use crate::statsd::StatsD;
+use crate::status::{self, DeviceStatus};
+use crate::swaps::Swaps;
+use crate::syncer::Syncer;
+use crate::tick::{
+    DeviceData, IOMonitor, SyncRequest, TickEnv, check_vetoes, execute, finish_sync, follow_links,
+    reconcile_renamed, stop, sync_block_device, update, wake, wants_global_sync, watch_files,
+};
+use crate::timers::{self, Timer};
+use crate::{elogln, http, logln, signals, sys};
+
+/// Refresh period in monitor mode, when no device has an idle time.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+/// Refreshes after which a missing device which wasn't listed in the
+/// arguments is forgotten.
+const MISSING_TICKS: u32 = 10;
+/// Age after which the idle timers saved by a previous run are discarded.
+const TIMERS_MAX_AGE: Duration = Duration::from_secs(3600);
+/// Token of the HTTP listener in the event loop.
+const HTTP_EVENT: u64 = 1;
+/// Token of the control socket in the event loop.
+const SOCKET_EVENT: u64 = 2;
+/// Token of the metrics listener in the event loop.
+const METRICS_EVENT: u64 = 3;
+/// Token of the completions of the background syncs in the event loop.
+const SYNC_EVENT: u64 = 4;
+
+/// Reaches the system and the disks for the daemon: their activity, the
+/// tables of the kernel, the commands sent to the drives. The tests replace it
+/// with fixtures.
+#[derive(Clone, Copy)]
+pub struct System {
+    /// Polls the activity of the disks, without `--events`.
+    pub(crate) diskstats: fn() -> Result<IOMonitor>,
+    pub(crate) mounts: fn() -> Result<Mounts>,
+    pub(crate) swaps: fn() -> Result<Swaps>,
+    /// Stable identity of a disk (WWID).
+    pub(crate) wwid: fn(&OsStr) -> Option<OsString>,
+    /// Sends the spin down and up commands to a disk.
+    pub(crate) backend: fn(&OsStr, &DeviceConfig) -> Box<dyn sys::SpindownBackend>,
+    /// Applies the firmware settings of a drive, see [`configure_firmware`].
+    pub(crate) firmware: fn(&OsStr, &mut DeviceConfig, bool),
+    /// Resolves a path to the disks it stands for, see [`resolve_disks`].
+    pub(crate) resolve: fn(&OsStr, u8) -> Result<Vec<OsString>>,
+    /// Time zone of the spin down windows.
+    pub(crate) time_zone: fn() -> Result<TimeZone>,
+    /// Syncs all the filesystems.
+    pub(crate) sync_all: fn() -> Result<()>,
+}
+
+impl Default for System {
+    fn default() -> Self {
+        Self {
+            diskstats: IOMonitor::new,
+            mounts: Mounts::new,
+            swaps: Swaps::new,
+            wwid: sys::wwid,
+            backend: sys::select_backend,
+            firmware: configure_firmware,
+            resolve: resolve_disks,
+            time_zone: TimeZone::system,
+            sync_all: sys::sync,
+        }
+    }
+}
+
+/// The daemon, refreshing the devices and serving the requests of its
+/// interfaces until a termination signal.
+pub struct App {
+    devices_monitor: IOMonitor,
+    mounts: Mounts,
+    swaps: Option<Swaps>,
+    open_devices: OpenDevices,
+    policy: Box<dyn Policy>,
+    default_config: DeviceConfig,
+    interval: Duration,
+    /// Maximal refresh period while all the devices are spun down.
+    parked_interval: Option<Duration>,
+    /// Refresh period during `interval` after a spin down or up.
+    quick_interval: Option<Duration>,
+    /// Current refresh period.
+    poll: Duration,
+    /// Total # of spin downs and ups, and time it last changed.
+    cycles: u64,
+    last_cycle: Option<Instant>,
+    /// Boot clock at the end of the last tick, to detect the resumes.
+    last_boot_time: Duration,
+    monitor: bool,
+    dry_run: bool,
+    slow_command: Option<Duration>,
+    status: Option<OsString>,
+    state_file: Option<OsString>,
+    /// Idle timers saved by a previous run, restored to the devices detected
+    /// until they are `TIMERS_MAX_AGE` old.
+    saved_timers: Vec<(OsString, Timer)>,
+    http: Option<http::Server>,
+    socket: Option<control::Server>,
+    /// Only serves `GET /metrics`.
+    metrics: Option<http::Server>,
+    events: EventLoop,
+    /// Thread running the syncs, unless monitoring or in a dry run.
+    syncer: Option<Syncer<SyncRequest>>,
+    statsd: Option<StatsD>,
+    audit: Option<Audit>,
+    /// Notifies systemd of the readiness and pings its watchdog.
+    notifier: Option<Notifier>,
+    busy_files: Vec<(OsString, PathBuf)>,
+    hooks: hooks::Commands,
+    /// Hooks still running.
+    hook_runner: hooks::Runner,
+    links: Vec<(OsString, PathBuf)>,
+    /// Windows given as options, for the devices which aren't listed.
+    windows: schedule::Windows,
+    /// Time zone of the spin down windows, if any.
+    time_zone: Option<TimeZone>,
+    watch_files: bool,
+    only_listed: bool,
+    system: System,
+    _pidfile: Option<PidFile>,
+}
+
+impl App {
+    pub fn new(
+        default_config: DeviceConfig,
+        mut device_configs: Vec<(OsString, DeviceConfig)>,
+        options: Options,
+    ) -> Result<Option<Self>> {
+        let system = options.system;
+        let mut devices_monitor = match &options.events {
+            Some(path) => IOMonitor::with_events(path)?,
+            None => (system.diskstats)()?,
+        };
+        for (dev, members) in &options.members {
+            devices_monitor.set_members(dev, members);
+        }
+        devices_monitor.shrink_after(options.shrink_after);
+        let saved_timers = match &options.state_file {
+            Some(path) => timers::load(Path::new(path), SystemTime::now() - TIMERS_MAX_AGE)
+                .unwrap_or_else(|e| {
+                    elogln!("<4>{}, not restoring the idle timers", e);
+                    Vec::new()
+                }),
+            None => Vec::new(),
+        };
+        let mut min_idle_time = if default_config.idle_time > Duration::ZERO && !options.only_listed
+        {
+            default_config.idle_time
+        } else {
+            Duration::MAX
+        };
+
+        // Insert configured devices in the IOMonitor while checking for duplicates
+        device_configs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut prev_name = OsStr::new("");
+        for (dev, mut config) in device_configs {
+            if prev_name == dev {
+                return Err(format!("Duplicated device: {}", dev.to_string_lossy()))
+                    .kind(ErrorKind::Config);
+            }
+            configure_device(
+                &system,
+                &dev,
+                &mut config,
+                !(options.monitor || options.dry_run),
+            );
+            if config.idle_time > Duration::ZERO {
+                min_idle_time = min_idle_time.min(config.idle_time);
+            }
+            let mut data = DeviceData {
+                listed: true,
+                link: link_of(&options.links, &dev),
+                ..device_data(&system, &dev, config, &options.busy_files, &options.hooks)
+            };
+            restore_timer(
+                &dev,
+                &mut data,
+                &saved_timers,
+                Instant::now(),
+                SystemTime::now(),
+            );
+            prev_name = devices_monitor.push(dev, data).name();
+        }
+
+        let interval = refresh_interval(min_idle_time, options.monitor);
+        if default_config.verbosity >= 2 {
+            logln!(
+                "<6>Default device configuration: {}. Refresh period: {}s",
+                default_config,
+                interval.as_secs()
+            );
+        }
+
+        Ok(if min_idle_time == Duration::MAX && !options.monitor {
+            None // No device with an idle_time > 0, show usage and exit
+        } else {
+            if let Some(adj) = options.oom_score_adj
+                && let Err(e) = sys::set_oom_score_adj(adj)
+            {
+                elogln!("<4>Failed to adjust the OOM score: {}", e);
+            }
+            if let Some(tag) = &options.name {
+                let mut name = OsString::from("rust-idle-");
+                name.push(tag);
+                sys::set_process_name(&name);
+            }
+            let mut mounts = (system.mounts)()?;
+            mounts.shrink_after(options.shrink_after);
+            let swaps = (system.swaps)()
+                .inspect_err(|e| elogln!("<4>{}, not checking the swap areas", e))
+                .ok()
+                .map(|mut swaps| {
+                    warn_swap_disks(&mut swaps, &devices_monitor);
+                    swaps
+                });
+            let http = options
+                .http
+                .as_deref()
+                .map(http::Server::bind)
+                .transpose()?;
+            let events = EventLoop::new()?;
+            if let Some(http) = &http {
+                events.add(http.as_raw_fd(), HTTP_EVENT)?;
+            }
+            let socket = options
+                .socket
+                .as_deref()
+                .map(|path| control::Server::bind(Path::new(path)))
+                .transpose()?;
+            if let Some(socket) = &socket {
+                events.add(socket.as_raw_fd(), SOCKET_EVENT)?;
+            }
+            let metrics = options
+                .metrics
+                .as_deref()
+                .map(http::Server::bind)
+                .transpose()?;
+            if let Some(metrics) = &metrics {
+                events.add(metrics.as_raw_fd(), METRICS_EVENT)?;
+            }
+            let syncer = if options.monitor || options.dry_run {
+                None
+            } else {
+                let mut mounts = (system.mounts)()?;
+                Syncer::spawn(move |request: &SyncRequest| {
+                    mounts.update();
+                    sync_block_device(&mut mounts, &request.dev, &request.config)
+                })
+                .inspect_err(|e| elogln!("<4>{}, syncing in the main loop", e))
+                .ok()
+            };
+            if let Some(syncer) = &syncer {
+                events.add(syncer.as_raw_fd(), SYNC_EVENT)?;
+            }
+            let app = Self {
+                devices_monitor,
+                mounts,
+                swaps,
+                open_devices: OpenDevices::new(),
+                policy: match options.policy {
+                    Some(policy) => policy,
+                    None if options.monitor => Box::new(MonitorPolicy),
+                    None => Box::new(IdleTimePolicy),
+                },
+                default_config,
+                interval,
+                parked_interval: options.parked_interval,
+                quick_interval: options.quick_interval,
+                poll: interval,
+                cycles: 0,
+                last_cycle: None,
+                last_boot_time: sys::boot_time(),
+                monitor: options.monitor,
+                dry_run: options.dry_run,
+                slow_command: options.slow_command,
+                status: options.status,
+                state_file: options.state_file,
+                saved_timers,
+                http,
+                socket,
+                metrics,
+                events,
+                syncer,
+                statsd: options
+                    .statsd
+                    .as_deref()
+                    .map(|addr| StatsD::connect(addr, options.statsd_interval))
+                    .transpose()?,
+                audit: options.audit.map(Audit::open).transpose()?,
+                notifier: Notifier::from_env().unwrap_or_else(|e| {
+                    elogln!("<4>{}", e);
+                    None
+                }),
+                busy_files: options.busy_files,
+                hooks: options.hooks,
+                hook_runner: hooks::Runner::default(),
+                links: options.links,
+                time_zone: (!options.windows.is_empty()).then(|| system_time_zone(&system)),
+                windows: options.windows,
+                watch_files: options.watch_files,
+                only_listed: options.only_listed,
+                system,
+                _pidfile: options.pidfile.map(PidFile::create).transpose()?,
+            };
+            if let Some(notifier) = &app.notifier
+                && let Err(e) = notifier.notify("READY=1")
+            {
+                elogln!("<4>{}", e);
+            }
+            Some(app)
+        })
+    }
+
+    fn tick(&mut self) -> Result<bool> {
+        self.hook_runner.reap();
+        self.mounts.update(); // Clear the mount table, will lazy load when needed.
+        if let Some(swaps) = &mut self.swaps {
+            swaps.update();
+        }
+        self.open_devices.update();
+        if self.watch_files {
+            for device in self.devices_monitor.devices_mut() {
+                if !device.data.watch_attempted {
+                    device.data.watch_attempted = true;
+                    watch_files(device, &mut self.mounts);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        self.check_resume(now);
+        let wall_time = SystemTime::now();
+        let mut env = TickEnv {
+            now,
+            wall_time,
+            mounts: &mut self.mounts,
+            swaps: self.swaps.as_mut(),
+            open_devices: &mut self.open_devices,
+            policy: &*self.policy,
+            slow_command: self.slow_command,
+            audit: self.audit.as_ref(),
+            dry_run: self.dry_run,
+            monitor: self.monitor,
+            hooks: &mut self.hook_runner,
+            syncer: self.syncer.as_ref(),
+            time_of_day: self
+                .time_zone
+                .as_ref()
+                .map(|zone| zone.minute_of_day(wall_time)),
+            disks_changed: false,
+        };
+        let mut will_sleep = true;
+
+        // Neither when only monitoring the drives, nor in a dry run
+        let program = !(self.monitor || self.dry_run);
+        let create = |name: &OsStr| {
+            if self.default_config.verbosity >= 1 {
+                logln!("<5>New device detected: {}", name.to_string_lossy());
+            }
+            let mut config = discovered_config(&self.default_config, self.only_listed);
+            config.windows = self.windows.of(name);
+            (self.system.firmware)(name, &mut config, program);
+            let mut data = device_data(&self.system, name, config, &self.busy_files, &self.hooks);
+            restore_timer(name, &mut data, &self.saved_timers, now, wall_time);
+            data
+        };
+        self.devices_monitor
+            .check_activity(|device| update(device, &mut env), create)?;
+        reconcile_renamed(&mut self.devices_monitor);
+        if env.disks_changed {
+            let resolve = |link: &Path| {
+                let mut disks = (self.system.resolve)(link.as_os_str(), 0).ok()?;
+                (disks.len() == 1).then(|| disks.pop()).flatten()
+            };
+            follow_links(&mut self.devices_monitor, resolve, create);
+        }
+        self.devices_monitor.retain(|device| {
+            let forget = !device.data.listed && device.data.missing_ticks >= MISSING_TICKS;
+            if forget && device.data.config.verbosity >= 2 {
+                logln!("<6>Forgetting {}", device.name().to_string_lossy());
+            }
+            !forget
+        });
+        // Spin down the disks synced in the background since the last refresh
+        while let Some((request, synced)) = self.syncer.as_ref().and_then(Syncer::completed) {
+            will_sleep &= !finish_sync(&mut self.devices_monitor, request, synced, &mut env);
+        }
+
+        // Cancel the vetoed spin downs first, not to sync for them
+        let mut devices: Vec<_> = self.devices_monitor.devices_mut().collect();
+        check_vetoes(&mut devices, &mut env);
+
+        // Sync everything before any device parks, if one of them asks for it
+        let devices = self.devices_monitor.devices();
+        if wants_global_sync(devices.map(|d| (&d.data.config, d.data.pending))) {
+            sync_all(&self.system, self.dry_run, self.default_config.verbosity);
+        }
+
+        if self.monitor {
+            for device in self.devices_monitor.devices() {
+                logln!(
+                    "<6>{}: {} sectors, idle for {}s ({:.1} sectors/s)",
+                    device.name().to_string_lossy(),
+                    device.data.sectors.total(),
+                    device.data.idle_time.as_secs(),
+                    device.data.rate
+                );
+            }
+        }
+
+        for device in self.devices_monitor.devices_mut() {
+            let acting = device.data.pending != Action::None;
+            let new_state = execute(device, &mut env);
+            // Immediately refresh the statistics while ignoring activity
+            // from syncing this device.
+            will_sleep &= !(acting && new_state == DeviceState::Synced());
+        }
+
+        // Spin down together the idle disks due, once synced and their spin
+        // down delay elapsed. The later ones are left to the next ticks.
+        let mut due: Vec<_> = (self.devices_monitor.devices_mut())
+            .filter(|device| device.data.deferred_stop.is_some_and(|at| at <= now))
+            .collect();
+        if !due.is_empty() {
+            stop(&mut due, &mut env);
+        }
+
+        // The counters read after the syncs are the baseline of the next
+        // tick, so that their writes are not taken for activity.
+        if !will_sleep {
+            self.devices_monitor.check_activity(
+                |device| {
+                    let (_, sectors, _, data) = device.into();
+                    if data.state == DeviceState::Synced() {
+                        data.sectors = sectors;
+                    }
+                },
+                create,
+            )?;
+        }
+
+        if let Some(path) = &self.status
+            && let Err(e) = self.write_status_file(path.as_ref(), now)
+        {
+            elogln!(
+                "<4>Failed to write the status to {}: {}",
+                path.to_string_lossy(),
+                e
+            );
+        }
+        if let Some(path) = &self.state_file
+            && let Err(e) = self.save_timers(path.as_ref(), now)
+        {
+            elogln!("<4>{}", e);
+        }
+        // Disks may show up late after a restart: their timers are kept until
+        // restored, or too old
+        let devices = &self.devices_monitor;
+        self.saved_timers.retain(|(key, timer)| {
+            wall_time.duration_since(timer.saved_at).unwrap_or_default() < TIMERS_MAX_AGE
+                && !devices
+                    .devices()
+                    .any(|device| device.data.id.as_deref().unwrap_or(device.name()) == key)
+        });
+
+        if let Some(statsd) = &mut self.statsd
+            && statsd.due(now)
+        {
+            for device in self.devices_monitor.devices() {
+                let (dev, data) = (device.name(), &device.data);
+                let spinning = data.state == DeviceState::Spinning();
+                statsd.gauge(dev, "spinning", spinning.into());
+                statsd.gauge(dev, "idle_seconds", data.idle_time.as_secs());
+                statsd.count(dev, "spindowns", data.spindowns);
+            }
+            statsd.flush(now);
+        }
+
+        let cycles = (self.devices_monitor.devices())
+            .map(|device| device.data.spindowns + device.data.spinups)
+            .sum();
+        if cycles != self.cycles {
+            self.cycles = cycles;
+            self.last_cycle = Some(now);
+        }
+        if let Some(quick) = quick_poll(self.quick_interval, self.interval, self.last_cycle, now) {
+            self.poll = quick;
+        } else if let Some(cap) = self.parked_interval {
+            let parked = all_parked(self.devices_monitor.devices().map(|device| &device.data));
+            self.poll = next_poll(self.poll, self.interval, cap, parked);
+        } else {
+            self.poll = self.interval;
+        }
+
+        self.last_boot_time = sys::boot_time();
+        Ok(will_sleep)
+    }
+
+    /// Restarts the idle timers after a suspend, detected when the boot clock
+    /// (which keeps running while suspended) ran much longer than the refresh
+    /// period since the last tick.
+    fn check_resume(&mut self, now: Instant) {
+        let slept = sys::boot_time().saturating_sub(self.last_boot_time);
+        if slept <= self.poll * 3 {
+            return;
+        }
+        if self.default_config.verbosity >= 2 {
+            logln!(
+                "<6>Resumed after {}s, restarting the idle timers",
+                slept.as_secs()
+            );
+        }
+        for device in self.devices_monitor.devices_mut() {
+            device.data.last_io = now;
+        }
+    }
+
+    /// Replaces the status file, through a temporary file.
+    fn write_status_file(&self, path: &Path, now: Instant) -> Result<()> {
+        let json = path.extension().is_some_and(|ext| ext == "json");
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut status = Vec::new();
+        self.write_status(&mut status, json, now)?;
+        std::fs::write(&tmp_path, status)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .context("Writing the status")
+    }
+
+    /// Saves the idle timers of the present devices, keyed by their identity.
+    fn save_timers(&self, path: &Path, now: Instant) -> Result<()> {
+        let saved_at = SystemTime::now();
+        let saved = (self.devices_monitor.devices())
+            .filter(|device| device.data.state != DeviceState::Missing())
+            .map(|device| {
+                let data = &device.data;
+                let timer = Timer {
+                    idle: data.state == DeviceState::Idle(),
+                    idle_time: now.saturating_duration_since(data.last_io),
+                    saved_at,
+                    sectors: data.sectors,
+                };
+                (data.id.as_deref().unwrap_or(device.name()), timer)
+            });
+        timers::save(path, saved)
+    }
+
+    /// Writes the status of the devices, one per line or as a JSON document.
+    fn write_status<W: Write>(&self, out: &mut W, json: bool, now: Instant) -> Result<()> {
+        let devices = self.devices_monitor.devices().map(|device| {
+            let data = &device.data;
+            DeviceStatus {
+                name: device.name(),
+                state: data.state,
+                idle_time: data.idle_time,
+                sectors: data.sectors,
+                rate: data.rate,
+                spindowns: data.spindowns,
+                spinups: data.spinups,
+                last_error: (data.last_error.as_ref())
+                    .map(|(time, msg)| (now.duration_since(*time), msg.as_str())),
+                last_access: data.last_access.as_ref(),
+            }
+        });
+        status::write(out, devices, json)?;
+        Ok(())
+    }
+
+    /// Writes the metrics of the devices in the Prometheus text format.
+    fn write_metrics<W: Write>(&self, out: &mut W) -> Result<()> {
+        let devices: Vec<_> = (self.devices_monitor.devices())
+            .map(|device| DeviceMetrics {
+                name: device.name(),
+                state: device.data.state,
+                idle_time: device.data.idle_time,
+                spindowns: device.data.spindowns,
+                spinups: device.data.spinups,
+            })
+            .collect();
+        metrics::write(out, &devices)?;
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        // The signals are only checked between the ticks, letting the syncs and
+        // spin-downs in progress complete
+        self.run_until(|| signals::received().is_some())?;
+        if let Some(signum) = signals::received() {
+            logln!("<5>Shutting down (signal {})", signum);
+        }
+        if let Some(path) = &self.state_file
+            && let Err(e) = self.save_timers(path.as_ref(), Instant::now())
+        {
+            elogln!("<4>{}", e);
+        }
+        Ok(())
+    }
+
+    /// Runs the main loop until `stop` returns true, checked after each tick.
+    fn run_until<F: FnMut() -> bool>(&mut self, mut stop: F) -> Result<()> {
+        loop {
+            if signals::take_hangup()
+                && let Err(e) = self.reload()
+            {
+                elogln!("<3>Failed to reload the configuration: {}", e);
+            }
+            self.keep_alive(Instant::now());
+            let will_sleep = self.tick()?;
+            if stop() {
+                return Ok(());
+            }
+            if will_sleep {
+                self.sleep(self.next_tick_in(Instant::now()))?;
+            }
+        }
+    }
+
+    /// Time until the next tick: the refresh period, or less for a deferred
+    /// spin down due before.
+    fn next_tick_in(&self, now: Instant) -> Duration {
+        (self.devices_monitor.devices())
+            .filter_map(|device| device.data.deferred_stop)
+            .map(|at| at.saturating_duration_since(now))
+            .fold(self.poll, Duration::min)
+    }
+
+    /// Waits for the next refresh, serving the HTTP requests (including the
+    /// metrics) and the commands of the control socket meanwhile.
+    fn sleep(&mut self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            // Woken up early to ping the watchdog
+            let wake_up = self
+                .keep_alive(now)
+                .map_or(deadline, |ping| ping.min(deadline));
+            self.events.set_timer(wake_up - now)?;
+            let blocked = signals::block()?;
+            if signals::received().is_some() || signals::hangup_pending() {
+                break;
+            }
+            let event = self.events.wait(Some(blocked.mask()))?;
+            drop(blocked);
+            match event {
+                Some(HTTP_EVENT) => {
+                    if let Some(conn) = self.http.as_ref().and_then(http::Server::accept) {
+                        self.serve(conn, false);
+                    }
+                }
+                Some(METRICS_EVENT) => {
+                    if let Some(conn) = self.metrics.as_ref().and_then(http::Server::accept) {
+                        self.serve(conn, true);
+                    }
+                }
+                Some(SOCKET_EVENT) => {
+                    if let Some(conn) = self.socket.as_ref().and_then(control::Server::accept) {
+                        self.serve_command(conn);
+                    }
+                }
+                // A synced disk may be spun down now
+                Some(SYNC_EVENT) => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Pings the watchdog of systemd if it is due, returning the time of the
+    /// next ping.
+    fn keep_alive(&mut self, now: Instant) -> Option<Instant> {
+        let notifier = self.notifier.as_mut()?;
+        notifier.keep_alive(now).unwrap_or_else(|e| {
+            elogln!("<4>{}", e);
+            None
+        })
+    }
+
+    /// Reads the command line and the configuration files again (on SIGHUP or
+    /// `POST /reload`), and applies the new configurations to the devices
+    /// without resetting their timers and statistics. The devices no longer
+    /// listed get the default configuration. The other options are kept,
+    /// except the links the devices are given by, the spin down windows, the
+    /// hooks and the busy files.
+    fn reload(&mut self) -> Result<()> {
+        let args = parse_arguments(env::args_os(), self.system)?;
+        self.links = args.options.links;
+        self.windows = args.options.windows;
+        self.hooks = args.options.hooks;
+        self.busy_files = args.options.busy_files;
+        self.reconfigure(args.default_config, args.device_configs);
+        Ok(())
+    }
+
+    /// Applies new configurations to the devices, see [`App::reload`].
+    fn reconfigure(
+        &mut self,
+        default_config: DeviceConfig,
+        mut device_configs: Vec<(OsString, DeviceConfig)>,
+    ) {
+        let program = !(self.monitor || self.dry_run);
+        for device in self.devices_monitor.devices_mut() {
+            let (dev, _, _, data) = device.into();
+            let idx = device_configs.iter().position(|(name, _)| name == dev);
+            data.listed = idx.is_some();
+            data.link = link_of(&self.links, dev);
+            data.busy_file = busy_file_of(&self.busy_files, dev);
+            data.hooks = self.hooks.of(dev);
+            data.config = match idx {
+                Some(idx) => {
+                    let (_, mut config) = device_configs.swap_remove(idx);
+                    configure_device(&self.system, dev, &mut config, program);
+                    config
+                }
+                None => {
+                    let mut config = discovered_config(&default_config, self.only_listed);
+                    config.windows = self.windows.of(dev);
+                    (self.system.firmware)(dev, &mut config, program);
+                    config
+                }
+            };
+            data.backend = (self.system.backend)(dev, &data.config);
+        }
+        for (dev, mut config) in device_configs {
+            configure_device(&self.system, &dev, &mut config, program);
+            let data = DeviceData {
+                listed: true,
+                link: link_of(&self.links, &dev),
+                ..device_data(&self.system, &dev, config, &self.busy_files, &self.hooks)
+            };
+            self.devices_monitor.push(dev, data);
+        }
+
+        let mut min_idle_time = if default_config.idle_time > Duration::ZERO && !self.only_listed {
+            default_config.idle_time
+        } else {
+            Duration::MAX
+        };
+        for device in self.devices_monitor.devices() {
+            if device.data.config.idle_time > Duration::ZERO {
+                min_idle_time = min_idle_time.min(device.data.config.idle_time);
+            }
+        }
+        if min_idle_time == Duration::MAX && !self.monitor {
+            elogln!("<4>No disk configured with an idle time > 0, will do nothing");
+        }
+        // The windows given again may be the first ones
+        if self.time_zone.is_none()
+            && self
+                .devices_monitor
+                .devices()
+                .any(|device| !device.data.config.windows.is_empty())
+        {
+            self.time_zone = Some(system_time_zone(&self.system));
+        }
+        self.default_config = default_config;
+        // Waits for another reload at the pace of the monitor mode, if unmanaged
+        self.interval = refresh_interval(min_idle_time, true);
+        self.poll = self.interval;
+        if self.default_config.verbosity >= 1 {
+            logln!(
+                "<5>Configuration reloaded. Refresh period: {}s",
+                self.interval.as_secs()
+            );
+        }
+    }
+
+    /// Answers a request of the HTTP control API, or only the metrics if
+    /// `metrics_only`.
+    fn serve(&mut self, mut conn: http::Connection, metrics_only: bool) {
+        let now = Instant::now();
+        let text = |status, body: Vec<u8>| (status, "text/plain", body);
+        let (status, content_type, body) = match conn.read_request() {
+            Ok(request) if metrics_only && request != http::Request::Metrics => {
+                text(404, http::reason(404).as_bytes().to_vec())
+            }
+            Ok(http::Request::Status) => {
+                let mut status = Vec::new();
+                match self.write_status(&mut status, true, now) {
+                    Ok(()) => (200, "application/json", status),
+                    Err(e) => text(500, e.to_string().into_bytes()),
+                }
+            }
+            Ok(http::Request::Metrics) => {
+                let mut metrics = Vec::new();
+                match self.write_metrics(&mut metrics) {
+                    Ok(()) => (200, "text/plain; version=0.0.4", metrics),
+                    Err(e) => text(500, e.to_string().into_bytes()),
+                }
+            }
+            Ok(http::Request::Spinup(dev)) => {
+                let (status, body) = self.control(&dev, true, now, "http");
+                text(status, body)
+            }
+            Ok(http::Request::Spindown(dev)) => {
+                let (status, body) = self.control(&dev, false, now, "http");
+                text(status, body)
+            }
+            Ok(http::Request::Reload) => match self.reload() {
+                Ok(()) => text(200, b"OK".to_vec()),
+                Err(e) => text(500, e.to_string().into_bytes()),
+            },
+            Err(status) => text(status, http::reason(status).as_bytes().to_vec()),
+        };
+        if let Err(e) = conn.respond(status, content_type, &body)
+            && self.default_config.verbosity >= 2
+        {
+            elogln!("<4>{}", e);
+        }
+    }
+
+    /// Answers a command of the control socket.
+    fn serve_command(&mut self, mut conn: control::Connection) {
+        let now = Instant::now();
+        let response = match conn.read_command() {
+            Ok(control::Command::Status { json }) => {
+                let mut status = Vec::new();
+                match self.write_status(&mut status, json, now) {
+                    Ok(()) => status,
+                    Err(e) => format!("error: {}\n", e).into_bytes(),
+                }
+            }
+            Ok(control::Command::Spindown(dev)) => match self.control(&dev, false, now, "socket") {
+                (200, _) => b"OK\n".to_vec(),
+                (202, message) => {
+                    format!("OK: {}\n", String::from_utf8_lossy(&message)).into_bytes()
+                }
+                (_, message) => {
+                    format!("error: {}\n", String::from_utf8_lossy(&message)).into_bytes()
+                }
+            },
+            Ok(control::Command::Config(dev)) => match self.devices_monitor.get_mut(&dev) {
+                Some(device) => format!("{}\n", device.data.config).into_bytes(),
+                None => b"error: Unknown device\n".to_vec(),
+            },
+            Err(message) => format!("error: {}\n", message).into_bytes(),
+        };
+        if let Err(e) = conn.respond(&response)
+            && self.default_config.verbosity >= 2
+        {
+            elogln!("<4>{}", e);
+        }
+    }
+
+    /// Spins a device up or down on request, outside of its idle policy,
+    /// unless only monitoring. `reason` tells where the request came from, for
+    /// the log and audit trail.
+    ///
+    /// The request takes the path of the policy's actions: a spin down is
+    /// vetoed like them, and synced as configured, which may defer it (202).
+    fn control(
+        &mut self,
+        dev: &OsStr,
+        spinup: bool,
+        now: Instant,
+        reason: &'static str,
+    ) -> (u16, Vec<u8>) {
+        if self.monitor {
+            return (409, b"Only monitoring the devices".to_vec());
+        }
+        let Some(device) = self.devices_monitor.get_mut(dev) else {
+            return (404, b"Unknown device".to_vec());
+        };
+        let (dev_name, _, _, data) = (&mut *device).into();
+        if data.state == DeviceState::Missing() {
+            return (409, b"Missing device".to_vec());
+        }
+        if !spinup
+            && let Some(swaps) = &mut self.swaps
+            && swaps.on_dev(dev_name).unwrap_or(false)
+        {
+            return (409, b"Holds an active swap area".to_vec());
+        }
+        if !spinup && (data.syncing || data.deferred_stop.is_some()) {
+            return (202, b"Already spinning down".to_vec());
+        }
+        let state = data.state;
+        if spinup {
+            let awake_until = now + data.config.min_uptime;
+            wake(data, now, awake_until);
+        } else {
+            let config = &data.config;
+            let sync = config.sync_flags & SYNC_SPIN_DOWN != 0;
+            data.pending = Action::Spindown { sync };
+            // Ignore the writes of the sync at the next ticks
+            if sync || config.sync_flags & SYNC_GLOBAL != 0 {
+                data.state = DeviceState::Synced();
+                data.grace_ticks = config.grace_ticks;
+            } else {
+                data.state = DeviceState::Idle();
+            }
+        }
+        data.request = Some(reason);
+        let counters = (data.spinups, data.spindowns);
+
+        let mut env = TickEnv {
+            now,
+            wall_time: SystemTime::now(),
+            mounts: &mut self.mounts,
+            swaps: self.swaps.as_mut(),
+            open_devices: &mut self.open_devices,
+            policy: &*self.policy,
+            slow_command: self.slow_command,
+            audit: self.audit.as_ref(),
+            dry_run: self.dry_run,
+            monitor: self.monitor,
+            hooks: &mut self.hook_runner,
+            syncer: self.syncer.as_ref(),
+            time_of_day: None,
+            disks_changed: false,
+        };
+        check_vetoes(&mut [&mut *device], &mut env);
+        if device.data.pending == Action::None {
+            device.data.state = state;
+            device.data.request = None;
+            return (409, b"Spin down vetoed".to_vec());
+        }
+        if wants_global_sync([(&device.data.config, device.data.pending)]) {
+            sync_all(&self.system, self.dry_run, self.default_config.verbosity);
+        }
+        execute(device, &mut env);
+        if device.data.syncing {
+            return (202, b"Spinning down once synced".to_vec());
+        }
+        if device.data.deferred_stop.is_some_and(|at| at > now) {
+            return (202, b"Spinning down after its delay".to_vec());
+        }
+        if !spinup {
+            stop(&mut [&mut *device], &mut env);
+        }
+
+        let data = &device.data;
+        if self.dry_run {
+            (200, b"OK (dry-run)".to_vec())
+        } else if (data.spinups, data.spindowns) != counters {
+            (200, b"OK".to_vec())
+        } else if data.not_ready {
+            (503, b"Not ready".to_vec())
+        } else {
+            let message = data.last_error.as_ref().map(|(_, e)| e.as_str());
+            (500, message.unwrap_or("Failed").as_bytes().to_vec())
+        }
+    }
+}
+
+/// Syncs all the filesystems, before a device asking for it spins down.
+fn sync_all(system: &System, dry_run: bool, verbosity: u8) {
+    if dry_run {
+        if verbosity >= 1 {
+            logln!("<5>Would sync all filesystems (dry-run)");
+        }
+        return;
+    }
+    if verbosity >= 2 {
+        logln!("<6>Syncing all filesystems");
+    }
+    if let Err(e) = (system.sync_all)() {
+        elogln!("<4>Failed to sync all filesystems: {}", e);
+    }
+}
+
+/// Describes a disk by its vendor, model and serial number, if it answers a
+/// SCSI INQUIRY.
+fn identify(dev: &OsStr, verbosity: u8) -> Option<String> {
+    let id = sys::inquiry(dev, verbosity).ok()?;
+    let mut identity = format!("{} {}", id.vendor, id.product);
+    if let Ok(serial) = sys::serial_number(dev, verbosity) {
+        identity.push_str(", S/N ");
+        identity.push_str(&serial);
+    }
+    Some(identity)
+}
+
+/// Sets the Advanced Power Management level of the drive, if configured and
+/// the drives may be `program`med.
+fn apply_apm_level(dev: &OsStr, config: &DeviceConfig, program: bool) {
+    if config.apm_level == 0 || !program {
+        return;
+    }
+    match sys::set_apm(dev, config.apm_level, config.verbosity) {
+        Ok(()) if config.verbosity >= 2 => logln!(
+            "<6>APM level of {} set to {}",
+            dev.to_string_lossy(),
+            config.apm_level
+        ),
+        Ok(()) => {}
+        Err(e) => elogln!(
+            "<4>Failed to set the APM level of {}: {}",
+            dev.to_string_lossy(),
+            e
+        ),
+    }
+}
+
+/// Sets the idle time slightly shorter than the standby timer of the drive's
+/// firmware, if the configuration leaves the spin down to it. The timer is
+/// programmed first when the configuration sets one, and `program` allows it.
+///
+/// Falls back on spinning down the disk after the configured idle time when
+/// the timer can't be read or is disabled.
+fn apply_firmware_timer(dev: &OsStr, config: &mut DeviceConfig, program: bool) {
+    if !config.firmware_timer {
+        return;
+    }
+    if config.standby_timer > Duration::ZERO && program {
+        match sys::set_standby_timer(dev, config.standby_timer, config.verbosity) {
+            Ok(timer) => {
+                config.idle_time = timer * 4 / 5;
+                if config.verbosity >= 2 {
+                    logln!(
+                        "<6>Standby timer of {} set to {}s, syncing it after {}s",
+                        dev.to_string_lossy(),
+                        timer.as_secs(),
+                        config.idle_time.as_secs()
+                    );
+                }
+                return;
+            }
+            Err(e) => elogln!(
+                "<4>Failed to set the standby timer of {}: {}",
+                dev.to_string_lossy(),
+                e
+            ),
+        }
+    }
+    match sys::standby_timer(dev, config.verbosity) {
+        Ok(Some(timer)) => {
+            config.idle_time = timer * 4 / 5;
+            if config.verbosity >= 2 {
+                logln!(
+                    "<6>Standby timer of {}: {}s, syncing it after {}s",
+                    dev.to_string_lossy(),
+                    timer.as_secs(),
+                    config.idle_time.as_secs()
+                );
+            }
+        }
+        Ok(None) => {
+            elogln!(
+                "<4>Standby timer of {} is disabled, spinning it down after {}s",
+                dev.to_string_lossy(),
+                config.idle_time.as_secs()
+            );
+            config.firmware_timer = false;
+        }
+        Err(e) => {
+            elogln!(
+                "<4>Failed to read the standby timer of {}, spinning it down after {}s: {}",
+                dev.to_string_lossy(),
+                config.idle_time.as_secs(),
+                e
+            );
+            config.firmware_timer = false;
+        }
+    }
+}
+
+/// Applies the firmware settings of the drive: its standby timer and APM
+/// level, programmed if configured and the drives may be `program`med.
+fn configure_firmware(dev: &OsStr, config: &mut DeviceConfig, program: bool) {
+    apply_firmware_timer(dev, config, program);
+    apply_apm_level(dev, config, program);
+}
+
+/// Applies the firmware settings of a configured device (unless the drives
+/// may not be `program`med), and reports it.
+fn configure_device(system: &System, dev: &OsStr, config: &mut DeviceConfig, program: bool) {
+    (system.firmware)(dev, config, program);
+    if config.verbosity >= 2
+        && let Some(identity) = identify(dev, config.verbosity)
+    {
+        logln!("<6>Configured {} ({})", dev.to_string_lossy(), identity);
+    }
+    if config.verbosity >= 2 {
+        logln!(
+            "<6>Device {} configured as {}",
+            dev.to_string_lossy(),
+            config
+        );
+    }
+}
+
+/// Initial state of a newly monitored device.
+fn device_data(
+    system: &System,
+    dev: &OsStr,
+    config: DeviceConfig,
+    busy_files: &[(OsString, PathBuf)],
+    hooks: &hooks::Commands,
+) -> DeviceData {
+    DeviceData {
+        id: (system.wwid)(dev),
+        backend: (system.backend)(dev, &config),
+        busy_file: busy_file_of(busy_files, dev),
+        hooks: hooks.of(dev),
+        ..config.into()
+    }
+}
+
+/// Restores the idle timer saved by a previous run for a new device, found by
+/// its identity (or its name). The sectors saved along tell the accesses made
+/// in between, at the first update.
+fn restore_timer(
+    dev: &OsStr,
+    data: &mut DeviceData,
+    saved: &[(OsString, Timer)],
+    now: Instant,
+    wall_time: SystemTime,
+) {
+    let key = data.id.as_deref().unwrap_or(dev);
+    let Some((_, timer)) = saved.iter().find(|(saved_key, _)| saved_key == key) else {
+        return;
+    };
+    let idle_time = timer.idle_time_at(wall_time);
+    data.last_io = saturating_sub(now, idle_time);
+    data.sectors = timer.sectors;
+    if timer.idle {
+        data.state = DeviceState::Idle();
+    }
+    if data.config.verbosity >= 2 {
+        logln!(
+            "<6>Restored the idle timer of {}: idle for {}s{}",
+            dev.to_string_lossy(),
+            idle_time.as_secs(),
+            if timer.idle { ", spun down" } else { "" }
+        );
+    }
+}
+
+/// `instant - duration`, or the oldest instant representable (eg. shortly
+/// after boot).
+fn saturating_sub(instant: Instant, duration: Duration) -> Instant {
+    let (mut oldest, mut remaining, mut step) = (instant, duration, duration);
+    while !step.is_zero() {
+        match oldest.checked_sub(step).filter(|_| step <= remaining) {
+            Some(older) => {
+                oldest = older;
+                remaining -= step;
+            }
+            None => step /= 2,
+        }
+    }
+    oldest
+}
+
+/// Warns about the listed disks holding an active swap area, which are never
+/// spun down.
+fn warn_swap_disks(swaps: &mut Swaps, devices_monitor: &IOMonitor) {
+    for device in devices_monitor
+        .devices()
+        .filter(|device| device.data.listed)
+    {
+        match swaps.on_dev(device.name()) {
+            Ok(false) => {}
+            Ok(true) => elogln!(
+                "<4>{} holds an active swap area, it won't be spun down",
+                device.name().to_string_lossy()
+            ),
+            Err(e) => elogln!("<4>{}", e),
+        }
+    }
+}
+
+/// Refresh period: a tenth of the shortest idle time, at least a second.
+fn refresh_interval(min_idle_time: Duration, monitor: bool) -> Duration {
+    if monitor && min_idle_time == Duration::MAX {
+        MONITOR_INTERVAL
+    } else {
+        (min_idle_time / 10).max(Duration::from_secs(1))
+    }
+}
+
+/// Time zone of the spin down windows, falling back to UTC.
+fn system_time_zone(system: &System) -> TimeZone {
+    (system.time_zone)().unwrap_or_else(|e| {
+        elogln!("<4>{}, the spin down windows are in UTC", e);
+        TimeZone::UTC
+    })
+}
+
+/// Configuration of a device not listed in the arguments: the default one,
+/// or an unmanaged one (only keeping the verbosity) with `only_listed`.
+fn discovered_config(default_config: &DeviceConfig, only_listed: bool) -> DeviceConfig {
+    if only_listed {
+        DeviceConfig {
+            verbosity: default_config.verbosity,
+            ..Default::default()
+        }
+    } else {
+        default_config.clone()
+    }
+}
+
+/// Whether all the managed devices are spun down or missing, and there is at
+/// least one. The disks never spun down (eg. SSDs) are ignored.
+fn all_parked<'a>(devices: impl Iterator<Item = &'a DeviceData>) -> bool {
+    let mut managed = devices
+        .filter(|data| data.config.idle_time > Duration::ZERO)
+        .peekable();
+    managed.peek().is_some()
+        && managed.all(|data| matches!(data.state, DeviceState::Idle() | DeviceState::Missing()))
+}
+
+/// Returns the refresh period following `poll`: it doubles while all the
+/// devices are parked, up to `cap`, and returns to `interval` otherwise.
+fn next_poll(poll: Duration, interval: Duration, cap: Duration, parked: bool) -> Duration {
+    if parked {
+        (poll * 2).min(cap).max(interval)
+    } else {
+        interval
+    }
+}
+
+/// Returns the quick refresh period while `interval` didn't elapse since the
+/// last spin down or up (`last_cycle`), never longer than `interval`.
+fn quick_poll(
+    quick: Option<Duration>,
+    interval: Duration,
+    last_cycle: Option<Instant>,
+    now: Instant,
+) -> Option<Duration> {
+    let recent = last_cycle.is_some_and(|last_cycle| now < last_cycle + interval);
+    quick.filter(|_| recent).map(|quick| quick.min(interval))
+}
+
+fn link_of(links: &[(OsString, PathBuf)], dev: &OsStr) -> Option<PathBuf> {
+    links
+        .iter()
+        .find(|(name, _)| name == dev)
+        .map(|(_, link)| link.clone())
+}
+
+fn busy_file_of(busy_files: &[(OsString, PathBuf)], dev: &OsStr) -> Option<PathBuf> {
+    busy_files
+        .iter()
+        .find(|(name, _)| name == dev)
+        .map(|(_, path)| path.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iomonitor::Sectors;
+    use crate::policy::SYNC_SPIN_UP;
+    use crate::schedule::Window;
+    use crate::testing;
+    use crate::utils::TempDir;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn run_bounded_ticks() {
+        // Block events fixture, read as a stream: sdz is busy at the first tick
+        let dir = TempDir::new("rust-idle-events");
+        let path = dir.write("events", "sdz 8\n");
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let options = Options {
+            events: Some(path.clone().into()),
+            dry_run: true,
+            ..testing::options()
+        };
+        let mut app = App::new(
+            DeviceConfig::default(),
+            vec![("sdz".into(), config)],
+            options,
+        )
+        .unwrap()
+        .expect("a device is configured");
+        let mut ticks = 0;
+        app.run_until(|| {
+            ticks += 1;
+            ticks == 2
+        })
+        .unwrap();
+        assert_eq!(ticks, 2);
+        let state = |app: &mut App| {
+            let sdz = app.devices_monitor.get_mut(OsStr::new("sdz")).unwrap();
+            (sdz.data.state, sdz.data.sectors.total())
+        };
+        assert_eq!(state(&mut app), (DeviceState::Spinning(), 8));
+
+        // Idle for longer than idle_time: spun down (only logged in a dry run)
+        for device in app.devices_monitor.devices_mut() {
+            device.data.last_io -= Duration::from_secs(10);
+        }
+        app.tick().unwrap();
+        assert_eq!(state(&mut app), (DeviceState::Idle(), 8));
+
+        // New events spin it up
+        let mut events = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        events.write_all(b"sdz 16\n").unwrap();
+        app.tick().unwrap();
+        assert_eq!(state(&mut app), (DeviceState::Spinning(), 24));
+    }
+
+    #[test]
+    fn deferred_spindown() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(10),
+            sync_flags: SYNC_SPIN_DOWN,
+            spindown_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let system = System {
+            diskstats: || {
+                Ok(IOMonitor::from_snapshots(&[&testing::diskstats(
+                    "sdz", 0, 0,
+                )]))
+            },
+            ..testing::system()
+        };
+        let options = Options {
+            dry_run: true,
+            system,
+            ..testing::options()
+        };
+        let mut app = App::new(
+            DeviceConfig::default(),
+            vec![("sdz".into(), config)],
+            options,
+        )
+        .unwrap()
+        .expect("a device is configured");
+        for device in app.devices_monitor.devices_mut() {
+            device.data.last_io -= Duration::from_secs(10);
+        }
+        // Synced, the spin down is left to a later tick instead of waiting
+        app.tick().unwrap();
+        let now = Instant::now();
+        let deadline = |app: &mut App| {
+            let sdz = app.devices_monitor.get_mut(OsStr::new("sdz")).unwrap();
+            sdz.data.deferred_stop
+        };
+        let stop_at = deadline(&mut app).expect("the spin down is deferred");
+        assert!(stop_at > now);
+        assert!(app.next_tick_in(now) <= stop_at - now);
+
+        app.tick().unwrap();
+        assert_eq!(deadline(&mut app), Some(stop_at));
+        for device in app.devices_monitor.devices_mut() {
+            device.data.deferred_stop = Some(Instant::now());
+        }
+        app.tick().unwrap();
+        assert_eq!(deadline(&mut app), None);
+    }
+
+    #[test]
+    fn only_listed_devices() {
+        let default = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            sync_flags: SYNC_SPIN_DOWN | SYNC_SPIN_UP,
+            wake_period: Duration::from_secs(86400),
+            verbosity: 1,
+            ..Default::default()
+        };
+        assert!(discovered_config(&default, false) == default);
+        let unmanaged = DeviceConfig {
+            verbosity: 1,
+            ..Default::default()
+        };
+        assert!(discovered_config(&default, true) == unmanaged);
+
+        // The default idle time alone doesn't start the daemon
+        let options = Options {
+            only_listed: true,
+            ..testing::options()
+        };
+        assert!(App::new(default, Vec::new(), options).unwrap().is_none());
+    }
+
+    #[test]
+    fn resume_restarts_timers() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let devices = vec![(OsString::from("sdz"), config)];
+        let mut app = App::new(DeviceConfig::default(), devices, testing::options())
+            .unwrap()
+            .expect("a device is configured");
+        let now = Instant::now() + Duration::from_secs(3600);
+        app.check_resume(now);
+        let last_io = |app: &App| app.devices_monitor.devices().next().unwrap().data.last_io;
+        assert!(last_io(&app) < now);
+
+        // Suspended since boot, in a millisecond tick
+        app.poll = Duration::from_millis(1);
+        app.last_boot_time = Duration::ZERO;
+        app.check_resume(now);
+        assert_eq!(last_io(&app), now);
+    }
+
+    #[test]
+    fn reload_keeps_timers() {
+        let secs = Duration::from_secs;
+        let config = |idle_time| DeviceConfig {
+            idle_time,
+            ..Default::default()
+        };
+        let devices = vec![(OsString::from("sdz"), config(secs(600)))];
+        let mut app = App::new(DeviceConfig::default(), devices, testing::options())
+            .unwrap()
+            .expect("a device is configured");
+        let last_io = Instant::now() - secs(60);
+        for device in app.devices_monitor.devices_mut() {
+            device.data.last_io = last_io;
+            device.data.sectors.written = 42;
+        }
+
+        // sdz is no longer listed, sdy is, and spin down windows are given
+        let devices = vec![(OsString::from("sdy"), config(secs(1200)))];
+        let night = Window::parse(b"22:00-07:00").unwrap();
+        let default_config = DeviceConfig {
+            windows: vec![night],
+            ..config(secs(300))
+        };
+        app.windows.add(OsStr::new("22:00-07:00")).unwrap();
+        let hook = OsStr::new("sdz=/usr/local/bin/led-off");
+        app.hooks.add(hooks::Kind::OnSpindown, hook).unwrap();
+        app.busy_files.push(("sdz".into(), "/run/sdz-busy".into()));
+        assert!(app.time_zone.is_none());
+        app.reconfigure(default_config, devices);
+        assert_eq!(app.interval, secs(30));
+        assert!(app.time_zone.is_some());
+        let sdz = app.devices_monitor.get_mut(OsStr::new("sdz")).unwrap();
+        assert_eq!(sdz.data.config.idle_time, secs(300));
+        assert_eq!(sdz.data.config.windows, [night]);
+        assert_eq!(
+            sdz.data.hooks.on_spindown.as_deref(),
+            Some(Path::new("/usr/local/bin/led-off"))
+        );
+        assert_eq!(
+            sdz.data.busy_file.as_deref(),
+            Some(Path::new("/run/sdz-busy"))
+        );
+        assert_eq!((sdz.data.last_io, sdz.data.sectors.total()), (last_io, 42));
+        let sdy = app.devices_monitor.get_mut(OsStr::new("sdy")).unwrap();
+        assert_eq!(sdy.data.config.idle_time, secs(1200));
+    }
+
+    #[test]
+    fn links_resolved_on_changes() {
+        static RESOLVED: AtomicUsize = AtomicUsize::new(0);
+        let system = System {
+            diskstats: || {
+                Ok(IOMonitor::from_snapshots(&[&testing::diskstats(
+                    "sdz", 0, 0,
+                )]))
+            },
+            resolve: |_, _| {
+                RESOLVED.fetch_add(1, Ordering::Relaxed);
+                Err("No disk".into())
+            },
+            ..testing::system()
+        };
+        let options = Options {
+            system,
+            ..testing::options()
+        };
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let mut app = App::new(
+            DeviceConfig::default(),
+            vec![("sdz".into(), config)],
+            options,
+        )
+        .unwrap()
+        .expect("a device is configured");
+        let sdz = app.devices_monitor.get_mut(OsStr::new("sdz")).unwrap();
+        sdz.data.link = Some("/dev/disk/by-id/ata-DISK_1".into());
+        // Once the device shows up, and then no longer
+        app.tick().unwrap();
+        app.tick().unwrap();
+        assert_eq!(RESOLVED.load(Ordering::Relaxed), 1);
+        // Until another device shows up, or goes missing
+        app.devices_monitor
+            .push("sdy".into(), DeviceConfig::default().into());
+        app.tick().unwrap();
+        app.tick().unwrap();
+        assert_eq!(RESOLVED.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn restored_timers() {
+        let dir = TempDir::new("rust-idle-timers");
+        let path = dir.join("timers");
+        let saved_at = SystemTime::now() - Duration::from_secs(60);
+        let timer = Timer {
+            idle: true,
+            idle_time: Duration::from_secs(540),
+            saved_at,
+            sectors: Sectors {
+                read: 100,
+                written: 80,
+            },
+        };
+        let stale = Timer {
+            saved_at: saved_at - TIMERS_MAX_AGE,
+            ..timer
+        };
+        let late = Timer {
+            idle: false,
+            idle_time: Duration::from_secs(300),
+            ..timer
+        };
+        let saved = [
+            (OsStr::new("sdz"), timer),
+            (OsStr::new("sdy"), stale),
+            (OsStr::new("sdzl"), late),
+        ];
+        timers::save(&path, saved).unwrap();
+
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let devices = vec![("sdz".into(), config.clone()), ("sdy".into(), config)];
+        // Polling /proc/diskstats without any of the disks, then with sdzl
+        let system = System {
+            diskstats: || {
+                let sdzl = testing::diskstats("sdzl", 100, 80);
+                Ok(IOMonitor::from_snapshots(&["", &sdzl]))
+            },
+            ..testing::system()
+        };
+        let options = Options {
+            state_file: Some(path.clone().into()),
+            system,
+            ..testing::options()
+        };
+        let before = Instant::now();
+        let mut app = App::new(DeviceConfig::default(), devices, options)
+            .unwrap()
+            .expect("devices are configured");
+        let sdz = &app.devices_monitor.get_mut(OsStr::new("sdz")).unwrap().data;
+        assert_eq!(
+            (sdz.state, sdz.sectors),
+            (DeviceState::Idle(), timer.sectors)
+        );
+        let idle_since = before.checked_sub(Duration::from_secs(600));
+        assert!(idle_since.is_none_or(|idle_since| sdz.last_io <= idle_since));
+        let sdy = &app.devices_monitor.get_mut(OsStr::new("sdy")).unwrap().data;
+        assert_eq!(sdy.state, DeviceState::Spinning());
+        assert!(sdy.last_io >= before);
+
+        // Both disks are missing and not saved again
+        app.tick().unwrap();
+        let now = SystemTime::now();
+        assert!(
+            timers::load(&path, now - TIMERS_MAX_AGE)
+                .unwrap()
+                .is_empty()
+        );
+        // The timer of a disk showing up late is kept for it
+        let saved: Vec<_> = app.saved_timers.iter().map(|(key, _)| key).collect();
+        assert_eq!(saved, ["sdzl"]);
+        app.tick().unwrap();
+        assert!(app.saved_timers.is_empty());
+        let sdzl = &app
+            .devices_monitor
+            .get_mut(OsStr::new("sdzl"))
+            .unwrap()
+            .data;
+        assert_eq!(sdzl.state, DeviceState::Spinning());
+        assert!(sdzl.idle_time >= Duration::from_secs(360));
+    }
+
+    #[test]
+    fn saturated_instants() {
+        let now = Instant::now();
+        assert_eq!(
+            saturating_sub(now, Duration::from_secs(60)),
+            now - Duration::from_secs(60)
+        );
+        // Not representable, the oldest instant is the closest
+        let oldest = saturating_sub(now, Duration::MAX);
+        assert!(oldest <= now && oldest.checked_sub(Duration::from_nanos(1)).is_none());
+    }
+
+    #[test]
+    fn parked_poll() {
+        let secs = Duration::from_secs;
+        let mut poll = secs(60);
+        let mut polls = Vec::new();
+        for _ in 0..5 {
+            poll = next_poll(poll, secs(60), secs(600), true);
+            polls.push(poll.as_secs());
+        }
+        assert_eq!(polls, [120, 240, 480, 600, 600]);
+        assert_eq!(next_poll(poll, secs(60), secs(600), false), secs(60));
+        // A cap below the refresh period doesn't shorten it
+        assert_eq!(next_poll(secs(60), secs(60), secs(10), true), secs(60));
+    }
+
+    #[test]
+    fn parked_devices() {
+        let device = |idle_time, state| {
+            let mut data = DeviceData::from(DeviceConfig {
+                idle_time: Duration::from_secs(idle_time),
+                ..Default::default()
+            });
+            data.state = state;
+            data
+        };
+        let disk = device(600, DeviceState::Idle());
+        let ssd = device(0, DeviceState::Spinning());
+        let missing = device(600, DeviceState::Missing());
+        let spinning = device(600, DeviceState::Spinning());
+        assert!(all_parked([&disk, &ssd, &missing].into_iter()));
+        assert!(!all_parked([&disk, &spinning].into_iter()));
+        // Nothing to park
+        assert!(!all_parked([&ssd].into_iter()));
+        assert!(!all_parked(std::iter::empty()));
+    }
+
+    #[test]
+    fn quick_poll_after_cycles() {
+        let secs = Duration::from_secs;
+        let now = Instant::now();
+        let quick = Some(secs(5));
+        assert_eq!(
+            quick_poll(quick, secs(60), Some(now - secs(30)), now),
+            quick
+        );
+        assert_eq!(quick_poll(quick, secs(60), Some(now - secs(60)), now), None);
+        assert_eq!(quick_poll(quick, secs(60), None, now), None);
+        assert_eq!(quick_poll(None, secs(60), Some(now), now), None);
+        // Never longer than the refresh period
+        assert_eq!(
+            quick_poll(Some(secs(90)), secs(60), Some(now), now),
+            Some(secs(60))
+        );
+    }
+
+    #[test]
+    fn monitor_without_idle_time() {
+        let options = Options {
+            monitor: true,
+            ..testing::options()
+        };
+        let mut app = App::new(DeviceConfig::default(), Vec::new(), options)
+            .unwrap()
+            .expect("monitor mode runs without idle time");
+        assert_eq!(app.interval, MONITOR_INTERVAL);
+        assert!(app.tick().unwrap());
+        assert!(app.tick().unwrap());
+        for device in app.devices_monitor.devices() {
+            assert_eq!(device.data.state, DeviceState::Spinning());
+        }
+    }
+
+    #[test]
+    fn monitor_refuses_control() {
+        let dir = TempDir::new("rust-idle-monitor-control");
+        let path = dir.join("rust-idle.sock");
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let options = Options {
+            monitor: true,
+            socket: Some(path.clone().into()),
+            ..testing::options()
+        };
+        let mut app = App::new(
+            DeviceConfig::default(),
+            vec![("sdz".into(), config)],
+            options,
+        )
+        .unwrap()
+        .expect("a device is configured");
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"spindown sdz\n").unwrap();
+        let conn = app
+            .socket
+            .as_ref()
+            .and_then(control::Server::accept)
+            .unwrap();
+        app.serve_command(conn);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "error: Only monitoring the devices\n");
+        let (status, _) = app.control(OsStr::new("sdz"), true, Instant::now(), "http");
+        assert_eq!(status, 409);
+        let sdz = &app.devices_monitor.get_mut(OsStr::new("sdz")).unwrap().data;
+        assert_eq!(
+            (sdz.state, sdz.spindowns, sdz.spinups),
+            (DeviceState::Spinning(), 0, 0)
+        );
+    }
+
+    #[test]
+    fn requested_spindown() {
+        static GLOBAL_SYNCS: AtomicUsize = AtomicUsize::new(0);
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            sync_flags: SYNC_GLOBAL,
+            grace_ticks: 2,
+            ..Default::default()
+        };
+        let system = System {
+            sync_all: || {
+                GLOBAL_SYNCS.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            ..testing::system()
+        };
+        let options = Options {
+            system,
+            ..testing::options()
+        };
+        let mut app = App::new(
+            DeviceConfig::default(),
+            vec![("sdz".into(), config)],
+            options,
+        )
+        .unwrap()
+        .expect("a device is configured");
+        let sdz = OsStr::new("sdz");
+        let now = Instant::now();
+        let device = app.devices_monitor.get_mut(sdz).unwrap();
+        device.data.hooks.pre_spindown = Some("/bin/false".into());
+        assert_eq!(app.control(sdz, false, now, "http").0, 409);
+        let data = &mut app.devices_monitor.get_mut(sdz).unwrap().data;
+        assert_eq!(
+            (data.state, data.pending, data.spindowns),
+            (DeviceState::Spinning(), Action::None, 0)
+        );
+        assert_eq!(GLOBAL_SYNCS.load(Ordering::Relaxed), 0);
+
+        data.hooks.pre_spindown = None;
+        assert_eq!(app.control(sdz, false, now, "http"), (200, b"OK".to_vec()));
+        let data = &app.devices_monitor.get_mut(sdz).unwrap().data;
+        assert_eq!(
+            (data.state, data.grace_ticks, data.spindowns, data.request),
+            (DeviceState::Synced(), 2, 1, None)
+        );
+        assert_eq!(GLOBAL_SYNCS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn requested_spindown_synced_first() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            sync_flags: SYNC_SPIN_DOWN,
+            ..Default::default()
+        };
+        let mut app = App::new(
+            DeviceConfig::default(),
+            vec![("sdz".into(), config)],
+            testing::options(),
+        )
+        .unwrap()
+        .expect("a device is configured");
+        let sdz = OsStr::new("sdz");
+        let now = Instant::now();
+        let (status, _) = app.control(sdz, false, now, "socket");
+        assert_eq!(status, 202);
+        let data = &app.devices_monitor.get_mut(sdz).unwrap().data;
+        assert!(data.syncing);
+        assert_eq!((data.request, data.spindowns), (Some("socket"), 0));
+        // Spun down by the refresh completing the sync, not asked again
+        let (status, body) = app.control(sdz, false, now, "socket");
+        assert_eq!(
+            (status, &body[..]),
+            (202, b"Already spinning down".as_slice())
+        );
+    }
+
+    #[test]
+    fn requested_spinup() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            min_uptime: Duration::from_secs(300),
+            sync_flags: SYNC_SPIN_UP,
+            ..Default::default()
+        };
+        let mut app = App::new(
+            DeviceConfig::default(),
+            vec![("sdz".into(), config)],
+            testing::options(),
+        )
+        .unwrap()
+        .expect("a device is configured");
+        let sdz = OsStr::new("sdz");
+        let now = Instant::now();
+        let data = &mut app.devices_monitor.get_mut(sdz).unwrap().data;
+        data.state = DeviceState::Idle();
+        assert_eq!(app.control(sdz, true, now, "http"), (200, b"OK".to_vec()));
+        let data = &app.devices_monitor.get_mut(sdz).unwrap().data;
+        assert_eq!(
+            (data.state, data.pending, data.spinups),
+            (DeviceState::Spinning(), Action::None, 1)
+        );
+        assert_eq!(data.awake_until, now + Duration::from_secs(300));
+        // Synced in the background, like after the other spin ups
+        let syncer = app.syncer.as_ref().unwrap();
+        let (request, _) = (0..500)
+            .find_map(|_| {
+                std::thread::sleep(Duration::from_millis(10));
+                syncer.completed()
+            })
+            .expect("the disk is synced");
+        assert_eq!(
+            (&request.dev[..], request.reason, request.spindown),
+            (sdz, "http", false)
+        );
+    }
+
+    #[test]
+    fn status_formats() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let devices = vec![(OsString::from("sdz"), config.clone())];
+        let mut app = App::new(DeviceConfig::default(), devices, testing::options())
+            .unwrap()
+            .expect("a device is configured");
+        let failed = Instant::now();
+        let now = failed + Duration::from_secs(120);
+        let device = app.devices_monitor.devices_mut().next().unwrap();
+        device.data.last_error = Some((
+            failed,
+            "Failed to spin down sdz: \"no such device\"".to_owned(),
+        ));
+
+        let mut text = Vec::new();
+        app.write_status(&mut text, false, now).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "sdz: spinning, idle for 0s, 0 sectors, 0.0 sectors/s, 0 spin downs, \
+             0 spin ups, last error 120s ago: Failed to spin down sdz: \"no such device\"\n"
+        );
+
+        let mut json = Vec::new();
+        app.write_status(&mut json, true, now).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"devices\":[{\"name\":\"sdz\",\"state\":\"spinning\",\"idle_time\":0,\
+             \"sectors\":0,\"read_sectors\":0,\"write_sectors\":0,\"rate\":0.0,\
+             \"spindowns\":0,\"spinups\":0,\"last_error\":{\"age\":120,\
+             \"message\":\"Failed to spin down sdz: \\\"no such device\\\"\"}}]}\n"
+        );
+    }
+
+    #[test]
+    fn missing_devices() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let devices = vec![(OsString::from("sdz"), config)];
+        let mut app = App::new(DeviceConfig::default(), devices, testing::options())
+            .unwrap()
+            .expect("a device is configured");
+        // The fixture of /proc/diskstats lists none of the disks. Discovered, then unplugged
+        app.devices_monitor
+            .push("sdzy".into(), DeviceConfig::default().into());
+        for _ in 0..MISSING_TICKS {
+            app.tick().unwrap();
+        }
+        let sdz = app.devices_monitor.get_mut(OsStr::new("sdz")).unwrap();
+        assert_eq!(sdz.data.state, DeviceState::Missing());
+        let (status, _) = app.control(OsStr::new("sdz"), false, Instant::now(), "http");
+        assert_eq!(status, 409);
+        // Only the listed device is kept
+        assert!(app.devices_monitor.get_mut(OsStr::new("sdzy")).is_none());
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Audit trail of the actions changing the state of the disks (`--audit`):
+//! syncs, spin downs and spin ups, one line per action appended to a file:
+//!
+//! ```text
+//! time=1700000000 device=sda action=spindown reason=idle idle_time=600 result=ok
+//! ```
+//!
+//! Each line is flushed to the disk before returning. The file should live on a
+//! disk which is not spun down, which would otherwise be woken up.
+
+use std::ffi::OsStr;
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::errors::{Context, Result};
+
+/// An action on a device.
+pub struct Entry<'a> {
+    pub device: &'a OsStr,
+    /// `sync`, `spindown`, `spinup`...
+    pub action: &'a str,
+    /// What triggered the action: `idle`, `activity`, `scheduled`, `http`...
+    pub reason: &'a str,
+    pub idle_time: Duration,
+}
+
+pub struct Audit {
+    file: File,
+}
+
+impl Audit {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o640)
+            .open(path)
+            .with_context(|| format!("Opening the audit trail '{}'", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Appends an entry with the outcome of the action.
+    pub fn record(&self, now: SystemTime, entry: &Entry, result: &Result<()>) -> Result<()> {
+        let line = format_entry(now, entry, result);
+        // `&File` is `Write`: records can be appended through shared references
+        let mut file = &self.file;
+        file.write_all(line.as_bytes())
+            .and_then(|_| self.file.sync_data())
+            .context("Writing the audit trail")
+    }
+}
+
+fn format_entry(now: SystemTime, entry: &Entry, result: &Result<()>) -> String {
+    let time = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut line = format!(
+        "time={} device={} action={} reason={} idle_time={} result=",
+        time,
+        Value(&entry.device.to_string_lossy()),
+        Value(entry.action),
+        Value(entry.reason),
+        entry.idle_time.as_secs()
+    );
+    let _ = match result {
+        Ok(()) => writeln!(line, "ok"),
+        Err(e) => writeln!(line, "error error={}", Value(&e.to_string())),
+    };
+    line
+}
+
+/// Formats a value, quoted if it holds spaces or quotes.
+struct Value<'a>(&'a str);
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.0.is_empty() && !self.0.contains([' ', '"', '=', '\n']) {
+            return f.write_str(self.0);
+        }
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let entry = Entry {
+            device: OsStr::new("sda"),
+            action: "spindown",
+            reason: "idle",
+            idle_time: Duration::from_secs(600),
+        };
+        assert_eq!(
+            format_entry(now, &entry, &Ok(())),
+            "time=1700000000 device=sda action=spindown reason=idle idle_time=600 result=ok\n"
+        );
+        let err = Err("Failed to spin down sda: \"Input/output error\"".into());
+        assert_eq!(
+            format_entry(now, &entry, &err),
+            "time=1700000000 device=sda action=spindown reason=idle idle_time=600 \
+             result=error error=\"Failed to spin down sda: \\\"Input/output error\\\"\"\n"
+        );
+    }
+}
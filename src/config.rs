@@ -0,0 +1,359 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Configuration files holding command line arguments, which can include other
+//! files.
+//!
+//! Each line holds whitespace separated arguments, optionally after a
+//! `RUST_IDLE_OPTS=` prefix (so the environment file of the service can be
+//! loaded), or an `include <path>` directive. `#` starts a comment. Included
+//! paths are relative to the including file, and may contain a `*` wildcard in
+//! their last component (eg. `rust-idle.d/*.conf`), expanded in lexical order.
+//!
+//! A `[default]` or `[device]` header starts a section of `key = value` lines
+//! instead, translated to the equivalent flags: `:<flags>` for the default
+//! configuration, `<device>:<flags>` for a device. A section ends at the next
+//! header or include.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::{Context, Result};
+
+/// Reads the arguments of a configuration file, with its includes expanded in
+/// place.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    load_into(path.as_ref(), &mut Vec::new(), &mut args)?;
+    Ok(args)
+}
+
+/// Flags set by the keys of a section: `(key, flag, takes a value)`.
+const FLAG_KEYS: &[(&str, u8, bool)] = &[
+    ("sync_all", b'G', false),
+    ("dirty_only", b'L', false),
+    ("deadband", b'd', true),
+    ("min_rate", b'e', true),
+    ("min_sectors", b'n', true),
+    ("wake_period", b'w', true),
+    ("wake_duration", b'W', true),
+    ("min_uptime", b'u', true),
+    ("max_spindowns", b'c', true),
+    ("spindown_delay", b'D', true),
+    ("grace_ticks", b'g', true),
+    ("nvme_power_state", b'N', true),
+    ("apm_level", b'a', true),
+    ("power_condition", b'p', true),
+    ("open_check", b'o', false),
+    ("ignore_reads", b'r', false),
+    ("ignore_writes", b'x', false),
+    ("firmware_timer", b't', true),
+];
+
+/// A `[default]` or `[device]` section being read.
+struct Section {
+    /// Line of the header, for the errors.
+    line: usize,
+    device: Option<OsString>,
+    is_default: bool,
+    idle_time: Option<Vec<u8>>,
+    flags: Vec<u8>,
+}
+
+/// Checks a numeric value, with an optional `s`, `m` or `h` unit for a
+/// duration. Seconds are dropped, as a `s` after the digits of the flags would
+/// be taken for the sync flag.
+fn amount(value: &[u8], duration: bool) -> Option<&[u8]> {
+    let (digits, keep_unit) = match value.split_last() {
+        Some((b's', digits)) if duration => (digits, false),
+        Some((b'm' | b'h', digits)) if duration => (digits, true),
+        _ => (value, true),
+    };
+    let valid = !digits.is_empty() && digits.iter().all(u8::is_ascii_digit);
+    valid.then_some(if keep_unit { value } else { digits })
+}
+
+impl Section {
+    /// Parses a `key = value` line.
+    fn set(&mut self, key: &[u8], value: &[u8]) -> std::result::Result<(), String> {
+        let key = String::from_utf8_lossy(key);
+        let invalid = || format!("invalid value for '{}': '{}'", key, value.escape_ascii());
+        let enabled = match value {
+            b"yes" | b"true" | b"on" => Some(true),
+            b"no" | b"false" | b"off" => Some(false),
+            _ => None,
+        };
+        match &*key {
+            "device" if !self.is_default => self.device = Some(OsStr::from_bytes(value).into()),
+            "idle" => self.idle_time = Some(amount(value, true).ok_or_else(invalid)?.to_vec()),
+            "sync" => self.flags.extend_from_slice(match value {
+                b"none" => b"-s-S",
+                b"spin_down" => b"s-S",
+                b"spin_up" => b"-sS",
+                b"both" => b"sS",
+                _ => return Err(invalid()),
+            }),
+            "verbosity" => {
+                let verbosity = match value {
+                    [level @ b'0'..=b'3'] => level - b'0',
+                    _ => return Err(invalid()),
+                };
+                // Relative to the inherited verbosity
+                self.flags.extend_from_slice(b"-vvv+");
+                let verbosity = std::iter::repeat_n(b'v', verbosity as usize);
+                self.flags.extend(verbosity);
+            }
+            _ => {
+                let &(_, flag, takes_value) = FLAG_KEYS
+                    .iter()
+                    .find(|(name, _, _)| *name == key)
+                    .ok_or_else(|| format!("unknown key '{}'", key))?;
+                let duration = matches!(flag, b'd' | b'w' | b'W' | b'u' | b'D' | b't');
+                match (enabled, amount(value, duration)) {
+                    (Some(false), _) => self.flags.extend_from_slice(&[b'-', flag]),
+                    (Some(true), _) if !takes_value || flag == b't' => self.flags.push(flag),
+                    (None, Some(amount)) if takes_value => {
+                        self.flags.push(flag);
+                        self.flags.extend_from_slice(amount);
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Translates the section to an argument.
+    fn into_arg(self) -> std::result::Result<Option<OsString>, String> {
+        // The idle time goes first, as it could be taken for the value of a flag
+        let mut flags = self.idle_time.unwrap_or_default();
+        flags.extend(self.flags);
+        let mut arg = if self.is_default {
+            if flags.is_empty() {
+                return Ok(None);
+            }
+            OsString::new()
+        } else {
+            self.device.ok_or("[device] section without a device")?
+        };
+        if !flags.is_empty() {
+            arg.push(":");
+            arg.push(OsStr::from_bytes(&flags));
+        }
+        Ok(Some(arg))
+    }
+}
+
+/// `stack` holds the files being read, to detect include cycles.
+fn load_into(path: &Path, stack: &mut Vec<PathBuf>, args: &mut Vec<OsString>) -> Result<()> {
+    let path = fs::canonicalize(path)
+        .with_context(|| format!("Opening configuration file '{}'", path.display()))?;
+    if stack.contains(&path) {
+        return Err(format!("Include cycle on '{}'", path.display()).into());
+    }
+    let content = fs::read(&path).with_context(|| format!("Reading '{}'", path.display()))?;
+    let error = |line: usize, message: String| format!("{}:{}: {}", path.display(), line, message);
+    let mut section: Option<Section> = None;
+    let end_section = |section: Option<Section>, args: &mut Vec<OsString>| -> Result<()> {
+        if let Some(section) = section {
+            let line = section.line;
+            args.extend(section.into_arg().map_err(|e| error(line, e))?);
+        }
+        Ok(())
+    };
+    stack.push(path.clone());
+
+    for (idx, line) in content.split(|c| *c == b'\n').enumerate() {
+        let line = match line.iter().position(|c| *c == b'#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let line = line.trim_ascii();
+        if let Some(header) = line.strip_prefix(b"[").and_then(|h| h.strip_suffix(b"]")) {
+            end_section(section.take(), args)?;
+            let is_default = match header.trim_ascii() {
+                b"default" => true,
+                b"device" => false,
+                _ => {
+                    let message = format!("unknown section '{}'", header.escape_ascii());
+                    return Err(error(idx + 1, message).into());
+                }
+            };
+            section = Some(Section {
+                line: idx + 1,
+                device: None,
+                is_default,
+                idle_time: None,
+                flags: Vec::new(),
+            });
+            continue;
+        }
+        let line = line.strip_prefix(b"RUST_IDLE_OPTS=").unwrap_or(line);
+        if let Some(pattern) = line.strip_prefix(b"include")
+            && pattern.first().is_some_and(u8::is_ascii_whitespace)
+        {
+            end_section(section.take(), args)?;
+            let dir = stack
+                .last()
+                .and_then(|path| path.parent())
+                .unwrap_or(Path::new("/"));
+            let pattern = dir.join(OsStr::from_bytes(pattern.trim_ascii()));
+            for include in expand(&pattern)? {
+                load_into(&include, stack, args)?;
+            }
+            continue;
+        }
+        if let Some(section) = &mut section {
+            if line.is_empty() {
+                continue;
+            }
+            let equal = line
+                .iter()
+                .position(|c| *c == b'=')
+                .ok_or_else(|| error(idx + 1, "expected 'key = value'".into()))?;
+            let (key, value) = (&line[..equal], &line[equal + 1..]);
+            section
+                .set(key.trim_ascii(), value.trim_ascii())
+                .map_err(|e| error(idx + 1, e))?;
+            continue;
+        }
+        args.extend(
+            line.split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| OsStr::from_bytes(arg).to_owned()),
+        );
+    }
+
+    stack.pop();
+    end_section(section, args)
+}
+
+/// Expands a `*` wildcard in the last component of a path, sorting the matches.
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let name = pattern.file_name().unwrap_or_default().as_bytes();
+    let Some(star) = name.iter().position(|c| *c == b'*') else {
+        return Ok(vec![pattern.to_owned()]);
+    };
+    let (prefix, suffix) = (&name[..star], &name[star + 1..]);
+    let dir = pattern.parent().unwrap_or(Path::new("/"));
+
+    let mut matches = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Listing '{}'", dir.display()))? {
+        let entry = entry.with_context(|| format!("Listing '{}'", dir.display()))?;
+        let file_name = entry.file_name();
+        let file_name = file_name.as_bytes();
+        if file_name.len() >= prefix.len() + suffix.len()
+            && file_name.starts_with(prefix)
+            && file_name.ends_with(suffix)
+            && !file_name.starts_with(b".")
+        {
+            matches.push(entry.path());
+        }
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn includes() {
+        let dir = TempDir::new("rust-idle-includes");
+        dir.write("rust-idle.d/20-backup.conf", "/dev/sdb:7200s");
+        dir.write("rust-idle.d/10-media.conf", "/dev/sdc:600 # media\n");
+        dir.write("rust-idle.d/.hidden.conf", "/dev/sdd:1");
+        let main = dir.write(
+            "rust-idle.conf",
+            "# Defaults\nRUST_IDLE_OPTS=:600vSs --pidfile /run/rust-idle.pid\n\
+             include rust-idle.d/*.conf\n/dev/sda:0\n",
+        );
+        assert_eq!(
+            strings(load(main).unwrap()),
+            [
+                ":600vSs",
+                "--pidfile",
+                "/run/rust-idle.pid",
+                "/dev/sdc:600",
+                "/dev/sdb:7200s",
+                "/dev/sda:0"
+            ]
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let dir = TempDir::new("rust-idle-sections");
+        let main = dir.write(
+            "rust-idle.conf",
+            "--pidfile /run/rust-idle.pid\n\
+             [default]\nidle = 600\nsync = spin_down\nverbosity = 2\n\n\
+             [device] # backups\ndevice = /dev/disk/by-id/ata-X\nsync = both\n\
+             deadband = 30\nfirmware_timer = yes\nidle = 2h\n\
+             [device]\ndevice = /dev/sdc\n\
+             [device]\ndevice = /dev/sdd\nidle = 300s\nspindown_delay = 90s\n\
+             [device]\ndevice = mount:/media\napm_level = no\nopen_check = on\n",
+        );
+        assert_eq!(
+            strings(load(main).unwrap()),
+            [
+                "--pidfile",
+                "/run/rust-idle.pid",
+                ":600s-S-vvv+vv",
+                "/dev/disk/by-id/ata-X:2hsSd30t",
+                "/dev/sdc",
+                "/dev/sdd:300D90",
+                "mount:/media:-ao"
+            ]
+        );
+
+        for (content, err) in [
+            (
+                "[default]\nidle = 600\nspeed = 3\n",
+                ":3: unknown key 'speed'",
+            ),
+            (
+                "[device]\nidle = 600\n",
+                ":1: [device] section without a device",
+            ),
+            ("[default]\nidle 600\n", ":2: expected 'key = value'"),
+            (
+                "[default]\nsync_all = 3\n",
+                ":2: invalid value for 'sync_all': '3'",
+            ),
+            (
+                "[default]\ngrace_ticks = 3m\n",
+                ":2: invalid value for 'grace_ticks': '3m'",
+            ),
+            (
+                "[default]\nmin_sectors = 64s\n",
+                ":2: invalid value for 'min_sectors': '64s'",
+            ),
+            ("\n[disks]\n", ":2: unknown section 'disks'"),
+        ] {
+            let path = dir.write("bad.conf", content);
+            let message = load(&path).unwrap_err().to_string();
+            assert!(message.ends_with(err), "{}", message);
+        }
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = TempDir::new("rust-idle-cycle");
+        dir.write("a.conf", "include b.conf");
+        let b = dir.write("b.conf", ":600\ninclude a.conf");
+        let err = load(b).unwrap_err().to_string();
+        assert!(err.starts_with("Include cycle"), "{}", err);
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Control socket (`--socket`): text commands on a Unix domain socket, for
+//! scripts (eg. `echo status | socat - UNIX-CONNECT:/run/rust-idle.sock`):
+//! `status [--json]`, `spindown <device>` and `config <device>`.
+//!
+//! Like the HTTP API, connections are served one at a time by the main loop.
+//! Each one carries a single command, answered by lines of text before the
+//! connection is closed.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::errors::{Context, Result};
+
+/// Path of the socket when `--socket` is given without one.
+pub const DEFAULT_PATH: &str = "/run/rust-idle.sock";
+/// Upper bound of the length of a command.
+const MAX_COMMAND_LEN: usize = 1024;
+/// Time given to clients to send their whole command, and to read the
+/// response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// The status of the devices, as JSON with `--json`.
+    Status {
+        json: bool,
+    },
+    Spindown(OsString),
+    Config(OsString),
+}
+
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Server {
+    /// Listens on `path`, only accessible to the owner. A socket left over by
+    /// a previous instance is replaced, unless it is still answering; any
+    /// other file is left alone.
+    pub fn bind(path: &Path) -> Result<Self> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("'{}' is in use by another instance", path.display()).into());
+        }
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                fs::remove_file(path).with_context(|| format!("Removing '{}'", path.display()))?
+            }
+            Ok(_) => {
+                return Err(format!("'{}' exists and is not a socket", path.display()).into());
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Checking '{}'", path.display())),
+        }
+        let listener =
+            bind_private(path).with_context(|| format!("Listening on '{}'", path.display()))?;
+        let server = Self {
+            listener,
+            path: path.to_owned(),
+        };
+        server
+            .listener
+            .set_nonblocking(true)
+            .context("Setting the listener non-blocking")?;
+        Ok(server)
+    }
+
+    /// Accepts a pending client, without blocking.
+    pub fn accept(&self) -> Option<Connection> {
+        let (stream, _) = self.listener.accept().ok()?;
+        stream.set_nonblocking(false).ok()?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+        Some(Connection { stream })
+    }
+}
+
+impl AsRawFd for Server {
+    fn as_raw_fd(&self) -> i32 {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds the socket in a directory only accessible to the owner, then moves
+/// it to `path` once restricted: no other user can connect in between.
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(format!(".{}", std::process::id()));
+    let dir = PathBuf::from(dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let socket = dir.join("socket");
+    let bound = UnixListener::bind(&socket).and_then(|listener| {
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&socket, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+/// A client, expecting the response to one command.
+pub struct Connection {
+    stream: UnixStream,
+}
+
+impl Connection {
+    /// Reads a command, up to the end of the line or of the stream. Fails with
+    /// the message to respond with.
+    ///
+    /// The whole command must arrive within `CLIENT_TIMEOUT`: a client
+    /// trickling bytes would otherwise hold the main loop indefinitely.
+    pub fn read_command(&mut self) -> std::result::Result<Command, String> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        let mut line = Vec::with_capacity(64);
+        let mut buf = [0u8; 256];
+        while !line.contains(&b'\n') {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || self.stream.set_read_timeout(Some(remaining)).is_err() {
+                return Err("timed out reading the command".to_owned());
+            }
+            match self.stream.read(&mut buf) {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err("timed out reading the command".to_owned());
+                }
+                Ok(0) => break,
+                Ok(n) => line.extend_from_slice(&buf[..n]),
+                Err(e) => return Err(format!("reading the command: {}", e)),
+            }
+            if line.len() > MAX_COMMAND_LEN {
+                return Err("command too long".to_owned());
+            }
+        }
+        let line = line.split(|c| *c == b'\n').next().unwrap_or_default();
+        parse_command(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    /// Writes the response, which should end with a newline.
+    pub fn respond(mut self, response: &[u8]) -> Result<()> {
+        self.stream
+            .write_all(response)
+            .context("Writing the response to the control socket")
+    }
+}
+
+/// Parses `status [--json]`, `spindown <device>` or `config <device>`. The
+/// devices may be given with their `/dev/` prefix.
+fn parse_command(line: &[u8]) -> std::result::Result<Command, String> {
+    let mut it = line
+        .split(u8::is_ascii_whitespace)
+        .filter(|s| !s.is_empty());
+    let (command, device) = (it.next(), it.next());
+    if it.next().is_some() {
+        return Err("too many arguments".to_owned());
+    }
+    let dev_name = || {
+        let dev = device.ok_or_else(|| "missing device".to_owned())?;
+        let dev = dev.strip_prefix(b"/dev/").unwrap_or(dev);
+        Ok(OsStr::from_bytes(dev).to_owned())
+    };
+    match (command, device) {
+        (Some(b"status"), None) => Ok(Command::Status { json: false }),
+        (Some(b"status"), Some(b"--json")) => Ok(Command::Status { json: true }),
+        (Some(b"spindown"), _) => dev_name().map(Command::Spindown),
+        (Some(b"config"), _) => dev_name().map(Command::Config),
+        (Some(command), _) => Err(format!("unknown command '{}'", command.escape_ascii())),
+        (None, _) => Err("empty command".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            parse_command(b"status"),
+            Ok(Command::Status { json: false })
+        );
+        assert_eq!(
+            parse_command(b"status --json"),
+            Ok(Command::Status { json: true })
+        );
+        assert_eq!(
+            parse_command(b"spindown /dev/sdb"),
+            Ok(Command::Spindown("sdb".into()))
+        );
+        assert_eq!(
+            parse_command(b"  config sdc "),
+            Ok(Command::Config("sdc".into()))
+        );
+        assert!(parse_command(b"spindown").is_err());
+        assert!(parse_command(b"status sda").is_err());
+        assert!(parse_command(b"spinup sda").is_err());
+        assert!(parse_command(b"").is_err());
+    }
+
+    #[test]
+    fn serve_one() {
+        let dir = TempDir::new("rust-idle-control");
+        let path = dir.join("rust-idle.sock");
+        let server = Server::bind(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"config sda\n").unwrap();
+        let mut conn = server.accept().unwrap();
+        assert_eq!(conn.read_command(), Ok(Command::Config("sda".into())));
+        conn.respond(b"{ idle_time: 600s }\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "{ idle_time: 600s }\n");
+        assert!(Server::bind(&path).is_err(), "the socket is in use");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn slow_client() {
+        let dir = TempDir::new("rust-idle-control-slow");
+        let path = dir.join("rust-idle.sock");
+        let server = Server::bind(&path).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let client = std::thread::spawn(move || {
+            // Each byte arrives before the read timeout, never the whole line
+            for _ in 0..30 {
+                if stream.write_all(b"s").is_err() {
+                    break;
+                }
+                std::thread::sleep(CLIENT_TIMEOUT / 10);
+            }
+        });
+        let mut conn = server.accept().unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            conn.read_command(),
+            Err("timed out reading the command".to_owned())
+        );
+        assert!(start.elapsed() < 2 * CLIENT_TIMEOUT);
+        drop(conn);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn keeps_other_files() {
+        let dir = TempDir::new("rust-idle-control-file");
+        let path = dir.write("rust-idle.sock", "not a socket");
+        let err = Server::bind(&path).err().unwrap();
+        assert!(err.to_string().contains("is not a socket"), "{}", err);
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+    }
+}
@@ -16,12 +16,71 @@ pub type Result<T> = result::Result<T, Error>;
 struct ErrorRepr {
     chain: Vec<CowStr>,
     source: Option<io::Error>,
+    kind: Option<ErrorKind>,
 }
 
+/// Class of a failure, deciding the exit code of the daemon.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Invalid arguments or configuration file.
+    Config,
+    /// A configured disk can't be found or isn't supported.
+    Device,
+    /// Missing privileges (eg. not running as root).
+    Permission,
+    /// Failed system call or file access.
+    Io,
+    Other,
+}
+
+impl ErrorKind {
+    /// Exit code of the daemon, after `sysexits.h`.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Config => 64,     // EX_USAGE
+            ErrorKind::Device => 69,     // EX_UNAVAILABLE
+            ErrorKind::Permission => 77, // EX_NOPERM
+            ErrorKind::Io => 74,         // EX_IOERR
+            ErrorKind::Other => 1,
+        }
+    }
+}
+
+impl Error {
+    /// The class of the error: denied accesses are permission errors whatever
+    /// the class given with [`Context::kind`], then comes that class, then I/O
+    /// errors.
+    pub fn kind(&self) -> ErrorKind {
+        let repr = &*self.0;
+        match (&repr.source, repr.kind) {
+            (Some(e), _) if e.kind() == io::ErrorKind::PermissionDenied => ErrorKind::Permission,
+            (_, Some(kind)) => kind,
+            (Some(_), None) => ErrorKind::Io,
+            (None, None) => ErrorKind::Other,
+        }
+    }
+
+    /// Is the device not ready: the call would have blocked (`EAGAIN`), or
+    /// there is no media (`ENOMEDIUM`, `ENXIO`)? Worth retrying later.
+    pub fn not_ready(&self) -> bool {
+        (self.0.source.as_ref()).is_some_and(|e| {
+            e.kind() == io::ErrorKind::WouldBlock
+                || matches!(e.raw_os_error(), Some(nc::ENOMEDIUM | nc::ENXIO))
+        })
+    }
+}
+
+/// The default format joins the context chain on a single line (`a: b: c`),
+/// the alternate format (`{:#}`) prints one cause per line.
 impl fmt::Display for Error {
     #[cold]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let repr = &*self.0;
+        let separator = if f.alternate() {
+            "\n    caused by: "
+        } else {
+            ": "
+        };
         for ctx in repr
             .chain
             .iter()
@@ -29,7 +88,7 @@ impl fmt::Display for Error {
             .rev()
         {
             f.write_str(ctx)?;
-            f.write_str(": ")?;
+            f.write_str(separator)?;
         }
         if let Some(source) = &repr.source {
             fmt::Display::fmt(source, f)
@@ -60,6 +119,7 @@ impl From<CowStr> for Error {
         Error(Box::new(ErrorRepr {
             chain: vec![msg],
             source: None,
+            kind: None,
         }))
     }
 }
@@ -82,6 +142,7 @@ impl From<io::Error> for Error {
         Error(Box::new(ErrorRepr {
             chain: Vec::new(),
             source: Some(source),
+            kind: None,
         }))
     }
 }
@@ -100,6 +161,8 @@ pub trait Context<T> {
     where
         CowStr: From<M>,
         F: FnOnce() -> M;
+    /// Classifies the error, unless an inner call already did.
+    fn kind(self, kind: ErrorKind) -> Result<T>;
 }
 
 impl<T, E> Context<T> for result::Result<T, E>
@@ -128,6 +191,14 @@ where
             |e| add_context(e, f()),
         )
     }
+
+    fn kind(self, kind: ErrorKind) -> Result<T> {
+        self.map_err(|e| {
+            let mut this = Error::from(e);
+            this.0.kind.get_or_insert(kind);
+            this
+        })
+    }
 }
 
 #[cold]
@@ -167,4 +238,44 @@ mod tests {
             "a: b: Success (os error 0)",
         );
     }
+
+    #[test]
+    fn kinds() {
+        let kind = |res: Result<()>| res.unwrap_err().kind();
+        assert_eq!(kind(Err("a".into())), ErrorKind::Other);
+        assert_eq!(kind(Err(nc::EIO.into())), ErrorKind::Io);
+        assert_eq!(kind(Err(nc::EACCES.into())), ErrorKind::Permission);
+        let res = Err::<(), _>("bad flag").kind(ErrorKind::Config);
+        assert_eq!(kind(res.context("parsing")), ErrorKind::Config);
+        // The innermost class is kept
+        let res = Err::<(), _>("a").kind(ErrorKind::Device);
+        assert_eq!(kind(res.kind(ErrorKind::Config)), ErrorKind::Device);
+        let res = Err::<(), _>(nc::EPERM).kind(ErrorKind::Device);
+        assert_eq!(kind(res), ErrorKind::Permission);
+    }
+
+    #[test]
+    fn not_ready() {
+        let res = Err::<(), _>(nc::EAGAIN).context("Could not open device '/dev/sr0'");
+        assert!(res.unwrap_err().not_ready());
+        assert!(Error::from(nc::ENOMEDIUM).not_ready());
+        assert!(Error::from(nc::ENXIO).not_ready());
+        assert!(!Error::from(nc::EIO).not_ready());
+        assert!(!Error::from("a").not_ready());
+    }
+
+    #[test]
+    fn alternate_format() {
+        let err = Err::<(), _>("c").context("b").with_context(|| "a");
+        assert_eq!(
+            format!("{:#}", err.err().unwrap()),
+            "a\n    caused by: b\n    caused by: c"
+        );
+        let err = Err::<(), _>(0_i32).context("a");
+        assert_eq!(
+            format!("{:#}", err.err().unwrap()),
+            "a\n    caused by: Success (os error 0)"
+        );
+        assert_eq!(format!("{:#}", Error::from("a")), "a");
+    }
 }
@@ -0,0 +1,139 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Waiting of the main loop between two refreshes: an `epoll` instance
+//! multiplexes a `timerfd`, expiring at the next refresh, with the other
+//! sources of events (eg. the HTTP control API).
+//!
+//! Termination signals interrupt the wait, since `epoll_wait()` is never
+//! restarted after a signal handler. They are unblocked only during the wait
+//! (see [`crate::signals::block`]), as with `pselect()`.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::time::Duration;
+
+use crate::errors::{Context, Result};
+
+/// Token of the expiration of the timer.
+pub const TIMER: u64 = 0;
+
+/// `struct epoll_event`, packed on x86_64 (unlike `nc::epoll_event_t`).
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+#[derive(Default, Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+pub struct EventLoop {
+    epoll: File,
+    timer: File,
+}
+
+impl EventLoop {
+    pub fn new() -> Result<Self> {
+        let epoll = unsafe { nc::epoll_create1(nc::EPOLL_CLOEXEC) }.context("epoll_create1")?;
+        let epoll = unsafe { File::from_raw_fd(epoll) };
+        let flags = nc::TFD_CLOEXEC | nc::TFD_NONBLOCK;
+        let timer =
+            unsafe { nc::timerfd_create(nc::CLOCK_MONOTONIC, flags) }.context("timerfd_create")?;
+        let timer = unsafe { File::from_raw_fd(timer) };
+        let event_loop = Self { epoll, timer };
+        event_loop.add(event_loop.timer.as_raw_fd(), TIMER)?;
+        Ok(event_loop)
+    }
+
+    /// Watches a file descriptor for input, reported by [`Self::wait`] with
+    /// `token`.
+    pub fn add(&self, fd: i32, token: u64) -> Result<()> {
+        let mut event = EpollEvent {
+            events: nc::EPOLLIN,
+            data: token,
+        };
+        unsafe {
+            nc::syscalls::syscall4(
+                nc::SYS_EPOLL_CTL,
+                self.epoll.as_raw_fd() as usize,
+                nc::EPOLL_CTL_ADD as usize,
+                fd as usize,
+                std::ptr::addr_of_mut!(event) as usize,
+            )
+        }
+        .map(drop)
+        .context("Adding a file descriptor to epoll")
+    }
+
+    /// Expires the timer once, after `duration`.
+    pub fn set_timer(&self, duration: Duration) -> Result<()> {
+        // A zero expiration disarms the timer
+        let duration = duration.max(Duration::from_nanos(1));
+        let value = nc::itimerspec_t {
+            it_interval: nc::timespec_t::default(),
+            it_value: nc::timespec_t {
+                tv_sec: duration.as_secs() as nc::time_t,
+                tv_nsec: duration.subsec_nanos() as isize,
+            },
+        };
+        unsafe { nc::timerfd_settime(self.timer.as_raw_fd(), 0, &value, None) }
+            .context("Setting the timer")
+    }
+
+    /// Waits for the next event: returns the token of a file descriptor ready
+    /// for input, [`TIMER`] when the timer expired, or `None` if interrupted by
+    /// a signal. The signal mask is replaced by `sigmask` during the wait.
+    pub fn wait(&mut self, sigmask: Option<&nc::sigset_t>) -> Result<Option<u64>> {
+        let sigmask_ptr = sigmask.map_or(0, |sigmask| sigmask as *const nc::sigset_t as usize);
+        let mut event = EpollEvent::default();
+        let ready = unsafe {
+            nc::syscalls::syscall6(
+                nc::SYS_EPOLL_PWAIT,
+                self.epoll.as_raw_fd() as usize,
+                std::ptr::addr_of_mut!(event) as usize,
+                1,
+                -1_isize as usize,
+                sigmask_ptr,
+                std::mem::size_of::<nc::sigset_t>(),
+            )
+        };
+        match ready {
+            Ok(0) | Err(nc::EINTR) => return Ok(None),
+            Ok(_) => {}
+            Err(e) => return Err(e).context("epoll_pwait"),
+        }
+        let token = event.data;
+        if token == TIMER {
+            // Acknowledges the expiration
+            let mut expirations = [0u8; 8];
+            match self.timer.read(&mut expirations) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e).context("Reading the timer"),
+            }
+        }
+        Ok(Some(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_and_input() {
+        let mut event_loop = EventLoop::new().unwrap();
+        event_loop.set_timer(Duration::from_millis(1)).unwrap();
+        assert_eq!(event_loop.wait(None).unwrap(), Some(TIMER));
+
+        let mut fds = [0; 2];
+        unsafe { nc::pipe2(&mut fds, nc::O_CLOEXEC) }.unwrap();
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        event_loop.add(reader.as_raw_fd(), 42).unwrap();
+        event_loop.set_timer(Duration::from_secs(3600)).unwrap();
+        std::io::Write::write_all(&mut &writer, b"x").unwrap();
+        assert_eq!(event_loop.wait(None).unwrap(), Some(42));
+    }
+}
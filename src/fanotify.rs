@@ -0,0 +1,168 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! File-level attribution of the accesses to a disk (`--watch-files`), with
+//! fanotify marks on its mount points, to tell which file woke it up.
+//!
+//! This is only a diagnostic: the accesses served from the page cache are
+//! reported too, and the activity is still measured from `/proc/diskstats`.
+//! Requires `CAP_SYS_ADMIN`.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use crate::errors::{Context, Result};
+
+/// Size of `struct fanotify_event_metadata`.
+const METADATA_LEN: usize = 24;
+
+/// The last file accessed on a disk.
+pub struct Access {
+    pub path: PathBuf,
+    pub pid: i32,
+    /// Name of the process, if it was still running.
+    pub process: Option<String>,
+}
+
+/// Formats as `<path> by <process> (pid <pid>)`.
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        match &self.process {
+            Some(process) => write!(f, " by {} (pid {})", process, self.pid),
+            None => write!(f, " by pid {}", self.pid),
+        }
+    }
+}
+
+/// A fanotify group, watching the mount points of a disk.
+pub struct Watcher {
+    file: File,
+    buf: Vec<u8>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Self> {
+        let flags = nc::FAN_CLASS_NOTIF | nc::FAN_NONBLOCK | nc::FAN_CLOEXEC;
+        let event_flags = (nc::O_RDONLY | nc::O_LARGEFILE) as u32;
+        let fd = unsafe { nc::fanotify_init(flags, event_flags) }.context("fanotify_init")?;
+        Ok(Self {
+            file: unsafe { File::from_raw_fd(fd) },
+            buf: vec![0; 4096],
+        })
+    }
+
+    /// Reports the accesses and modifications through a mount point.
+    pub fn watch_mount(&self, mount_point: &OsStr) -> Result<()> {
+        let flags = nc::FAN_MARK_ADD | nc::FAN_MARK_MOUNT;
+        let mask = nc::FAN_ACCESS | nc::FAN_MODIFY | nc::FAN_OPEN;
+        let mut path = mount_point.as_bytes().to_vec();
+        path.push(0);
+        // `nc::fanotify_mark()` frees the path before the system call
+        unsafe {
+            nc::syscalls::syscall5(
+                nc::SYS_FANOTIFY_MARK,
+                self.file.as_raw_fd() as usize,
+                flags as usize,
+                mask as usize,
+                nc::AT_FDCWD as usize,
+                path.as_ptr() as usize,
+            )
+        }
+        .map(drop)
+        .with_context(|| format!("Watching '{}'", mount_point.to_string_lossy()))
+    }
+
+    /// Consumes the pending events, without blocking, and returns the last
+    /// access among them. The accesses of the daemon (eg. its syncs) are
+    /// skipped.
+    pub fn drain(&mut self) -> Result<Option<Access>> {
+        let own_pid = std::process::id() as i32;
+        let mut last = None;
+        loop {
+            let len = match self.file.read(&mut self.buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Reading fanotify events"),
+            };
+            if len == 0 {
+                break;
+            }
+            for (fd, pid) in parse_events(&self.buf[..len]) {
+                if fd < 0 {
+                    continue; // FAN_NOFD: queue overflow
+                }
+                // The event holds an open file descriptor to the file
+                let file = unsafe { File::from_raw_fd(fd) };
+                if pid != own_pid {
+                    last = Some((file, pid));
+                }
+            }
+        }
+        Ok(last.and_then(|(file, pid)| {
+            let path = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).ok()?;
+            let process = std::fs::read_to_string(format!("/proc/{}/comm", pid))
+                .ok()
+                .map(|comm| comm.trim_end().to_owned());
+            Some(Access { path, pid, process })
+        }))
+    }
+}
+
+/// Iterates over the `(fd, pid)` of the events of a read buffer.
+fn parse_events(mut buf: &[u8]) -> impl Iterator<Item = (i32, i32)> + '_ {
+    std::iter::from_fn(move || {
+        let header = buf.get(..METADATA_LEN)?;
+        let event_len = u32::from_ne_bytes(header[0..4].try_into().unwrap()) as usize;
+        if header[4] != nc::FANOTIFY_METADATA_VERSION as u8 || event_len < METADATA_LEN {
+            return None;
+        }
+        let fd = i32::from_ne_bytes(header[16..20].try_into().unwrap());
+        let pid = i32::from_ne_bytes(header[20..24].try_into().unwrap());
+        buf = buf.get(event_len..).unwrap_or_default();
+        Some((fd, pid))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(fd: i32, pid: i32) -> Vec<u8> {
+        let mut event = Vec::new();
+        event.extend_from_slice(&(METADATA_LEN as u32).to_ne_bytes());
+        event.extend_from_slice(&[nc::FANOTIFY_METADATA_VERSION as u8, 0]);
+        event.extend_from_slice(&(METADATA_LEN as u16).to_ne_bytes());
+        event.extend_from_slice(&u64::from(nc::FAN_ACCESS).to_ne_bytes());
+        event.extend_from_slice(&fd.to_ne_bytes());
+        event.extend_from_slice(&pid.to_ne_bytes());
+        event
+    }
+
+    #[test]
+    fn events() {
+        let mut buf = event(5, 100);
+        buf.extend(event(-1, 0));
+        buf.extend(event(6, 200));
+        buf.extend_from_slice(&[0; 10]); // truncated
+        let events: Vec<_> = parse_events(&buf).collect();
+        assert_eq!(events, [(5, 100), (-1, 0), (6, 200)]);
+
+        let access = Access {
+            path: "/media/movies/x.mkv".into(),
+            pid: 42,
+            process: Some("smbd".into()),
+        };
+        assert_eq!(access.to_string(), "/media/movies/x.mkv by smbd (pid 42)");
+
+        let mut bad_version = event(7, 1);
+        bad_version[4] = 2;
+        assert_eq!(parse_events(&bad_version).count(), 0);
+    }
+}
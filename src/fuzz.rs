@@ -0,0 +1,23 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Entry points for the fuzz targets (`cargo fuzz run parsers`), only built
+//! with `--cfg fuzzing`.
+
+use std::ffi::OsStr;
+
+use crate::{iomonitor, mounts, swaps};
+
+/// Feeds each line of `data` to the diskstats, block events, mount table and
+/// swaps parsers, which must reject malformed lines without panicking.
+pub fn parsers(data: &[u8]) {
+    for line in data.split(|c| *c == b'\n') {
+        let _ = iomonitor::parse_line(line);
+        let _ = iomonitor::parse_event(line);
+        let _ = mounts::parse_line(&mut line.to_vec(), OsStr::new("sda"), &[]);
+        let _ = mounts::parse_entry(&mut line.to_vec());
+        let _ = mounts::disk_name(line);
+        let _ = swaps::parse_line(&mut line.to_vec());
+    }
+}
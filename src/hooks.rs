@@ -0,0 +1,287 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Commands run when a disk spins down or up (`--on-spindown`, `--on-spinup`),
+//! eg. to light a LED or send a notification. The event is described by the
+//! environment of the command:
+//!
+//! ```text
+//! IDLE_DEVICE=sdb IDLE_STATE=idle IDLE_IDLE_SECS=600
+//! ```
+//!
+//! The commands are spawned without waiting for them, and reaped at the
+//! following refreshes. The exception is `--pre-spindown`, run before spinning
+//! a disk down and waited for: it vetoes the spin down by failing.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::{Context, Result};
+
+/// Hook commands of a device.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Hooks {
+    pub on_spindown: Option<PathBuf>,
+    pub on_spinup: Option<PathBuf>,
+    /// Asked before spinning the disk down, which it prevents by failing.
+    pub pre_spindown: Option<PathBuf>,
+}
+
+/// Which hook a command is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    OnSpindown,
+    OnSpinup,
+    PreSpindown,
+}
+
+impl Kind {
+    /// Option giving the commands of this hook.
+    pub fn option(self) -> &'static str {
+        match self {
+            Kind::OnSpindown => "on-spindown",
+            Kind::OnSpinup => "on-spinup",
+            Kind::PreSpindown => "pre-spindown",
+        }
+    }
+}
+
+/// Hook commands given as options, for a device or all of them (`None`).
+#[derive(Default)]
+pub struct Commands {
+    on_spindown: Vec<(Option<OsString>, PathBuf)>,
+    on_spinup: Vec<(Option<OsString>, PathBuf)>,
+    pre_spindown: Vec<(Option<OsString>, PathBuf)>,
+}
+
+impl Commands {
+    /// Adds a `[<device>=]<command>` argument.
+    pub fn add(&mut self, kind: Kind, arg: &OsStr) -> std::result::Result<(), String> {
+        let bytes = arg.as_bytes();
+        // The command is a path, the device never holds a slash
+        let (dev, command) = match bytes.iter().position(|c| *c == b'=') {
+            Some(eq) if !bytes[..eq].contains(&b'/') => (Some(&bytes[..eq]), &bytes[eq + 1..]),
+            _ => (None, bytes),
+        };
+        if command.is_empty() || dev.is_some_and(<[u8]>::is_empty) {
+            return Err(format!(
+                "expected [<device>=]<command>: '{}'",
+                arg.to_string_lossy()
+            ));
+        }
+        let hooks = match kind {
+            Kind::OnSpindown => &mut self.on_spindown,
+            Kind::OnSpinup => &mut self.on_spinup,
+            Kind::PreSpindown => &mut self.pre_spindown,
+        };
+        hooks.push((
+            dev.map(|dev| OsStr::from_bytes(dev).to_owned()),
+            PathBuf::from(OsStr::from_bytes(command)),
+        ));
+        Ok(())
+    }
+
+    /// The commands given, in order, with the device each is given for.
+    pub fn iter(&self) -> impl Iterator<Item = (Kind, Option<&OsStr>, &Path)> {
+        [
+            (Kind::OnSpindown, &self.on_spindown),
+            (Kind::OnSpinup, &self.on_spinup),
+            (Kind::PreSpindown, &self.pre_spindown),
+        ]
+        .into_iter()
+        .flat_map(|(kind, hooks)| {
+            (hooks.iter()).map(move |(dev, command)| (kind, dev.as_deref(), command.as_path()))
+        })
+    }
+
+    /// Hooks of a device: its own commands, or else the ones of all devices.
+    pub fn of(&self, dev: &OsStr) -> Hooks {
+        let find = |hooks: &[(Option<OsString>, PathBuf)]| {
+            let command = |device: Option<&OsStr>| {
+                hooks
+                    .iter()
+                    .rev()
+                    .find(|(name, _)| name.as_deref() == device)
+                    .map(|(_, command)| command.clone())
+            };
+            command(Some(dev)).or_else(|| command(None))
+        };
+        Hooks {
+            on_spindown: find(&self.on_spindown),
+            on_spinup: find(&self.on_spinup),
+            pre_spindown: find(&self.pre_spindown),
+        }
+    }
+}
+
+/// A spin down or up of a device, passed to its hook.
+pub struct Event<'a> {
+    pub device: &'a OsStr,
+    /// State entered by the device: `idle` or `spinning`.
+    pub state: &'a str,
+    pub idle_time: Duration,
+}
+
+/// Prepares `command`, with the event in its environment.
+fn command(command: &Path, event: &Event) -> Command {
+    let mut command = Command::new(command);
+    command
+        .env("IDLE_DEVICE", event.device)
+        .env("IDLE_STATE", event.state)
+        .env("IDLE_IDLE_SECS", event.idle_time.as_secs().to_string())
+        .stdin(Stdio::null());
+    command
+}
+
+/// Runs `command` and waits for its exit status, killing it once `timeout`
+/// has elapsed.
+pub fn run_with_timeout(path: &Path, event: &Event, timeout: Duration) -> Result<ExitStatus> {
+    let mut child = command(path, event)
+        .spawn()
+        .with_context(|| format!("Running the hook '{}'", path.display()))?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        let waited = child
+            .try_wait()
+            .with_context(|| format!("Waiting for the hook '{}'", path.display()))?;
+        if let Some(status) = waited {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!(
+                "The hook '{}' timed out after {}s",
+                path.display(),
+                timeout.as_secs()
+            )
+            .into());
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Hooks still running.
+#[derive(Default)]
+pub struct Runner {
+    children: Vec<(PathBuf, Child)>,
+}
+
+impl Runner {
+    /// Spawns `command` with the event in its environment.
+    pub fn spawn(&mut self, command: &Path, event: &Event) -> Result<()> {
+        let child = self::command(command, event)
+            .spawn()
+            .with_context(|| format!("Running the hook '{}'", command.display()))?;
+        self.children.push((command.to_owned(), child));
+        Ok(())
+    }
+
+    /// Reaps the hooks which exited, logging their failures.
+    pub fn reap(&mut self) {
+        self.children
+            .retain_mut(|(command, child)| match child.try_wait() {
+                Ok(Some(status)) if status.success() => false,
+                Ok(Some(status)) => {
+                    crate::elogln!("<4>Hook '{}' failed: {}", command.display(), status);
+                    false
+                }
+                Ok(None) => true,
+                Err(e) => {
+                    crate::elogln!("<4>Waiting for the hook '{}': {}", command.display(), e);
+                    false
+                }
+            });
+    }
+
+    /// # of hooks still running.
+    pub fn running(&self) -> usize {
+        self.children.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn commands() {
+        let mut commands = Commands::default();
+        commands
+            .add(Kind::OnSpindown, OsStr::new("/bin/led"))
+            .unwrap();
+        commands
+            .add(Kind::OnSpindown, OsStr::new("sdb=/bin/notify"))
+            .unwrap();
+        commands
+            .add(Kind::OnSpinup, OsStr::new("/opt/a=b/hook"))
+            .unwrap();
+        assert!(commands.add(Kind::OnSpinup, OsStr::new("sdb=")).is_err());
+        assert!(
+            commands
+                .add(Kind::OnSpinup, OsStr::new("=/bin/led"))
+                .is_err()
+        );
+        assert_eq!(
+            commands.of(OsStr::new("sdb")),
+            Hooks {
+                on_spindown: Some("/bin/notify".into()),
+                on_spinup: Some("/opt/a=b/hook".into()),
+                pre_spindown: None,
+            }
+        );
+        assert_eq!(
+            commands.of(OsStr::new("sdc")).on_spindown,
+            Some("/bin/led".into())
+        );
+    }
+
+    #[test]
+    fn spawn_and_reap() {
+        let mut runner = Runner::default();
+        let event = Event {
+            device: OsStr::new("sdb"),
+            state: "idle",
+            idle_time: Duration::from_secs(600),
+        };
+        runner.spawn(Path::new("/bin/true"), &event).unwrap();
+        assert!(runner.spawn(Path::new("/nonexistent"), &event).is_err());
+        assert_eq!(runner.running(), 1);
+        while runner.running() > 0 {
+            std::thread::sleep(Duration::from_millis(10));
+            runner.reap();
+        }
+    }
+
+    #[test]
+    fn vetoes() {
+        let event = Event {
+            device: OsStr::new("sdb"),
+            state: "idle",
+            idle_time: Duration::from_secs(600),
+        };
+        let timeout = Duration::from_secs(5);
+        let status = |path: &str| run_with_timeout(Path::new(path), &event, timeout);
+        assert!(status("/bin/true").unwrap().success());
+        assert!(!status("/bin/false").unwrap().success());
+        assert!(status("/nonexistent").is_err());
+        // A hung hook is killed
+        let dir = TempDir::new("rust-idle-hook");
+        let path = dir.write(
+            "hook",
+            "#!/bin/sh\n[ \"$IDLE_DEVICE\" = sdb ] && exec sleep 10\n",
+        );
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let started = Instant::now();
+        let hung = run_with_timeout(&path, &event, Duration::from_millis(100));
+        assert!(hung.is_err());
+        assert!(started.elapsed() < timeout);
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Minimal HTTP control API (`--http`), for NAS management interfaces:
+//! `GET /status`, `GET /metrics` (Prometheus), `POST /spinup/<device>`,
+//! `POST /spindown/<device>` and `POST /reload`.
+//!
+//! Connections are served one at a time by the main loop, while it waits for
+//! the next refresh. Request bodies are ignored, and the connection is closed
+//! after each response.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, Instant};
+
+use crate::errors::{Context, Result};
+
+/// Upper bound of the request head (request line and headers).
+const MAX_HEAD_LEN: usize = 8192;
+/// Time given to clients to send their whole request, and to read the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    Status,
+    Metrics,
+    Spinup(OsString),
+    Spindown(OsString),
+    Reload,
+}
+
+pub struct Server {
+    listener: TcpListener,
+}
+
+impl Server {
+    /// Listens on `<host>:<port>`, or on localhost if only a port is given.
+    pub fn bind(addr: &str) -> Result<Self> {
+        let addr = if addr.contains(':') {
+            addr.to_owned()
+        } else {
+            format!("127.0.0.1:{}", addr)
+        };
+        let listener =
+            TcpListener::bind(&addr).with_context(|| format!("Listening on {}", addr))?;
+        listener
+            .set_nonblocking(true)
+            .context("Setting the listener non-blocking")?;
+        Ok(Self { listener })
+    }
+
+    /// Waits up to `timeout` for a client, returning early (with `None`) when
+    /// interrupted by a signal.
+    pub fn accept_timeout(&self, timeout: Duration) -> Option<Connection> {
+        let mut fds = [nc::pollfd_t {
+            fd: self.listener.as_raw_fd(),
+            events: nc::POLLIN,
+            revents: 0,
+        }];
+        let timeout = nc::timespec_t {
+            tv_sec: timeout.as_secs() as nc::time_t,
+            tv_nsec: timeout.subsec_nanos() as isize,
+        };
+        match unsafe { nc::ppoll(&mut fds, Some(&timeout), None) } {
+            Ok(n) if n > 0 => {}
+            _ => return None,
+        }
+        self.accept()
+    }
+
+    /// Accepts a pending client, without blocking.
+    pub fn accept(&self) -> Option<Connection> {
+        let (stream, _) = self.listener.accept().ok()?;
+        stream.set_nonblocking(false).ok()?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+        Some(Connection { stream })
+    }
+}
+
+impl AsRawFd for Server {
+    fn as_raw_fd(&self) -> i32 {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// A client, expecting one response.
+pub struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    /// Reads the request head. Fails with the status code to respond with.
+    ///
+    /// The whole head must arrive within `CLIENT_TIMEOUT`: a client trickling
+    /// bytes would otherwise hold the main loop indefinitely.
+    pub fn read_request(&mut self) -> std::result::Result<Request, u16> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        let mut head = Vec::with_capacity(1024);
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || self.stream.set_read_timeout(Some(remaining)).is_err() {
+                return Err(408);
+            }
+            match self.stream.read(&mut buf) {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(408);
+                }
+                Ok(0) | Err(_) => return Err(400),
+                Ok(n) => head.extend_from_slice(&buf[..n]),
+            }
+            if head.len() > MAX_HEAD_LEN {
+                return Err(431);
+            }
+        }
+        let line = head.split(|c| *c == b'\r').next().unwrap_or_default();
+        parse_request_line(line)
+    }
+
+    pub fn respond(mut self, status: u16, content_type: &str, body: &[u8]) -> Result<()> {
+        write!(
+            self.stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            status,
+            reason(status),
+            content_type,
+            body.len()
+        )
+        .and_then(|_| self.stream.write_all(body))
+        .context("Writing the HTTP response")
+    }
+}
+
+/// Parses `<method> <path> HTTP/<version>`.
+fn parse_request_line(line: &[u8]) -> std::result::Result<Request, u16> {
+    let mut it = line.split(|c| *c == b' ');
+    let (Some(method), Some(path), Some(version), None) =
+        (it.next(), it.next(), it.next(), it.next())
+    else {
+        return Err(400);
+    };
+    if !version.starts_with(b"HTTP/1.") {
+        return Err(505);
+    }
+    let device = |path: &[u8], prefix: &[u8]| {
+        path.strip_prefix(prefix)
+            .filter(|dev| !dev.is_empty() && !dev.contains(&b'/'))
+            .map(|dev| OsStr::from_bytes(dev).to_owned())
+    };
+    let request = match path {
+        b"/status" => (b"GET".as_slice(), Request::Status),
+        b"/metrics" => (b"GET".as_slice(), Request::Metrics),
+        b"/reload" => (b"POST".as_slice(), Request::Reload),
+        _ => {
+            if let Some(dev) = device(path, b"/spinup/") {
+                (b"POST".as_slice(), Request::Spinup(dev))
+            } else if let Some(dev) = device(path, b"/spindown/") {
+                (b"POST".as_slice(), Request::Spindown(dev))
+            } else {
+                return Err(404);
+            }
+        }
+    };
+    if method == request.0 {
+        Ok(request.1)
+    } else {
+        Err(405)
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_lines() {
+        assert_eq!(
+            parse_request_line(b"GET /status HTTP/1.1"),
+            Ok(Request::Status)
+        );
+        assert_eq!(
+            parse_request_line(b"POST /spindown/sdb HTTP/1.0"),
+            Ok(Request::Spindown("sdb".into()))
+        );
+        assert_eq!(
+            parse_request_line(b"POST /spinup/sdc HTTP/1.1"),
+            Ok(Request::Spinup("sdc".into()))
+        );
+        assert_eq!(
+            parse_request_line(b"GET /metrics HTTP/1.1"),
+            Ok(Request::Metrics)
+        );
+        assert_eq!(
+            parse_request_line(b"POST /reload HTTP/1.1"),
+            Ok(Request::Reload)
+        );
+        assert_eq!(parse_request_line(b"GET /spindown/sdb HTTP/1.1"), Err(405));
+        assert_eq!(parse_request_line(b"POST /spindown/ HTTP/1.1"), Err(404));
+        assert_eq!(
+            parse_request_line(b"POST /spinup/../sda HTTP/1.1"),
+            Err(404)
+        );
+        assert_eq!(parse_request_line(b"GET /status"), Err(400));
+        assert_eq!(parse_request_line(b"GET /status HTTP/2"), Err(505));
+    }
+
+    #[test]
+    fn serve_one() {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET /status HTTP/1.1\r\nHost: x\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        let mut conn = server.accept_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(conn.read_request(), Ok(Request::Status));
+        conn.respond(200, "application/json", b"{}").unwrap();
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n{}"), "{}", response);
+    }
+
+    #[test]
+    fn slow_client() {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // Each byte arrives before the read timeout, never the whole head
+            for _ in 0..30 {
+                if stream.write_all(b"G").is_err() {
+                    break;
+                }
+                std::thread::sleep(CLIENT_TIMEOUT / 10);
+            }
+        });
+        let mut conn = server.accept_timeout(Duration::from_secs(5)).unwrap();
+        let start = Instant::now();
+        assert_eq!(conn.read_request(), Err(408));
+        assert!(start.elapsed() < 2 * CLIENT_TIMEOUT);
+        drop(conn);
+        client.join().unwrap();
+    }
+}
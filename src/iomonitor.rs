@@ -3,105 +3,292 @@
 // the LICENSE file.
 
 use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::os::unix::prelude::OsStrExt;
+use std::path::Path;
 
 use crate::errors::{Context, Result};
 
-use crate::utils::{parse_integer, BulkReader};
+use crate::utils::{BulkReader, StreamReader, parse_integer};
 
 const DISKSTATS_PATH: &str = "/proc/diskstats";
+/// Upper estimate of the length of a line of /proc/diskstats, with large counters.
+const DISKSTATS_LINE_LEN: usize = 192;
+
+/// # of sectors moved by a device, read or written (including discards).
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Sectors {
+    pub read: usize,
+    pub written: usize,
+}
+
+impl Sectors {
+    /// Sectors moved in both directions.
+    pub fn total(self) -> usize {
+        self.read.wrapping_add(self.written)
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        Self {
+            read: self.read.wrapping_add(other.read),
+            written: self.written.wrapping_add(other.written),
+        }
+    }
+}
 
 pub struct Device<T> {
     name: OsString,
-    current_sectors: usize,
+    current_sectors: Sectors,
+    /// Name of the last process issuing I/O, only known from block events.
+    actor: Vec<u8>,
+    /// # of I/Os in progress, only known from /proc/diskstats.
+    in_flight: usize,
+    /// Was the device listed by the last read of /proc/diskstats?
+    present: bool,
     pub data: T,
 }
 
 impl<T> Device<T> {
+    fn new(name: OsString, current_sectors: Sectors, data: T) -> Self {
+        Self {
+            name,
+            current_sectors,
+            actor: Vec::new(),
+            in_flight: 0,
+            present: true,
+            data,
+        }
+    }
+
     pub fn name(&self) -> &OsStr {
         &self.name
     }
+
+    /// Is the device still present on the system? Always true with block
+    /// events, which don't tell about removals.
+    pub fn present(&self) -> bool {
+        self.present
+    }
+
+    /// # of I/Os in progress at the last read of /proc/diskstats.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
 }
 
-impl<'a, T> From<&'a mut Device<T>> for (&'a OsStr, usize, &'a mut T) {
+impl<'a, T> From<&'a mut Device<T>> for (&'a OsStr, Sectors, Option<&'a OsStr>, &'a mut T) {
     fn from(device: &'a mut Device<T>) -> Self {
-        (&device.name, device.current_sectors, &mut device.data)
+        let actor = (!device.actor.is_empty()).then(|| OsStr::from_bytes(&device.actor));
+        (
+            &device.name,
+            device.current_sectors,
+            actor,
+            &mut device.data,
+        )
     }
 }
 
-/// Ressources for polling the # of touched sectors from /proc/diskstats
+/// Where the activity is read from.
+enum Source {
+    /// Snapshots of cumulated statistics from /proc/diskstats.
+    Diskstats(BulkReader),
+    /// A stream of block events, one per line: `<device> <sectors> [<process>]`.
+    Events(StreamReader),
+}
+
+/// Ressources for polling the # of touched sectors from /proc/diskstats, or
+/// from a stream of block events.
 ///
 /// `data : T ` is attached user data.
 pub struct IOMonitor<T> {
-    file: BulkReader,
+    source: Source,
+    /// Sorted by name.
     state: Vec<Device<T>>,
+    /// Explicit (diskstats name, device) mapping, overriding the selection of
+    /// the whole-disk entries.
+    members: Vec<(OsString, OsString)>,
 }
 
-/// Linear search by device name
-fn get_entry_idx<T>(slice: &[Device<T>], name: &OsStr, hint: usize) -> Option<usize> {
-    let pred = |&i: &usize| slice[i].name == name;
-    (hint..slice.len())
-        .find(pred)
-        .or_else(|| (0..hint).find(pred))
+/// Estimates the size of /proc/diskstats from the block devices (including
+/// partitions) listed in /sys/class/block.
+fn diskstats_capacity() -> usize {
+    let devices = fs::read_dir("/sys/class/block").map_or(0, Iterator::count);
+    (devices * DISKSTATS_LINE_LEN).max(4096)
+}
+
+/// Search by device name in the devices sorted by name. `hint` and the next
+/// entry are tried first, the next line of /proc/diskstats usually being
+/// there, then a binary search. `Err` holds the index keeping the order.
+fn get_entry_idx<T>(
+    slice: &[Device<T>],
+    name: &OsStr,
+    hint: usize,
+) -> std::result::Result<usize, usize> {
+    for idx in [hint, hint + 1] {
+        if slice.get(idx).is_some_and(|device| device.name == name) {
+            return Ok(idx);
+        }
+    }
+    slice.binary_search_by(|device| device.name.as_os_str().cmp(name))
 }
 
 impl<T> IOMonitor<T> {
+    /// Polls /proc/diskstats, with a buffer sized after the number of block
+    /// devices of the system.
     pub fn new() -> Result<Self> {
+        Self::with_diskstats(DISKSTATS_PATH, diskstats_capacity())
+    }
+
+    /// Polls a file in the format of /proc/diskstats (eg. a fixture), with a
+    /// buffer of `capacity` bytes.
+    pub fn with_diskstats<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
         Ok(Self {
-            file: BulkReader::open(DISKSTATS_PATH)?,
+            source: Source::Diskstats(BulkReader::open_with_capacity(path, capacity)?),
             state: Vec::with_capacity(16),
+            members: Vec::new(),
+        })
+    }
+
+    /// Polls scripted snapshots of /proc/diskstats, one per refresh.
+    #[cfg(test)]
+    pub fn from_snapshots(snapshots: &[&str]) -> Self {
+        Self {
+            source: Source::Diskstats(BulkReader::from_snapshots(snapshots.iter().copied())),
+            state: Vec::with_capacity(16),
+            members: Vec::new(),
+        }
+    }
+
+    /// Monitors the events read from a stream (eg. a FIFO fed by a blktrace or
+    /// eBPF script) instead of polling /proc/diskstats.
+    pub fn with_events<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            source: Source::Events(StreamReader::open(path)?),
+            state: Vec::with_capacity(16),
+            members: Vec::new(),
         })
     }
 
     pub fn push(&mut self, name: OsString, data: T) -> &mut Device<T> {
-        let idx = get_entry_idx(&self.state, &name, 0);
-        if let Some(idx) = idx {
-            let slot = &mut self.state[idx];
-            slot.data = data;
-            slot
-        } else {
-            self.state.push(Device {
-                name,
-                current_sectors: 0,
-                data,
-            });
-            self.state.last_mut().unwrap()
+        match get_entry_idx(&self.state, &name, 0) {
+            Ok(idx) => {
+                let slot = &mut self.state[idx];
+                slot.data = data;
+                slot
+            }
+            Err(idx) => {
+                self.state
+                    .insert(idx, Device::new(name, Sectors::default(), data));
+                &mut self.state[idx]
+            }
+        }
+    }
+
+    /// Accounts the activity of the given entries (eg. partitions, multipath
+    /// members) to `device`, instead of its own whole-disk entry.
+    pub fn set_members(&mut self, device: &OsStr, members: &[OsString]) {
+        self.members.retain(|(_, dev)| dev != device);
+        for member in members {
+            self.members.retain(|(name, _)| name != member);
+            self.members.push((member.clone(), device.to_owned()));
+        }
+    }
+
+    /// Shrinks the buffer of /proc/diskstats after `reads` reads filling less
+    /// than half of it. See [`BulkReader::shrink_after`].
+    pub fn shrink_after(&mut self, reads: u32) {
+        if let Source::Diskstats(file) = &mut self.source {
+            file.shrink_after(reads);
         }
     }
 
+    pub fn get_mut(&mut self, name: &OsStr) -> Option<&mut Device<T>> {
+        let idx = get_entry_idx(&self.state, name, 0).ok()?;
+        Some(&mut self.state[idx])
+    }
+
+    /// Only keeps the devices for which `keep` returns true.
+    pub fn retain<F: FnMut(&Device<T>) -> bool>(&mut self, keep: F) {
+        self.state.retain(keep);
+    }
+
+    pub fn remove(&mut self, name: &OsStr) -> Option<Device<T>> {
+        let idx = get_entry_idx(&self.state, name, 0).ok()?;
+        Some(self.state.remove(idx))
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &Device<T>> {
+        self.state.iter()
+    }
+
+    pub fn devices_mut(&mut self) -> impl Iterator<Item = &mut Device<T>> {
+        self.state.iter_mut()
+    }
+
     pub fn check_activity<'s, U, D>(&'s mut self, mut update_cb: U, create: D) -> Result<()>
     where
         U: FnMut(&mut Device<T>),
         D: Fn(&'s OsStr) -> T,
     {
-        for device in &mut self.state {
-            device.current_sectors = 0;
-        }
-
-        let mut entry_idx = 0;
-
-        for line in self.file.read_lines()? {
-            if let Some((name, sectors)) = parse_line(line)
-                .with_context(|| format!("Parsing line '{}'", String::from_utf8_lossy(line)))?
-            {
-                if let Some(new_entry_idx) = get_entry_idx(&self.state, name, entry_idx) {
-                    entry_idx = new_entry_idx;
-                    let entry_sectors = &mut self.state[entry_idx].current_sectors;
-                    *entry_sectors = entry_sectors.wrapping_add(sectors);
-                } else {
-                    entry_idx = self.state.len().min(entry_idx + 1);
-                    let data = create(name);
-                    let device = Device {
-                        name: name.into(),
-                        current_sectors: sectors,
-                        data,
+        let Self {
+            source,
+            state,
+            members,
+        } = self;
+        match source {
+            Source::Diskstats(file) => {
+                for device in state.iter_mut() {
+                    device.current_sectors = Sectors::default();
+                    device.in_flight = 0;
+                    device.present = false;
+                }
+
+                let mut entry_idx = 0;
+                for line in file.read_lines()? {
+                    let (major, name, sectors, in_flight) =
+                        parse_line(line).with_context(|| {
+                            format!("Parsing line '{}'", String::from_utf8_lossy(line))
+                        })?;
+                    let whole_disk = (crate::sys::is_scsi(major) || crate::sys::is_nvme(name))
+                        && !is_partition(name);
+                    if let Some(name) = resolve(members, name, whole_disk) {
+                        let device = get_or_insert(state, name, &mut entry_idx, &create);
+                        device.current_sectors = device.current_sectors.wrapping_add(sectors);
+                        device.in_flight = device.in_flight.wrapping_add(in_flight);
+                        device.present = true;
+                    }
+                }
+            }
+            Source::Events(stream) => {
+                // Events are not cumulated by the kernel: keep a running total.
+                // Their direction is unknown, they are counted as writes.
+                let mut entry_idx = 0;
+                for line in stream.read_lines()? {
+                    let (name, sectors, actor) = parse_event(line).with_context(|| {
+                        format!("Parsing event '{}'", String::from_utf8_lossy(line))
+                    })?;
+                    // Unlike /proc/diskstats, the I/Os of a partition don't
+                    // show up on its disk: count them there
+                    let disk = match partition_parent(name) {
+                        Some(disk) => {
+                            resolve(members, name, false).or_else(|| resolve(members, disk, true))
+                        }
+                        None => resolve(members, name, true),
                     };
-                    self.state.insert(entry_idx, device);
+                    if let Some(name) = disk {
+                        let device = get_or_insert(state, name, &mut entry_idx, &create);
+                        device.current_sectors.written =
+                            device.current_sectors.written.wrapping_add(sectors);
+                        if sectors != 0 && !actor.is_empty() {
+                            device.actor.clear();
+                            device.actor.extend_from_slice(actor);
+                        }
+                    }
                 }
             }
         }
 
-        for device in &mut self.state {
+        for device in state {
             update_cb(device);
         }
 
@@ -109,41 +296,122 @@ impl<T> IOMonitor<T> {
     }
 }
 
-fn parse_line(line: &[u8]) -> Result<Option<(&OsStr, usize)>> {
+/// Finds the device by name, starting the search from `entry_idx`, or inserts
+/// a newly discovered device in order.
+fn get_or_insert<'a, 's, T, D>(
+    state: &'a mut Vec<Device<T>>,
+    name: &'s OsStr,
+    entry_idx: &mut usize,
+    create: &D,
+) -> &'a mut Device<T>
+where
+    D: Fn(&'s OsStr) -> T,
+{
+    match get_entry_idx(state, name, *entry_idx) {
+        Ok(idx) => *entry_idx = idx,
+        Err(idx) => {
+            *entry_idx = idx;
+            let data = create(name);
+            state.insert(idx, Device::new(name.into(), Sectors::default(), data));
+        }
+    }
+    &mut state[*entry_idx]
+}
+
+/// Reads a supplementary activity signal: the device is busy when the file
+/// holds a non-zero number (eg. the I/Os in flight of `/sys/block/<dev>/inflight`).
+pub fn read_busy_file(path: &Path) -> Result<bool> {
+    let content = fs::read(path).with_context(|| format!("Reading '{}'", path.display()))?;
+    parse_busy(&content).with_context(|| format!("Parsing '{}'", path.display()))
+}
+
+fn parse_busy(content: &[u8]) -> Result<bool> {
+    for number in content
+        .split(u8::is_ascii_whitespace)
+        .filter(|s| !s.is_empty())
+    {
+        if parse_integer(number)? != 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Name of the device monitored for an entry: the device it is a member of,
+/// or the entry itself if it is a `whole_disk` not overridden by members.
+fn resolve<'a>(
+    members: &'a [(OsString, OsString)],
+    name: &'a OsStr,
+    whole_disk: bool,
+) -> Option<&'a OsStr> {
+    if let Some((_, device)) = members.iter().find(|(member, _)| member == name) {
+        Some(device)
+    } else if whole_disk && !members.iter().any(|(_, device)| device == name) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// `sda1`, `nvme0n1p1` or `mmcblk0p1`.
+fn is_partition(name: &OsStr) -> bool {
+    partition_parent(name).is_some()
+}
+
+/// The disk holding a partition, `None` for a whole disk. The disks whose
+/// names end with a digit (`nvme0n1`, `mmcblk0`) suffix their partitions with
+/// `p<n>`, the SCSI, IDE and virtio ones (`sda`, `hda`, `vda`, `xvda`) with
+/// `<n>`. The other devices (`dm-0`, `loop0`, `md127`, `sr0`) are not
+/// partitions.
+fn partition_parent(name: &OsStr) -> Option<&OsStr> {
+    let name = name.as_bytes();
+    let parent = if name.starts_with(b"nvme") || name.starts_with(b"mmcblk") {
+        let p = name.iter().rposition(|c| *c == b'p')?;
+        let (disk, number) = (&name[..p], &name[p + 1..]);
+        if number.is_empty() || !number.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        disk
+    } else if [b"sd".as_slice(), b"hd", b"vd", b"xvd"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    {
+        let digits = name.iter().rev().take_while(|c| c.is_ascii_digit()).count();
+        &name[..name.len() - digits]
+    } else {
+        return None;
+    };
+    (!parent.is_empty() && parent.len() < name.len()).then(|| OsStr::from_bytes(parent))
+}
+
+/// Parses any line of /proc/diskstats, returning the major number, name,
+/// # of read and written/discarded sectors and # of I/Os in progress of the
+/// device.
+pub(crate) fn parse_line(line: &[u8]) -> Result<(usize, &OsStr, Sectors, usize)> {
     let mut it = line.split(|c| *c == b' ').filter(|s| !s.is_empty());
     let mut next_tok = move || it.next().ok_or("Expected token");
 
-    // major
-    if !crate::sys::is_scsi(parse_integer(next_tok()?)?) {
-        return Ok(None);
-    }
+    let major = parse_integer(next_tok()?)?;
     next_tok()?; // minor
 
     let name = next_tok()?; // block identifier
-    let name_digits = name
-        .iter()
-        .rev()
-        .take_while(|c| c.wrapping_sub(b'0') <= 9)
-        .count();
-    if name_digits != 0 {
-        return Ok(None); // a partition
-    }
 
     next_tok()?; // of reads completed (unsigned long)
     next_tok()?; // of reads merged, field 6 – # of writes merged (unsigned long)
 
     // of sectors read (unsigned long)
-    let mut sectors = parse_integer(next_tok()?)?;
+    let read = parse_integer(next_tok()?)?;
 
     next_tok()?; // of milliseconds spent reading (unsigned int)
     next_tok()?; // of writes completed (unsigned long)
     next_tok()?; // of writes merged (unsigned long)
 
     // of sectors written (unsigned long)
-    sectors = sectors.wrapping_add(parse_integer(next_tok()?)?);
+    let mut written = parse_integer(next_tok()?)?;
 
     next_tok()?; // of milliseconds spent writing (unsigned int)
-    next_tok()?; // of I/Os currently in progress (unsigned int)
+    // of I/Os currently in progress (unsigned int)
+    let in_flight = parse_integer(next_tok()?)?;
     next_tok()?; // of milliseconds spent doing I/Os (unsigned int)
     next_tok()?; // weighted # of milliseconds spent doing I/Os (unsigned int)
 
@@ -151,10 +419,209 @@ fn parse_line(line: &[u8]) -> Result<Option<(&OsStr, usize)>> {
     next_tok()?; // of discards merged (unsigned long)
 
     // of sectors discarded (unsigned long)
-    sectors = sectors.wrapping_add(parse_integer(next_tok()?)?);
+    written = written.wrapping_add(parse_integer(next_tok()?)?);
 
-    Ok(Some((
+    Ok((
+        major,
         OsStr::from_bytes(name),
-        sectors,
-    )))
+        Sectors { read, written },
+        in_flight,
+    ))
+}
+
+/// Parses a block event: `<device> <sectors> [<process>]`.
+///
+/// Events with no sectors (eg. flushes) are kept but don't count as activity.
+pub(crate) fn parse_event(line: &[u8]) -> Result<(&OsStr, usize, &[u8])> {
+    let mut it = line.split(|c| *c == b' ').filter(|s| !s.is_empty());
+    let mut next_tok = || it.next().ok_or("Expected token");
+
+    let name = next_tok()?;
+    let sectors = parse_integer(next_tok()?)?;
+    let actor = it.next().unwrap_or_default();
+
+    Ok((OsStr::from_bytes(name), sectors, actor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn presized_diskstats() {
+        // 500 partitions with large counters
+        let mut diskstats = String::new();
+        for i in 0..500 {
+            diskstats += &format!(
+                "  65 {:7} sd{}{} 4294967295 4294967295 18446744073709551615 4294967295 \
+                 4294967295 4294967295 18446744073709551615 4294967295 0 4294967295 \
+                 4294967295 0 0 0 0\n",
+                i,
+                char::from(b'a' + (i / 15) as u8 % 26),
+                i % 15 + 1
+            );
+        }
+        assert!(diskstats.lines().all(|l| l.len() <= DISKSTATS_LINE_LEN));
+        let dir = TempDir::new("rust-idle-diskstats");
+        let path = dir.write("diskstats", &diskstats);
+
+        let capacity = 500 * DISKSTATS_LINE_LEN;
+        let mut reader = BulkReader::open_with_capacity(&path, capacity).unwrap();
+        assert_eq!(reader.read().unwrap().len(), diskstats.len());
+        assert_eq!(reader.capacity(), capacity, "the buffer was reallocated");
+
+        let mut reader = BulkReader::open(&path).unwrap();
+        reader.read().unwrap();
+        assert!(reader.capacity() > 4096);
+    }
+
+    #[test]
+    fn scripted_ticks() {
+        const LINE: &str = "   8       0 sda 10 0 100 0 0 0 {} 0 0 0 0 0 0 0 0\n";
+        let tick = |written: usize| {
+            LINE.replace("{}", &written.to_string())
+                + "   8       1 sda1 10 0 100 0 0 0 0 0 0 0 0 0 0 0 0\n"
+        };
+        let (first, second) = (tick(50), tick(80));
+        let mut monitor = IOMonitor::from_snapshots(&[&first, &second]);
+        let mut sectors = Vec::new();
+        for _ in 0..3 {
+            monitor
+                .check_activity(
+                    |device| sectors.push(device.current_sectors.total()),
+                    |_| (),
+                )
+                .unwrap();
+        }
+        // The partition isn't monitored, the last snapshot is repeated
+        assert_eq!(sectors, [150, 180, 180]);
+    }
+
+    #[test]
+    fn unpartitioned_disk() {
+        // A wiped disk only has its own line, next to a partitioned one
+        let diskstats = "   8       0 sda 10 0 100 0 0 0 50 0 0 0 0 0 0 0 0\n\
+                         8       1 sda1 10 0 100 0 0 0 50 0 0 0 0 0 0 0 0\n\
+                         8      16 sdb 10 0 20 0 0 0 30 0 3 0 0 0 0 0 0\n";
+        let mut monitor = IOMonitor::from_snapshots(&[diskstats]);
+        let mut sectors = Vec::new();
+        monitor
+            .check_activity(
+                |device| sectors.push((device.current_sectors.total(), device.in_flight())),
+                |_| (),
+            )
+            .unwrap();
+        let names: Vec<_> = monitor.devices().map(|device| device.name()).collect();
+        assert_eq!(names, ["sda", "sdb"]);
+        assert_eq!(sectors, [(150, 0), (50, 3)]);
+    }
+
+    #[test]
+    fn read_and_written_sectors() {
+        let line = b"   8       0 sda 10 0 100 0 20 0 50 0 2 0 0 0 0 30 0";
+        let (major, name, sectors, in_flight) = parse_line(line).unwrap();
+        assert_eq!((major, name.to_str()), (8, Some("sda")));
+        // Discards count as writes
+        assert_eq!(
+            sectors,
+            Sectors {
+                read: 100,
+                written: 80
+            }
+        );
+        assert_eq!(in_flight, 2);
+    }
+
+    #[test]
+    fn block_events() {
+        let (name, sectors, actor) = parse_event(b"sda1 8 kworker/u8:2").unwrap();
+        assert_eq!(
+            (name.to_str(), sectors, actor),
+            (Some("sda1"), 8, &b"kworker/u8:2"[..])
+        );
+        // Flushes move no sectors, the process is optional
+        let (name, sectors, actor) = parse_event(b"  nvme0n1  0").unwrap();
+        assert_eq!(
+            (name.to_str(), sectors, actor),
+            (Some("nvme0n1"), 0, &b""[..])
+        );
+
+        // Truncated
+        assert!(parse_event(b"").is_err());
+        assert!(parse_event(b"sda").is_err());
+        // Unknown event, not a # of sectors
+        assert!(parse_event(b"sda flush").is_err());
+        assert!(parse_event(b"sda 8k").is_err());
+    }
+
+    #[test]
+    fn sorted_devices() {
+        // /proc/diskstats lists the devices by major number
+        let diskstats = "   8      16 sdb 10 0 100 0 0 0 50 0 0 0 0 0 0 0 0\n\
+                         259       0 nvme0n1 10 0 100 0 0 0 50 0 0 0 0 0 0 0 0\n\
+                         65       0 sdq 10 0 100 0 0 0 50 0 0 0 0 0 0 0 0\n";
+        let mut monitor = IOMonitor::from_snapshots(&[diskstats]);
+        monitor.push("sdc".into(), ());
+        monitor.push("sda".into(), ());
+        monitor.check_activity(|_| (), |_| ()).unwrap();
+        let names: Vec<_> = monitor.devices().map(|device| device.name()).collect();
+        assert_eq!(names, ["nvme0n1", "sda", "sdb", "sdc", "sdq"]);
+        assert!(monitor.get_mut(OsStr::new("sdq")).is_some());
+        assert!(monitor.remove(OsStr::new("sdb")).is_some());
+        assert!(monitor.get_mut(OsStr::new("sdb")).is_none());
+    }
+
+    #[test]
+    fn explicit_members() {
+        let members = [
+            (OsString::from("sdb1"), OsString::from("sdb")),
+            (OsString::from("sdc"), OsString::from("sdb")),
+        ];
+        let resolve = |name: &'static str, whole_disk| {
+            resolve(&members, OsStr::new(name), whole_disk).map(|dev| dev.to_os_string())
+        };
+        assert_eq!(resolve("sdb1", false), Some("sdb".into()));
+        assert_eq!(resolve("sdc", true), Some("sdb".into()));
+        // Overridden by its members
+        assert_eq!(resolve("sdb", true), None);
+        assert_eq!(resolve("sda", true), Some("sda".into()));
+        assert_eq!(resolve("sda1", false), None);
+    }
+
+    #[test]
+    fn nvme_namespaces() {
+        let diskstats = " 259       0 nvme0n1 10 0 100 0 0 0 50 0 0 0 0 0 0 0 0\n\
+                         259       1 nvme0n1p1 10 0 100 0 0 0 50 0 0 0 0 0 0 0 0\n";
+        let mut monitor = IOMonitor::from_snapshots(&[diskstats]);
+        monitor.check_activity(|_| (), |_| ()).unwrap();
+        let names: Vec<_> = monitor.devices().map(|device| device.name()).collect();
+        assert_eq!(names, ["nvme0n1"]);
+    }
+
+    #[test]
+    fn partitions() {
+        let parent = |name| partition_parent(OsStr::new(name)).map(OsStr::to_str);
+        assert_eq!(parent("sda1"), Some(Some("sda")));
+        assert_eq!(parent("sdab12"), Some(Some("sdab")));
+        assert_eq!(parent("nvme0n1p2"), Some(Some("nvme0n1")));
+        assert_eq!(parent("mmcblk0p1"), Some(Some("mmcblk0")));
+        assert_eq!(parent("vdb3"), Some(Some("vdb")));
+        assert_eq!(parent("xvda1"), Some(Some("xvda")));
+        for disk in ["sda", "nvme0n1", "nvme10n2", "mmcblk0", "123"] {
+            assert_eq!(parent(disk), None, "{}", disk);
+        }
+        // Whole devices whose names end with a number
+        for disk in ["dm-0", "loop0", "md127", "sr0", "zram0", "nbd12"] {
+            assert_eq!(parent(disk), None, "{}", disk);
+        }
+    }
+
+    #[test]
+    fn busy_signal() {
+        assert!(!parse_busy(b"       0        0\n").unwrap());
+        assert!(parse_busy(b"       0        2\n").unwrap());
+        assert!(parse_busy(b"1").unwrap());
+        assert!(parse_busy(b"busy").is_err());
+    }
 }
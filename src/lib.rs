@@ -0,0 +1,44 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Building blocks of the `rust-idle` daemon: activity monitoring
+//! ([`iomonitor`]), system calls for syncing and spinning down disks ([`sys`])
+//! and the idle decision logic ([`policy`]), run by the daemon ([`app`]) as
+//! its command line configures it ([`options`]).
+
+pub mod app;
+pub mod audit;
+pub mod config;
+pub mod control;
+pub mod errors;
+pub mod eventloop;
+pub mod fanotify;
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzz;
+pub mod hooks;
+pub mod http;
+pub mod iomonitor;
+pub mod localtime;
+pub mod log;
+pub mod metrics;
+pub mod mounts;
+pub mod notify;
+pub mod openfiles;
+pub mod options;
+pub mod pidfile;
+pub mod policy;
+pub mod probe;
+pub mod schedule;
+pub mod signals;
+pub mod statsd;
+pub mod status;
+pub mod swaps;
+pub mod syncer;
+pub mod sys;
+#[cfg(test)]
+mod testing;
+pub mod tick;
+pub mod timers;
+pub mod utils;
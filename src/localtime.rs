@@ -0,0 +1,458 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Local time of day, from the time zone of the system: `$TZ`, or else
+//! `/etc/localtime`.
+//!
+//! Only what is needed to find the offset from UTC at a given time is read:
+//! the transitions of a TZif file (see tzfile(5)), and the POSIX rule of its
+//! footer (eg. `CET-1CEST,M3.5.0,M10.5.0/3`) for the times past them.
+
+use std::env;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::errors::{Context, Result};
+
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+const DAY: i64 = 24 * 3600;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TimeZone {
+    /// Times of the transitions, with the offset from UTC (in seconds, east
+    /// positive) taking effect.
+    transitions: Vec<(i64, i32)>,
+    /// Offset before the first transition.
+    initial: i32,
+    /// Offsets after the last transition.
+    rule: Option<Rule>,
+}
+
+impl TimeZone {
+    pub const UTC: Self = Self {
+        transitions: Vec::new(),
+        initial: 0,
+        rule: None,
+    };
+
+    /// Time zone of the system: `$TZ` (a POSIX rule or the path of a TZif file,
+    /// relative to /usr/share/zoneinfo), or else /etc/localtime.
+    pub fn system() -> Result<Self> {
+        match env::var_os("TZ") {
+            Some(tz) if !tz.is_empty() => {
+                let tz = tz.to_string_lossy();
+                let name = tz.strip_prefix(':').unwrap_or(&tz);
+                match Rule::parse(name) {
+                    Some(rule) if !tz.starts_with(':') => Ok(Self {
+                        rule: Some(rule),
+                        ..Self::UTC
+                    }),
+                    _ => Self::read(&Path::new(ZONEINFO).join(name)),
+                }
+            }
+            _ => Self::read(Path::new("/etc/localtime")),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Reading '{}'", path.display()))?;
+        Self::parse(&data)
+            .ok_or_else(|| format!("Invalid time zone file '{}'", path.display()).into())
+    }
+
+    /// Parses a TZif file, its 64-bit section from version 2.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let header = Header::parse(data)?;
+        let (header, body, time_len) = if header.version >= b'2' {
+            let body = data.get(44 + header.len(4)..)?;
+            (Header::parse(body)?, body, 8)
+        } else {
+            (header, data, 4)
+        };
+        let mut rest = body.get(44..)?;
+        let mut take = |len: usize| {
+            let bytes = rest.get(..len)?;
+            rest = &rest[len..];
+            Some(bytes)
+        };
+        let times = take(header.timecnt * time_len)?;
+        let indices = take(header.timecnt)?;
+        let types = take(header.typecnt * 6)?;
+        take(header.len(time_len) - header.timecnt * (time_len + 1) - header.typecnt * 6)?;
+        let utoff = |idx: usize| {
+            let ttinfo = types.get(idx * 6..idx * 6 + 4)?;
+            Some(i32::from_be_bytes(ttinfo.try_into().ok()?))
+        };
+        let transitions = times
+            .chunks(time_len)
+            .zip(indices)
+            .map(|(time, idx)| {
+                let time = match time_len {
+                    8 => i64::from_be_bytes(time.try_into().ok()?),
+                    _ => i32::from_be_bytes(time.try_into().ok()?).into(),
+                };
+                Some((time, utoff((*idx).into())?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        // The footer follows the 64-bit section: "\n<rule>\n"
+        let rule = match (time_len, rest) {
+            (8, [b'\n', footer @ ..]) => footer
+                .split(|c| *c == b'\n')
+                .next()
+                .and_then(|rule| std::str::from_utf8(rule).ok())
+                .and_then(Rule::parse),
+            _ => None,
+        };
+        Some(Self {
+            transitions,
+            initial: utoff(0)?,
+            rule,
+        })
+    }
+
+    /// Offset from UTC at `time` (seconds since the epoch), in seconds.
+    pub fn offset(&self, time: i64) -> i32 {
+        match self.transitions.partition_point(|(t, _)| *t <= time) {
+            0 if !self.transitions.is_empty() => self.initial,
+            idx if idx == self.transitions.len() => self.rule.as_ref().map_or_else(
+                || {
+                    self.transitions
+                        .last()
+                        .map_or(self.initial, |(_, off)| *off)
+                },
+                |rule| rule.offset(time),
+            ),
+            idx => self.transitions[idx - 1].1,
+        }
+    }
+
+    /// Local time of the day, in minutes since midnight.
+    pub fn minute_of_day(&self, time: SystemTime) -> u16 {
+        let time = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let local = time + i64::from(self.offset(time));
+        (local.rem_euclid(DAY) / 60) as u16
+    }
+}
+
+struct Header {
+    version: u8,
+    isutcnt: usize,
+    isstdcnt: usize,
+    leapcnt: usize,
+    timecnt: usize,
+    typecnt: usize,
+    charcnt: usize,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let header = data.get(..44).filter(|h| h.starts_with(b"TZif"))?;
+        let count = |idx: usize| {
+            let bytes = header[20 + idx * 4..24 + idx * 4].try_into().ok()?;
+            usize::try_from(u32::from_be_bytes(bytes)).ok()
+        };
+        Some(Self {
+            version: header[4],
+            isutcnt: count(0)?,
+            isstdcnt: count(1)?,
+            leapcnt: count(2)?,
+            timecnt: count(3)?,
+            typecnt: count(4)?,
+            charcnt: count(5)?,
+        })
+    }
+
+    /// Length of the data block, with times (and leap seconds) of `time_len`
+    /// bytes.
+    fn len(&self, time_len: usize) -> usize {
+        self.timecnt * (time_len + 1)
+            + self.typecnt * 6
+            + self.charcnt
+            + self.leapcnt * (time_len + 4)
+            + self.isstdcnt
+            + self.isutcnt
+    }
+}
+
+/// POSIX time zone rule: `std offset [dst [offset] [,start[/time],end[/time]]]`.
+#[derive(Debug, PartialEq, Eq)]
+struct Rule {
+    /// Offsets from UTC, east positive.
+    std: i32,
+    dst: Option<Dst>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Dst {
+    offset: i32,
+    /// Dates and local times (in seconds) of the changes to and from the
+    /// daylight saving time.
+    start: (Date, i32),
+    end: (Date, i32),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Date {
+    /// `Jn`: day of the year, from 1, never counting February 29.
+    Julian(u16),
+    /// `n`: day of the year, from 0.
+    Day(u16),
+    /// `Mm.w.d`: day `d` (0: Sunday) of the week `w` (5: the last one) of the
+    /// month `m`.
+    Month(u8, u8, u8),
+}
+
+impl Rule {
+    fn parse(s: &str) -> Option<Self> {
+        let mut s = s.as_bytes();
+        skip_name(&mut s)?;
+        let std = -parse_offset(&mut s)?;
+        if s.is_empty() {
+            return Some(Self { std, dst: None });
+        }
+        skip_name(&mut s)?;
+        let offset = match s.first() {
+            Some(b',') | None => std + 3600,
+            _ => -parse_offset(&mut s)?,
+        };
+        // The rules of the US by default
+        let (start, end) = match s {
+            [] => ((Date::Month(3, 2, 0), 7200), (Date::Month(11, 1, 0), 7200)),
+            [b',', rest @ ..] => {
+                s = rest;
+                let start = parse_change(&mut s)?;
+                s = s.strip_prefix(b",")?;
+                (start, parse_change(&mut s)?)
+            }
+            _ => return None,
+        };
+        s.is_empty().then_some(Self {
+            std,
+            dst: Some(Dst { offset, start, end }),
+        })
+    }
+
+    fn offset(&self, time: i64) -> i32 {
+        let Some(dst) = &self.dst else {
+            return self.std;
+        };
+        let (year, _, _) = civil_from_days((time + i64::from(self.std)).div_euclid(DAY));
+        // Times of the changes in UTC, from the local time in effect before
+        let start = dst.start.0.day(year) * DAY + i64::from(dst.start.1 - self.std);
+        let end = dst.end.0.day(year) * DAY + i64::from(dst.end.1 - dst.offset);
+        let in_dst = if start <= end {
+            (start..end).contains(&time)
+        } else {
+            !(end..start).contains(&time)
+        };
+        if in_dst { dst.offset } else { self.std }
+    }
+}
+
+impl Date {
+    /// Days since the epoch of this date in `year`.
+    fn day(&self, year: i64) -> i64 {
+        let jan1 = days_from_civil(year, 1, 1);
+        match *self {
+            Date::Julian(n) => {
+                let n = i64::from(n);
+                jan1 + n - 1 + i64::from(is_leap(year) && n > 59)
+            }
+            Date::Day(n) => jan1 + i64::from(n),
+            Date::Month(month, week, weekday) => {
+                let first = days_from_civil(year, month.into(), 1);
+                // 1970-01-01 was a Thursday
+                let first_weekday = (first + 4).rem_euclid(7);
+                let mut day = first + (i64::from(weekday) - first_weekday).rem_euclid(7);
+                day += 7 * (i64::from(week) - 1);
+                let next_month = match month {
+                    12 => days_from_civil(year + 1, 1, 1),
+                    _ => days_from_civil(year, i64::from(month) + 1, 1),
+                };
+                while day >= next_month {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+}
+
+/// Skips a zone name: letters, or any characters between `<` and `>`.
+fn skip_name(s: &mut &[u8]) -> Option<()> {
+    let len = match s.first()? {
+        b'<' => s.iter().position(|c| *c == b'>')? + 1,
+        _ => s.iter().take_while(|c| c.is_ascii_alphabetic()).count(),
+    };
+    (len >= 3).then(|| *s = &s[len..])
+}
+
+/// Parses `[+-]hh[:mm[:ss]]`, in seconds.
+fn parse_offset(s: &mut &[u8]) -> Option<i32> {
+    let sign = match s.first()? {
+        b'-' => -1,
+        b'+' => 1,
+        _ => 0,
+    };
+    if sign != 0 {
+        *s = &s[1..];
+    }
+    let mut seconds = 0;
+    for (idx, unit) in [3600, 60, 1].into_iter().enumerate() {
+        if idx > 0 {
+            match s.strip_prefix(b":") {
+                Some(rest) => *s = rest,
+                None => break,
+            }
+        }
+        let digits = s.iter().take_while(|c| c.is_ascii_digit()).count();
+        let value: i32 = std::str::from_utf8(&s[..digits]).ok()?.parse().ok()?;
+        *s = &s[digits..];
+        seconds += value * unit;
+    }
+    Some(if sign < 0 { -seconds } else { seconds })
+}
+
+/// Parses `date[/time]`, the time defaulting to 02:00.
+fn parse_change(s: &mut &[u8]) -> Option<(Date, i32)> {
+    let number = |s: &mut &[u8]| {
+        let digits = s.iter().take_while(|c| c.is_ascii_digit()).count();
+        let value = std::str::from_utf8(&s[..digits]).ok()?.parse().ok();
+        *s = &s[digits..];
+        value
+    };
+    let date = match s.first()? {
+        b'J' => {
+            *s = &s[1..];
+            Date::Julian(number(s).filter(|n| (1..=365).contains(n))?)
+        }
+        b'M' => {
+            *s = &s[1..];
+            let month = number(s).filter(|m| (1..=12).contains(m))?;
+            *s = s.strip_prefix(b".")?;
+            let week = number(s).filter(|w| (1..=5).contains(w))?;
+            *s = s.strip_prefix(b".")?;
+            let weekday = number(s).filter(|d| *d <= 6)?;
+            Date::Month(month as u8, week as u8, weekday as u8)
+        }
+        _ => Date::Day(number(s).filter(|n| *n <= 365)?),
+    };
+    let time = match s.strip_prefix(b"/") {
+        Some(rest) => {
+            *s = rest;
+            parse_offset(s)?
+        }
+        None => 7200,
+    };
+    Some((date, time))
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// Days since the epoch of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date of a number of days since the epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calendar() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2024, 3, 31), 19813);
+        assert_eq!(civil_from_days(19813), (2024, 3, 31));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        // Last Sunday of March and October 2024
+        assert_eq!(Date::Month(3, 5, 0).day(2024), 19813);
+        assert_eq!(
+            Date::Month(10, 5, 0).day(2024),
+            days_from_civil(2024, 10, 27)
+        );
+        assert_eq!(Date::Julian(60).day(2024), days_from_civil(2024, 3, 1));
+    }
+
+    #[test]
+    fn posix_rules() {
+        let paris = Rule::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(paris.std, 3600);
+        let at = |y, m, d, secs| days_from_civil(y, m, d) * DAY + secs;
+        // The change happens at 01:00 UTC
+        assert_eq!(paris.offset(at(2024, 3, 31, 3599)), 3600);
+        assert_eq!(paris.offset(at(2024, 3, 31, 3600)), 7200);
+        assert_eq!(paris.offset(at(2024, 10, 27, 3599)), 7200);
+        assert_eq!(paris.offset(at(2024, 10, 27, 3600)), 3600);
+        // Southern hemisphere, DST over the new year
+        let sydney = Rule::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(sydney.offset(at(2024, 1, 15, 0)), 11 * 3600);
+        assert_eq!(sydney.offset(at(2024, 7, 15, 0)), 10 * 3600);
+        let quoted = Rule::parse("<+0530>-5:30").unwrap();
+        assert_eq!(
+            quoted,
+            Rule {
+                std: 19800,
+                dst: None
+            }
+        );
+        assert!(Rule::parse("Europe/Paris").is_none());
+    }
+
+    #[test]
+    fn tzif() {
+        // Version 2: an empty 32-bit section, then a transition from UTC to
+        // UTC+2 at 1000, with a footer
+        let header = |timecnt: u32, typecnt: u32, charcnt: u32| {
+            let mut header = b"TZif2".to_vec();
+            header.resize(20, 0);
+            for count in [0, 0, 0, timecnt, typecnt, charcnt] {
+                header.extend_from_slice(&u32::to_be_bytes(count));
+            }
+            header
+        };
+        let mut data = header(0, 1, 4);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(b"UTC\0");
+        data.extend(header(1, 2, 8));
+        data.extend_from_slice(&1000i64.to_be_bytes());
+        data.push(1);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&7200i32.to_be_bytes());
+        data.extend_from_slice(&[1, 4]);
+        data.extend_from_slice(b"UTC\0EET\0");
+        data.extend_from_slice(b"\nEET-2\n");
+        let zone = TimeZone::parse(&data).unwrap();
+        assert_eq!(zone.offset(999), 0);
+        assert_eq!(zone.offset(1000), 7200);
+        assert_eq!(zone.offset(1 << 40), 7200);
+        let noon = UNIX_EPOCH + std::time::Duration::from_secs(10 * 3600);
+        assert_eq!(zone.minute_of_day(noon), 12 * 60);
+        assert!(TimeZone::parse(&data[..60]).is_none());
+    }
+}
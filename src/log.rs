@@ -0,0 +1,178 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Log messages, prefixed by their syslog level (`<N>`) for journald. On a
+//! terminal, the prefix is rendered as a severity word, colored unless
+//! `NO_COLOR` is set.
+//!
+//! Messages are written with [`logln!`](crate::logln) (stdout) and
+//! [`elogln!`](crate::elogln) (stderr), like `println!` and `eprintln!`. With
+//! `--syslog`, they are sent to the local syslog daemon instead.
+
+use std::fmt;
+use std::io::IsTerminal;
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::errors::{Context, Result};
+
+/// Keep the `<N>` prefixes.
+const PREFIX: u8 = 0;
+/// Severity words.
+const WORDS: u8 = 1;
+/// Colored severity words.
+const COLORS: u8 = 2;
+
+static STDOUT_STYLE: AtomicU8 = AtomicU8::new(PREFIX);
+static STDERR_STYLE: AtomicU8 = AtomicU8::new(PREFIX);
+
+/// Socket of the syslog daemon, once opened by [`open_syslog`].
+static SYSLOG: OnceLock<UnixDatagram> = OnceLock::new();
+const SYSLOG_PATH: &str = "/dev/log";
+/// `LOG_DAEMON` facility.
+const FACILITY_DAEMON: u8 = 3;
+
+/// Selects the rendering of stdout and stderr, depending on whether they are
+/// terminals.
+pub fn init() {
+    let tty_style = if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        WORDS
+    } else {
+        COLORS
+    };
+    if std::io::stdout().is_terminal() {
+        STDOUT_STYLE.store(tty_style, Ordering::Relaxed);
+    }
+    if std::io::stderr().is_terminal() {
+        STDERR_STYLE.store(tty_style, Ordering::Relaxed);
+    }
+}
+
+/// Sends the messages to the syslog daemon (`/dev/log`) from now on, instead
+/// of stdout and stderr.
+pub fn open_syslog() -> Result<()> {
+    let socket = UnixDatagram::unbound()
+        .and_then(|socket| socket.connect(SYSLOG_PATH).map(|_| socket))
+        .with_context(|| format!("Connecting to {}", SYSLOG_PATH))?;
+    let _ = SYSLOG.set(socket);
+    Ok(())
+}
+
+#[macro_export]
+macro_rules! logln {
+    ($($arg:tt)*) => {
+        $crate::log::write(false, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! elogln {
+    ($($arg:tt)*) => {
+        $crate::log::write(true, format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+pub fn write(stderr: bool, args: fmt::Arguments) {
+    // Falls back to stdout and stderr if the syslog daemon is gone
+    if let Some(syslog) = SYSLOG.get()
+        && syslog
+            .send(datagram(&args.to_string(), stderr).as_bytes())
+            .is_ok()
+    {
+        return;
+    }
+    let style = if stderr { &STDERR_STYLE } else { &STDOUT_STYLE };
+    match style.load(Ordering::Relaxed) {
+        PREFIX if stderr => eprintln!("{}", args),
+        PREFIX => println!("{}", args),
+        style => {
+            let line = render(&args.to_string(), style == COLORS);
+            if stderr {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+/// Splits the `<N>` prefix of a message.
+fn split_level(message: &str) -> Option<(u8, &str)> {
+    let level = message
+        .strip_prefix('<')
+        .and_then(|m| m.get(..2))
+        .and_then(|m| m.strip_suffix('>'))
+        .and_then(|level| level.parse::<u8>().ok())?;
+    Some((level, &message[3..]))
+}
+
+/// Formats a message as an RFC 3164 datagram, `<PRI>rust-idle[PID]: MSG`,
+/// leaving the timestamp and host name to the syslog daemon. Messages without
+/// a level are errors on stderr, informational on stdout.
+fn datagram(message: &str, stderr: bool) -> String {
+    let default_level = if stderr { 3 } else { 6 };
+    let (level, message) = split_level(message).unwrap_or((default_level, message));
+    format!(
+        "<{}>rust-idle[{}]: {}",
+        FACILITY_DAEMON * 8 + level.min(7),
+        std::process::id(),
+        message
+    )
+}
+
+/// Replaces the `<N>` prefix of a message by a severity word.
+fn render(message: &str, color: bool) -> String {
+    let Some((level, message)) = split_level(message) else {
+        return message.to_owned();
+    };
+    let (word, ansi) = match level {
+        0..=3 => ("error", "31"),
+        4 => ("warning", "33"),
+        5 => ("notice", "32"),
+        6 => ("info", ""),
+        _ => ("debug", "2"),
+    };
+    // Avoid "error: error: ..."
+    let message = message
+        .strip_prefix(word)
+        .and_then(|m| m.strip_prefix(": "))
+        .unwrap_or(message);
+    if color && !ansi.is_empty() {
+        format!("\x1b[{}m{}:\x1b[0m {}", ansi, word, message)
+    } else {
+        format!("{}: {}", word, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tty_rendering() {
+        assert_eq!(
+            render("<5>sda has gone idle.", false),
+            "notice: sda has gone idle."
+        );
+        assert_eq!(render("<3>error: boom", true), "\x1b[31merror:\x1b[0m boom");
+        assert_eq!(render("<6>Syncing sda", true), "info: Syncing sda");
+        assert_eq!(render("No prefix", true), "No prefix");
+        assert_eq!(render("<x>", false), "<x>");
+    }
+
+    #[test]
+    fn syslog_datagrams() {
+        let pid = std::process::id();
+        assert_eq!(
+            datagram("<5>sda has gone idle.", false),
+            format!("<29>rust-idle[{}]: sda has gone idle.", pid)
+        );
+        assert_eq!(
+            datagram("Failed", true),
+            format!("<27>rust-idle[{}]: Failed", pid)
+        );
+    }
+}
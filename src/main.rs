@@ -2,401 +2,262 @@
 // reserved. Use of this source is governed by MIT License that can be found in
 // the LICENSE file.
 
-mod errors;
-mod iomonitor;
-mod mounts;
-mod sys;
-mod utils;
-
-use std::env;
-use std::ffi::{OsStr, OsString};
-use std::fmt;
-use std::io::{stderr, Write};
+use std::ffi::OsString;
+use std::io::{Write, stderr};
 use std::process::exit;
-use std::time::{Duration, SystemTime};
-
-use os_str_bytes::{RawOsStr, RawOsString};
-
-use errors::{Context, Result};
-use mounts::Mounts;
-
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum DeviceState {
-    Spinning(),
-    /// The disk was synced: next update will ignore activity and transition to `Idle`.
-    Synced(),
-    Idle(),
-}
 
-/// Stores disk config and retained statistics. `IOMonitor` wraps instances
-/// inside `Device<DeviceData>`s which adds statistics read from /dev/diskstats.
-struct DeviceData {
-    sectors: usize,
-    state: DeviceState,
-    last_io: SystemTime,
-    config: DeviceConfig,
-}
-
-impl From<DeviceConfig> for DeviceData {
-    fn from(config: DeviceConfig) -> Self {
-        Self {
-            config,
-            state: DeviceState::Spinning(),
-            sectors: 0,
-            last_io: SystemTime::UNIX_EPOCH,
+use rust_idle::app::App;
+use rust_idle::errors::{Context, Result};
+use rust_idle::mounts::Mounts;
+use rust_idle::options::{Arguments, dump_config, read_arguments};
+use rust_idle::{elogln, log, probe, signals};
+
+/// Writes the compatibility report of the configured drives, then of the
+/// other SCSI drives of the system.
+fn probe_all(mut devices: Vec<OsString>, verbosity: u8) -> Result<()> {
+    for dev in probe::scsi_disks()? {
+        if !devices.contains(&dev) {
+            devices.push(dev);
         }
     }
-}
-
-type IOMonitor = iomonitor::IOMonitor<DeviceData>;
-type Device = iomonitor::Device<DeviceData>;
-
-impl Device {
-    /// Main state transition function.
-    ///
-    /// Runtime errors are handled here and recovered from after writing to
-    /// stderr.
-    fn tick(self: &mut Device, now: SystemTime, mounts: &mut Mounts) -> DeviceState {
-        let (dev_name, new_sectors, device_data) = self.into();
-        let config = &device_data.config;
-
-        // Difference in read/write/discarded sectors tells us if the disk was
-        // busy between two time steps.
-        let sectors_inc = new_sectors.wrapping_sub(device_data.sectors);
-        let busy = sectors_inc != 0;
-
-        let idle_time = if busy {
-            // Update retained statistics in DeviceData
-            if config.verbosity >= 3 && device_data.sectors != 0 {
-                println!(
-                    "<7>Activity detected on {}, sectors: {} => {} (+{})",
-                    dev_name.to_string_lossy(),
-                    device_data.sectors,
-                    new_sectors,
-                    sectors_inc
-                );
-            }
-            device_data.sectors = new_sectors;
-            device_data.last_io = now;
-
-            Duration::ZERO
-        } else {
-            now.duration_since(device_data.last_io)
-                .expect("non monotonic time")
-        };
-
-        // Skip unconfigured disks
-        if config.idle_time == Duration::ZERO {
-            return DeviceState::Spinning();
-        }
-
-        // Compute and execute state transitions
-        device_data.state = match device_data.state {
-            DeviceState::Spinning() => {
-                if idle_time >= config.idle_time {
-                    if config.verbosity >= 1 {
-                        println!(
-                            "<5>{} has gone idle. (idle_time: {}s >= {}s)",
-                            dev_name.to_string_lossy(),
-                            idle_time.as_secs(),
-                            config.idle_time.as_secs()
-                        );
-                    }
-                    let next_state = if config.sync_flags & SYNC_SPIN_DOWN == 0 {
-                        DeviceState::Idle()
-                    } else {
-                        sync_block_device(mounts, dev_name, config.verbosity);
-                        DeviceState::Synced()
-                    };
-                    if config.verbosity >= 2 {
-                        println!("<6>Spinning down {}", dev_name.to_string_lossy());
-                    }
-                    if let Err(e) = sys::spindown_disk(dev_name) {
-                        eprintln!(
-                            "<4>Failed to spin down {}: {}",
-                            dev_name.to_string_lossy(),
-                            e
-                        );
-                    }
-                    next_state
-                } else {
-                    DeviceState::Spinning()
-                }
-            }
-            DeviceState::Synced() => DeviceState::Idle(),
-            DeviceState::Idle() => {
-                if busy {
-                    if config.verbosity >= 1 {
-                        println!(
-                            "<5>{} has spun up. (idle_time: {}s)",
-                            dev_name.to_string_lossy(),
-                            idle_time.as_secs()
-                        );
-                    }
-                    if config.sync_flags & SYNC_SPIN_UP != 0 {
-                        sync_block_device(mounts, dev_name, config.verbosity);
-                    }
-                    DeviceState::Spinning()
-                } else {
-                    DeviceState::Idle()
-                }
-            }
-        };
-        device_data.state
-    }
-}
-
-/// Syncfs all filesystems associated with the given device, then sync the
-/// device buffers.
-///
-/// mounts: utility object to read and cache the mount points.
-fn sync_block_device(mounts: &mut Mounts, dev: &OsStr, verbosity: u8) {
-    if verbosity >= 2 {
-        println!("<6>Syncing {}", dev.to_string_lossy());
-    }
-
-    if let Err(e) = mounts
-        .for_dev(dev, |mount_point| {
-            if verbosity >= 3 {
-                println!(
-                    "<7>syncfs({})",
-                    String::from_utf8_lossy(mount_point.to_bytes())
-                );
-            }
-            sys::syncfs(mount_point)
-        })
-        //FIXME: is this redundant?
-        .and_then(|_| sys::sync_blockdev(dev))
-    {
-        eprintln!("<4>Failed to sync {}: {}\n", dev.to_string_lossy(), e);
+    if devices.is_empty() {
+        println!("No SCSI drive found");
     }
-}
-
-#[derive(Clone, Default)]
-struct DeviceConfig {
-    idle_time: Duration,
-    sync_flags: u8,
-    verbosity: u8,
-}
-
-const SYNC_SPIN_DOWN: u8 = 1;
-const SYNC_SPIN_UP: u8 = 2;
-
-impl fmt::Display for DeviceConfig {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        const SYNC_BOTH: u8 = SYNC_SPIN_DOWN | SYNC_SPIN_UP;
-        let sync_flags = match self.sync_flags {
-            0 => "NONE",
-            SYNC_SPIN_DOWN => "SPIN_DOWN",
-            SYNC_SPIN_UP => "SPIN_UP",
-            SYNC_BOTH => "SPIN_DOWN | SPIN_UP",
-            _ => "UNKNOWN",
-        };
-        write!(
-            f,
-            "{{ idle_time: {}s, sync_flags: {}, verbosity: {} }}",
-            self.idle_time.as_secs(),
-            sync_flags,
-            self.verbosity
-        )
+    let mut mounts = Mounts::new()?;
+    let mut out = std::io::stdout();
+    for dev in devices {
+        probe::report(&mut out, &mut mounts, &dev, verbosity)?;
     }
+    Ok(())
 }
 
-struct App {
-    devices_monitor: IOMonitor,
-    mounts: Mounts,
-    default_config: DeviceConfig,
-    interval: Duration,
-}
-
-impl App {
-    fn new(
-        default_config: DeviceConfig,
-        mut device_configs: Vec<(OsString, DeviceConfig)>,
-    ) -> Result<Option<Self>> {
-        let mut devices_monitor = IOMonitor::new()?;
-        let mut min_idle_time = if default_config.idle_time > Duration::ZERO {
-            default_config.idle_time
-        } else {
-            Duration::MAX
-        };
-
-        // Insert configured devices in the IOMonitor while checking for duplicates
-        device_configs.sort_by(|(a, _), (b, _)| a.cmp(b));
-        let mut prev_name = OsStr::new("");
-        for (dev, config) in device_configs {
-            if prev_name == dev {
-                return Err(format!("Duplicated device: {}", dev.to_string_lossy()).into());
-            }
-            if config.verbosity >= 2 {
-                println!(
-                    "<6>Device {} configured as {}",
-                    dev.to_string_lossy(),
-                    config
-                );
-            }
-            if config.idle_time > Duration::ZERO {
-                min_idle_time = min_idle_time.min(config.idle_time);
-            }
-            prev_name = devices_monitor.push(dev, config.into()).name();
-        }
-
-        let interval = (min_idle_time / 10).max(Duration::from_secs(1));
-        if default_config.verbosity >= 2 {
-            println!(
-                "<6>Default device configuration: {}. Refresh period: {}s",
-                default_config,
-                interval.as_secs()
-            );
-        }
-
-        Ok(if min_idle_time == Duration::MAX {
-            None // No device with an idle_time > 0, show usage and exit
-        } else {
-            Some(Self {
-                devices_monitor,
-                mounts: Mounts::new()?,
-                default_config,
-                interval,
-            })
-        })
-    }
-
-    fn tick(&mut self) -> Result<bool> {
-        self.mounts.update(); // Clear the mount table, will lazy load when needed.
-
-        let now = SystemTime::now();
-        let mut will_sleep = true;
-
-        self.devices_monitor.check_activity(
-            |device| {
-                let new_state = device.tick(now, &mut self.mounts);
-                // Immediately refresh the statistics while ignoring activity
-                // from syncing this device.
-                will_sleep &= new_state != DeviceState::Synced();
-            },
-            |name| {
-                if self.default_config.verbosity >= 1 {
-                    println!("<5>New device detected: {}", name.to_string_lossy());
-                }
-                self.default_config.clone().into()
-            },
-        )?;
-
-        Ok(will_sleep)
-    }
-
-    fn run(&mut self) -> Result<()> {
-        loop {
-            if self.tick()? {
-                std::thread::sleep(self.interval);
-            }
-        }
+fn parse_args() -> Result<App> {
+    // Resolving the devices may be slow: catch termination signals from here
+    signals::install()?;
+    let Arguments {
+        bin_name,
+        default_config,
+        device_configs,
+        options,
+    } = read_arguments()?;
+
+    if options.syslog {
+        log::open_syslog()?;
     }
-}
-
-fn parse_flags(flags: &RawOsStr, default: &DeviceConfig) -> Result<DeviceConfig> {
-    let mut config = default.clone();
-    let mut idle_time = 0;
-    let mut idle_time_sealed = false;
-    let mut prefix = b'+';
-    let mut prev_flag = b' ';
-    for &c in flags.as_encoded_bytes() {
-        if prev_flag != b'-' && c != prev_flag {
-            prefix = b'+'; // Reset modifier to the default (+), but not for '-vv' (equivalent to '-v-v')
-        }
-        let digit = u64::from(c.wrapping_sub(b'0'));
-        if digit < 10 {
-            if idle_time_sealed {
-                return Err("idle time already set".into());
-            }
-            idle_time = idle_time * 10 + digit;
-        } else {
-            idle_time_sealed = idle_time > 0;
-            match c {
-                b's' => {
-                    if prefix == b'+' {
-                        config.sync_flags |= SYNC_SPIN_DOWN;
-                    } else {
-                        config.sync_flags &= !SYNC_SPIN_DOWN;
-                    }
-                }
-                b'S' => {
-                    if prefix == b'+' {
-                        config.sync_flags |= SYNC_SPIN_UP;
-                    } else {
-                        config.sync_flags &= !SYNC_SPIN_UP;
-                    }
-                }
-                b'v' => {
-                    config.verbosity = if prefix == b'+' {
-                        config.verbosity.saturating_add(1)
-                    } else {
-                        config.verbosity.saturating_sub(1)
-                    }
-                }
-                b'+' | b'-' => {
-                    prefix = c;
-                }
-                _ => {
-                    return Err(format!("invalid flag '{}'", c as char).into());
-                }
-            }
-        }
-        prev_flag = c;
+    if options.list {
+        probe::list(&mut std::io::stdout(), default_config.verbosity)?;
+        exit(0)
     }
-    if idle_time > 0 || idle_time_sealed {
-        config.idle_time = Duration::from_secs(idle_time);
+    if options.probe_all {
+        let configured = device_configs.iter().map(|(dev, _)| dev.clone());
+        probe_all(configured.collect(), default_config.verbosity)?;
+        exit(0)
     }
-    Ok(config)
-}
-
-fn parse_args() -> Result<App> {
-    let mut args = env::args_os().map(RawOsString::new);
-    let mut default_config = DeviceConfig::default();
-    let mut device_configs = Vec::with_capacity(args.len() - 1);
-
-    let bin_name = args.next();
-    for arg in args {
-        let (disk, flags) = arg
-            .split_once(':')
-            .map_or((arg.as_ref(), None), |(disk, flags)| (disk, Some(flags)));
-
-        let config = if let Some(flags) = flags {
-            // "[disk]:flags" -> use the config made with flags on top of config
-            parse_flags(flags, &default_config)?
-        } else {
-            // "disk" -> use the default config
-            default_config.clone()
-        };
-
-        if disk.is_empty() {
-            // ":flags" -> assign flags to the default config
-            default_config = config;
-        } else {
-            // "disk:[flags]" -> set the config of the device
-            let dev = sys::link_to_scsi_name(disk.as_os_str())
-                .with_context(|| format!("getting device for {}", disk.to_str_lossy()))?;
-            device_configs.push((dev, config));
-        }
+    // Before creating the daemon: no pidfile, nor drive programmed
+    if options.dump_config {
+        dump_config(
+            &mut std::io::stdout(),
+            &default_config,
+            device_configs,
+            &options,
+        )?;
+        exit(0)
     }
 
-    App::new(default_config, device_configs)?.map_or_else(
-        || {
+    App::new(default_config, device_configs, options)?.map_or_else(
+        || -> Result<App> {
             write!(
                 stderr(),
                 r#"No disk configured with an idle time > 0, will do nothing.
 
-Usage: {} :<default flags> <device path or symlink>[:<flags>]
+Usage: {} [options] :<default flags> <device>[:<flags>]
+
+<device> is a path or symlink to a disk, or `mount:<path>` for the disk holding
+the filesystem mounted on <path>. A partition stands for its disk, and a logical
+volume (LVM, LUKS) or software RAID for all the disks backing it. A symlink (eg.
+in /dev/disk/by-id) is followed if the kernel later gives the disk another name.
+
+options:
+    -c <path>:        read a configuration file before the other arguments,
+                      which override it. Besides arguments, it may hold
+                      `[default]` and `[device]` sections of `key = value`
+                      lines, setting the flags below: `device = <device>`,
+                      `idle = <secs>`, `sync = none|spin_down|spin_up|both`,
+                      `verbosity = <0-3>`, and `sync_all` (G), `dirty_only`
+                      (L), `deadband` (d), `min_rate` (e), `min_sectors` (n),
+                      `wake_period` (w), `wake_duration` (W), `min_uptime` (u),
+                      `max_spindowns` (c), `spindown_delay` (D), `grace_ticks`
+                      (g), `nvme_power_state` (N), `apm_level` (a),
+                      `power_condition` (p), `open_check` (o),
+                      `ignore_reads` (r), `ignore_writes` (x), `firmware_timer`
+                      (t) set to a value, `yes` or `no`. Durations may end
+                      with a `s`, `m` or `h` unit (eg. `idle = 300s`)
+    --config <path>:  read arguments from a file, as whitespace separated words
+                      after an optional `RUST_IDLE_OPTS=`. It may include other
+                      files with `include <path>` lines (eg. `include
+                      rust-idle.d/*.conf`); later files override the devices
+                      configured by earlier ones. SIGHUP reloads the
+                      configuration of the devices, keeping their idle times
+    --events <path>: read block events from a stream (eg. a FIFO) instead of
+                     polling /proc/diskstats. One event per line:
+                     `<device> <sectors> [<process>]`
+    --diskstats <device>=<name>[,<name>...]: account the activity of these
+                      entries of /proc/diskstats (eg. partitions or multipath
+                      members) to the disk <device> (eg. `sdb=sdb1,sdb2`),
+                      instead of its own whole-disk entry
+    --busy-file <device>[=<path>]: also consider the disk busy while <path>
+                      holds a non-zero number, for activity not moving sectors.
+                      Defaults to the I/Os in flight, /sys/block/<device>/inflight
+    --on-spindown [<device>=]<command>: run <command> when the disk (or any
+                      disk) spins down, without waiting for it. Its environment
+                      holds IDLE_DEVICE, IDLE_STATE (idle) and IDLE_IDLE_SECS
+    --on-spinup [<device>=]<command>: same, when the disk spins up
+                      (IDLE_STATE=spinning)
+    --pre-spindown [<device>=]<command>: run <command> before spinning the
+                      disk down, with the same environment, and wait for it (up
+                      to 10s): the disk keeps spinning if it fails or hangs
+    --spindown-window [<device>=]<HH:MM>-<HH:MM>: only spin down the disk (or
+                      any disk) within this window of the day (eg. 22:00-07:00),
+                      in the time zone of $TZ or /etc/localtime. Repeatable
+    --pidfile <path>: write the PID of the daemon to a file, refuse to start if
+                      it refers to another running instance
+    --audit <path>:   append each sync, spin down and spin up of the disks to
+                      a file, with its time, reason, idle time and result. The
+                      file should not be on a disk which is spun down
+    --oom-score-adj <value>: adjust the score of the daemon for the OOM killer,
+                      from -1000 (never killed) to 1000
+    --syslog:         send the log messages to the syslog daemon (/dev/log),
+                      rather than to stdout and stderr (eg. when not run by
+                      systemd)
+    --name <tag>:     set the command name of the process to `rust-idle-<tag>`
+                      (truncated to 15 characters), shown by `top` and `ps -o
+                      comm`. The command line shown by `ps -ef` is unchanged
+    --only-listed:    only manage the disks given as arguments: the default
+                      flags don't apply to the other disks, which are left alone
+    --dump-config:    print the effective configuration in the format of the
+                      configuration file, then exit
+    --monitor:        only report the activity and idle time of the disks at each
+                      refresh, never sync nor spin them down, nor up: the
+                      requests of the control interfaces are refused
+    --dry-run:        log the syncs and spin downs instead of executing them,
+                      the disks being handled as if they were spun down. Their
+                      APM level and standby timer are not programmed either
+    --slow-command <ms>: warn when spinning a disk up or down takes longer
+                      than <ms> milliseconds (eg. a struggling drive or bridge)
+    --shrink-after <n>: shrink the buffers holding /proc/diskstats and the
+                      mount table once <n> consecutive refreshes used less than
+                      half of them, eg. after many devices were detached
+    --parked-interval <secs>: once all the disks with an idle time are spun
+                      down, double the refresh period at each refresh, up to
+                      <secs> seconds, letting the CPU sleep deeper (activity
+                      is detected later)
+    --quick-interval <secs>: refresh every <secs> seconds during a refresh
+                      period after a disk spins down or up, to detect sooner
+                      that it spins up again or goes idle
+    --watch-files:    watch the accesses to the files of the mounted disks
+                      (with fanotify), and report the last one when a disk spins
+                      up, to tell which file woke it up. Filesystems mounted
+                      after the disk was detected are not watched
+    --probe-all:      print a compatibility report (model, transport, write
+                      cache...) of the configured and other SCSI drives, without
+                      spinning them down, then exit
+    --list:           print the SCSI disks of the system with their device
+                      number, model and power mode (without waking them up),
+                      then exit
+    --status <path>:  write the state, idle time, activity and last error of
+                      each disk to a file at each refresh, as JSON if <path> ends
+                      with `.json`
+    --state-file[=<path>]: save the idle timers of the disks to a file (default:
+                      /run/rust-idle.timers) at each refresh and on shutdown,
+                      and restore them to the disks unless older than an hour, so
+                      that a restart doesn't start the idle times over
+    --http [<host>:]<port>: serve a control API over HTTP, on localhost unless
+                      <host> is given: `GET /status` (JSON), `GET /metrics`,
+                      `POST /spinup/<device>`, `POST /spindown/<device>` and
+                      `POST /reload` (like SIGHUP)
+    --metrics [<host>:]<port>: serve the state, idle time, spin downs and spin
+                      ups of each disk as Prometheus metrics on `GET /metrics`,
+                      on localhost unless <host> is given
+    --socket[=<path>]: accept text commands on a Unix domain socket (default:
+                      /run/rust-idle.sock), one per connection, answered by
+                      lines of text: `status [--json]`, `spindown <device>` and
+                      `config <device>` (eg. `echo status | socat -
+                      UNIX-CONNECT:<path>`)
+    --statsd <host>:<port>: push the state, idle time and spin downs of each
+                      disk as StatsD metrics (`rust_idle.<device>.<metric>`)
+    --statsd-interval <secs>: send the StatsD metrics at most every <secs>
+                      seconds (default: at each refresh)
 
 flags:
     <number>: idle time in seconds before spinning down a drive, if equal to zero,
               no spinning down is performed. Can only be specified once per
-              flag set.
+              flag set. A `m` or `h` suffix counts it in minutes or hours (eg.
+              `2h`), like the durations of the d, w, W, D and t flags. A
+              trailing `s` is the s flag, not seconds: `7200s` syncs.
     s:        sync the disk before spinning down
    -s:        don't sync the disk before spinning down
     S:        sync the disk when spinning up is detected
    -S:        don't sync the disk when spinning up is detected
+    G:        sync all the filesystems of the system (once per refresh) before
+              spinning down the disk
+   -G:        don't sync all the filesystems before spinning down the disk
+    L:        only sync the filesystems of the disk holding dirty data. Needs
+              debugfs (/sys/kernel/debug), otherwise they are all synced
+   -L:        always sync the writable filesystems of the disk
+    d<secs>:  deadband before the idle time: activity within it only resets
+              the idle time to the start of the deadband (hysteresis)
+   -d:        disable the deadband
+    e<rate>:  only reset the idle time when the moving average of the activity
+              (over a minute) exceeds <rate> sectors/s
+   -e:        reset the idle time on any activity
+    n<n>:     ignore the activity moving fewer than <n> sectors between two
+              refreshes (eg. filesystem metadata), logged with -vvv
+   -n:        count any moved sector as activity
+    w<secs>:  spin up the disk every <secs> seconds, to exercise it
+   -w:        disable scheduled spin-ups
+    W<secs>:  keep the disk spinning at least <secs> seconds after a scheduled
+              spin-up
+    u<secs>:  keep the disk spinning at least <secs> seconds after any spin-up,
+              so that sparse bursts of activity don't wear it with spin ups
+              and downs
+   -u:        allow spinning down the disk right after it spun up
+    c<n>:     spin down the disk at most <n> times within an hour, keeping it
+              spinning (with a warning) once reached, against wearing it out
+   -c:        don't limit the spin downs
+    D<secs>:  wait <secs> seconds between syncing the disk and spinning it
+              down, for drives rejecting the STOP command right after a flush
+   -D:        spin down the disk right after syncing it
+    g<n>:     keep ignoring the activity for <n> more refreshes after syncing
+              the disk, for filesystems slow to settle
+   -g:        only ignore the activity of the refresh following a sync
+    N<n>:     put NVMe drives in the power state <n> of their controller
+              instead of spinning them down (default: the deepest one)
+   -N:        use the deepest power state of NVMe drives
+    a<level>: set the Advanced Power Management level of ATA drives, from 1
+              (most power saving) to 254, or 255 to disable it. The levels
+              below 128 let the drive spin down by itself
+   -a:        leave the APM level unchanged
+    p<n>:     spin down SCSI disks with the power condition <n> of the START
+              STOP UNIT command (eg. 3: STANDBY) instead of a plain STOP, for
+              drives which don't park otherwise
+   -p:        spin down SCSI disks with a plain STOP
+    o:        don't spin down the disk while a process holds it open
+   -o:        ignore processes holding the disk open
+    r:        reads don't reset the idle time, only writes do (eg. for data
+              mostly read from the page cache). Reads still tell that a spun
+              down disk has spun up
+   -r:        reset the idle time on reads
+    x:        writes and discards don't reset the idle time, only reads do
+              (--events counts all the activity as writes: x ignores it all)
+   -x:        reset the idle time on writes
+    t:        leave the spin down to the standby timer of the drive's firmware
+              (from its power condition mode page): the idle time is set to 80%
+              of the timer, after which the disk is only synced. <idle time> is
+              kept if the timer can't be read, as for ATA disks whose timer
+              (eg. set with `hdparm -S`) can't be read back: use t<secs>
+    t<secs>:  like t, programming the standby timer of the drive to <secs>
+              seconds first (not saved across power cycles)
+   -t:        spin down the disk with the idle time
     v:        increases verbosity (can be repeated up to 3 times)
    -v:        decreases verbosity
 
@@ -423,12 +284,13 @@ verbosity=0 and sync on spin-up events.
 }
 
 fn main() {
+    log::init();
     exit(
         match parse_args().and_then(|mut app| app.run().context("main loop")) {
             Ok(_) => 0,
             Err(e) => {
-                eprintln!("<3>error: {}\n", e);
-                1
+                elogln!("<3>error: {}\n", e);
+                e.kind().exit_code()
             }
         },
     )
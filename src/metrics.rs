@@ -0,0 +1,137 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Metrics of the devices in the Prometheus text exposition format, served on
+//! `GET /metrics` (`--metrics`, or the HTTP control API).
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::policy::DeviceState;
+
+/// Snapshot of the metrics of a device.
+pub struct DeviceMetrics<'a> {
+    pub name: &'a OsStr,
+    pub state: DeviceState,
+    pub idle_time: Duration,
+    /// # of successful spin downs.
+    pub spindowns: u64,
+    /// # of spin ups, detected or scheduled.
+    pub spinups: u64,
+}
+
+/// Value of `rust_idle_device_state`.
+fn state_value(state: DeviceState) -> u8 {
+    match state {
+        DeviceState::Spinning() => 0,
+        DeviceState::Synced() => 1,
+        DeviceState::Idle() => 2,
+        DeviceState::Missing() => 3,
+    }
+}
+
+/// Formats a device name as a label value, escaped.
+struct Label<'a>(&'a OsStr);
+
+impl fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.to_string_lossy().chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A metric: name, type, help and value for a device.
+type Family = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&DeviceMetrics) -> u64,
+);
+
+const FAMILIES: [Family; 4] = [
+    (
+        "rust_idle_device_state",
+        "gauge",
+        "State of the device: 0 spinning, 1 synced, 2 idle, 3 missing.",
+        |device| state_value(device.state).into(),
+    ),
+    (
+        "rust_idle_idle_seconds",
+        "gauge",
+        "Time since the last activity of the device.",
+        |device| device.idle_time.as_secs(),
+    ),
+    (
+        "rust_idle_spindown_total",
+        "counter",
+        "Spin downs of the device.",
+        |device| device.spindowns,
+    ),
+    (
+        "rust_idle_spinup_total",
+        "counter",
+        "Spin ups of the device, detected or scheduled.",
+        |device| device.spinups,
+    ),
+];
+
+/// Writes the metrics of the devices, grouped by metric.
+pub fn write<W: Write>(out: &mut W, devices: &[DeviceMetrics]) -> io::Result<()> {
+    for (name, kind, help, value) in FAMILIES {
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} {}", name, kind)?;
+        for device in devices {
+            writeln!(
+                out,
+                "{}{{device=\"{}\"}} {}",
+                name,
+                Label(device.name),
+                value(device)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_format() {
+        let devices = [DeviceMetrics {
+            name: OsStr::new("sd\"b"),
+            state: DeviceState::Idle(),
+            idle_time: Duration::from_secs(742),
+            spindowns: 3,
+            spinups: 2,
+        }];
+        let mut out = Vec::new();
+        write(&mut out, &devices).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let samples: Vec<_> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            [
+                "rust_idle_device_state{device=\"sd\\\"b\"} 2",
+                "rust_idle_idle_seconds{device=\"sd\\\"b\"} 742",
+                "rust_idle_spindown_total{device=\"sd\\\"b\"} 3",
+                "rust_idle_spinup_total{device=\"sd\\\"b\"} 2",
+            ]
+        );
+        assert!(out.starts_with(
+            "# HELP rust_idle_device_state State of the device: 0 spinning, 1 synced, \
+             2 idle, 3 missing.\n# TYPE rust_idle_device_state gauge\n"
+        ));
+    }
+}
@@ -2,56 +2,472 @@
 // reserved. Use of this source is governed by MIT License that can be found in
 // the LICENSE file.
 
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::ErrorKind;
 use std::os::unix::prelude::OsStrExt;
+use std::path::Path;
 
 use crate::errors::{Context, Result};
 use crate::sys;
-use crate::utils::BulkReader;
+use crate::utils::{BulkReader, parse_integer};
 
 const MOUNTS_PATH: &str = "/proc/self/mounts";
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
 
 pub struct Mounts(BulkReader);
 
+/// A line of the mount table.
+pub struct MountEntry<'a> {
+    /// Name of the source block device, under `/dev/`.
+    pub source: &'a OsStr,
+    pub mount_point: &'a sys::CStr,
+    pub fs_type: &'a [u8],
+    /// Mounted with the `ro` option.
+    pub read_only: bool,
+}
+
+impl MountEntry<'_> {
+    /// Has the filesystem recorded errors? From the error count of ext4 and
+    /// the health of XFS, and for any filesystem, a read-only remount by the
+    /// kernel (eg. `errors=remount-ro`).
+    pub fn has_errors(&self) -> Result<bool> {
+        let errors = match self.fs_type {
+            b"ext4" => ext4_errors(self.source)?,
+            b"xfs" => sys::xfs_sick(self.mount_point)?,
+            _ => false,
+        };
+        Ok(errors || (self.read_only && remounted_read_only(self.source)?))
+    }
+}
+
+fn ext4_errors(source: &OsStr) -> Result<bool> {
+    let path = Path::new("/sys/fs/ext4").join(source).join("errors_count");
+    match fs::read(&path) {
+        Ok(count) => Ok(parse_integer(count.trim_ascii())? != 0),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Reading '{}'", path.display())),
+    }
+}
+
+/// Was a filesystem mounted from `source` switched to read-only by the kernel?
+fn remounted_read_only(source: &OsStr) -> Result<bool> {
+    let mountinfo = fs::read(MOUNTINFO_PATH).context("Reading the mount info")?;
+    Ok(mountinfo
+        .split(|c| *c == b'\n')
+        .any(|line| is_kernel_remount(line, source.as_bytes())))
+}
+
+/// Matches a line of /proc/self/mountinfo whose filesystem is mounted from
+/// `/dev/<source>` read-write, while its superblock became read-only. A
+/// remount by the user changes both.
+fn is_kernel_remount(line: &[u8], source: &[u8]) -> bool {
+    // <id> <parent> <major:minor> <root> <mount point> <mount options>
+    // [<optional fields>...] - <type> <source> <superblock options>
+    let mut it = line.split(|c| *c == b' ');
+    let mount_options = it.nth(5).unwrap_or_default();
+    let mut it = it.skip_while(|token| *token != b"-").skip(2);
+    let (Some(mount_source), Some(super_options)) = (it.next(), it.next()) else {
+        return false;
+    };
+    let read_only = |options: &[u8]| options.split(|c| *c == b',').next() == Some(b"ro");
+    mount_source.strip_prefix(b"/dev/") == Some(source)
+        && !read_only(mount_options)
+        && read_only(super_options)
+}
+
 impl Mounts {
     pub fn new() -> Result<Self> {
         Ok(Self(BulkReader::open(MOUNTS_PATH)?))
     }
 
+    /// Reads scripted snapshots of the mount table, one per update.
+    #[cfg(test)]
+    pub fn from_snapshots(snapshots: &[&str]) -> Self {
+        Self(BulkReader::from_snapshots(snapshots.iter().copied()))
+    }
+
+    /// Shrinks the buffer of the mount table after `reads` reads filling less
+    /// than half of it. See [`BulkReader::shrink_after`].
+    pub fn shrink_after(&mut self, reads: u32) {
+        self.0.shrink_after(reads);
+    }
+
     pub fn update(&mut self) {
         self.0.clear();
     }
 
+    /// Calls `f` on the filesystems mounted from the device, including the
+    /// multi-device btrfs filesystems with a member on it, whose mounts show
+    /// another member as their source.
     pub fn for_dev<F>(&mut self, dev_name: &OsStr, mut f: F) -> Result<()>
     where
-        F: FnMut(&sys::CStr) -> Result<()>,
+        F: FnMut(&MountEntry) -> Result<()>,
     {
         if self.0.empty() {
             self.0.read()?;
         }
+        let members = btrfs_members(Path::new("/sys/fs/btrfs"), dev_name);
         for line in self.0.parse_lines_mut() {
-            if let Some(mount_point) = parse_line(line, dev_name)? {
-                f(mount_point)?;
+            if let Some(entry) = parse_line(line, dev_name, &members)? {
+                f(&entry)?;
             }
         }
         Ok(())
     }
+
+    /// Finds the source block device (name under `/dev/`) mounted on
+    /// `mount_point`. The last mount wins when several are stacked.
+    pub fn source_of(&mut self, mount_point: &OsStr) -> Result<Option<OsString>> {
+        if self.0.empty() {
+            self.0.read()?;
+        }
+        let mut source = None;
+        for line in self.0.parse_lines_mut() {
+            if let Some(entry) = parse_entry(line)?
+                && entry.mount_point.to_bytes() == mount_point.as_bytes()
+            {
+                source = Some(entry.source.to_owned());
+            }
+        }
+        Ok(source)
+    }
+
+    /// Lists the mount points of the device whose filesystem recorded errors.
+    pub fn for_dev_errors<F>(&mut self, dev_name: &OsStr, mut f: F) -> Result<()>
+    where
+        F: FnMut(&sys::CStr),
+    {
+        self.for_dev(dev_name, |entry| {
+            if entry.has_errors()? {
+                f(entry.mount_point);
+            }
+            Ok(())
+        })
+    }
 }
 
-fn parse_line<'a>(line: &'a mut [u8], dev_name: &OsStr) -> Result<Option<&'a sys::CStr>> {
-    let mut it = line.split_inclusive_mut(|c| *c == b' ' || *c == b'\0');
+/// Parses a line of the mount table if its source is on the disk `dev_name`,
+/// or is one of the `btrfs_members` of its filesystems.
+pub(crate) fn parse_line<'a>(
+    line: &'a mut [u8],
+    dev_name: &OsStr,
+    btrfs_members: &[OsString],
+) -> Result<Option<MountEntry<'a>>> {
+    parse_source(line, |source| {
+        is_on_disk(source, dev_name) || btrfs_members.iter().any(|m| m.as_bytes() == source)
+    })
+}
+
+/// Is the device the disk `dev_name` or one of its partitions?
+fn is_on_disk(name: &[u8], dev_name: &OsStr) -> bool {
+    name.strip_prefix(dev_name.as_bytes())
+        .is_some_and(is_partition_suffix)
+}
+
+/// Lists the devices of the btrfs filesystems with a member on the disk
+/// `dev_name`, from `<root>/<uuid>/devices/`.
+fn btrfs_members(root: &Path, dev_name: &OsStr) -> Vec<OsString> {
+    let mut members = Vec::new();
+    for filesystem in fs::read_dir(root).into_iter().flatten().flatten() {
+        let devices = fs::read_dir(filesystem.path().join("devices"));
+        let devices: Vec<_> = devices
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.file_name())
+            .collect();
+        let on_disk = |dev: &OsString| is_on_disk(dev.as_bytes(), dev_name);
+        if devices.iter().any(on_disk) {
+            members.extend(devices);
+        }
+    }
+    members
+}
+
+/// Parses any line of the mount table with a block device as source.
+pub(crate) fn parse_entry(line: &mut [u8]) -> Result<Option<MountEntry<'_>>> {
+    parse_source(line, |_| true)
+}
+
+fn parse_source<F>(line: &mut [u8], matches: F) -> Result<Option<MountEntry<'_>>>
+where
+    F: FnOnce(&[u8]) -> bool,
+{
+    // Tokens keep their delimiter, overwritten by the NUL terminator of the
+    // mount point. Lines must keep their '\n' for a mount point in last position.
+    let mut it = line.split_inclusive_mut(|c| matches!(c, b' ' | b'\0' | b'\n'));
     let mut next_tok = move || it.next().ok_or_else(|| "Expected token".into());
 
-    let source = next_tok().context("Parsing mount source")?;
-    if !source.starts_with(b"/dev/") {
+    let source: &[u8] = next_tok().context("Parsing mount source")?;
+    let Some(source) = strip_delimiter(source).strip_prefix(b"/dev/") else {
         return Ok(None); // not a block device
-    }
-    if !source[5..].starts_with(dev_name.as_bytes()) {
+    };
+    if !matches(source) {
         return Ok(None); // not the device we're looking for
     }
 
-    next_tok()
-        .and_then(sys::make_inplace_cstr)
-        .map(Some)
-        .context("Parsing mount point")
+    let mount_point = next_tok()
+        .and_then(|token| {
+            let path_len = strip_delimiter(token).len();
+            if path_len == token.len() {
+                return sys::make_inplace_cstr(token); // No terminator to overwrite
+            }
+            let len = unescape_in_place(&mut token[..path_len]);
+            token[len] = b'\0';
+            sys::make_inplace_cstr(&mut token[..=len])
+        })
+        .context("Parsing mount point")?;
+    // Only absent from truncated lines
+    let fs_type = next_tok().unwrap_or_default();
+    let options = next_tok().unwrap_or_default();
+    let read_only = strip_delimiter(options)
+        .split(|c| *c == b',')
+        .any(|option| option == b"ro");
+
+    Ok(Some(MountEntry {
+        source: OsStr::from_bytes(source),
+        mount_point,
+        fs_type: strip_delimiter(fs_type),
+        read_only,
+    }))
+}
+
+fn strip_delimiter(token: &[u8]) -> &[u8] {
+    match token {
+        [token @ .., b' ' | b'\0' | b'\n'] => token,
+        token => token,
+    }
+}
+
+/// Decodes in place the octal escapes of a path of the mount table (`\040`
+/// for a space, also used for tabs, newlines and backslashes), returning its
+/// decoded length.
+pub(crate) fn unescape_in_place(path: &mut [u8]) -> usize {
+    let (mut read, mut write) = (0, 0);
+    while read < path.len() {
+        let octal = path
+            .get(read + 1..read + 4)
+            .filter(|octal| path[read] == b'\\' && octal.iter().all(is_octal));
+        let c = match octal {
+            Some(octal) => {
+                read += 4;
+                octal.iter().fold(0, |byte, c| byte << 3 | (c - b'0'))
+            }
+            None => {
+                read += 1;
+                path[read - 1]
+            }
+        };
+        path[write] = c;
+        write += 1;
+    }
+    write
+}
+
+fn is_octal(c: &u8) -> bool {
+    matches!(c, b'0'..=b'7')
+}
+
+/// Strips the partition suffix from a SCSI (`sda1` -> `sda`) or `p`-separated
+/// (`nvme0n1p2` -> `nvme0n1`) device name. Names of whole disks are returned
+/// as-is.
+pub fn disk_name(name: &[u8]) -> &[u8] {
+    let digits = name.iter().rev().take_while(|c| c.is_ascii_digit()).count();
+    let base = &name[..name.len() - digits];
+    match base {
+        _ if digits == 0 => name,
+        // `<disk ending with a digit>p<n>`
+        [disk @ .., c, b'p'] if c.is_ascii_digit() => &name[..disk.len() + 1],
+        // `sd<letters><n>`, while `nvme0n1` is a whole disk
+        _ if !base.iter().any(u8::is_ascii_digit) => base,
+        _ => name,
+    }
+}
+
+/// Matches the suffix of a partition name after the disk name: `1` in `sda1`,
+/// `p1` in `nvme0n1p1`, or empty for the whole disk.
+fn is_partition_suffix(suffix: &[u8]) -> bool {
+    let digits = suffix.strip_prefix(b"p").unwrap_or(suffix);
+    (suffix.is_empty() || !digits.is_empty()) && digits.iter().all(u8::is_ascii_digit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    /// Parses a mount table line, returning the source and mount point if it
+    /// matches the device.
+    fn parse(line: &str, dev_name: &str) -> Option<(String, String)> {
+        let mut line = line.as_bytes().to_vec();
+        parse_line(&mut line, OsStr::new(dev_name), &[])
+            .unwrap()
+            .map(|entry| {
+                (
+                    entry.source.to_str().unwrap().to_owned(),
+                    String::from_utf8(entry.mount_point.to_bytes().to_vec()).unwrap(),
+                )
+            })
+    }
+
+    fn some(source: &str, mount_point: &str) -> Option<(String, String)> {
+        Some((source.to_owned(), mount_point.to_owned()))
+    }
+
+    #[test]
+    fn ext4_mount() {
+        let line = "/dev/sda1 /mnt/data ext4 rw,relatime 0 0";
+        assert_eq!(parse(line, "sda"), some("sda1", "/mnt/data"));
+        assert_eq!(parse(line, "sdb"), None);
+
+        let mut line = line.as_bytes().to_vec();
+        let entry = parse_line(&mut line, OsStr::new("sda"), &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.fs_type, b"ext4");
+        assert!(!entry.read_only);
+
+        let mut line = b"/dev/sda2 /mnt/archive ext4 ro,relatime 0 0".to_vec();
+        let entry = parse_line(&mut line, OsStr::new("sda"), &[])
+            .unwrap()
+            .unwrap();
+        assert!(entry.read_only);
+    }
+
+    #[test]
+    fn whole_disk_and_nvme_partitions() {
+        assert_eq!(
+            parse("/dev/sdc /mnt/raw xfs rw 0 0", "sdc"),
+            some("sdc", "/mnt/raw")
+        );
+        assert_eq!(
+            parse("/dev/nvme0n1p2 /home btrfs rw 0 0", "nvme0n1"),
+            some("nvme0n1p2", "/home")
+        );
+    }
+
+    #[test]
+    fn escaped_space() {
+        assert_eq!(
+            parse("/dev/sdb1 /mnt/My\\040Disk vfat rw 0 0", "sdb"),
+            some("sdb1", "/mnt/My Disk")
+        );
+        assert_eq!(
+            parse("/dev/sdb1 /mnt/a\\011b\\134c\\040\n", "sdb"),
+            some("sdb1", "/mnt/a\tb\\c ")
+        );
+        // Not an escape
+        assert_eq!(
+            parse("/dev/sdb1 /a\\b\\0 vfat rw 0 0", "sdb"),
+            some("sdb1", "/a\\b\\0")
+        );
+    }
+
+    #[test]
+    fn bind_mount() {
+        // Bind mounts show the source device of the original mount
+        assert_eq!(
+            parse("/dev/sda2 /srv/export ext4 rw 0 0", "sda"),
+            some("sda2", "/srv/export")
+        );
+    }
+
+    #[test]
+    fn not_a_block_device() {
+        assert_eq!(parse("tmpfs /tmp tmpfs rw,nosuid 0 0", "sda"), None);
+        assert_eq!(parse("/devices /mnt ext4 rw 0 0", "ices"), None);
+    }
+
+    #[test]
+    fn prefix_of_another_device() {
+        assert_eq!(parse("/dev/sdaa1 /mnt/a ext4 rw 0 0", "sda"), None);
+        assert_eq!(
+            parse("/dev/sdaa1 /mnt/a ext4 rw 0 0", "sdaa"),
+            some("sdaa1", "/mnt/a")
+        );
+        assert_eq!(parse("/dev/sda /mnt/a ext4 rw 0 0", "sdaa"), None);
+    }
+
+    #[test]
+    fn minimal_line() {
+        // The mount point is the last token, terminated by the newline
+        assert_eq!(parse("/dev/sda1 /mnt\n", "sda"), some("sda1", "/mnt"));
+        assert_eq!(parse("/dev/sda1 /mnt ext4\n", "sda"), some("sda1", "/mnt"));
+        // No terminator to overwrite in place
+        let mut line = b"/dev/sda1 /mnt".to_vec();
+        assert!(parse_line(&mut line, OsStr::new("sda"), &[]).is_err());
+    }
+
+    #[test]
+    fn source_lookup() {
+        let mut line = b"/dev/sdb1 /mnt/My\\040Disk vfat rw 0 0".to_vec();
+        let entry = parse_entry(&mut line).unwrap().unwrap();
+        assert_eq!(entry.source, "sdb1");
+        assert_eq!(entry.mount_point.to_bytes(), b"/mnt/My Disk");
+        assert!(
+            parse_entry(&mut b"tmpfs /tmp tmpfs rw 0 0".to_vec())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn btrfs_raid() {
+        let dir = TempDir::new("sys-fs-btrfs");
+        let root = dir.path();
+        for (uuid, devices) in [
+            ("0b8f-raid1", &["sdb1", "sdc", "sdd1"][..]),
+            ("5d2e-single", &["sda2"]),
+        ] {
+            for dev in devices {
+                fs::create_dir_all(root.join(uuid).join("devices").join(dev)).unwrap();
+            }
+        }
+        fs::create_dir_all(root.join("features")).unwrap();
+        let members = |dev| btrfs_members(root, OsStr::new(dev));
+        let mut raid = members("sdc");
+        raid.sort();
+        assert_eq!(raid, ["sdb1", "sdc", "sdd1"]);
+        assert_eq!(members("sda"), ["sda2"]);
+        assert!(members("sde").is_empty());
+        assert!(members("sd").is_empty());
+
+        // A mount of the filesystem shows another member as its source
+        let mut line = b"/dev/sdb1 /srv btrfs rw 0 0".to_vec();
+        let entry = parse_line(&mut line, OsStr::new("sdc"), &raid).unwrap();
+        assert_eq!(entry.unwrap().mount_point.to_bytes(), b"/srv");
+    }
+
+    #[test]
+    fn kernel_remounts() {
+        let remounted =
+            b"36 25 8:1 / /mnt/data rw,relatime shared:1 - ext4 /dev/sda1 ro,errors=remount-ro";
+        assert!(is_kernel_remount(remounted, b"sda1"));
+        assert!(!is_kernel_remount(remounted, b"sda2"));
+        // Remounted read-only by the user
+        let user = b"36 25 8:1 / /mnt/data ro,relatime shared:1 - ext4 /dev/sda1 ro";
+        assert!(!is_kernel_remount(user, b"sda1"));
+        let read_write = b"37 25 8:17 / /mnt/backup rw - xfs /dev/sdb1 rw,attr2";
+        assert!(!is_kernel_remount(read_write, b"sdb1"));
+        assert!(!is_kernel_remount(
+            b"37 25 8:17 / /mnt/backup rw -",
+            b"sdb1"
+        ));
+    }
+
+    #[test]
+    fn disk_names() {
+        assert_eq!(disk_name(b"sda1"), b"sda");
+        assert_eq!(disk_name(b"sdab12"), b"sdab");
+        assert_eq!(disk_name(b"sdc"), b"sdc");
+        assert_eq!(disk_name(b"nvme0n1p2"), b"nvme0n1");
+        assert_eq!(disk_name(b"nvme0n1"), b"nvme0n1");
+    }
+
+    #[test]
+    fn truncated_line() {
+        let mut line = b"/dev/sda1".to_vec();
+        assert!(parse_line(&mut line, OsStr::new("sda"), &[]).is_err());
+    }
 }
@@ -0,0 +1,114 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Notifications of the service manager (systemd's `Type=notify`): readiness,
+//! and the keep-alive pings of its watchdog (`WatchdogSec=`), sent as
+//! datagrams to the socket named by `$NOTIFY_SOCKET`.
+
+use std::env;
+use std::ffi::OsStr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+use crate::errors::{Context, Result};
+
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// Period of the keep-alive pings, half the timeout of the watchdog.
+    watchdog: Option<Duration>,
+    /// Time of the next keep-alive ping.
+    next_ping: Instant,
+}
+
+impl Notifier {
+    /// Connects to the socket of the service manager, if the daemon was started
+    /// by one.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let addr = parse_addr(&path)
+            .with_context(|| format!("Invalid NOTIFY_SOCKET: '{}'", path.to_string_lossy()))?;
+        let socket = UnixDatagram::unbound().context("Creating the notification socket")?;
+        // The watchdog may be meant for another process of the service
+        let for_us =
+            env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|usec| *usec > 0 && for_us)
+            .map(|usec| Duration::from_micros(usec) / 2);
+        Ok(Some(Self {
+            socket,
+            addr,
+            watchdog,
+            next_ping: Instant::now(),
+        }))
+    }
+
+    /// Sends a `KEY=VALUE` state (eg. `READY=1`).
+    pub fn notify(&self, state: &str) -> Result<()> {
+        self.socket
+            .send_to_addr(state.as_bytes(), &self.addr)
+            .map(drop)
+            .with_context(|| format!("Notifying the service manager of {}", state))
+    }
+
+    /// Pings the watchdog if it is due, and returns the time of the next ping,
+    /// `None` without a watchdog.
+    pub fn keep_alive(&mut self, now: Instant) -> Result<Option<Instant>> {
+        let Some(period) = self.watchdog else {
+            return Ok(None);
+        };
+        if now >= self.next_ping {
+            self.next_ping = now + period;
+            self.notify("WATCHDOG=1")?;
+        }
+        Ok(Some(self.next_ping))
+    }
+}
+
+/// A path, or an abstract name after a `@`.
+fn parse_addr(path: &OsStr) -> std::io::Result<SocketAddr> {
+    match path.as_bytes() {
+        [b'@', name @ ..] => SocketAddr::from_abstract_name(name),
+        _ => SocketAddr::from_pathname(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn notifications() {
+        let dir = TempDir::new("rust-idle-notify");
+        let path = dir.join("notify");
+        let manager = UnixDatagram::bind(&path).unwrap();
+        let mut notifier = Notifier {
+            socket: UnixDatagram::unbound().unwrap(),
+            addr: parse_addr(path.as_os_str()).unwrap(),
+            watchdog: Some(Duration::from_secs(30)),
+            next_ping: Instant::now(),
+        };
+        let mut buf = [0u8; 64];
+        notifier.notify("READY=1").unwrap();
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        let now = Instant::now();
+        let next = notifier.keep_alive(now).unwrap();
+        assert_eq!(next, Some(now + Duration::from_secs(30)));
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        // Not due yet
+        assert_eq!(notifier.keep_alive(now).unwrap(), next);
+
+        assert!(parse_addr(OsStr::new("@/org/freedesktop/systemd1/notify")).is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+
+use crate::errors::{Context, Result};
+
+/// Upper bound on the # of file descriptors inspected by a scan.
+const MAX_SCANNED_FDS: usize = 1 << 16;
+
+/// Block devices opened by processes, found by scanning `/proc/*/fd`.
+///
+/// The scan is expensive: it is only done on demand, at most once per
+/// `update()`.
+#[derive(Default)]
+pub struct OpenDevices {
+    names: Vec<OsString>,
+    loaded: bool,
+}
+
+impl OpenDevices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self) {
+        self.names.clear();
+        self.loaded = false;
+    }
+
+    /// Is the device or any of its partitions opened by a process?
+    pub fn is_open(&mut self, dev_name: &OsStr) -> Result<bool> {
+        if !self.loaded {
+            self.loaded = true;
+            self.scan().context("Scanning opened devices")?;
+        }
+        Ok(self.names.iter().any(|name| {
+            name.as_bytes()
+                .strip_prefix(dev_name.as_bytes())
+                .is_some_and(|suffix| suffix.iter().all(u8::is_ascii_digit))
+        }))
+    }
+
+    fn scan(&mut self) -> Result<()> {
+        let self_pid = std::process::id().to_string();
+        let mut scanned = 0;
+        for process in fs::read_dir("/proc")? {
+            let process = process?;
+            let pid = process.file_name();
+            if pid == *self_pid || !pid.as_bytes().iter().all(u8::is_ascii_digit) {
+                continue;
+            }
+            // The process may have exited, or belong to another user
+            let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+                continue;
+            };
+            for fd in fds.flatten() {
+                scanned += 1;
+                if scanned > MAX_SCANNED_FDS {
+                    return Err("Too many opened files".into());
+                }
+                if let Ok(target) = fs::read_link(fd.path())
+                    && let Ok(name) = target.strip_prefix("/dev/")
+                    && !self.names.iter().any(|n| n == name.as_os_str())
+                {
+                    self.names.push(name.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1144 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Command line of the daemon: the global options, the flags of the devices
+//! and the configuration files read along.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+
+use os_str_bytes::{RawOsStr, RawOsString};
+
+use crate::app::System;
+use crate::config;
+use crate::control;
+use crate::errors::{Context, ErrorKind, Result};
+use crate::hooks;
+use crate::mounts;
+use crate::policy::{
+    DeviceConfig, Policy, SYNC_DIRTY_ONLY, SYNC_GLOBAL, SYNC_SPIN_DOWN, SYNC_SPIN_UP,
+};
+use crate::schedule;
+use crate::timers;
+use crate::{elogln, logln, signals, sys};
+
+/// Global options, given as `--<name> [<value>]` arguments.
+#[derive(Default)]
+pub struct Options {
+    /// Read block events from this stream instead of polling /proc/diskstats.
+    pub events: Option<OsString>,
+    /// Write the PID of the daemon to this file.
+    pub pidfile: Option<OsString>,
+    /// Adjustment of the OOM killer score of the daemon (-1000..=1000).
+    pub oom_score_adj: Option<i16>,
+    /// Tag appended to the command name of the process (not its argv).
+    pub name: Option<OsString>,
+    /// Print the effective configuration and exit.
+    pub dump_config: bool,
+    /// Print a compatibility report of the drives and exit.
+    pub probe_all: bool,
+    /// Print the SCSI disks of the system and exit.
+    pub list: bool,
+    /// Write the status of the devices to this file at each tick.
+    pub status: Option<OsString>,
+    /// Save the idle timers of the devices to this file at each tick, and
+    /// restore them at startup.
+    pub state_file: Option<OsString>,
+    /// Only report the activity of the devices, never sync nor spin them down.
+    pub monitor: bool,
+    /// Warn about SCSI commands taking longer than this.
+    pub slow_command: Option<Duration>,
+    /// Serve the HTTP control API on this address.
+    pub http: Option<String>,
+    /// Listen for text commands on this Unix domain socket.
+    pub socket: Option<OsString>,
+    /// Serve the Prometheus metrics on this address.
+    pub metrics: Option<String>,
+    /// Send the log messages to the syslog daemon.
+    pub syslog: bool,
+    /// Only log the syncs and spin downs, without executing them.
+    pub dry_run: bool,
+    /// Entries of /proc/diskstats (or block events) accounted to a device.
+    pub members: Vec<(OsString, Vec<OsString>)>,
+    /// Files holding a supplementary activity signal of a device.
+    pub busy_files: Vec<(OsString, PathBuf)>,
+    /// Commands run when the devices spin down or up.
+    pub hooks: hooks::Commands,
+    /// Stable links (eg. in /dev/disk/by-id) the devices were given by.
+    pub links: Vec<(OsString, PathBuf)>,
+    /// Windows of the day allowing the spin downs.
+    pub windows: schedule::Windows,
+    /// Push metrics to this StatsD server.
+    pub statsd: Option<String>,
+    /// Minimal period between two StatsD flushes.
+    pub statsd_interval: Duration,
+    /// Shrink the read buffers after this many reads using less than half of
+    /// them (0: never).
+    pub shrink_after: u32,
+    /// Append the actions on the devices to this file.
+    pub audit: Option<OsString>,
+    /// Longest refresh period while all the devices are spun down.
+    pub parked_interval: Option<Duration>,
+    /// Refresh period after a spin down or up.
+    pub quick_interval: Option<Duration>,
+    /// Watch the accesses to the files of the devices.
+    pub watch_files: bool,
+    /// Leave the devices not listed in the arguments unmanaged.
+    pub only_listed: bool,
+    /// Decides the transitions of the devices instead of the built-in policies.
+    pub policy: Option<Box<dyn Policy>>,
+    /// Reaches the disks and the tables of the system.
+    pub system: System,
+}
+
+/// The command line, with the arguments of the configuration files it reads.
+pub struct Arguments {
+    pub bin_name: Option<RawOsString>,
+    pub default_config: DeviceConfig,
+    pub device_configs: Vec<(OsString, DeviceConfig)>,
+    pub options: Options,
+}
+
+/// Reads the command line of the daemon, and the configuration files it
+/// names.
+pub fn read_arguments() -> Result<Arguments> {
+    parse_arguments(env::args_os(), System::default())
+}
+
+/// Parses a command line, whose disks are looked up on `system`.
+pub(crate) fn parse_arguments<I>(args: I, system: System) -> Result<Arguments>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    // Stack of the remaining arguments, flagged when read from a config file
+    let mut args: Vec<_> = (args.into_iter())
+        .map(|arg| (RawOsString::new(arg), false))
+        .collect();
+    args.reverse();
+    let mut default_config = DeviceConfig::default();
+    let mut device_configs = Vec::with_capacity(args.len());
+    // Arguments of the configured devices, for reporting aliases
+    let mut device_args = Vec::with_capacity(args.len());
+    let mut options = Options {
+        system,
+        ..Default::default()
+    };
+
+    let bin_name = args.pop().map(|(arg, _)| arg);
+    // "-c <path>": the files are read before the rest of the command line
+    let mut files = Vec::new();
+    while let Some(idx) = args.iter().rposition(|(arg, _)| arg == "-c") {
+        if idx == 0 {
+            return Err("missing value for -c").kind(ErrorKind::Config);
+        }
+        files.push(args.remove(idx - 1).0);
+        args.remove(idx - 1);
+    }
+    for path in files.iter().rev() {
+        let file_args = config::load(path.as_os_str()).kind(ErrorKind::Config)?;
+        args.extend(
+            file_args
+                .into_iter()
+                .rev()
+                .map(|arg| (RawOsString::new(arg), true)),
+        );
+    }
+    while let Some((arg, from_file)) = args.pop() {
+        if let Some(signum) = signals::received() {
+            elogln!("<5>Interrupted by signal {} during startup", signum);
+            exit(128 + signum)
+        }
+        if arg == "--config" {
+            if from_file {
+                return Err("--config in a configuration file, use include instead")
+                    .kind(ErrorKind::Config);
+            }
+            let path = args
+                .pop()
+                .ok_or("missing value for --config")
+                .kind(ErrorKind::Config)?
+                .0;
+            let file_args = config::load(path.as_os_str()).kind(ErrorKind::Config)?;
+            args.extend(
+                file_args
+                    .into_iter()
+                    .rev()
+                    .map(|arg| (RawOsString::new(arg), true)),
+            );
+            continue;
+        }
+        if let Some(name) = arg.strip_prefix("--") {
+            let mut values = std::iter::from_fn(|| args.pop().map(|(arg, _)| arg));
+            parse_option(name, &mut values, &mut options).kind(ErrorKind::Config)?;
+            continue;
+        }
+        // "mount:<mount point>[:flags]" -> the disk holding the filesystem
+        let (by_mount, spec) = match arg.strip_prefix("mount:") {
+            Some(mount_point) => (true, mount_point),
+            None => (false, arg.as_ref()),
+        };
+        let (disk, flags) = spec
+            .split_once(':')
+            .map_or((spec, None), |(disk, flags)| (disk, Some(flags)));
+
+        let config = if let Some(flags) = flags {
+            // "[disk]:flags" -> use the config made with flags on top of config
+            parse_flags(flags, &default_config).kind(ErrorKind::Config)?
+        } else {
+            // "disk" -> use the default config
+            default_config.clone()
+        };
+
+        let devs = if by_mount {
+            mount_to_scsi_names(&options.system, disk.as_os_str())
+                .kind(ErrorKind::Device)
+                .with_context(|| format!("getting device mounted on {}", disk.to_str_lossy()))?
+        } else if disk.is_empty() {
+            // ":flags" -> assign flags to the default config
+            default_config = config;
+            continue;
+        } else {
+            // "disk:[flags]" -> set the config of the device
+            (options.system.resolve)(disk.as_os_str(), default_config.verbosity)
+                .kind(ErrorKind::Device)
+                .with_context(|| format!("getting device for {}", disk.to_str_lossy()))?
+        };
+        // Followed if the kernel renames the disk
+        if let [dev] = devs.as_slice() {
+            options.links.retain(|(name, _)| name != dev);
+            if !by_mount && Path::new(disk.as_os_str()).is_symlink() {
+                options
+                    .links
+                    .push((dev.clone(), PathBuf::from(disk.as_os_str())));
+            }
+        }
+        // The disks under a logical volume share its configuration
+        for dev in devs {
+            add_device(
+                &mut device_configs,
+                &mut device_args,
+                (dev, config.clone()),
+                &arg.to_str_lossy(),
+                from_file,
+            )
+            .kind(ErrorKind::Config)?;
+        }
+    }
+    // The windows may be given before or after the devices
+    default_config.windows = options.windows.of_all();
+    for (dev, config) in &mut device_configs {
+        config.windows = options.windows.of(dev);
+    }
+    Ok(Arguments {
+        bin_name,
+        default_config,
+        device_configs,
+        options,
+    })
+}
+
+fn parse_flags(flags: &RawOsStr, default: &DeviceConfig) -> Result<DeviceConfig> {
+    let mut config = default.clone();
+    let mut idle_time = 0;
+    let mut idle_time_sealed = false;
+    let mut prefix = b'+';
+    let mut prev_flag = b' ';
+    // Flag taking a numeric value (eg. 'd30'), which collects the following digits
+    let mut value_flag = None;
+    let mut value = None;
+    for &c in flags.as_encoded_bytes() {
+        if prev_flag != b'-' && c != prev_flag {
+            prefix = b'+'; // Reset modifier to the default (+), but not for '-vv' (equivalent to '-v-v')
+        }
+        let digit = u64::from(c.wrapping_sub(b'0'));
+        if digit < 10 {
+            if value_flag.is_some() {
+                value = Some(
+                    value
+                        .unwrap_or(0_u64)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+            } else {
+                if idle_time_sealed {
+                    return Err("idle time already set".into());
+                }
+                idle_time = idle_time * 10 + digit;
+            }
+        } else if matches!(c, b'm' | b'h') && prev_flag.is_ascii_digit() {
+            // Unit of the preceding duration, in seconds otherwise
+            let unit = if c == b'm' { 60 } else { 3600 };
+            match value_flag.take() {
+                Some(flag @ (b'd' | b'w' | b'W' | b'u' | b'D' | b't')) => {
+                    let value = value.take().map(|value| value.saturating_mul(unit));
+                    set_flag_value(&mut config, flag, value)?;
+                }
+                Some(flag) => {
+                    return Err(format!("flag '{}' doesn't take a duration", flag as char).into());
+                }
+                None => {
+                    idle_time = idle_time.saturating_mul(unit);
+                    idle_time_sealed = true;
+                }
+            }
+        } else {
+            if let Some(flag) = value_flag.take() {
+                set_flag_value(&mut config, flag, value.take())?;
+            }
+            idle_time_sealed = idle_time > 0;
+            match c {
+                b's' => {
+                    if prefix == b'+' {
+                        config.sync_flags |= SYNC_SPIN_DOWN;
+                    } else {
+                        config.sync_flags &= !SYNC_SPIN_DOWN;
+                    }
+                }
+                b'S' => {
+                    if prefix == b'+' {
+                        config.sync_flags |= SYNC_SPIN_UP;
+                    } else {
+                        config.sync_flags &= !SYNC_SPIN_UP;
+                    }
+                }
+                b'G' => {
+                    if prefix == b'+' {
+                        config.sync_flags |= SYNC_GLOBAL;
+                    } else {
+                        config.sync_flags &= !SYNC_GLOBAL;
+                    }
+                }
+                b'L' => {
+                    if prefix == b'+' {
+                        config.sync_flags |= SYNC_DIRTY_ONLY;
+                    } else {
+                        config.sync_flags &= !SYNC_DIRTY_ONLY;
+                    }
+                }
+                b'd' | b'e' | b'n' | b'w' | b'W' | b'u' | b'c' | b'D' | b'g' | b'N' | b'a'
+                | b'p' => {
+                    if prefix == b'+' {
+                        value_flag = Some(c);
+                    } else if c == b'a' {
+                        // Unlike `a0`, which is no APM level
+                        config.apm_level = 0;
+                    } else {
+                        set_flag_value(&mut config, c, Some(0))?;
+                    }
+                }
+                b'o' => config.open_check = prefix == b'+',
+                b'r' => config.ignore_reads = prefix == b'+',
+                b'x' => config.ignore_writes = prefix == b'+',
+                b't' => {
+                    config.firmware_timer = prefix == b'+';
+                    if prefix == b'+' {
+                        value_flag = Some(c);
+                    } else {
+                        config.standby_timer = Duration::ZERO;
+                    }
+                }
+                b'v' => {
+                    config.verbosity = if prefix == b'+' {
+                        config.verbosity.saturating_add(1)
+                    } else {
+                        config.verbosity.saturating_sub(1)
+                    }
+                }
+                b'+' | b'-' => {
+                    prefix = c;
+                }
+                _ => {
+                    return Err(format!("invalid flag '{}'", c as char).into());
+                }
+            }
+        }
+        prev_flag = c;
+    }
+    if let Some(flag) = value_flag {
+        set_flag_value(&mut config, flag, value)?;
+    }
+    if idle_time > 0 || idle_time_sealed {
+        config.idle_time = Duration::from_secs(idle_time);
+    }
+    Ok(config)
+}
+
+/// Assigns the value following a numeric flag.
+fn set_flag_value(config: &mut DeviceConfig, flag: u8, value: Option<u64>) -> Result<()> {
+    // The timer of 't' is optional
+    if flag == b't' && value.is_none() {
+        return Ok(());
+    }
+    let value = value.ok_or_else(|| format!("missing value after flag '{}'", flag as char))?;
+    match flag {
+        b'd' => config.deadband = Duration::from_secs(value),
+        b'e' => config.rate_threshold = value,
+        b'n' => config.min_sectors = value,
+        b'w' => config.wake_period = Duration::from_secs(value),
+        b'W' => config.wake_duration = Duration::from_secs(value),
+        b'u' => config.min_uptime = Duration::from_secs(value),
+        b'c' => {
+            config.max_spindowns = value
+                .try_into()
+                .map_err(|_| format!("too many spin downs per hour: {}", value))?
+        }
+        b'D' => config.spindown_delay = Duration::from_secs(value),
+        b'g' => {
+            config.grace_ticks = value
+                .try_into()
+                .map_err(|_| format!("too many grace ticks: {}", value))?
+        }
+        b't' => config.standby_timer = Duration::from_secs(value),
+        b'N' => {
+            config.nvme_power_state = value
+                .try_into()
+                .ok()
+                .filter(|ps| *ps < 32)
+                .ok_or_else(|| format!("invalid NVMe power state: {}", value))?
+        }
+        b'a' => {
+            config.apm_level = value
+                .try_into()
+                .ok()
+                .filter(|level| *level != 0)
+                .ok_or_else(|| format!("invalid APM level: {}, expected 1 to 255", value))?
+        }
+        b'p' => {
+            config.power_condition = value
+                .try_into()
+                .ok()
+                .filter(|pc| *pc <= sys::MAX_POWER_CONDITION)
+                .ok_or_else(|| format!("invalid power condition: {} (0 to 11)", value))?
+        }
+        _ => unreachable!("not a numeric flag"),
+    }
+    Ok(())
+}
+
+/// Formats a configuration as flags, the inverse of `parse_flags()` applied
+/// on top of the empty default configuration.
+struct Flags<'a>(&'a DeviceConfig);
+
+impl fmt::Display for Flags<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let config = self.0;
+        write!(f, "{}", config.idle_time.as_secs())?;
+        for (flag, c) in [
+            (SYNC_SPIN_DOWN, 's'),
+            (SYNC_SPIN_UP, 'S'),
+            (SYNC_GLOBAL, 'G'),
+            (SYNC_DIRTY_ONLY, 'L'),
+        ] {
+            if config.sync_flags & flag != 0 {
+                write!(f, "{}", c)?;
+            }
+        }
+        for (value, c) in [
+            (config.deadband.as_secs(), 'd'),
+            (config.rate_threshold, 'e'),
+            (config.min_sectors, 'n'),
+            (config.wake_period.as_secs(), 'w'),
+            (config.wake_duration.as_secs(), 'W'),
+            (config.min_uptime.as_secs(), 'u'),
+            (config.max_spindowns.into(), 'c'),
+            (config.spindown_delay.as_secs(), 'D'),
+            (config.grace_ticks.into(), 'g'),
+            (config.nvme_power_state.into(), 'N'),
+            (config.apm_level.into(), 'a'),
+            (config.power_condition.into(), 'p'),
+        ] {
+            if value != 0 {
+                write!(f, "{}{}", c, value)?;
+            }
+        }
+        if config.open_check {
+            f.write_str("o")?;
+        }
+        if config.ignore_reads {
+            f.write_str("r")?;
+        }
+        if config.ignore_writes {
+            f.write_str("x")?;
+        }
+        if config.firmware_timer {
+            f.write_str("t")?;
+            if config.standby_timer > Duration::ZERO {
+                write!(f, "{}", config.standby_timer.as_secs())?;
+            }
+        }
+        for _ in 0..config.verbosity {
+            f.write_str("v")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_option<I>(name: &RawOsStr, args: &mut I, options: &mut Options) -> Result<()>
+where
+    I: Iterator<Item = RawOsString>,
+{
+    let mut value = || {
+        args.next()
+            .map(RawOsString::into_os_string)
+            .ok_or_else(|| format!("missing value for --{}", name.to_str_lossy()))
+    };
+    match name.as_encoded_bytes() {
+        b"events" => options.events = Some(value()?),
+        b"status" => options.status = Some(value()?),
+        b"statsd" => {
+            let addr = value()?;
+            options.statsd = Some(
+                addr.into_string()
+                    .map_err(|addr| format!("invalid address: '{}'", addr.to_string_lossy()))?,
+            );
+        }
+        b"shrink-after" => {
+            let reads = value()?;
+            options.shrink_after = reads
+                .to_str()
+                .and_then(|reads| reads.parse().ok())
+                .ok_or_else(|| format!("invalid number of reads: '{}'", reads.to_string_lossy()))?;
+        }
+        b"parked-interval" => {
+            let secs = value()?;
+            options.parked_interval = Some(Duration::from_secs(
+                secs.to_str()
+                    .and_then(|secs| secs.parse().ok())
+                    .ok_or_else(|| format!("invalid interval: '{}'", secs.to_string_lossy()))?,
+            ));
+        }
+        b"quick-interval" => {
+            let secs = value()?;
+            options.quick_interval = Some(Duration::from_secs(
+                secs.to_str()
+                    .and_then(|secs| secs.parse().ok())
+                    .filter(|secs| *secs > 0)
+                    .ok_or_else(|| format!("invalid interval: '{}'", secs.to_string_lossy()))?,
+            ));
+        }
+        b"statsd-interval" => {
+            let secs = value()?;
+            options.statsd_interval = Duration::from_secs(
+                secs.to_str()
+                    .and_then(|secs| secs.parse().ok())
+                    .ok_or_else(|| format!("invalid interval: '{}'", secs.to_string_lossy()))?,
+            );
+        }
+        b"busy-file" => {
+            let arg = value()?;
+            let bytes = arg.as_bytes();
+            let (dev, path) = match bytes.iter().position(|c| *c == b'=') {
+                Some(eq) => (
+                    &bytes[..eq],
+                    PathBuf::from(OsStr::from_bytes(&bytes[eq + 1..])),
+                ),
+                None => {
+                    let dev = bytes.strip_prefix(b"/dev/").unwrap_or(bytes);
+                    let path = Path::new("/sys/block")
+                        .join(OsStr::from_bytes(dev))
+                        .join("inflight");
+                    (bytes, path)
+                }
+            };
+            let dev = dev.strip_prefix(b"/dev/").unwrap_or(dev);
+            options
+                .busy_files
+                .push((OsStr::from_bytes(dev).to_owned(), path));
+        }
+        b"on-spindown" => options.hooks.add(hooks::Kind::OnSpindown, &value()?)?,
+        b"on-spinup" => options.hooks.add(hooks::Kind::OnSpinup, &value()?)?,
+        b"pre-spindown" => options.hooks.add(hooks::Kind::PreSpindown, &value()?)?,
+        b"spindown-window" => options.windows.add(&value()?)?,
+        b"diskstats" => {
+            let arg = value()?;
+            let bytes = arg.as_bytes();
+            let (dev, members) = bytes
+                .iter()
+                .position(|c| *c == b'=')
+                .map(|eq| (&bytes[..eq], &bytes[eq + 1..]))
+                .filter(|(dev, members)| !dev.is_empty() && !members.is_empty())
+                .ok_or_else(|| {
+                    format!(
+                        "expected <device>=<name>[,<name>...]: '{}'",
+                        arg.to_string_lossy()
+                    )
+                })?;
+            let dev = dev.strip_prefix(b"/dev/").unwrap_or(dev);
+            let members = members
+                .split(|c| *c == b',')
+                .filter(|name| !name.is_empty())
+                .map(|name| OsStr::from_bytes(name).to_owned())
+                .collect();
+            options
+                .members
+                .push((OsStr::from_bytes(dev).to_owned(), members));
+        }
+        b"http" => {
+            let addr = value()?;
+            options.http = Some(
+                addr.into_string()
+                    .map_err(|addr| format!("invalid address: '{}'", addr.to_string_lossy()))?,
+            );
+        }
+        b"socket" => options.socket = Some(control::DEFAULT_PATH.into()),
+        name if name.starts_with(b"socket=") => {
+            options.socket = Some(OsStr::from_bytes(&name[b"socket=".len()..]).to_owned());
+        }
+        b"state-file" => options.state_file = Some(timers::DEFAULT_PATH.into()),
+        name if name.starts_with(b"state-file=") => {
+            options.state_file = Some(OsStr::from_bytes(&name[b"state-file=".len()..]).to_owned());
+        }
+        b"metrics" => {
+            let addr = value()?;
+            options.metrics = Some(
+                addr.into_string()
+                    .map_err(|addr| format!("invalid address: '{}'", addr.to_string_lossy()))?,
+            );
+        }
+        b"pidfile" => options.pidfile = Some(value()?),
+        b"audit" => options.audit = Some(value()?),
+        b"name" => options.name = Some(value()?),
+        b"dump-config" => options.dump_config = true,
+        b"probe-all" => options.probe_all = true,
+        b"list" => options.list = true,
+        b"monitor" => options.monitor = true,
+        b"dry-run" => options.dry_run = true,
+        b"syslog" => options.syslog = true,
+        b"watch-files" => options.watch_files = true,
+        b"only-listed" => options.only_listed = true,
+        b"slow-command" => {
+            let ms = value()?;
+            options.slow_command = Some(Duration::from_millis(
+                ms.to_str()
+                    .and_then(|ms| ms.parse().ok())
+                    .ok_or_else(|| format!("invalid duration: '{}'", ms.to_string_lossy()))?,
+            ));
+        }
+        b"oom-score-adj" => {
+            let adj = value()?;
+            options.oom_score_adj = Some(
+                adj.to_str()
+                    .and_then(|adj| adj.parse().ok())
+                    .filter(|adj| (-1000..=1000).contains(adj))
+                    .ok_or_else(|| {
+                        format!("invalid OOM score adjustment: '{}'", adj.to_string_lossy())
+                    })?,
+            );
+        }
+        _ => return Err(format!("unknown option --{}", name.to_str_lossy()).into()),
+    }
+    Ok(())
+}
+
+/// Adds the configuration of a device, resolved to its kernel name from `arg`.
+/// Configuration files override the devices configured before them, and the
+/// command line overrides the devices of the files read before it (`-c`).
+/// Otherwise two arguments resolving to the same disk (eg. a link in
+/// /dev/disk/by-id and another in /dev/disk/by-path) are rejected.
+fn add_device(
+    device_configs: &mut Vec<(OsString, DeviceConfig)>,
+    device_args: &mut Vec<(String, bool)>,
+    (dev, config): (OsString, DeviceConfig),
+    arg: &str,
+    from_file: bool,
+) -> Result<()> {
+    match device_configs.iter().position(|(d, _)| *d == dev) {
+        Some(idx) if from_file || device_args[idx].1 => {
+            device_configs[idx].1 = config;
+            device_args[idx] = (arg.to_owned(), from_file);
+        }
+        Some(idx) => {
+            return Err(format!(
+                "'{}' and '{}' are the same disk ({})",
+                device_args[idx].0,
+                arg,
+                dev.to_string_lossy()
+            )
+            .into());
+        }
+        None => {
+            device_configs.push((dev, config));
+            device_args.push((arg.to_owned(), from_file));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the configuration as a `RUST_IDLE_OPTS` line, read back as the same
+/// configuration: the global options, every device (by the link it was given
+/// by, if any) and the default flags. The devices come before the default
+/// flags, so that they are read on top of the empty configuration like their
+/// flags are written.
+pub fn dump_config<W: Write>(
+    out: &mut W,
+    default_config: &DeviceConfig,
+    mut device_configs: Vec<(OsString, DeviceConfig)>,
+    options: &Options,
+) -> Result<()> {
+    device_configs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    write!(out, "RUST_IDLE_OPTS=")?;
+    for arg in option_args(options) {
+        out.write_all(arg.as_bytes())?;
+        write!(out, " ")?;
+    }
+    for (dev, config) in &device_configs {
+        match options.links.iter().find(|(name, _)| name == dev) {
+            Some((_, link)) => out.write_all(link.as_os_str().as_bytes())?,
+            None => write!(out, "/dev/{}", dev.to_string_lossy())?,
+        }
+        write!(out, ":{} ", Flags(config))?;
+    }
+    writeln!(out, ":{}", Flags(default_config))?;
+    Ok(())
+}
+
+/// Arguments giving the global options, the inverse of `parse_option()`.
+fn option_args(options: &Options) -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut option = |name: &str, value: Option<OsString>| {
+        args.push(OsString::from(format!("--{}", name)));
+        args.extend(value);
+    };
+    // "[<device>=]<value>"
+    let for_device = |dev: Option<&OsStr>, value: &OsStr| {
+        let mut arg = OsString::new();
+        if let Some(dev) = dev {
+            arg.push(dev);
+            arg.push("=");
+        }
+        arg.push(value);
+        arg
+    };
+    for (name, value) in [
+        ("events", &options.events),
+        ("status", &options.status),
+        ("pidfile", &options.pidfile),
+        ("audit", &options.audit),
+        ("name", &options.name),
+    ] {
+        if let Some(value) = value {
+            option(name, Some(value.clone()));
+        }
+    }
+    for (name, addr) in [
+        ("http", &options.http),
+        ("metrics", &options.metrics),
+        ("statsd", &options.statsd),
+    ] {
+        if let Some(addr) = addr {
+            option(name, Some(addr.into()));
+        }
+    }
+    let seconds = |duration: Duration| Some(duration.as_secs().to_string().into());
+    if !options.statsd_interval.is_zero() {
+        option("statsd-interval", seconds(options.statsd_interval));
+    }
+    if let Some(interval) = options.parked_interval {
+        option("parked-interval", seconds(interval));
+    }
+    if let Some(interval) = options.quick_interval {
+        option("quick-interval", seconds(interval));
+    }
+    if options.shrink_after != 0 {
+        option(
+            "shrink-after",
+            Some(options.shrink_after.to_string().into()),
+        );
+    }
+    if let Some(slow) = options.slow_command {
+        option("slow-command", Some(slow.as_millis().to_string().into()));
+    }
+    if let Some(adj) = options.oom_score_adj {
+        option("oom-score-adj", Some(adj.to_string().into()));
+    }
+    for (name, set) in [
+        ("monitor", options.monitor),
+        ("dry-run", options.dry_run),
+        ("syslog", options.syslog),
+        ("watch-files", options.watch_files),
+        ("only-listed", options.only_listed),
+    ] {
+        if set {
+            option(name, None);
+        }
+    }
+    for (dev, members) in &options.members {
+        let members = members.join(OsStr::new(","));
+        option("diskstats", Some(for_device(Some(dev), &members)));
+    }
+    for (dev, path) in &options.busy_files {
+        option("busy-file", Some(for_device(Some(dev), path.as_os_str())));
+    }
+    for (kind, dev, command) in options.hooks.iter() {
+        option(kind.option(), Some(for_device(dev, command.as_os_str())));
+    }
+    for (dev, window) in options.windows.iter() {
+        let window = window.to_string();
+        option("spindown-window", Some(for_device(dev, window.as_ref())));
+    }
+    // "--<name>=<path>", their value being optional
+    for (name, path) in [
+        ("socket", &options.socket),
+        ("state-file", &options.state_file),
+    ] {
+        if let Some(path) = path {
+            let mut arg = OsString::from(format!("--{}=", name));
+            arg.push(path);
+            args.push(arg);
+        }
+    }
+    args
+}
+
+/// Returns the names of the disks holding the filesystem mounted on
+/// `mount_point`: the disk of its partition, or the disks backing a
+/// device-mapper (LVM, LUKS) or software RAID volume.
+fn mount_to_scsi_names(system: &System, mount_point: &OsStr) -> Result<Vec<OsString>> {
+    let source = (system.mounts)()?
+        .source_of(mount_point)?
+        .ok_or("no block device mounted there")?;
+    source_to_scsi_names(&source, sys::stacked_disks, sys::link_to_scsi_name)
+}
+
+/// Resolves the `source` of a mount (its name under `/dev/`) to the disks
+/// backing it, with `stacked` and `link` resolving the stacked devices and the
+/// disks from their nodes.
+fn source_to_scsi_names<S, L>(source: &OsStr, stacked: S, link: L) -> Result<Vec<OsString>>
+where
+    S: Fn(&OsStr) -> Result<Option<Vec<OsString>>>,
+    L: Fn(&OsStr) -> Result<OsString>,
+{
+    let mut path = OsString::from("/dev/");
+    path.push(source);
+    if let Some(disks) = stacked(&path)? {
+        return Ok(disks);
+    }
+    let mut path = OsString::from("/dev/");
+    path.push(OsStr::from_bytes(mounts::disk_name(source.as_bytes())));
+    link(&path).map(|dev| vec![dev])
+}
+
+/// Returns the names of the disks `path` stands for: the disk itself, the one
+/// holding a partition, or the disks backing a stacked device.
+pub(crate) fn resolve_disks(path: &OsStr, verbosity: u8) -> Result<Vec<OsString>> {
+    path_to_scsi_names(
+        path,
+        verbosity,
+        sys::partition_parent,
+        sys::link_to_scsi_names,
+    )
+}
+
+/// Resolves `path` to the disks it stands for, with `parent` finding the disk
+/// of a partition and `link` the disks from their nodes.
+fn path_to_scsi_names<P, L>(
+    path: &OsStr,
+    verbosity: u8,
+    parent: P,
+    link: L,
+) -> Result<Vec<OsString>>
+where
+    P: Fn(&OsStr) -> Option<(OsString, OsString)>,
+    L: Fn(&OsStr) -> Result<Vec<OsString>>,
+{
+    let Some((partition, disk)) = parent(path) else {
+        return link(path);
+    };
+    if verbosity >= 2 {
+        logln!(
+            "<6>{} is a partition; monitoring parent {}",
+            partition.to_string_lossy(),
+            disk.to_string_lossy()
+        );
+    }
+    let mut path = OsString::from("/dev/");
+    path.push(disk);
+    link(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn dumped_config() {
+        let default = parse_flags(RawOsStr::new("600s"), &DeviceConfig::default()).unwrap();
+        let backup = parse_flags(RawOsStr::new("7200"), &default).unwrap();
+        let devices = vec![
+            ("sdc".into(), backup.clone()),
+            ("sdb".into(), default.clone()),
+        ];
+        let mut out = Vec::new();
+        dump_config(&mut out, &default, devices, &testing::options()).unwrap();
+        // Even the devices with the default configuration, left unmanaged
+        // by --only-listed otherwise
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "RUST_IDLE_OPTS=/dev/sdb:{} /dev/sdc:{} :{}\n",
+                Flags(&default),
+                Flags(&backup),
+                Flags(&default)
+            )
+        );
+    }
+
+    #[test]
+    fn dumped_config_reads_back() {
+        let dir = TempDir::new("rust-idle-dump");
+        let link = dir.join("ata-WDC_WD40EFRX-WCC7K123");
+        std::os::unix::fs::symlink("/dev/sdd", &link).unwrap();
+        // The disks are their nodes in /dev, and the link of sdd
+        let system = System {
+            resolve: |path, _| {
+                let path = Path::new(path);
+                if path.ends_with("ata-WDC_WD40EFRX-WCC7K123") {
+                    return Ok(vec!["sdd".into()]);
+                }
+                let dev = path.strip_prefix("/dev").map_err(|_| "not a disk")?;
+                Ok(vec![dev.as_os_str().to_owned()])
+            },
+            ..testing::system()
+        };
+        let parse = |args: Vec<OsString>| {
+            let args = std::iter::once("rust-idle".into()).chain(args);
+            parse_arguments(args, system).unwrap()
+        };
+        let dump = |arguments: Arguments| {
+            let mut out = Vec::new();
+            let Arguments {
+                default_config,
+                device_configs,
+                options,
+                ..
+            } = arguments;
+            dump_config(&mut out, &default_config, device_configs, &options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let mut link_arg = link.into_os_string();
+        link_arg.push(":1800");
+        let args = [
+            "--only-listed",
+            "--watch-files",
+            "--socket=/run/idle.sock",
+            "--busy-file",
+            "sdb=/run/busy",
+            "--diskstats",
+            "sdc=sdc,sdx",
+            "--on-spindown",
+            "/bin/true",
+            "--pre-spindown",
+            "sdb=/bin/false",
+            "--spindown-window",
+            "22:00-07:00",
+            "--spindown-window",
+            "sdc=12:00-13:00",
+            ":600s",
+            "/dev/sdb",
+            "/dev/sdc:7200S",
+        ];
+        let args: Vec<OsString> = args.into_iter().map(OsString::from).collect();
+        let first = parse([args, vec![link_arg]].concat());
+        let mut configs = first.device_configs.clone();
+        configs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let default_config = first.default_config.clone();
+        let links = first.options.links.clone();
+        let line = dump(first);
+        for arg in [
+            "--only-listed",
+            "--busy-file sdb=/run/busy",
+            "--diskstats sdc=sdc,sdx",
+            "--pre-spindown sdb=/bin/false",
+            "--spindown-window sdc=12:00-13:00",
+            "--socket=/run/idle.sock",
+            "/dev/sdb:600s",
+            "WCC7K123:1800s",
+        ] {
+            assert!(line.contains(arg), "{} in {}", arg, line);
+        }
+
+        let args = line
+            .strip_prefix("RUST_IDLE_OPTS=")
+            .unwrap()
+            .split_whitespace();
+        let second = parse(args.map(OsString::from).collect());
+        assert!(second.default_config == default_config);
+        let mut read_back = second.device_configs.clone();
+        read_back.sort_by(|(a, _), (b, _)| a.cmp(b));
+        assert!(read_back == configs);
+        assert_eq!(second.options.links, links);
+        assert_eq!(dump(second), line);
+    }
+
+    #[test]
+    fn mount_sources() {
+        // A logical volume spanning two disks
+        let stacked = |path: &OsStr| {
+            Ok((path == "/dev/mapper/vg-data").then(|| vec!["sdb".into(), "sdc".into()]))
+        };
+        let link = |path: &OsStr| match path.to_str() {
+            Some("/dev/sda") => Ok("sda".into()),
+            _ => Err(format!("Not a SCSI or NVMe device: '{}'", path.to_string_lossy()).into()),
+        };
+        let disks = |source| {
+            source_to_scsi_names(OsStr::new(source), stacked, link).map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            disks("mapper/vg-data"),
+            Ok(vec!["sdb".into(), "sdc".into()])
+        );
+        assert_eq!(disks("sda1"), Ok(vec!["sda".into()]));
+        assert!(disks("mapper/vg-home").is_err());
+    }
+
+    /// Resolves the fixture nodes of `aliased_devices` like the nodes of /dev:
+    /// the disk of a link is the node it points to, and a partition node is
+    /// named after its disk.
+    fn fixture_disks(path: &OsStr, verbosity: u8) -> Result<Vec<OsString>> {
+        let node = |path: &OsStr| {
+            let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+            path.file_name().unwrap().to_owned()
+        };
+        let parent = |path: &OsStr| {
+            let name = node(path);
+            let disk = name.as_bytes().strip_suffix(b"1")?;
+            Some((name.clone(), OsStr::from_bytes(disk).to_owned()))
+        };
+        let link = |path: &OsStr| Ok(vec![node(path)]);
+        path_to_scsi_names(path, verbosity, parent, link)
+    }
+
+    #[test]
+    fn aliased_devices() {
+        // Fixture of /dev: a disk node and its partition, with links by model
+        // and by WWN
+        let dev_dir = TempDir::new("rust-idle-dev");
+        std::fs::create_dir_all(dev_dir.join("disk/by-id")).unwrap();
+        dev_dir.write("sdz", "");
+        dev_dir.write("sdz1", "");
+        let by_id = dev_dir.join("disk/by-id/ata-WDC_WD40EFRX-WCC7K123");
+        let by_wwn = dev_dir.join("disk/by-id/wwn-0x50014ee2b5d1c2a3");
+        let part = dev_dir.join("disk/by-id/ata-WDC_WD40EFRX-WCC7K123-part1");
+        std::os::unix::fs::symlink("../../sdz", &by_id).unwrap();
+        std::os::unix::fs::symlink("../../sdz", &by_wwn).unwrap();
+        std::os::unix::fs::symlink("../../sdz1", &part).unwrap();
+        for link in [&by_id, &by_wwn, &part] {
+            assert_eq!(fixture_disks(link.as_os_str(), 0).unwrap(), ["sdz"]);
+        }
+
+        let system = System {
+            resolve: fixture_disks,
+            ..testing::system()
+        };
+        let parse = |args: &[&OsStr]| {
+            let args = std::iter::once(OsStr::new("rust-idle")).chain(args.iter().copied());
+            parse_arguments(args.map(OsString::from), system)
+        };
+        let arg = |link: &Path, idle_time: &str| {
+            let mut arg = link.as_os_str().to_owned();
+            arg.push(":");
+            arg.push(idle_time);
+            arg
+        };
+        for alias in [&by_wwn, &part] {
+            let args = [arg(&by_id, "600"), arg(alias, "1200")];
+            let args: Vec<_> = args.iter().map(OsString::as_os_str).collect();
+            let err = parse(&args).err().unwrap().to_string();
+            assert!(
+                err.ends_with(&format!(
+                    "'{}:600' and '{}:1200' are the same disk (sdz)",
+                    by_id.display(),
+                    alias.display()
+                )),
+                "{}",
+                err
+            );
+        }
+        // Overridden by a configuration file
+        let config = dev_dir.write("rust-idle.conf", format!("{}:1800", by_wwn.display()));
+        let by_id = arg(&by_id, "600");
+        let args = [&by_id, OsStr::new("--config"), config.as_os_str()];
+        let device_configs = parse(&args).unwrap().device_configs;
+        assert_eq!(device_configs.len(), 1);
+        assert_eq!(device_configs[0].0, "sdz");
+        assert_eq!(device_configs[0].1.idle_time, Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn command_line_overrides_files() {
+        let mut device_configs = Vec::new();
+        let mut device_args = Vec::new();
+        for (arg, idle_time, from_file) in [("a.conf", 600, true), ("/dev/sdz:1200", 1200, false)] {
+            let config = DeviceConfig {
+                idle_time: Duration::from_secs(idle_time),
+                ..Default::default()
+            };
+            let dev = OsString::from("sdz");
+            add_device(
+                &mut device_configs,
+                &mut device_args,
+                (dev, config),
+                arg,
+                from_file,
+            )
+            .unwrap();
+        }
+        assert_eq!(device_configs.len(), 1);
+        assert_eq!(device_configs[0].1.idle_time, Duration::from_secs(1200));
+    }
+
+    #[test]
+    fn flags_roundtrip() {
+        let default = DeviceConfig::default();
+        for flags in [
+            "0",
+            "600sSGLd30e8n64w86400W600u120c6D2g3N4a128p3ovv",
+            "1200S",
+            "900st",
+            "900st600",
+            "600rx",
+        ] {
+            let config = parse_flags(RawOsStr::new(flags), &default).unwrap();
+            assert_eq!(Flags(&config).to_string(), flags);
+        }
+        for (flags, seconds) in [
+            ("10m", "600"),
+            ("2hs", "7200s"),
+            ("d5m30", "30d300"),
+            ("30sw24hW10m", "30sw86400W600"),
+            ("15mt1h", "900t3600"),
+        ] {
+            let config = parse_flags(RawOsStr::new(flags), &default).unwrap();
+            assert_eq!(Flags(&config).to_string(), seconds);
+        }
+        // A `s` after the idle time is the sync flag, not a unit
+        let unsynced = parse_flags(RawOsStr::new("-s"), &default).unwrap();
+        let config = parse_flags(RawOsStr::new("7200s"), &unsynced).unwrap();
+        assert_eq!(config.idle_time, Duration::from_secs(7200));
+        assert_eq!(config.sync_flags & SYNC_SPIN_DOWN, SYNC_SPIN_DOWN);
+        for flags in ["1h30", "m", "g3m"] {
+            assert!(parse_flags(RawOsStr::new(flags), &default).is_err());
+        }
+        for flags in ["a256", "N32", "p12"] {
+            assert!(parse_flags(RawOsStr::new(flags), &default).is_err());
+        }
+        for (flags, level) in [("a0", 0), ("a256", 256)] {
+            let err = parse_flags(RawOsStr::new(flags), &default).err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid APM level: {}, expected 1 to 255", level)
+            );
+        }
+        let config = parse_flags(RawOsStr::new("a128-a"), &default).unwrap();
+        assert_eq!(config.apm_level, 0);
+    }
+}
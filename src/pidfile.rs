@@ -0,0 +1,52 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::errors::{Context, Result};
+use crate::sys;
+use crate::utils::parse_integer;
+
+/// A file holding the PID of the running daemon, removed when dropped.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Writes the PID of the current process to `path`.
+    ///
+    /// Fails if the file refers to another live process, overwrites it if the
+    /// process is gone.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match fs::read(path) {
+            Ok(content) => {
+                let pid = parse_integer(content.trim_ascii()).unwrap_or(0);
+                if pid != 0 && pid != std::process::id() as usize && sys::process_exists(pid) {
+                    return Err(format!(
+                        "Already running with PID {} (from '{}')",
+                        pid,
+                        path.display()
+                    )
+                    .into());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Reading PID file '{}'", path.display()));
+            }
+        }
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Writing PID file '{}'", path.display()))?;
+        Ok(Self(path.to_owned()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            crate::elogln!("<4>Failed to remove '{}': {}", self.0.display(), e);
+        }
+    }
+}
@@ -0,0 +1,365 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::schedule::Window;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceState {
+    Spinning(),
+    /// The disk was synced: next update will ignore activity and transition to `Idle`.
+    Synced(),
+    Idle(),
+    /// The device vanished from the system (eg. unplugged): it is left alone
+    /// until it comes back.
+    Missing(),
+}
+
+impl DeviceState {
+    /// Stable name of the state, for the status and metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceState::Spinning() => "spinning",
+            DeviceState::Synced() => "synced",
+            DeviceState::Idle() => "idle",
+            DeviceState::Missing() => "missing",
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub idle_time: Duration,
+    /// Activity within this margin before `idle_time` doesn't fully reset the
+    /// idle timer, only rewinds it to `idle_time - deadband`.
+    pub deadband: Duration,
+    /// When non-zero, the idle timer is only reset when the moving average of
+    /// the activity reaches this rate (sectors/s).
+    pub rate_threshold: u64,
+    /// Fewer sectors moved between two refreshes are taken for background
+    /// noise (eg. filesystem metadata), and don't reset the idle timer.
+    pub min_sectors: u64,
+    /// Period of the scheduled spin-ups, exercising the disk.
+    pub wake_period: Duration,
+    /// Minimal time spent spinning after a scheduled spin-up.
+    pub wake_duration: Duration,
+    /// Minimal time spent spinning after any spin-up, against spinning the
+    /// disk up and down on sparse bursts of activity.
+    pub min_uptime: Duration,
+    /// Most spin downs within an hour, against wearing the disk out (0: no
+    /// limit).
+    pub max_spindowns: u16,
+    /// Delay between syncing the disk and spinning it down, for drives which
+    /// don't accept the STOP command right after a flush.
+    pub spindown_delay: Duration,
+    /// Additional refreshes during which the activity is ignored after a sync,
+    /// for filesystems still writing back once the sync has returned.
+    pub grace_ticks: u8,
+    /// Power state of an NVMe controller entered instead of spinning down, or
+    /// its deepest one if 0.
+    pub nvme_power_state: u8,
+    /// Advanced Power Management level set on ATA drives (255 disables APM),
+    /// or 0 to leave it unchanged.
+    pub apm_level: u8,
+    /// Power condition field of the SCSI START STOP UNIT spinning the disk
+    /// down, 0 for a plain STOP.
+    pub power_condition: u8,
+    pub sync_flags: u8,
+    pub verbosity: u8,
+    /// Reset the idle timer while a process holds the device open.
+    pub open_check: bool,
+    /// Reads don't reset the idle timer (eg. served from the page cache most
+    /// of the time). They still tell a spun down disk has spun up.
+    pub ignore_reads: bool,
+    /// Writes and discards don't reset the idle timer.
+    pub ignore_writes: bool,
+    /// Leave the spin down to the standby timer of the drive's firmware, only
+    /// syncing the disk before it expires.
+    pub firmware_timer: bool,
+    /// Standby timer programmed into the drive with `firmware_timer`, or zero
+    /// to keep its own.
+    pub standby_timer: Duration,
+    /// Windows of the day allowing the spin downs, any time if empty.
+    pub windows: Vec<Window>,
+}
+
+pub const SYNC_SPIN_DOWN: u8 = 1;
+pub const SYNC_SPIN_UP: u8 = 2;
+/// Sync all the filesystems of the system before spinning down.
+pub const SYNC_GLOBAL: u8 = 4;
+/// Skip the syncs of a disk holding no dirty data, and its read-only mounts.
+pub const SYNC_DIRTY_ONLY: u8 = 8;
+
+/// Formats the bits of `DeviceConfig::sync_flags`.
+struct SyncFlags(u8);
+
+impl fmt::Display for SyncFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [(u8, &str); 4] = [
+            (SYNC_SPIN_DOWN, "SPIN_DOWN"),
+            (SYNC_SPIN_UP, "SPIN_UP"),
+            (SYNC_GLOBAL, "GLOBAL"),
+            (SYNC_DIRTY_ONLY, "DIRTY_ONLY"),
+        ];
+        if self.0 == 0 {
+            return f.write_str("NONE");
+        }
+        let mut sep = "";
+        for (flag, name) in NAMES {
+            if self.0 & flag != 0 {
+                write!(f, "{}{}", sep, name)?;
+                sep = " | ";
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{ idle_time: {}s", self.idle_time.as_secs())?;
+        write!(f, ", deadband: {}s", self.deadband.as_secs())?;
+        write!(f, ", rate_threshold: {}/s", self.rate_threshold)?;
+        write!(f, ", min_sectors: {}", self.min_sectors)?;
+        write!(f, ", wake_period: {}s", self.wake_period.as_secs())?;
+        write!(f, ", wake_duration: {}s", self.wake_duration.as_secs())?;
+        write!(f, ", min_uptime: {}s", self.min_uptime.as_secs())?;
+        write!(f, ", max_spindowns: {}/h", self.max_spindowns)?;
+        write!(f, ", spindown_delay: {}s", self.spindown_delay.as_secs())?;
+        write!(f, ", grace_ticks: {}", self.grace_ticks)?;
+        write!(f, ", nvme_power_state: {}", self.nvme_power_state)?;
+        write!(f, ", apm_level: {}", self.apm_level)?;
+        write!(f, ", power_condition: {}", self.power_condition)?;
+        write!(f, ", sync_flags: {}", SyncFlags(self.sync_flags))?;
+        write!(f, ", verbosity: {}", self.verbosity)?;
+        write!(f, ", open_check: {}", self.open_check)?;
+        write!(f, ", ignore_reads: {}", self.ignore_reads)?;
+        write!(f, ", ignore_writes: {}", self.ignore_writes)?;
+        write!(f, ", firmware_timer: {}", self.firmware_timer)?;
+        write!(f, ", standby_timer: {}s", self.standby_timer.as_secs())?;
+        f.write_str(", windows: [")?;
+        let mut sep = "";
+        for window in &self.windows {
+            write!(f, "{}{}", sep, window)?;
+            sep = ", ";
+        }
+        f.write_str("] }")
+    }
+}
+
+/// Activity of a device, sampled at each tick.
+pub struct Activity {
+    pub now: Instant,
+    /// # of sectors moved since the previous tick, only counting the
+    /// directions which aren't ignored while the device is spinning.
+    pub sectors_inc: usize,
+    /// Time elapsed since the last tick with activity.
+    pub idle_time: Duration,
+    /// Supplementary activity signal, not moving sectors (eg. I/Os in flight).
+    pub busy_signal: bool,
+}
+
+impl Activity {
+    pub fn busy(&self) -> bool {
+        self.sectors_inc != 0 || self.busy_signal
+    }
+}
+
+/// Side effects of a state transition, executed by the caller.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    None,
+    /// Sync the filesystems and buffers of the device.
+    Sync,
+    /// Spin down the device, after syncing it if `sync` is set.
+    Spindown {
+        sync: bool,
+    },
+    /// The device has spun up, sync it if `sync` is set.
+    Spinup {
+        sync: bool,
+    },
+    /// Spin up the device.
+    Wake,
+    /// The firmware of the device is about to spin it down: sync it, but let
+    /// the firmware park it.
+    SyncBeforePark,
+}
+
+/// Decision logic driving the devices' state machine.
+pub trait Policy {
+    /// Given the current state of a device, its activity and configuration,
+    /// returns the next state and the action to perform.
+    fn decide(
+        &self,
+        state: DeviceState,
+        activity: &Activity,
+        config: &DeviceConfig,
+    ) -> (DeviceState, Action);
+}
+
+/// The built-in policy: spin down after `config.idle_time` without activity.
+pub struct IdleTimePolicy;
+
+impl Policy for IdleTimePolicy {
+    fn decide(
+        &self,
+        state: DeviceState,
+        activity: &Activity,
+        config: &DeviceConfig,
+    ) -> (DeviceState, Action) {
+        // Skip unconfigured disks
+        if config.idle_time == Duration::ZERO {
+            return (DeviceState::Spinning(), Action::None);
+        }
+
+        match state {
+            DeviceState::Spinning() => {
+                if activity.idle_time >= config.idle_time && config.firmware_timer {
+                    (DeviceState::Synced(), Action::SyncBeforePark)
+                } else if activity.idle_time >= config.idle_time {
+                    let sync = config.sync_flags & SYNC_SPIN_DOWN != 0;
+                    // Skip the activity caused by syncing
+                    if sync || config.sync_flags & SYNC_GLOBAL != 0 {
+                        (DeviceState::Synced(), Action::Spindown { sync })
+                    } else {
+                        (DeviceState::Idle(), Action::Spindown { sync })
+                    }
+                } else {
+                    (DeviceState::Spinning(), Action::None)
+                }
+            }
+            DeviceState::Synced() => (DeviceState::Idle(), Action::None),
+            DeviceState::Missing() => (DeviceState::Missing(), Action::None),
+            DeviceState::Idle() => {
+                if activity.busy() {
+                    let sync = config.sync_flags & SYNC_SPIN_UP != 0;
+                    (DeviceState::Spinning(), Action::Spinup { sync })
+                } else {
+                    (DeviceState::Idle(), Action::None)
+                }
+            }
+        }
+    }
+}
+
+/// Observation only: devices are always considered spinning and no action is
+/// ever taken.
+pub struct MonitorPolicy;
+
+impl Policy for MonitorPolicy {
+    fn decide(&self, _: DeviceState, _: &Activity, _: &DeviceConfig) -> (DeviceState, Action) {
+        (DeviceState::Spinning(), Action::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decide(
+        state: DeviceState,
+        idle_secs: u64,
+        busy: bool,
+        config: &DeviceConfig,
+    ) -> (DeviceState, Action) {
+        let activity = Activity {
+            now: Instant::now(),
+            sectors_inc: busy as usize,
+            idle_time: Duration::from_secs(idle_secs),
+            busy_signal: false,
+        };
+        IdleTimePolicy.decide(state, &activity, config)
+    }
+
+    #[test]
+    fn idle_time_spindowns() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let spinning = DeviceState::Spinning();
+        assert_eq!(
+            decide(spinning, 599, false, &config),
+            (spinning, Action::None)
+        );
+        assert_eq!(
+            decide(spinning, 600, false, &config),
+            (DeviceState::Idle(), Action::Spindown { sync: false })
+        );
+
+        // Syncing goes through Synced, skipping the activity it causes
+        let synced = DeviceConfig {
+            sync_flags: SYNC_SPIN_DOWN,
+            ..config.clone()
+        };
+        assert_eq!(
+            decide(spinning, 600, false, &synced),
+            (DeviceState::Synced(), Action::Spindown { sync: true })
+        );
+        let global = DeviceConfig {
+            sync_flags: SYNC_GLOBAL,
+            ..config.clone()
+        };
+        assert_eq!(
+            decide(spinning, 600, false, &global),
+            (DeviceState::Synced(), Action::Spindown { sync: false })
+        );
+        assert_eq!(
+            decide(DeviceState::Synced(), 0, true, &synced),
+            (DeviceState::Idle(), Action::None)
+        );
+
+        let firmware = DeviceConfig {
+            firmware_timer: true,
+            ..config
+        };
+        assert_eq!(
+            decide(spinning, 600, false, &firmware),
+            (DeviceState::Synced(), Action::SyncBeforePark)
+        );
+    }
+
+    #[test]
+    fn idle_time_spinups() {
+        let config = DeviceConfig {
+            idle_time: Duration::from_secs(600),
+            sync_flags: SYNC_SPIN_UP,
+            ..Default::default()
+        };
+        let idle = DeviceState::Idle();
+        assert_eq!(decide(idle, 900, false, &config), (idle, Action::None));
+        assert_eq!(
+            decide(idle, 0, true, &config),
+            (DeviceState::Spinning(), Action::Spinup { sync: true })
+        );
+        // A supplementary signal is activity too
+        let activity = Activity {
+            now: Instant::now(),
+            sectors_inc: 0,
+            idle_time: Duration::ZERO,
+            busy_signal: true,
+        };
+        assert_eq!(
+            IdleTimePolicy.decide(idle, &activity, &config).0,
+            DeviceState::Spinning()
+        );
+
+        let missing = DeviceState::Missing();
+        assert_eq!(decide(missing, 900, true, &config), (missing, Action::None));
+    }
+
+    #[test]
+    fn unconfigured_devices() {
+        let config = DeviceConfig::default();
+        for state in [DeviceState::Spinning(), DeviceState::Idle()] {
+            assert_eq!(
+                decide(state, 900, false, &config),
+                (DeviceState::Spinning(), Action::None)
+            );
+        }
+    }
+}
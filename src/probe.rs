@@ -0,0 +1,144 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Compatibility report of the drives (`--probe-all`): identifies them and
+//! queries their capabilities without spinning them down.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::errors::{Context, Result};
+use crate::mounts::Mounts;
+use crate::sys;
+
+const CACHING_MODE_PAGE: u8 = 0x08;
+
+/// Lists the SCSI disks (excluding partitions) present on the system.
+pub fn scsi_disks() -> Result<Vec<OsString>> {
+    let mut disks = Vec::new();
+    for entry in fs::read_dir("/sys/class/block").context("Listing block devices")? {
+        let entry = entry.context("Listing block devices")?;
+        if entry.path().join("partition").exists() {
+            continue;
+        }
+        let mut path = OsString::from("/dev/");
+        path.push(entry.file_name());
+        if let Ok(dev) = sys::link_to_scsi_name(&path) {
+            disks.push(dev);
+        }
+    }
+    disks.sort();
+    Ok(disks)
+}
+
+/// Writes a table of the SCSI disks of the system, with their device number,
+/// model and power mode (queried without waking them up).
+pub fn list<W: Write>(out: &mut W, verbosity: u8) -> Result<()> {
+    let mut rows = vec![[
+        "NAME".to_owned(),
+        "MAJ:MIN".to_owned(),
+        "MODEL".to_owned(),
+        "POWER".to_owned(),
+    ]];
+    for dev in scsi_disks()? {
+        let number = fs::read_to_string(Path::new("/sys/class/block").join(&dev).join("dev"))
+            .map_or_else(|_| "?".to_owned(), |number| number.trim().to_owned());
+        let model = match sys::inquiry(&dev, verbosity) {
+            Ok(id) => format!("{} {}", id.vendor, id.product),
+            Err(_) => "?".to_owned(),
+        };
+        let power = match sys::check_power_mode(&dev, verbosity) {
+            Ok(mode) => mode.to_string(),
+            Err(_) => "unknown".to_owned(),
+        };
+        rows.push([dev.to_string_lossy().into_owned(), number, model, power]);
+    }
+
+    let mut widths = [0; 3];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for [name, number, model, power] in &rows {
+        writeln!(
+            out,
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            name,
+            number,
+            model,
+            power,
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the compatibility report of a disk.
+pub fn report<W: Write>(
+    out: &mut W,
+    mounts: &mut Mounts,
+    dev: &OsStr,
+    verbosity: u8,
+) -> Result<()> {
+    let inquiry = sys::inquiry(dev, verbosity);
+    match &inquiry {
+        Ok(id) => writeln!(
+            out,
+            "/dev/{}: {} {} {}",
+            dev.to_string_lossy(),
+            id.vendor,
+            id.product,
+            id.revision
+        )?,
+        Err(e) => writeln!(out, "/dev/{}: unidentified ({})", dev.to_string_lossy(), e)?,
+    }
+
+    let usb = sys::is_usb(dev);
+    let nvme = sys::is_nvme(dev);
+    let transport = match &inquiry {
+        _ if nvme => "NVMe",
+        _ if usb => "USB bridge",
+        Ok(id) if id.is_ata() => "SATA (SCSI/ATA translation)",
+        _ => "SCSI",
+    };
+    writeln!(out, "    transport: {}", transport)?;
+    let command = match &inquiry {
+        _ if nvme => "NVMe SET FEATURES (Power Management)",
+        _ if usb => "ATA STANDBY IMMEDIATE (ATA PASS-THROUGH), or SCSI START STOP UNIT",
+        Ok(id) if id.is_ata() => "ATA STANDBY IMMEDIATE (ATA PASS-THROUGH)",
+        _ => "SCSI START STOP UNIT, or ATA STANDBY IMMEDIATE if rejected",
+    };
+    writeln!(out, "    spin down command: {}", command)?;
+
+    match sys::mode_sense(dev, CACHING_MODE_PAGE, verbosity) {
+        Ok(page) if page.get(2).is_some_and(|flags| flags & 0x04 != 0) => writeln!(
+            out,
+            "    write cache: enabled, syncing before spinning down is advised"
+        )?,
+        Ok(_) => writeln!(out, "    write cache: disabled")?,
+        Err(e) => writeln!(out, "    write cache: unknown ({})", e)?,
+    }
+    match sys::standby_timer(dev, verbosity) {
+        Ok(Some(timer)) => writeln!(out, "    firmware standby timer: {}s", timer.as_secs())?,
+        Ok(None) => writeln!(out, "    firmware standby timer: disabled")?,
+        Err(e) => writeln!(out, "    firmware standby timer: unknown ({})", e)?,
+    }
+
+    let mut mount_points = Vec::new();
+    mounts.for_dev(dev, |entry| {
+        mount_points.push(String::from_utf8_lossy(entry.mount_point.to_bytes()).into_owned());
+        Ok(())
+    })?;
+    if mount_points.is_empty() {
+        writeln!(out, "    filesystems to sync: none mounted")?;
+    } else {
+        writeln!(out, "    filesystems to sync: {}", mount_points.join(", "))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Windows of the day allowing the spin downs (`--spindown-window`), eg. only at
+//! night: outside of them, the disks keep spinning whatever their idle time.
+//! The times are local, see [`crate::localtime`].
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+
+/// Range of local times, in minutes since midnight. It spans midnight when it
+/// ends before its start (eg. `22:00-07:00`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    start: u16,
+    end: u16,
+}
+
+impl Window {
+    /// Parses `<HH:MM>-<HH:MM>`.
+    pub fn parse(s: &[u8]) -> Option<Self> {
+        let dash = s.iter().position(|c| *c == b'-')?;
+        let (start, end) = (parse_time(&s[..dash])?, parse_time(&s[dash + 1..])?);
+        (start != end).then_some(Self { start, end })
+    }
+
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Parses `HH:MM`, up to `24:00`.
+fn parse_time(s: &[u8]) -> Option<u16> {
+    let colon = s.iter().position(|c| *c == b':')?;
+    let number = |digits: &[u8]| {
+        (digits.len() == 2 && digits.iter().all(u8::is_ascii_digit))
+            .then(|| u16::from(digits[0] - b'0') * 10 + u16::from(digits[1] - b'0'))
+    };
+    let (hours, minutes) = (number(&s[..colon])?, number(&s[colon + 1..])?);
+    let time = hours * 60 + minutes;
+    (minutes < 60 && time <= 24 * 60).then_some(time)
+}
+
+/// Windows given as options, for a device or all of them (`None`).
+#[derive(Default)]
+pub struct Windows {
+    windows: Vec<(Option<OsString>, Window)>,
+}
+
+impl Windows {
+    /// Adds a `[<device>=]<HH:MM>-<HH:MM>` argument.
+    pub fn add(&mut self, arg: &OsStr) -> std::result::Result<(), String> {
+        let bytes = arg.as_bytes();
+        let (dev, window) = match bytes.iter().position(|c| *c == b'=') {
+            Some(eq) => (Some(&bytes[..eq]), &bytes[eq + 1..]),
+            None => (None, bytes),
+        };
+        let dev = dev.map(|dev| dev.strip_prefix(b"/dev/").unwrap_or(dev));
+        let window = Window::parse(window)
+            .filter(|_| !dev.is_some_and(<[u8]>::is_empty))
+            .ok_or_else(|| {
+                format!(
+                    "expected [<device>=]<HH:MM>-<HH:MM>: '{}'",
+                    arg.to_string_lossy()
+                )
+            })?;
+        self.windows
+            .push((dev.map(|dev| OsStr::from_bytes(dev).to_owned()), window));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// The windows given, in order, with the device each is given for.
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&OsStr>, Window)> {
+        (self.windows.iter()).map(|(dev, window)| (dev.as_deref(), *window))
+    }
+
+    /// Windows of a device: its own ones, or else the ones of all devices.
+    pub fn of(&self, dev: &OsStr) -> Vec<Window> {
+        let own = self.given_for(Some(dev));
+        if own.is_empty() { self.of_all() } else { own }
+    }
+
+    /// Windows given for all the devices.
+    pub fn of_all(&self) -> Vec<Window> {
+        self.given_for(None)
+    }
+
+    fn given_for(&self, device: Option<&OsStr>) -> Vec<Window> {
+        self.windows
+            .iter()
+            .filter(|(name, _)| name.as_deref() == device)
+            .map(|(_, window)| *window)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let night = Window::parse(b"22:00-07:30").unwrap();
+        assert!(night.contains(23 * 60) && night.contains(0) && night.contains(7 * 60 + 29));
+        assert!(!night.contains(7 * 60 + 30) && !night.contains(12 * 60));
+        let day = Window::parse(b"09:00-24:00").unwrap();
+        assert!(day.contains(9 * 60) && day.contains(23 * 60 + 59) && !day.contains(0));
+        assert_eq!(night.to_string(), "22:00-07:30");
+        for invalid in [
+            "9:00-17:00",
+            "09:00-09:00",
+            "09:60-10:00",
+            "09:00-24:01",
+            "09:00",
+        ] {
+            assert!(Window::parse(invalid.as_bytes()).is_none(), "{}", invalid);
+        }
+
+        let mut windows = Windows::default();
+        windows.add(OsStr::new("01:00-06:00")).unwrap();
+        windows.add(OsStr::new("/dev/sdb=22:00-07:00")).unwrap();
+        windows.add(OsStr::new("sdb=12:00-13:00")).unwrap();
+        assert!(windows.add(OsStr::new("=12:00-13:00")).is_err());
+        assert_eq!(windows.of(OsStr::new("sdb")).len(), 2);
+        assert_eq!(
+            windows.of(OsStr::new("sdc")),
+            [Window::parse(b"01:00-06:00").unwrap()]
+        );
+        assert_eq!(windows.of_all(), windows.of(OsStr::new("sdc")));
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Termination signals (SIGTERM, SIGINT) and SIGHUP handling: the handlers only
+//! record the signal, which is polled by the startup code and the main loop.
+
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::Duration;
+
+use crate::errors::{Context, Result};
+
+/// Last termination signal received, 0 if none.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// Set by SIGHUP, asking to reload the configuration.
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signum: i32) {
+    RECEIVED.store(signum, Ordering::Relaxed);
+}
+
+extern "C" fn on_hangup(_: i32) {
+    HANGUP.store(true, Ordering::Relaxed);
+}
+
+fn action(handler: extern "C" fn(i32)) -> nc::sigaction_t {
+    #[cfg(not(target_arch = "riscv64"))]
+    let action = nc::sigaction_t {
+        sa_handler: handler as nc::sighandler_t,
+        sa_flags: nc::SA_RESTART | nc::SA_RESTORER,
+        sa_restorer: nc::restore::get_sa_restorer(),
+        ..Default::default()
+    };
+    #[cfg(target_arch = "riscv64")]
+    let action = nc::sigaction_t {
+        sa_handler: handler as nc::sighandler_t,
+        sa_flags: nc::SA_RESTART,
+        ..Default::default()
+    };
+    action
+}
+
+/// Installs the handlers for SIGTERM, SIGINT and SIGHUP.
+pub fn install() -> Result<()> {
+    let handlers = [
+        (nc::SIGTERM, on_signal as extern "C" fn(i32)),
+        (nc::SIGINT, on_signal),
+        (nc::SIGHUP, on_hangup),
+    ];
+    for (signum, handler) in handlers {
+        unsafe { nc::rt_sigaction(signum, Some(&action(handler)), None) }
+            .with_context(|| format!("Installing the handler of signal {}", signum))?;
+    }
+    Ok(())
+}
+
+/// Returns the termination signal received since [`install`], if any.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::Relaxed) {
+        0 => None,
+        signum => Some(signum),
+    }
+}
+
+/// Was a SIGHUP received since the last [`take_hangup`]?
+pub fn hangup_pending() -> bool {
+    HANGUP.load(Ordering::Relaxed)
+}
+
+/// Returns whether a SIGHUP was received, and clears it.
+pub fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::Relaxed)
+}
+
+/// Termination signals and SIGHUP blocked until dropped: checking [`received`] under this
+/// guard, then waiting with [`Blocked::mask`], cannot miss a signal.
+pub struct Blocked {
+    previous: nc::sigset_t,
+}
+
+impl Blocked {
+    /// Signal mask from before [`block`], to unblock the signals during a wait.
+    pub fn mask(&self) -> &nc::sigset_t {
+        &self.previous
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        let _ = unsafe { nc::rt_sigprocmask(nc::SIG_SETMASK, Some(&self.previous), None) };
+    }
+}
+
+/// Blocks SIGTERM, SIGINT and SIGHUP.
+pub fn block() -> Result<Blocked> {
+    let bits = [nc::SIGTERM, nc::SIGINT, nc::SIGHUP].map(|signum| 1 << (signum - 1));
+    let set = nc::sigset_t::from(bits.into_iter().sum::<usize>());
+    let mut previous = nc::sigset_t::default();
+    unsafe { nc::rt_sigprocmask(nc::SIG_BLOCK, Some(&set), Some(&mut previous)) }
+        .context("Blocking the signals")?;
+    Ok(Blocked { previous })
+}
+
+/// Sleeps for `duration`, returning early if a termination signal is received.
+pub fn sleep(duration: Duration) {
+    let mut remaining = nc::timespec_t {
+        tv_sec: duration.as_secs() as nc::time_t,
+        tv_nsec: duration.subsec_nanos() as isize,
+    };
+    while received().is_none() {
+        let request = remaining.clone();
+        match unsafe { nc::nanosleep(&request, Some(&mut remaining)) } {
+            Err(nc::EINTR) => continue,
+            _ => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupted_sleep() {
+        // Only for SIGINT, restoring the handler of the test harness after
+        let mut previous = nc::sigaction_t::default();
+        let handler = action(on_signal);
+        unsafe { nc::rt_sigaction(nc::SIGINT, Some(&handler), Some(&mut previous)) }.unwrap();
+        unsafe { nc::tgkill(nc::getpid(), nc::gettid(), nc::SIGINT) }.unwrap();
+        assert_eq!(received(), Some(nc::SIGINT));
+        // Would otherwise hang the test
+        sleep(Duration::from_secs(3600));
+
+        unsafe { nc::rt_sigaction(nc::SIGINT, Some(&previous), None) }.unwrap();
+        RECEIVED.store(0, Ordering::Relaxed);
+        assert_eq!(received(), None);
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Metrics pushed as StatsD UDP packets (`--statsd`), one `<name>:<value>|<type>`
+//! line per metric. Sending is fire-and-forget: errors are ignored.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use crate::errors::{Context, Result};
+
+/// Keeps the packets under the usual MTU, without fragmentation.
+const MAX_PACKET_LEN: usize = 1432;
+const PREFIX: &str = "rust_idle";
+
+pub struct StatsD {
+    socket: UdpSocket,
+    interval: Duration,
+    last_flush: Option<Instant>,
+    /// Metrics not sent yet, one per line.
+    buf: String,
+    /// Totals of the counters at the last flush, to send their increments.
+    reported: Vec<(OsString, &'static str, u64)>,
+}
+
+impl StatsD {
+    /// Sends to `addr` (`<host>:<port>`), at most once per `interval`.
+    pub fn connect(addr: &str, interval: Duration) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(addr).map(|_| socket))
+            .with_context(|| format!("Connecting to StatsD at {}", addr))?;
+        socket
+            .set_nonblocking(true)
+            .context("Setting the StatsD socket non-blocking")?;
+        Ok(Self {
+            socket,
+            interval,
+            last_flush: None,
+            buf: String::new(),
+            reported: Vec::new(),
+        })
+    }
+
+    /// Is it time to send the metrics?
+    pub fn due(&self, now: Instant) -> bool {
+        self.last_flush
+            .is_none_or(|last_flush| now.duration_since(last_flush) >= self.interval)
+    }
+
+    pub fn gauge(&mut self, dev: &OsStr, name: &str, value: u64) {
+        let _ = writeln!(self.buf, "{}.{}.{}:{}|g", PREFIX, Metric(dev), name, value);
+    }
+
+    /// Sends the increment of a counter since the last flush, from its total.
+    pub fn count(&mut self, dev: &OsStr, name: &'static str, total: u64) {
+        let prev = match self
+            .reported
+            .iter_mut()
+            .find(|(d, n, _)| d == dev && *n == name)
+        {
+            Some((_, _, prev)) => std::mem::replace(prev, total),
+            None => {
+                self.reported.push((dev.to_owned(), name, total));
+                0
+            }
+        };
+        let inc = total.saturating_sub(prev);
+        let _ = writeln!(self.buf, "{}.{}.{}:{}|c", PREFIX, Metric(dev), name, inc);
+    }
+
+    /// Sends the pending metrics, splitting them in packets on line boundaries.
+    pub fn flush(&mut self, now: Instant) {
+        self.last_flush = Some(now);
+        for packet in packets(&self.buf) {
+            let _ = self.socket.send(packet.as_bytes());
+        }
+        self.buf.clear();
+    }
+}
+
+/// Splits newline-terminated lines into packets of at most `MAX_PACKET_LEN`
+/// bytes, without their final newline.
+fn packets(lines: &str) -> impl Iterator<Item = &str> {
+    let mut rest = lines;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut end = None;
+        for (idx, _) in rest.match_indices('\n') {
+            if idx > MAX_PACKET_LEN && end.is_some() {
+                break;
+            }
+            end = Some(idx + 1);
+        }
+        let (packet, tail) = rest.split_at(end.unwrap_or(rest.len()));
+        rest = tail;
+        Some(packet.trim_end_matches('\n'))
+    })
+}
+
+/// Formats a device name as a metric name component, replacing the characters
+/// of the line protocol.
+struct Metric<'a>(&'a OsStr);
+
+impl std::fmt::Display for Metric<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for c in self.0.to_string_lossy().chars() {
+            f.write_char(match c {
+                ':' | '|' | '@' | '.' | '#' | '\n' | ' ' => '_',
+                c => c,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_protocol() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = receiver.local_addr().unwrap().to_string();
+        let mut statsd = StatsD::connect(&addr, Duration::from_secs(10)).unwrap();
+        let now = Instant::now();
+        assert!(statsd.due(now));
+
+        let dev = OsStr::new("sdb");
+        statsd.gauge(dev, "idle_seconds", 42);
+        statsd.count(dev, "spindowns", 3);
+        statsd.gauge(OsStr::new("by.id:x"), "state", 1);
+        statsd.flush(now);
+        assert!(!statsd.due(now + Duration::from_secs(5)));
+
+        let mut buf = [0u8; 1500];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            "rust_idle.sdb.idle_seconds:42|g\nrust_idle.sdb.spindowns:3|c\n\
+             rust_idle.by_id_x.state:1|g"
+        );
+
+        // Only the increment of the counter
+        statsd.count(dev, "spindowns", 5);
+        statsd.flush(now);
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"rust_idle.sdb.spindowns:2|c");
+    }
+
+    #[test]
+    fn split_packets() {
+        let line = format!("{}:1|g\n", "m".repeat(600));
+        let lines = line.repeat(5);
+        let packets: Vec<_> = packets(&lines).collect();
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET_LEN));
+        assert_eq!(packets.join("\n") + "\n", lines);
+    }
+}
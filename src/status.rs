@@ -0,0 +1,188 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Status of the devices, shared by the status file (`--status`), the HTTP API
+//! and the control socket: a line of text per device, or a JSON document.
+//!
+//! The JSON is written by hand, its schema being small and flat:
+//! `{"devices":[{"name":..,"state":..,"idle_time":..,"sectors":..,
+//! "read_sectors":..,"write_sectors":..,"rate":..,"spindowns":..,"spinups":..,
+//! "last_error":..}]}`, with an optional `"last_access"`.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::fanotify::Access;
+use crate::iomonitor::Sectors;
+use crate::policy::DeviceState;
+
+/// Formats a string as a JSON string literal.
+pub struct Json<'a>(pub &'a str);
+
+impl fmt::Display for Json<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if u32::from(c) < 0x20 => write!(f, "\\u{:04x}", u32::from(c))?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+/// Snapshot of a device, for the status.
+pub struct DeviceStatus<'a> {
+    pub name: &'a OsStr,
+    pub state: DeviceState,
+    pub idle_time: Duration,
+    pub sectors: Sectors,
+    /// Moving average of the activity, in sectors/s.
+    pub rate: f64,
+    /// # of spin downs and ups since the start of the daemon.
+    pub spindowns: u64,
+    pub spinups: u64,
+    /// Age and message of the last error.
+    pub last_error: Option<(Duration, &'a str)>,
+    pub last_access: Option<&'a Access>,
+}
+
+impl DeviceStatus<'_> {
+    /// Writes the device as a line of text.
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(
+            out,
+            "{}: {}, idle for {}s, {} sectors, {:.1} sectors/s, {} spin downs, {} spin ups",
+            self.name.to_string_lossy(),
+            self.state.name(),
+            self.idle_time.as_secs(),
+            self.sectors.total(),
+            self.rate,
+            self.spindowns,
+            self.spinups
+        )?;
+        if let Some((age, msg)) = self.last_error {
+            write!(out, ", last error {}s ago: {}", age.as_secs(), msg)?;
+        }
+        if let Some(access) = self.last_access {
+            write!(out, ", last file access: {}", access)?;
+        }
+        writeln!(out)
+    }
+
+    /// Writes the device as a JSON object.
+    pub fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(
+            out,
+            "{{\"name\":{},\"state\":\"{}\",\"idle_time\":{},\"sectors\":{},\
+             \"read_sectors\":{},\"write_sectors\":{},\"rate\":{:.1},\"spindowns\":{},\
+             \"spinups\":{},\"last_error\":",
+            Json(&self.name.to_string_lossy()),
+            self.state.name(),
+            self.idle_time.as_secs(),
+            self.sectors.total(),
+            self.sectors.read,
+            self.sectors.written,
+            self.rate,
+            self.spindowns,
+            self.spinups
+        )?;
+        match self.last_error {
+            Some((age, msg)) => write!(
+                out,
+                "{{\"age\":{},\"message\":{}}}",
+                age.as_secs(),
+                Json(msg)
+            )?,
+            None => write!(out, "null")?,
+        }
+        if let Some(access) = self.last_access {
+            write!(
+                out,
+                ",\"last_access\":{{\"path\":{},\"pid\":{},\"process\":",
+                Json(&access.path.to_string_lossy()),
+                access.pid
+            )?;
+            match &access.process {
+                Some(process) => write!(out, "{}}}", Json(process))?,
+                None => write!(out, "null}}")?,
+            }
+        }
+        write!(out, "}}")
+    }
+}
+
+/// Writes the status of the devices, one per line or as a JSON document.
+pub fn write<'a, W, I>(out: &mut W, devices: I, json: bool) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = DeviceStatus<'a>>,
+{
+    if !json {
+        for device in devices {
+            device.write_text(out)?;
+        }
+        return Ok(());
+    }
+    write!(out, "{{\"devices\":[")?;
+    let mut sep = "";
+    for device in devices {
+        write!(out, "{}", sep)?;
+        device.write_json(out)?;
+        sep = ",";
+    }
+    writeln!(out, "]}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_strings() {
+        assert_eq!(Json("sdb").to_string(), "\"sdb\"");
+        assert_eq!(
+            Json("a \"b\"\\\n").to_string(),
+            "\"a \\\"b\\\"\\\\\\u000a\""
+        );
+    }
+
+    #[test]
+    fn json_devices() {
+        let device = |name, state| DeviceStatus {
+            name,
+            state,
+            idle_time: Duration::from_secs(742),
+            sectors: Sectors {
+                read: 100,
+                written: 20,
+            },
+            rate: 0.0,
+            spindowns: 3,
+            spinups: 2,
+            last_error: None,
+            last_access: None,
+        };
+        let devices = [
+            device(OsStr::new("sdb"), DeviceState::Idle()),
+            device(OsStr::new("sdc"), DeviceState::Spinning()),
+        ];
+        let mut out = Vec::new();
+        write(&mut out, devices, true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"devices\":[{\"name\":\"sdb\",\"state\":\"idle\",\"idle_time\":742,\
+             \"sectors\":120,\"read_sectors\":100,\"write_sectors\":20,\"rate\":0.0,\
+             \"spindowns\":3,\"spinups\":2,\"last_error\":null},{\"name\":\"sdc\",\
+             \"state\":\"spinning\",\"idle_time\":742,\"sectors\":120,\"read_sectors\":100,\
+             \"write_sectors\":20,\"rate\":0.0,\"spindowns\":3,\"spinups\":2,\
+             \"last_error\":null}]}\n"
+        );
+    }
+}
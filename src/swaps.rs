@@ -0,0 +1,73 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Active swap areas, from /proc/swaps. The kernel may page out to them at
+//! any time: spinning down a disk holding one can hang the system.
+
+use std::ffi::OsStr;
+use std::os::unix::prelude::OsStrExt;
+
+use crate::errors::Result;
+use crate::mounts;
+use crate::sys;
+use crate::utils::BulkReader;
+
+const SWAPS_PATH: &str = "/proc/swaps";
+
+pub struct Swaps(BulkReader);
+
+impl Swaps {
+    pub fn new() -> Result<Self> {
+        Ok(Self(BulkReader::open(SWAPS_PATH)?))
+    }
+
+    /// Reads scripted snapshots of /proc/swaps, one per update.
+    #[cfg(test)]
+    pub fn from_snapshots(snapshots: &[&str]) -> Self {
+        Self(BulkReader::from_snapshots(snapshots.iter().copied()))
+    }
+
+    pub fn update(&mut self) {
+        self.0.clear();
+    }
+
+    /// Is an active swap area on the disk `dev_name`: one of its partitions,
+    /// a stacked device (LVM, LUKS) on it, or a file of a filesystem on it?
+    pub fn on_dev(&mut self, dev_name: &OsStr) -> Result<bool> {
+        if self.0.empty() {
+            self.0.read()?;
+        }
+        // Skip the header
+        for line in self.0.parse_lines_mut().skip(1) {
+            let disks = sys::disks_of(parse_line(line));
+            if disks.is_some_and(|disks| disks.iter().any(|disk| disk == dev_name)) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Parses the path of a swap area from a line of /proc/swaps, where it is
+/// padded with spaces and escaped like in the mount table.
+pub(crate) fn parse_line(line: &mut [u8]) -> &OsStr {
+    let end = (line.iter())
+        .position(|c| matches!(c, b' ' | b'\t' | b'\n'))
+        .unwrap_or(line.len());
+    let len = mounts::unescape_in_place(&mut line[..end]);
+    OsStr::from_bytes(&line[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap_areas() {
+        let mut line = b"/dev/sda2                   partition\t8388604\t0\t-2\n".to_vec();
+        assert_eq!(parse_line(&mut line), "/dev/sda2");
+        let mut line = b"/mnt/data/swap\\040file\tfile\t1048572\t0\t-3".to_vec();
+        assert_eq!(parse_line(&mut line), "/mnt/data/swap file");
+    }
+}
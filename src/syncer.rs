@@ -0,0 +1,117 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Syncs run by a background thread: flushing gigabytes of dirty pages can
+//! take seconds, during which the main loop keeps monitoring the other
+//! devices. The completions are signaled through an `eventfd`, to be watched
+//! by the event loop (see [`crate::eventloop`]).
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use crate::errors::{Context, Result};
+
+pub struct Syncer<T> {
+    requests: Option<Sender<T>>,
+    completions: Receiver<(T, Result<()>)>,
+    /// Readable once a sync completed.
+    eventfd: Arc<File>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> Syncer<T> {
+    /// Starts the thread, running `sync` on the requests in order.
+    pub fn spawn<F>(mut sync: F) -> Result<Self>
+    where
+        F: FnMut(&T) -> Result<()> + Send + 'static,
+    {
+        let flags = nc::EFD_CLOEXEC | nc::EFD_NONBLOCK;
+        let eventfd = unsafe { nc::eventfd2(0, flags) }.context("eventfd2")?;
+        let eventfd = Arc::new(unsafe { File::from_raw_fd(eventfd) });
+        let (requests, queue) = mpsc::channel::<T>();
+        let (done, completions) = mpsc::channel();
+        let signal = Arc::clone(&eventfd);
+        let thread = thread::Builder::new()
+            .name("rust-idle-sync".into())
+            .spawn(move || {
+                for request in queue {
+                    let res = sync(&request);
+                    if done.send((request, res)).is_err() {
+                        return;
+                    }
+                    let _ = (&*signal).write(&1u64.to_ne_bytes());
+                }
+            })
+            .context("Starting the sync thread")?;
+        Ok(Self {
+            requests: Some(requests),
+            completions,
+            eventfd,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues a sync.
+    pub fn request(&self, request: T) -> Result<()> {
+        (self.requests.as_ref())
+            .and_then(|requests| requests.send(request).ok())
+            .ok_or_else(|| "The sync thread is gone".into())
+    }
+}
+
+impl<T> Syncer<T> {
+    /// Returns the next completed sync, with its outcome.
+    pub fn completed(&self) -> Option<(T, Result<()>)> {
+        // Acknowledges the signals, the completions are all drained
+        let _ = (&*self.eventfd).read(&mut [0u8; 8]);
+        self.completions.try_recv().ok()
+    }
+}
+
+impl<T> AsRawFd for Syncer<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
+
+/// Lets the queued syncs finish before exiting.
+impl<T> Drop for Syncer<T> {
+    fn drop(&mut self) {
+        self.requests = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventloop::EventLoop;
+
+    #[test]
+    fn background_syncs() {
+        let syncer = Syncer::spawn(|dev: &&str| match *dev {
+            "sda" => Ok(()),
+            _ => Err("Failed to sync".into()),
+        })
+        .unwrap();
+        let mut events = EventLoop::new().unwrap();
+        events.add(syncer.as_raw_fd(), 42).unwrap();
+        syncer.request("sda").unwrap();
+        syncer.request("sdb").unwrap();
+        let mut completed = Vec::new();
+        while completed.len() < 2 {
+            assert_eq!(events.wait(None).unwrap(), Some(42));
+            while let Some((dev, res)) = syncer.completed() {
+                completed.push((dev, res.is_ok()));
+            }
+        }
+        assert_eq!(completed, [("sda", true), ("sdb", false)]);
+    }
+}
@@ -2,32 +2,34 @@
 // reserved. Use of this source is governed by MIT License that can be found in
 // the LICENSE file.
 
-use std::ffi::{c_void, OsStr, OsString};
+use std::ffi::{OsStr, OsString, c_void};
+use std::fmt;
 use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 pub use nc::c_str::CStr;
 
-use crate::errors::{Context, Result};
+use crate::errors::{Context, Error, Result};
+use crate::policy::DeviceConfig;
 
 /// Create a `CStr` by writing a '\0' in place at the end of a mutable byte slice.
 ///
-/// The last byte must be a whitespace character (' ', '\t', or '\0').
+/// The last byte must be a whitespace character (' ', '\t', '\n' or '\0').
 pub fn make_inplace_cstr(str: &mut [u8]) -> Result<&CStr> {
     let last = str.last_mut().ok_or("Empty string")?;
     match last {
-        b' ' | b'\t' | b'\0' => {
+        b' ' | b'\t' | b'\n' | b'\0' => {
             *last = b'\0';
             // Borrow-wise, it should be as safe as `return Ok(str)`:
             // No further mutation is possible while the returned &CStr is held.
             Ok(unsafe { &*(str as *const [u8] as *const CStr) })
         }
-        _ => {
-            Err(format!(
-                "Expected null or whitespace at the end of '{}'",
-                OsStr::from_bytes(str).to_string_lossy()
-            )
-            .into())
-        }
+        _ => Err(format!(
+            "Expected null or whitespace at the end of '{}'",
+            OsStr::from_bytes(str).to_string_lossy()
+        )
+        .into()),
     }
 }
 
@@ -35,19 +37,33 @@ pub const fn is_scsi(major: usize) -> bool {
     matches!(major, 8 | 65..=71)
 }
 
-/// Returns the device name (as found under `/dev/`) from a symlink, while
-/// ensuring that the device is indeed a SCSI device.
+/// Is it the name of an NVMe namespace or partition? Their major number is
+/// allocated dynamically.
+pub fn is_nvme(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"nvme")
+}
+
+/// Returns the kernel name of a device (as found in `/proc/diskstats`) from a
+/// device node or a symlink to it, while ensuring that the device is indeed a
+/// SCSI device. The node can live anywhere, eg. under `/dev/block/`.
 pub fn link_to_scsi_name(path: &OsStr) -> Result<OsString> {
-    let mut stat_buf = nc::stat_t::default();
-    unsafe { nc::stat(path, &mut stat_buf) }
-        .with_context(|| format!("stat {}", path.to_string_lossy()))?;
-    if stat_buf.st_mode & nc::S_IFMT != nc::S_IFBLK {
-        return Err(format!("Not a block device: '{}'", path.to_string_lossy()).into());
-    }
-    let major = stat_buf.st_rdev >> 8;
-    let minor = stat_buf.st_rdev & 0xff;
+    let (major, minor) = block_device_numbers(path)?;
     if !is_scsi(major) {
-        return Err(format!("Not a SCSI device: '{}'", path.to_string_lossy()).into());
+        // The partitions of NVMe namespaces have their own `partition` entry
+        if let Some(name) = kernel_name(major, minor)
+            && is_nvme(&name)
+        {
+            let sys_path = std::path::Path::new("/sys/class/block").join(&name);
+            if sys_path.join("partition").exists() {
+                return Err(format!(
+                    "'{}' is a partition, not a root device",
+                    path.to_string_lossy()
+                )
+                .into());
+            }
+            return Ok(name);
+        }
+        return Err(format!("Not a SCSI or NVMe device: '{}'", path.to_string_lossy()).into());
     }
     if minor % 16 != 0 {
         return Err(format!(
@@ -56,173 +72,1632 @@ pub fn link_to_scsi_name(path: &OsStr) -> Result<OsString> {
         )
         .into());
     }
-    let dev_path = std::fs::canonicalize(path)
-        .with_context(|| format!("getting cannonical path to '{}'", path.to_string_lossy()))?;
-    dev_path
-        .strip_prefix("/dev/")
-        .map_err(|_| {
-            format!(
-                "path '{}' doesn't resolves to device under '/dev/' ('{}')",
-                path.to_string_lossy(),
-                dev_path.to_string_lossy()
-            )
-            .into()
-        })
-        .map(|name| name.to_owned().into())
+    // The kernel name, as found in diskstats, whatever the name of the node
+    kernel_name(major, minor).ok_or_else(|| {
+        format!(
+            "No kernel name for '{}' ({}:{}) in /sys/dev/block",
+            path.to_string_lossy(),
+            major,
+            minor
+        )
+        .into()
+    })
+}
+
+/// Like [`link_to_scsi_name`], also resolving a device-mapper device (LVM
+/// logical volume, LUKS container) or a software RAID to the disks backing it.
+/// A volume spanning several disks returns each of them.
+pub fn link_to_scsi_names(path: &OsStr) -> Result<Vec<OsString>> {
+    match stacked_disks(path)? {
+        Some(disks) => Ok(disks),
+        None => link_to_scsi_name(path).map(|name| vec![name]),
+    }
+}
+
+/// Returns the disks under a stacked block device (device-mapper, md), found
+/// through the `slaves` links of sysfs, or `None` for another device.
+pub fn stacked_disks(path: &OsStr) -> Result<Option<Vec<OsString>>> {
+    let Some(name) = block_device_numbers(path)
+        .ok()
+        .and_then(|(major, minor)| kernel_name(major, minor))
+    else {
+        return Ok(None);
+    };
+    let root = Path::new("/sys/class/block");
+    if slaves(root, &name).is_empty() {
+        return Ok(None);
+    }
+    let mut disks = Vec::new();
+    backing_disks(root, &name, &mut disks)
+        .with_context(|| format!("Resolving the disks of '{}'", path.to_string_lossy()))?;
+    Ok(Some(disks))
+}
+
+/// Returns the SCSI or NVMe disks holding a block device (a partition, or a
+/// stacked device) or a file (the disks of its filesystem), `None` when they
+/// are not on such disks (eg. zram).
+pub fn disks_of(path: &OsStr) -> Option<Vec<OsString>> {
+    let mut stat_buf = nc::stat_t::default();
+    unsafe { nc::stat(path, &mut stat_buf) }.ok()?;
+    let dev = if stat_buf.st_mode & nc::S_IFMT == nc::S_IFBLK {
+        stat_buf.st_rdev
+    } else {
+        stat_buf.st_dev
+    };
+    let (major, minor) = split_dev(dev as u64);
+    let name = kernel_name(major, minor)?;
+    let mut disks = Vec::new();
+    backing_disks(Path::new("/sys/class/block"), &name, &mut disks).ok()?;
+    Some(disks)
+}
+
+/// Lists the devices a stacked device is built on, in `<root>/<name>/slaves`.
+fn slaves(root: &Path, name: &OsStr) -> Vec<OsString> {
+    let Ok(entries) = std::fs::read_dir(root.join(name).join("slaves")) else {
+        return Vec::new();
+    };
+    let mut slaves: Vec<_> = entries.flatten().map(|entry| entry.file_name()).collect();
+    slaves.sort();
+    slaves
+}
+
+/// Collects the SCSI or NVMe disks under a device, recursively (eg. a LUKS
+/// container on a logical volume), with the partitions resolved to their disk.
+fn backing_disks(root: &Path, name: &OsStr, disks: &mut Vec<OsString>) -> Result<()> {
+    let slaves = slaves(root, name);
+    if !slaves.is_empty() {
+        for slave in slaves {
+            backing_disks(root, &slave, disks)?;
+        }
+        return Ok(());
+    }
+    let sys_path = root.join(name);
+    // The directory of a partition is under the one of its disk
+    let disk = if sys_path.join("partition").exists() {
+        let path = std::fs::canonicalize(&sys_path)
+            .with_context(|| format!("Resolving '{}'", sys_path.display()))?;
+        path.parent()
+            .and_then(Path::file_name)
+            .ok_or_else(|| format!("No disk above partition '{}'", path.display()))?
+            .to_owned()
+    } else {
+        name.to_owned()
+    };
+    let numbers = std::fs::read(root.join(&disk).join("dev")).unwrap_or_default();
+    let major = numbers
+        .split(|c| *c == b':')
+        .next()
+        .and_then(|major| std::str::from_utf8(major).ok()?.parse().ok());
+    if !major.is_some_and(is_scsi) && !is_nvme(&disk) {
+        return Err(format!("Not a SCSI or NVMe disk: '{}'", disk.to_string_lossy()).into());
+    }
+    if !disks.contains(&disk) {
+        disks.push(disk);
+    }
+    Ok(())
+}
+
+/// Returns the numbers of a block device node.
+fn block_device_numbers(path: &OsStr) -> Result<(usize, usize)> {
+    if path.is_empty() {
+        return Err("Empty device path".into());
+    }
+    let mut stat_buf = nc::stat_t::default();
+    unsafe { nc::stat(path, &mut stat_buf) }
+        .with_context(|| format!("stat {}", path.to_string_lossy()))?;
+    if stat_buf.st_mode & nc::S_IFMT != nc::S_IFBLK {
+        return Err(format!("Not a block device: '{}'", path.to_string_lossy()).into());
+    }
+    Ok(split_dev(stat_buf.st_rdev as u64))
+}
+
+/// Splits a `dev_t` in its major and minor numbers, as encoded by glibc.
+fn split_dev(dev: u64) -> (usize, usize) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as usize, minor as usize)
+}
+
+/// Returns the kernel name of a block device from its numbers, following the
+/// `/sys/dev/block/<major>:<minor>` symlink to `/sys/devices/.../<name>`.
+pub fn kernel_name(major: usize, minor: usize) -> Option<OsString> {
+    let link = std::fs::read_link(format!("/sys/dev/block/{}:{}", major, minor)).ok()?;
+    link.file_name().map(|name| name.to_owned())
+}
+
+/// Returns the kernel names of a partition and of the disk holding it, or
+/// `None` if `path` is not a partition.
+pub fn partition_parent(path: &OsStr) -> Option<(OsString, OsString)> {
+    let (major, minor) = block_device_numbers(path).ok()?;
+    let dir = format!("/sys/dev/block/{}:{}", major, minor);
+    if !Path::new(&dir).join("partition").exists() {
+        return None;
+    }
+    // The directory of the partition lives in the one of its disk
+    let link = std::fs::read_link(&dir).ok()?;
+    let disk = link.parent()?.file_name()?;
+    Some((link.file_name()?.to_owned(), disk.to_owned()))
+}
+
+/// Returns the World Wide Identifier of a SCSI disk, a stable identity kept
+/// when the disk comes back under another name.
+pub fn wwid(dev: &OsStr) -> Option<OsString> {
+    let path = std::path::Path::new("/sys/class/block")
+        .join(dev)
+        .join("device/wwid");
+    let wwid = std::fs::read(path).ok()?;
+    let wwid = wwid.trim_ascii();
+    (!wwid.is_empty()).then(|| OsStr::from_bytes(wwid).to_owned())
+}
+
+/// Writeback statistics of the backing devices, in debugfs.
+const BDI_STATS_PATH: &str = "/sys/kernel/debug/bdi";
+
+/// Are the writeback statistics of the backing devices readable? debugfs may
+/// not be mounted, or only readable by root.
+pub fn has_writeback_stats() -> bool {
+    std::fs::read_dir(BDI_STATS_PATH).is_ok()
+}
+
+/// Does the disk hold dirty pages or inodes, not written back yet? Read from
+/// the writeback statistics of its backing device, in debugfs. `None` when
+/// they are not available (debugfs not mounted, or not readable).
+pub fn disk_has_dirty_data(dev: &OsStr) -> Option<bool> {
+    let numbers = Path::new("/sys/class/block").join(dev).join("dev");
+    let numbers = std::fs::read(numbers).ok()?;
+    let numbers = OsStr::from_bytes(numbers.trim_ascii());
+    let path = Path::new(BDI_STATS_PATH).join(numbers).join("stats");
+    parse_bdi_stats(&std::fs::read(path).ok()?)
+}
+
+/// Does the filesystem mounted on `mount_point`, from the disk `dev`, hold
+/// dirty data? The filesystems of the partitions of a disk share its backing
+/// device, and can't be told apart. `None` when unknown, as for the
+/// filesystems with their own backing device (eg. btrfs), which have an
+/// anonymous device number.
+pub fn has_dirty_data(mount_point: &CStr, dev: &OsStr) -> Option<bool> {
+    let mut stat_buf = nc::stat_t::default();
+    unsafe { nc::stat(OsStr::from_bytes(mount_point.to_bytes()), &mut stat_buf) }.ok()?;
+    let (major, _) = split_dev(stat_buf.st_dev as u64);
+    if major == 0 {
+        return None;
+    }
+    disk_has_dirty_data(dev)
+}
+
+fn parse_bdi_stats(stats: &[u8]) -> Option<bool> {
+    // Lazy timestamps (`b_dirty_time`) are left to their own writeback
+    const DIRTY: [&[u8]; 5] = [
+        b"BdiWriteback",
+        b"BdiReclaimable",
+        b"b_dirty",
+        b"b_io",
+        b"b_more_io",
+    ];
+    let mut found = false;
+    for line in stats.split(|c| *c == b'\n') {
+        let Some(colon) = line.iter().position(|c| *c == b':') else {
+            continue;
+        };
+        if !DIRTY.contains(&&line[..colon]) {
+            continue;
+        }
+        let value = line[colon + 1..]
+            .split(u8::is_ascii_whitespace)
+            .find(|s| !s.is_empty())?;
+        if crate::utils::parse_integer(value).ok()? != 0 {
+            return Some(true);
+        }
+        found = true;
+    }
+    found.then_some(false)
+}
+
+/// Checks with `kill(pid, 0)` if a process is alive.
+pub fn process_exists(pid: usize) -> bool {
+    match unsafe { nc::kill(pid as nc::pid_t, 0) } {
+        Ok(()) => true,
+        // The process exists, but belongs to another user
+        Err(errno) => errno == nc::EPERM,
+    }
+}
+
+/// Adjusts the score of the current process for the OOM killer. Lowering it
+/// requires `CAP_SYS_RESOURCE`.
+pub fn set_oom_score_adj(adj: i16) -> Result<()> {
+    std::fs::write("/proc/self/oom_score_adj", adj.to_string())
+        .context("Writing /proc/self/oom_score_adj")
+}
+
+/// Sets the command name (`comm`) of the current process, as shown by `top` and
+/// `ps -o comm`. The kernel truncates it to 15 bytes. The command line (argv,
+/// `/proc/<pid>/cmdline`) is left as is. Failures are ignored, the name being
+/// cosmetic.
+pub fn set_process_name(name: &OsStr) {
+    let mut comm = [0u8; 16];
+    let len = name.len().min(comm.len() - 1);
+    comm[..len].copy_from_slice(&name.as_bytes()[..len]);
+    let _ = unsafe { nc::prctl(nc::PR_SET_NAME, comm.as_ptr() as usize, 0, 0, 0) };
+}
+
+/// Time elapsed since boot, including the time spent suspended (unlike
+/// `Instant`).
+pub fn boot_time() -> Duration {
+    let mut ts = nc::timespec_t::default();
+    let _ = unsafe { nc::clock_gettime(nc::CLOCK_BOOTTIME, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Re-issues a system call interrupted by a signal (`EINTR`), eg. a SIGHUP
+/// during a sync.
+fn retry_eintr<T, F>(mut call: F) -> std::result::Result<T, nc::Errno>
+where
+    F: FnMut() -> std::result::Result<T, nc::Errno>,
+{
+    loop {
+        match call() {
+            Err(nc::EINTR) => {}
+            res => return res,
+        }
+    }
+}
+
+/// Closes a file descriptor. Unlike the other system calls, `close` is not
+/// retried on `EINTR`: Linux released the descriptor already, and it may have
+/// been reused meanwhile.
+unsafe fn close(fd: i32) -> std::result::Result<(), nc::Errno> {
+    match unsafe { nc::close(fd) } {
+        Err(nc::EINTR) => Ok(()),
+        res => res,
+    }
 }
 
 /// Bracket style wrapper to safely open a device as a raw fd.
 fn with_dev_fd<F, R>(dev_name: &OsStr, f: F) -> Result<R>
 where
-    F: Fn(i32) -> Result<R>,
+    F: FnOnce(i32) -> Result<R>,
 {
-    const MAX_PATH_LEN: usize = 16;
+    // Enough for the common names, longer ones are built on the heap
+    const STACK_PATH_LEN: usize = 32;
     const PATH_PREFIX: &[u8] = b"/dev/";
-    let path_len = PATH_PREFIX.len() + dev_name.len();
-    // == => no room for '\0'
-    if path_len >= MAX_PATH_LEN {
-        return Err(format!("Device name too long: '{}'", dev_name.to_string_lossy()).into());
+    // Would open /dev/ itself, or another file than the device
+    if dev_name.is_empty()
+        || dev_name
+            .as_bytes()
+            .iter()
+            .any(|c| matches!(c, b'/' | b'\0'))
+    {
+        return Err(format!("Invalid device name: '{}'", dev_name.to_string_lossy()).into());
     }
-    let mut path = [0u8; MAX_PATH_LEN];
+    let path_len = PATH_PREFIX.len() + dev_name.len();
+    let mut stack_path = [0u8; STACK_PATH_LEN];
+    let mut heap_path = Vec::new();
+    // +1 for the '\0'
+    let path = if path_len < STACK_PATH_LEN {
+        &mut stack_path[..]
+    } else {
+        heap_path.resize(path_len + 1, 0);
+        &mut heap_path[..]
+    };
     path[..PATH_PREFIX.len()].copy_from_slice(PATH_PREFIX);
     path[PATH_PREFIX.len()..path_len].copy_from_slice(dev_name.as_bytes());
     path[path_len] = b'\0';
 
     let filename_ptr = path.as_ptr() as usize;
+    // Don't wait for a device which is not ready: the open fails with EAGAIN,
+    // or the commands with ENOMEDIUM or ENXIO (eg. without media)
+    let flags = (nc::O_RDONLY | nc::O_NONBLOCK) as usize;
+    let fd =
+        retry_eintr(|| unsafe { nc::syscalls::syscall3(nc::SYS_OPEN, filename_ptr, flags, 0) })
+            .map(|ret| ret as i32)
+            .with_context(|| {
+                format!(
+                    "Could not open device '{}'",
+                    String::from_utf8_lossy(&path[..path_len])
+                )
+            })?;
+
+    let res = f(fd);
+
+    unsafe { close(fd) }.with_context(|| {
+        format!(
+            "Failed to close '{}'",
+            String::from_utf8_lossy(&path[..path_len]),
+        )
+    })?;
+    res
+}
+
+/// Bracket style wrapper to open a mount point as a raw fd.
+fn with_mount_fd<F, R>(path: &CStr, f: F) -> Result<R>
+where
+    F: FnOnce(i32) -> Result<R>,
+{
+    let path_ptr = path.as_ptr() as usize;
     let flags = nc::O_RDONLY as usize;
-    let fd = unsafe { nc::syscalls::syscall3(nc::SYS_OPEN, filename_ptr, flags, 0) }
+    let fd = retry_eintr(|| unsafe { nc::syscalls::syscall3(nc::SYS_OPEN, path_ptr, flags, 0) })
         .map(|ret| ret as i32)
         .with_context(|| {
             format!(
-                "Could not open device '{}'",
-                String::from_utf8_lossy(&path[..path_len])
+                "Could not open mount point '{}'",
+                String::from_utf8_lossy(path.to_bytes())
             )
         })?;
 
     let res = f(fd);
 
-    unsafe { nc::close(fd) }.with_context(|| {
+    unsafe { close(fd) }.with_context(|| {
         format!(
-            "Failed to close '{}'",
-            String::from_utf8_lossy(&path[..path_len]),
+            "Could not close mount point '{}'",
+            String::from_utf8_lossy(path.to_bytes())
         )
     })?;
     res
 }
 
 pub fn syncfs(path: &CStr) -> Result<()> {
-    let path_ptr = path.as_ptr() as usize;
-    let flags = nc::O_RDONLY as usize;
-    unsafe {
-        let fd = nc::syscalls::syscall3(nc::SYS_OPEN, path_ptr, flags, 0)
-            .map(|ret| ret as i32)
-            .with_context(|| {
-                format!(
-                    "Could not open mount point '{}'",
-                    String::from_utf8_lossy(path.to_bytes())
-                )
-            })?;
-        let res = nc::syncfs(fd).with_context(|| {
+    with_mount_fd(path, |fd| {
+        retry_eintr(|| unsafe { nc::syncfs(fd) }).with_context(|| {
             format!(
                 "Could not sync mount point '{}'",
                 String::from_utf8_lossy(path.to_bytes())
             )
-        });
-        nc::close(fd).with_context(|| {
-            format!(
-                "Could not close mount point '{}'",
-                String::from_utf8_lossy(path.to_bytes())
-            )
-        })?;
-        res
-    }
+        })
+    })
+}
+
+/// `_IOR('X', 126, struct xfs_fsop_geom)`: geometry of an XFS filesystem, v5.
+const XFS_IOC_FSGEOMETRY: u32 = 0x8100_587e;
+/// Size of `struct xfs_fsop_geom` and offset of its `sick` mask.
+const XFS_GEOMETRY_LEN: usize = 256;
+const XFS_GEOMETRY_SICK: usize = 112;
+
+/// Has XFS found corrupt metadata on the filesystem mounted on `path`? Read
+/// from the health mask of its geometry, reported since Linux 5.2: `false` on
+/// older kernels.
+pub fn xfs_sick(path: &CStr) -> Result<bool> {
+    let mut geometry = [0u8; XFS_GEOMETRY_LEN];
+    with_mount_fd(path, |fd| {
+        let geometry_ptr = geometry.as_mut_ptr() as *const c_void;
+        match retry_eintr(|| unsafe { nc::ioctl(fd, XFS_IOC_FSGEOMETRY, geometry_ptr) }) {
+            Ok(_) => {}
+            Err(nc::ENOTTY | nc::EINVAL) => return Ok(false),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Could not read the XFS geometry of '{}'",
+                        String::from_utf8_lossy(path.to_bytes())
+                    )
+                });
+            }
+        }
+        let sick = &geometry[XFS_GEOMETRY_SICK..XFS_GEOMETRY_SICK + 4];
+        Ok(sick.iter().any(|b| *b != 0))
+    })
+}
+
+/// Commits the buffers of all filesystems to disk.
+pub fn sync() -> Result<()> {
+    unsafe { nc::sync() }.context("sync()")
 }
 
 const BLKFLSBUF: u32 = nc::IO(0x12, 97);
 
 pub fn sync_blockdev(dev: &OsStr) -> Result<i32> {
     with_dev_fd(dev, |fd| {
-        unsafe { nc::ioctl(fd, BLKFLSBUF, std::ptr::null()) }
+        retry_eintr(|| unsafe { nc::ioctl(fd, BLKFLSBUF, std::ptr::null()) })
             .with_context(|| format!("Could not sync block device '{}'", dev.to_string_lossy()))
     })
 }
 
-/// Issue SCSI command to spin down a disk.
-//TODO: implement for ATA/USB devices.
-pub fn spindown_disk(dev: &OsStr) -> Result<()> {
-    /// Pulled from `/usr/include/scsi/sg.h`, comments are GNU 2.1 licensed,
-    /// Copyright (C) 1997-2022 Free Software Foundation, Inc.
-    #[repr(C)]
-    struct sg_io_hdr {
-        i32erface_id: i32,      /* [i] 'S' for SCSI generic (required) */
-        dxfer_direction: i32,   /* [i] data transfer direction  */
-        cmd_len: u8,            /* [i] SCSI command length ( <= 16 bytes) */
-        mx_sb_len: u8,          /* [i] max length to write to sbp */
-        iovec_count: u16,       /* [i] 0 implies no scatter gather */
-        dxfer_len: u32,         /* [i] byte count of data transfer */
-        dxferp: *mut c_void, /* [i], [*io] points to data transfer memory or scatter gather list */
-        cmdp: *const u8,     /* [i], [*i] points to command to perform */
-        sbp: *mut u8,        /* [i], [*o] ponts to sense_buffer memory */
-        timeout: u32,        /* [i] MAX_UINT->no timeout (unit: millisec) */
-        flags: u32,          /* [i] 0 -> default, see SG_FLAG... */
-        pack_id: i32,        /* [i->o] unused internally (normally) */
-        usr_ptr: *const c_void, /* [i->o] unused internally */
-        status: u8,          /* [o] scsi status */
-        masked_status: u8,   /* [o] shifted, masked scsi status */
-        msg_status: u8,      /* [o] messaging level data (optional) */
-        sb_len_wr: u8,       /* [o] byte count actually written to sbp */
-        host_status: u16,    /* [o] errors from host adapter */
-        driver_status: u16,  /* [o] errors from software driver */
-        resid: i32,          /* [o] dxfer_len - actual_transferred */
-        duration: u32,       /* [o] time taken by cmd (unit: millisec) */
-        info: u32,           /* [o] auxiliary information */
-    }
-
-    const SCSI_STOP_CMD: &[u8] = b"\x1b\x00\x00\x00\x00\x00";
-    const SG_DXFER_NONE: i32 = -1;
+/// Pulled from `/usr/include/scsi/sg.h`, comments are GNU 2.1 licensed,
+/// Copyright (C) 1997-2022 Free Software Foundation, Inc.
+#[repr(C)]
+struct sg_io_hdr {
+    i32erface_id: i32,      /* [i] 'S' for SCSI generic (required) */
+    dxfer_direction: i32,   /* [i] data transfer direction  */
+    cmd_len: u8,            /* [i] SCSI command length ( <= 16 bytes) */
+    mx_sb_len: u8,          /* [i] max length to write to sbp */
+    iovec_count: u16,       /* [i] 0 implies no scatter gather */
+    dxfer_len: u32,         /* [i] byte count of data transfer */
+    dxferp: *mut c_void,    /* [i], [*io] points to data transfer memory or scatter gather list */
+    cmdp: *const u8,        /* [i], [*i] points to command to perform */
+    sbp: *mut u8,           /* [i], [*o] ponts to sense_buffer memory */
+    timeout: u32,           /* [i] MAX_UINT->no timeout (unit: millisec) */
+    flags: u32,             /* [i] 0 -> default, see SG_FLAG... */
+    pack_id: i32,           /* [i->o] unused internally (normally) */
+    usr_ptr: *const c_void, /* [i->o] unused internally */
+    status: u8,             /* [o] scsi status */
+    masked_status: u8,      /* [o] shifted, masked scsi status */
+    msg_status: u8,         /* [o] messaging level data (optional) */
+    sb_len_wr: u8,          /* [o] byte count actually written to sbp */
+    host_status: u16,       /* [o] errors from host adapter */
+    driver_status: u16,     /* [o] errors from software driver */
+    resid: i32,             /* [o] dxfer_len - actual_transferred */
+    duration: u32,          /* [o] time taken by cmd (unit: millisec) */
+    info: u32,              /* [o] auxiliary information */
+}
+
+/// Formats bytes as space separated hexadecimal pairs.
+struct HexBytes<'a>(&'a [u8]);
+
+impl fmt::Display for HexBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = "";
+        for byte in self.0 {
+            write!(f, "{}{:02x}", sep, byte)?;
+            sep = " ";
+        }
+        Ok(())
+    }
+}
+
+const CHECK_CONDITION: u8 = 0x01;
+
+/// Outcome of a SCSI command.
+struct SgStatus {
+    masked_status: u8,
+    /// # of bytes written to the sense buffer.
+    sense_len: usize,
+    duration: Duration,
+}
+
+/// Sends a SCSI command to an opened device, reading its response into `data`
+/// (no data transfer if empty). Returns the time it took as reported by the
+/// kernel.
+///
+/// With `verbosity >= 3`, the command bytes and the returned status are logged.
+fn sg_io(fd: i32, dev: &OsStr, cmd: &[u8], data: &mut [u8], verbosity: u8) -> Result<Duration> {
+    let mut sense = [0u8; 255];
+    let status = sg_io_sense(fd, dev, cmd, data, &mut sense, verbosity)?;
+    let sense = &sense[..status.sense_len];
+    if status.masked_status == 0 {
+        Ok(status.duration)
+    } else {
+        Err(status_error(status.masked_status, sense))
+    }
+}
+
+fn status_error(masked_status: u8, sense: &[u8]) -> Error {
+    if masked_status == CHECK_CONDITION {
+        format!(
+            "SCSI command failed with CHECK_CONDITION: {} (sense data [{}])",
+            SenseText(sense),
+            HexBytes(sense)
+        )
+        .into()
+    } else {
+        format!("SCSI command failed with status {:#04x}", masked_status).into()
+    }
+}
+
+const SG_DXFER_NONE: i32 = -1;
+const SG_DXFER_TO_DEV: i32 = -2;
+const SG_DXFER_FROM_DEV: i32 = -3;
+
+/// Like `sg_io()`, but leaves the SCSI status and the sense data to the caller.
+fn sg_io_sense(
+    fd: i32,
+    dev: &OsStr,
+    cmd: &[u8],
+    data: &mut [u8],
+    sens_buf: &mut [u8],
+    verbosity: u8,
+) -> Result<SgStatus> {
+    let direction = if data.is_empty() {
+        SG_DXFER_NONE
+    } else {
+        SG_DXFER_FROM_DEV
+    };
+    sg_io_transfer(fd, dev, cmd, direction, data, sens_buf, verbosity)
+}
+
+/// Issue a SCSI command sending `data` to the device, returns the time it took.
+fn sg_io_write(fd: i32, dev: &OsStr, cmd: &[u8], data: &[u8], verbosity: u8) -> Result<Duration> {
+    let mut data = data.to_vec();
+    let mut sense = [0u8; 255];
+    let direction = SG_DXFER_TO_DEV;
+    let status = sg_io_transfer(fd, dev, cmd, direction, &mut data, &mut sense, verbosity)?;
+    match status.masked_status {
+        0 => Ok(status.duration),
+        masked_status => Err(status_error(masked_status, &sense[..status.sense_len])),
+    }
+}
+
+fn sg_io_transfer(
+    fd: i32,
+    dev: &OsStr,
+    cmd: &[u8],
+    direction: i32,
+    data: &mut [u8],
+    sens_buf: &mut [u8],
+    verbosity: u8,
+) -> Result<SgStatus> {
     const SG_IO: u32 = 0x2285;
-    const CHECK_CONDITION: u8 = 0x01;
 
+    if verbosity >= 3 {
+        crate::logln!(
+            "<7>SG_IO {}: command [{}]",
+            dev.to_string_lossy(),
+            HexBytes(cmd)
+        );
+    }
+
+    let mut hdr = sg_io_hdr {
+        i32erface_id: 'S' as i32,
+        dxfer_direction: direction,
+        cmd_len: cmd.len() as u8,
+        mx_sb_len: sens_buf.len().min(255) as u8,
+        iovec_count: 0,
+        dxfer_len: data.len() as u32,
+        dxferp: data.as_mut_ptr() as *mut c_void,
+        cmdp: cmd.as_ptr(),
+        sbp: sens_buf.as_mut_ptr(),
+        timeout: 0,
+        flags: 0,
+        pack_id: 0,
+        usr_ptr: std::ptr::null(),
+        status: 0,
+        masked_status: 0,
+        msg_status: 0,
+        sb_len_wr: 0,
+        host_status: 0,
+        driver_status: 0,
+        resid: 0,
+        duration: 0,
+        info: 0,
+    };
+    let hdr_ptr = std::ptr::addr_of_mut!(hdr) as *const c_void;
+    retry_eintr(|| unsafe { nc::ioctl(fd, SG_IO, hdr_ptr) })
+        .context("Could not send SCSI command")?;
+    let sense = &sens_buf[..hdr.sb_len_wr as usize];
+
+    if verbosity >= 3 {
+        crate::logln!(
+            "<7>SG_IO {}: status {:#04x}, host status {:#06x}, driver status {:#06x}, sense [{}], \
+             duration {}ms",
+            dev.to_string_lossy(),
+            hdr.masked_status,
+            hdr.host_status,
+            hdr.driver_status,
+            HexBytes(sense),
+            hdr.duration
+        );
+    }
+
+    Ok(SgStatus {
+        masked_status: hdr.masked_status,
+        sense_len: sense.len(),
+        duration: Duration::from_millis(hdr.duration.into()),
+    })
+}
+
+const SCSI_START_CMD: &[u8] = b"\x1b\x00\x00\x00\x01\x00";
+// ATA PASS-THROUGH(16), non-data protocol, STANDBY IMMEDIATE (0xe0)
+const ATA_STANDBY_IMMEDIATE_CMD: &[u8] =
+    b"\x85\x06\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xe0\x00";
+
+/// How a disk is spun down and up, and how its power state is queried. Each
+/// disk gets its own, chosen by [`select_backend`] when it is discovered. The
+/// disks going idle together are spun down from several threads.
+pub trait SpindownBackend: Send + Sync {
+    /// Spins down the disk, returns the time it took.
+    fn spindown(&self, dev: &OsStr) -> Result<Duration>;
+    /// Spins up the disk, returns the time it took.
+    fn spinup(&self, dev: &OsStr) -> Result<Duration>;
+    /// Queries the power state of the disk, without waking it up.
+    fn power_state(&self, dev: &OsStr) -> Result<PowerMode>;
+}
+
+/// Chooses the backend of a disk: [`NvmeBackend`] for NVMe namespaces,
+/// [`AtaBackend`] for ATA disks (behind libata or a USB bridge) and
+/// [`ScsiBackend`] for the other ones, unless they don't answer an INQUIRY
+/// but support runtime power management ([`SysfsBackend`]).
+///
+/// The backends follow the `nvme_power_state` and `power_condition` of the
+/// configuration of the disk.
+pub fn select_backend(dev: &OsStr, config: &DeviceConfig) -> Box<dyn SpindownBackend> {
+    let verbosity = config.verbosity;
+    let power_condition = config.power_condition;
+    if is_nvme(dev) {
+        let nvme_power_state = config.nvme_power_state;
+        return Box::new(NvmeBackend {
+            power_state: (nvme_power_state != 0).then_some(nvme_power_state),
+            verbosity,
+        });
+    }
+    if is_usb(dev) {
+        return Box::new(AtaBackend {
+            power_condition,
+            verbosity,
+        });
+    }
+    match inquiry(dev, verbosity) {
+        Ok(id) if id.is_ata() => Box::new(AtaBackend {
+            power_condition,
+            verbosity,
+        }),
+        Err(_) if SysfsBackend::is_supported(dev) => Box::new(SysfsBackend),
+        _ => Box::new(ScsiBackend {
+            power_condition,
+            verbosity,
+        }),
+    }
+}
+
+/// The highest power condition of START STOP UNIT (FORCE_STANDBY_0).
+pub const MAX_POWER_CONDITION: u8 = 0x0b;
+
+/// Builds a START STOP UNIT command stopping the disk, or moving it to a power
+/// condition (SBC: 2 is IDLE, 3 STANDBY, 0xa and 0xb force them with their
+/// timer reset).
+fn stop_cmd(power_condition: u8) -> [u8; 6] {
+    [0x1b, 0, 0, 0, (power_condition & 0x0f) << 4, 0]
+}
+
+/// SCSI START STOP UNIT, falling back on ATA STANDBY IMMEDIATE if the disk
+/// rejects it.
+pub struct ScsiBackend {
+    /// Power condition field of the command, 0 for a plain STOP.
+    pub power_condition: u8,
+    pub verbosity: u8,
+}
+
+impl SpindownBackend for ScsiBackend {
+    fn spindown(&self, dev: &OsStr) -> Result<Duration> {
+        let stop = stop_cmd(self.power_condition);
+        let commands = [
+            ("SCSI STOP", &stop[..]),
+            ("ATA STANDBY IMMEDIATE", ATA_STANDBY_IMMEDIATE_CMD),
+        ];
+        stop_with(dev, commands, self.verbosity)
+    }
+
+    fn spinup(&self, dev: &OsStr) -> Result<Duration> {
+        scsi_start(dev, self.verbosity)
+    }
+
+    fn power_state(&self, dev: &OsStr) -> Result<PowerMode> {
+        check_power_mode(dev, self.verbosity)
+    }
+}
+
+/// ATA STANDBY IMMEDIATE through ATA PASS-THROUGH(16), falling back on a SCSI
+/// STOP if the SCSI/ATA translation layer rejects it.
+pub struct AtaBackend {
+    /// Power condition field of the fallback STOP.
+    pub power_condition: u8,
+    pub verbosity: u8,
+}
+
+impl SpindownBackend for AtaBackend {
+    fn spindown(&self, dev: &OsStr) -> Result<Duration> {
+        let stop = stop_cmd(self.power_condition);
+        let commands = [
+            ("ATA STANDBY IMMEDIATE", ATA_STANDBY_IMMEDIATE_CMD),
+            ("SCSI STOP", &stop[..]),
+        ];
+        stop_with(dev, commands, self.verbosity)
+    }
+
+    // The translation layer turns a SCSI START into a media access
+    fn spinup(&self, dev: &OsStr) -> Result<Duration> {
+        scsi_start(dev, self.verbosity)
+    }
+
+    fn power_state(&self, dev: &OsStr) -> Result<PowerMode> {
+        check_power_mode(dev, self.verbosity)
+    }
+}
+
+/// Power states of an NVMe controller: spinning down moves it to
+/// `power_state` (`None`: the deepest one), spinning up to the state 0.
+pub struct NvmeBackend {
+    pub power_state: Option<u8>,
+    pub verbosity: u8,
+}
+
+impl SpindownBackend for NvmeBackend {
+    fn spindown(&self, dev: &OsStr) -> Result<Duration> {
+        nvme_set_power_state(dev, self.power_state, self.verbosity)
+    }
+
+    fn spinup(&self, dev: &OsStr) -> Result<Duration> {
+        nvme_set_power_state(dev, Some(0), self.verbosity)
+    }
+
+    fn power_state(&self, dev: &OsStr) -> Result<PowerMode> {
+        nvme_power_mode(dev, self.verbosity)
+    }
+}
+
+/// Runtime power management of the kernel, through `device/power/` in sysfs:
+/// the driver suspends the disk as soon as it has no request in flight.
+pub struct SysfsBackend;
+
+impl SysfsBackend {
+    fn power_dir(dev: &OsStr) -> PathBuf {
+        Path::new("/sys/class/block").join(dev).join("device/power")
+    }
+
+    fn is_supported(dev: &OsStr) -> bool {
+        Self::power_dir(dev).join("control").exists()
+    }
+
+    fn write(dev: &OsStr, attribute: &str, value: &str) -> Result<()> {
+        let path = Self::power_dir(dev).join(attribute);
+        std::fs::write(&path, value)
+            .with_context(|| format!("Writing '{}' to {}", value, path.display()))
+    }
+}
+
+impl SpindownBackend for SysfsBackend {
+    fn spindown(&self, dev: &OsStr) -> Result<Duration> {
+        let start = Instant::now();
+        Self::write(dev, "autosuspend_delay_ms", "0")?;
+        Self::write(dev, "control", "auto")?;
+        Ok(start.elapsed())
+    }
+
+    fn spinup(&self, dev: &OsStr) -> Result<Duration> {
+        let start = Instant::now();
+        Self::write(dev, "control", "on")?;
+        Ok(start.elapsed())
+    }
+
+    fn power_state(&self, dev: &OsStr) -> Result<PowerMode> {
+        let path = Self::power_dir(dev).join("runtime_status");
+        let status = std::fs::read_to_string(&path)
+            .with_context(|| format!("Reading {}", path.display()))?;
+        parse_runtime_status(&status)
+    }
+}
+
+fn parse_runtime_status(status: &str) -> Result<PowerMode> {
+    match status.trim_end() {
+        "suspended" => Ok(PowerMode::Standby),
+        "suspending" | "resuming" => Ok(PowerMode::Idle),
+        "active" => Ok(PowerMode::Active),
+        status => Err(format!("Unknown runtime power status '{}'", status).into()),
+    }
+}
+
+fn scsi_start(dev: &OsStr, verbosity: u8) -> Result<Duration> {
+    with_dev_fd(dev, |fd| sg_io(fd, dev, SCSI_START_CMD, &mut [], verbosity))
+}
+
+/// Sends the first spin down command that the disk doesn't reject as invalid,
+/// returns the time it took.
+fn stop_with(dev: &OsStr, commands: [(&str, &[u8]); 2], verbosity: u8) -> Result<Duration> {
     with_dev_fd(dev, |fd| {
-        let mut sens_buf = [0u8; 255];
-        let mut hdr = sg_io_hdr {
-            i32erface_id: 'S' as i32,
-            dxfer_direction: SG_DXFER_NONE,
-            cmd_len: SCSI_STOP_CMD.len() as u8,
-            mx_sb_len: sens_buf.len() as u8,
-            iovec_count: 0,
-            dxfer_len: 0,
-            dxferp: std::ptr::null_mut(),
-            cmdp: SCSI_STOP_CMD.as_ptr(),
-            sbp: sens_buf.as_mut_ptr(),
-            timeout: 0,
-            flags: 0,
-            pack_id: 0,
-            usr_ptr: std::ptr::null(),
-            status: 0,
-            masked_status: 0,
-            msg_status: 0,
-            sb_len_wr: 0,
-            host_status: 0,
-            driver_status: 0,
-            resid: 0,
-            duration: 0,
-            info: 0,
+        for (name, cmd) in commands {
+            let mut sense = [0u8; 64];
+            let status = sg_io_sense(fd, dev, cmd, &mut [], &mut sense, verbosity)?;
+            let sense = &sense[..status.sense_len];
+            match status.masked_status {
+                0 => {
+                    if verbosity >= 3 {
+                        crate::logln!("<7>{} spun down with {}", dev.to_string_lossy(), name);
+                    }
+                    return Ok(status.duration);
+                }
+                CHECK_CONDITION if is_invalid_command(sense) => {
+                    if verbosity >= 3 {
+                        crate::logln!("<7>{} rejected {}", dev.to_string_lossy(), name);
+                    }
+                }
+                masked_status => return Err(status_error(masked_status, sense)),
+            }
+        }
+        Err("Both SCSI STOP and ATA STANDBY IMMEDIATE were rejected".into())
+    })
+}
+
+/// Is the disk behind a USB bridge? Read from its device path in sysfs.
+pub fn is_usb(dev: &OsStr) -> bool {
+    std::fs::canonicalize(std::path::Path::new("/sys/class/block").join(dev))
+        .is_ok_and(|path| is_usb_path(&path))
+}
+
+fn is_usb_path(path: &std::path::Path) -> bool {
+    path.components()
+        .any(|c| c.as_os_str().as_bytes().starts_with(b"usb"))
+}
+
+/// Does the sense data reject the command as unsupported (ILLEGAL REQUEST,
+/// invalid command operation code or invalid field in the CDB)?
+fn is_invalid_command(sense: &[u8]) -> bool {
+    const ILLEGAL_REQUEST: u8 = 0x05;
+    matches!(sense_codes(sense), Some((ILLEGAL_REQUEST, 0x20 | 0x24, _)))
+}
+
+/// Formats the sense key, additional sense code and qualifier of sense data,
+/// eg. `NOT READY, LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED`.
+struct SenseText<'a>(&'a [u8]);
+
+impl fmt::Display for SenseText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const SENSE_KEYS: [&str; 16] = [
+            "NO SENSE",
+            "RECOVERED ERROR",
+            "NOT READY",
+            "MEDIUM ERROR",
+            "HARDWARE ERROR",
+            "ILLEGAL REQUEST",
+            "UNIT ATTENTION",
+            "DATA PROTECT",
+            "BLANK CHECK",
+            "VENDOR SPECIFIC",
+            "COPY ABORTED",
+            "ABORTED COMMAND",
+            "RESERVED",
+            "VOLUME OVERFLOW",
+            "MISCOMPARE",
+            "COMPLETED",
+        ];
+        let Some((key, asc, ascq)) = sense_codes(self.0) else {
+            return f.write_str("unknown sense data format");
         };
-        unsafe { nc::ioctl(fd, SG_IO, std::ptr::addr_of_mut!(hdr) as *const c_void) }
-            .context("Could not send SCSI command")?;
-        if hdr.masked_status == 0 {
-            Ok(())
-        } else {
-            Err(if hdr.masked_status == CHECK_CONDITION {
-                format!(
-                    "SCSI command failed with CHECK_CONDITION, sense_buf: {:?}",
-                    &sens_buf[..hdr.sb_len_wr as usize]
-                )
-                .into()
-            } else {
-                format!("SCSI command failed with status {:#04x}", hdr.masked_status).into()
-            })
+        f.write_str(SENSE_KEYS[usize::from(key)])?;
+        match additional_sense(asc, ascq) {
+            Some(text) => write!(f, ", {}", text),
+            None => write!(f, ", ASC {:#04x} ASCQ {:#04x}", asc, ascq),
+        }
+    }
+}
+
+/// Describes the most common additional sense codes and qualifiers.
+fn additional_sense(asc: u8, ascq: u8) -> Option<&'static str> {
+    Some(match (asc, ascq) {
+        (0x00, 0x00) => "NO ADDITIONAL SENSE INFORMATION",
+        (0x00, 0x1d) => "ATA PASS THROUGH INFORMATION AVAILABLE",
+        (0x04, 0x00) => "LOGICAL UNIT NOT READY, CAUSE NOT REPORTABLE",
+        (0x04, 0x01) => "LOGICAL UNIT IS IN PROCESS OF BECOMING READY",
+        (0x04, 0x02) => "LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED",
+        (0x04, 0x03) => "LOGICAL UNIT NOT READY, MANUAL INTERVENTION REQUIRED",
+        (0x04, 0x09) => "LOGICAL UNIT NOT READY, SELF-TEST IN PROGRESS",
+        (0x04, 0x11) => "LOGICAL UNIT NOT READY, NOTIFY (ENABLE SPINUP) REQUIRED",
+        (0x04, 0x1a) => "LOGICAL UNIT NOT READY, START STOP UNIT COMMAND IN PROGRESS",
+        (0x04, 0x22) => "LOGICAL UNIT NOT READY, POWER CYCLE REQUIRED",
+        (0x0b, 0x01) => "WARNING - SPECIFIED TEMPERATURE EXCEEDED",
+        (0x11, 0x00) => "UNRECOVERED READ ERROR",
+        (0x1a, 0x00) => "PARAMETER LIST LENGTH ERROR",
+        (0x20, 0x00) => "INVALID COMMAND OPERATION CODE",
+        (0x24, 0x00) => "INVALID FIELD IN CDB",
+        (0x25, 0x00) => "LOGICAL UNIT NOT SUPPORTED",
+        (0x26, 0x00) => "INVALID FIELD IN PARAMETER LIST",
+        (0x29, 0x00) => "POWER ON, RESET, OR BUS DEVICE RESET OCCURRED",
+        (0x2a, 0x01) => "MODE PARAMETERS CHANGED",
+        (0x3a, 0x00) => "MEDIUM NOT PRESENT",
+        (0x44, 0x00) => "INTERNAL TARGET FAILURE",
+        (0x5d, _) => "FAILURE PREDICTION THRESHOLD EXCEEDED",
+        (0x5e, 0x00) => "LOW POWER CONDITION ON",
+        (0x5e, 0x01 | 0x05 | 0x07) => "IDLE CONDITION ACTIVATED BY TIMER",
+        (0x5e, 0x02 | 0x09) => "STANDBY CONDITION ACTIVATED BY TIMER",
+        (0x5e, 0x03 | 0x06 | 0x08) => "IDLE CONDITION ACTIVATED BY COMMAND",
+        (0x5e, 0x04 | 0x0a) => "STANDBY CONDITION ACTIVATED BY COMMAND",
+        _ => return None,
+    })
+}
+
+/// Extracts the sense key, additional sense code and qualifier of sense data,
+/// in fixed or descriptor format.
+fn sense_codes(sense: &[u8]) -> Option<(u8, u8, u8)> {
+    let (key, asc, ascq) = match sense.first()? & 0x7f {
+        0x70 | 0x71 => (2, 12, 13),
+        0x72 | 0x73 => (1, 2, 3),
+        _ => return None,
+    };
+    Some((sense.get(key)? & 0x0f, *sense.get(asc)?, *sense.get(ascq)?))
+}
+
+/// `struct nvme_passthru_cmd` of `linux/nvme_ioctl.h`.
+#[repr(C)]
+#[derive(Default)]
+struct NvmePassthruCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/// Feature identifier of the power state, for SET/GET FEATURES.
+const NVME_POWER_MANAGEMENT: u32 = 0x02;
+
+/// Sends an NVMe admin command, returns the time it took.
+fn nvme_admin(fd: i32, dev: &OsStr, cmd: &mut NvmePassthruCmd, verbosity: u8) -> Result<Duration> {
+    // _IOWR('N', 0x41, struct nvme_passthru_cmd)
+    const NVME_IOCTL_ADMIN_CMD: u32 = 0xc048_4e41;
+    if verbosity >= 3 {
+        crate::logln!(
+            "<7>NVMe admin {}: opcode {:#04x}, cdw10 {:#x}, cdw11 {:#x}",
+            dev.to_string_lossy(),
+            cmd.opcode,
+            cmd.cdw10,
+            cmd.cdw11
+        );
+    }
+    let start = Instant::now();
+    let cmd_ptr = std::ptr::addr_of_mut!(*cmd) as *const c_void;
+    let status = retry_eintr(|| unsafe { nc::ioctl(fd, NVME_IOCTL_ADMIN_CMD, cmd_ptr) })
+        .context("Could not send NVMe admin command")?;
+    // Positive values are NVMe status codes
+    if status != 0 {
+        return Err(format!("NVMe admin command failed with status {:#06x}", status).into());
+    }
+    Ok(start.elapsed())
+}
+
+/// Returns the number of the deepest power state of the NVMe controller (NPSS),
+/// from its IDENTIFY data.
+fn nvme_deepest_power_state(fd: i32, dev: &OsStr, verbosity: u8) -> Result<u8> {
+    const IDENTIFY: u8 = 0x06;
+    const CNS_CONTROLLER: u32 = 0x01;
+    const NPSS_OFFSET: usize = 263;
+    let mut data = vec![0u8; 4096];
+    let mut cmd = NvmePassthruCmd {
+        opcode: IDENTIFY,
+        addr: data.as_mut_ptr() as u64,
+        data_len: data.len() as u32,
+        cdw10: CNS_CONTROLLER,
+        ..Default::default()
+    };
+    nvme_admin(fd, dev, &mut cmd, verbosity).context("Identifying the NVMe controller")?;
+    Ok(data[NPSS_OFFSET])
+}
+
+/// Moves an NVMe namespace to a power state of its controller (`None`: the
+/// deepest), with SET FEATURES (Power Management). Returns the time it took.
+pub fn nvme_set_power_state(
+    dev: &OsStr,
+    power_state: Option<u8>,
+    verbosity: u8,
+) -> Result<Duration> {
+    const SET_FEATURES: u8 = 0x09;
+    with_dev_fd(dev, |fd| {
+        let deepest = nvme_deepest_power_state(fd, dev, verbosity)?;
+        let power_state = check_power_state(power_state, deepest)
+            .with_context(|| format!("Invalid power state for {}", dev.to_string_lossy()))?;
+        let mut cmd = NvmePassthruCmd {
+            opcode: SET_FEATURES,
+            cdw10: NVME_POWER_MANAGEMENT,
+            cdw11: u32::from(power_state),
+            ..Default::default()
+        };
+        let duration = nvme_admin(fd, dev, &mut cmd, verbosity)?;
+        if verbosity >= 3 {
+            crate::logln!(
+                "<7>{} moved to the NVMe power state {}",
+                dev.to_string_lossy(),
+                power_state
+            );
+        }
+        Ok(duration)
+    })
+}
+
+/// Queries the current power state of an NVMe controller, with GET FEATURES
+/// (Power Management).
+fn nvme_power_mode(dev: &OsStr, verbosity: u8) -> Result<PowerMode> {
+    const GET_FEATURES: u8 = 0x0a;
+    with_dev_fd(dev, |fd| {
+        let deepest = nvme_deepest_power_state(fd, dev, verbosity)?;
+        let mut cmd = NvmePassthruCmd {
+            opcode: GET_FEATURES,
+            cdw10: NVME_POWER_MANAGEMENT,
+            ..Default::default()
+        };
+        nvme_admin(fd, dev, &mut cmd, verbosity)?;
+        Ok(classify_power_state(cmd.result & 0x1f, deepest))
+    })
+}
+
+/// The state 0 is the full power one, the deepest one stands for standby.
+fn classify_power_state(power_state: u32, deepest: u8) -> PowerMode {
+    match power_state {
+        0 => PowerMode::Active,
+        ps if ps >= u32::from(deepest) => PowerMode::Standby,
+        _ => PowerMode::Idle,
+    }
+}
+
+/// Validates a power state against the deepest one of the controller.
+fn check_power_state(power_state: Option<u8>, deepest: u8) -> Result<u8> {
+    match power_state {
+        None => Ok(deepest),
+        Some(ps) if ps <= deepest => Ok(ps),
+        Some(ps) => Err(format!(
+            "power state {} out of range, the controller supports 0 to {}",
+            ps, deepest
+        )
+        .into()),
+    }
+}
+
+/// Power mode of a disk, as reported by the ATA CHECK POWER MODE command.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerMode {
+    /// Stopped until it is started again (SCSI) or reset (ATA sleep).
+    Sleeping,
+    Standby,
+    Idle,
+    Active,
+}
+
+impl fmt::Display for PowerMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PowerMode::Sleeping => "sleeping",
+            PowerMode::Standby => "standby",
+            PowerMode::Idle => "idle",
+            PowerMode::Active => "active/idle",
+        })
+    }
+}
+
+/// Queries the power mode of an ATA disk behind a SCSI/ATA translation layer,
+/// without waking it up.
+pub fn power_mode(dev: &OsStr, verbosity: u8) -> Result<PowerMode> {
+    // ATA PASS-THROUGH(12), non-data protocol, returning the ATA registers
+    // (CK_COND), of CHECK POWER MODE (0xe5)
+    const ATA_CHECK_POWER_MODE_CMD: &[u8] = b"\xa1\x06\x20\x00\x00\x00\x00\x00\x00\xe5\x00\x00";
+    let mut sense = [0u8; 64];
+    let status = with_dev_fd(dev, |fd| {
+        sg_io_sense(
+            fd,
+            dev,
+            ATA_CHECK_POWER_MODE_CMD,
+            &mut [],
+            &mut sense,
+            verbosity,
+        )
+    })?;
+    let sense = &sense[..status.sense_len];
+    if status.masked_status != CHECK_CONDITION || is_invalid_command(sense) {
+        return Err("No ATA registers returned, not an ATA disk?".into());
+    }
+    match parse_ata_count(sense) {
+        Some(0x00) => Ok(PowerMode::Standby),
+        Some(0x80..=0x83) => Ok(PowerMode::Idle),
+        Some(0xff) => Ok(PowerMode::Active),
+        Some(count) => Err(format!("Unknown power mode {:#04x}", count).into()),
+        None => Err("No ATA registers in the sense data".into()),
+    }
+}
+
+/// Queries the power mode of a disk without waking it up: with the ATA CHECK
+/// POWER MODE command, or the power condition reported by a SCSI REQUEST
+/// SENSE if the disk isn't an ATA one.
+pub fn check_power_mode(dev: &OsStr, verbosity: u8) -> Result<PowerMode> {
+    power_mode(dev, verbosity).or_else(|_| {
+        const SCSI_REQUEST_SENSE_CMD: &[u8] = b"\x03\x00\x00\x00\xfc\x00";
+        let mut data = [0u8; 252];
+        with_dev_fd(dev, |fd| {
+            sg_io(fd, dev, SCSI_REQUEST_SENSE_CMD, &mut data, verbosity)
+        })?;
+        parse_power_condition(&data).ok_or_else(|| {
+            let sense = HexBytes(&data[..18]);
+            format!("No power condition in the sense data: {}", sense).into()
+        })
+    })
+}
+
+/// Decodes the power condition of the sense data returned by REQUEST SENSE.
+fn parse_power_condition(sense: &[u8]) -> Option<PowerMode> {
+    const NO_SENSE: u8 = 0x00;
+    const NOT_READY: u8 = 0x02;
+    match sense_codes(sense)? {
+        (NO_SENSE, 0x00, 0x00) => Some(PowerMode::Active),
+        // Low power condition on, by timer or command
+        (NO_SENSE, 0x5e, 0x00..=0x01 | 0x03 | 0x05..=0x08) => Some(PowerMode::Idle),
+        (NO_SENSE, 0x5e, 0x02 | 0x04 | 0x09 | 0x0a) => Some(PowerMode::Standby),
+        // Logical unit not ready, initializing command required
+        (NOT_READY, 0x04, 0x02) => Some(PowerMode::Sleeping),
+        _ => None,
+    }
+}
+
+/// Extracts the ATA count register from the sense data of an ATA
+/// PASS-THROUGH command, in descriptor or fixed format.
+fn parse_ata_count(sense: &[u8]) -> Option<u8> {
+    const ATA_STATUS_RETURN_DESCRIPTOR: u8 = 0x09;
+    match sense.first()? & 0x7f {
+        0x72 | 0x73 => {
+            let mut descriptors = sense.get(8..)?;
+            while let [code, len, ..] = *descriptors {
+                let (descriptor, rest) = descriptors.split_at_checked(2 + usize::from(len))?;
+                if code == ATA_STATUS_RETURN_DESCRIPTOR {
+                    return descriptor.get(5).copied();
+                }
+                descriptors = rest;
+            }
+            None
         }
+        // The information field holds the error, status, device and count
+        0x70 | 0x71 => sense.get(6).copied(),
+        _ => None,
+    }
+}
+
+/// Identification of a SCSI device, from the standard INQUIRY data.
+pub struct Inquiry {
+    pub vendor: String,
+    pub product: String,
+    pub revision: String,
+}
+
+impl Inquiry {
+    /// Is it an ATA disk, behind a SCSI/ATA translation layer (SAT)?
+    pub fn is_ata(&self) -> bool {
+        self.vendor == "ATA"
+    }
+}
+
+/// Issue a SCSI INQUIRY to identify a disk.
+pub fn inquiry(dev: &OsStr, verbosity: u8) -> Result<Inquiry> {
+    with_dev_fd(dev, |fd| read_inquiry(fd, dev, verbosity))
+}
+
+fn read_inquiry(fd: i32, dev: &OsStr, verbosity: u8) -> Result<Inquiry> {
+    const SCSI_INQUIRY_CMD: &[u8] = b"\x12\x00\x00\x00\x24\x00";
+    let mut data = [0u8; 36];
+    sg_io(fd, dev, SCSI_INQUIRY_CMD, &mut data, verbosity)?;
+    let field = |range: std::ops::Range<usize>| {
+        String::from_utf8_lossy(data[range].trim_ascii()).into_owned()
+    };
+    Ok(Inquiry {
+        vendor: field(8..16),
+        product: field(16..32),
+        revision: field(32..36),
     })
 }
+
+/// Sets the Advanced Power Management level of an ATA disk, with SET FEATURES:
+/// from 1 (most power saving) to 254, the levels below 128 letting the drive
+/// spin down by itself. 255 disables APM.
+pub fn set_apm(dev: &OsStr, level: u8, verbosity: u8) -> Result<()> {
+    let (feature, count) = apm_feature(level)?;
+    // ATA PASS-THROUGH(16), non-data protocol, SET FEATURES (0xef)
+    let mut cmd = *b"\x85\x06\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xef\x00";
+    cmd[4] = feature;
+    cmd[6] = count;
+    with_dev_fd(dev, |fd| sg_io(fd, dev, &cmd, &mut [], verbosity)).map(drop)
+}
+
+/// The SET FEATURES subcommand and count register setting an APM level.
+fn apm_feature(level: u8) -> Result<(u8, u8)> {
+    const ENABLE_APM: u8 = 0x05;
+    const DISABLE_APM: u8 = 0x85;
+    match level {
+        0 => Err("Invalid APM level 0".into()),
+        255 => Ok((DISABLE_APM, 0)),
+        level => Ok((ENABLE_APM, level)),
+    }
+}
+
+/// Reads the serial number of a disk from its Unit Serial Number VPD page.
+pub fn serial_number(dev: &OsStr, verbosity: u8) -> Result<String> {
+    // INQUIRY, EVPD, page 0x80
+    const SCSI_INQUIRY_SERIAL_CMD: &[u8] = b"\x12\x01\x80\x00\xfc\x00";
+    let mut data = [0u8; 252];
+    with_dev_fd(dev, |fd| {
+        sg_io(fd, dev, SCSI_INQUIRY_SERIAL_CMD, &mut data, verbosity)
+    })?;
+    parse_unit_serial(&data)
+}
+
+fn parse_unit_serial(page: &[u8]) -> Result<String> {
+    let [_, 0x80, _, len, ref serial @ ..] = *page else {
+        return Err("Unit serial number page not returned".into());
+    };
+    let serial = serial
+        .get(..usize::from(len))
+        .ok_or("Truncated unit serial number page")?
+        .trim_ascii();
+    if serial.is_empty() {
+        return Err("Empty serial number".into());
+    }
+    Ok(String::from_utf8_lossy(serial).into_owned())
+}
+
+/// Reads the standby timer of a disk from its power condition mode page.
+/// Returns `None` if the timer is disabled.
+///
+/// ATA disks have no command reading their timer back, and the mode page
+/// translated by libata (or a USB bridge) only holds defaults: they fail.
+pub fn standby_timer(dev: &OsStr, verbosity: u8) -> Result<Option<Duration>> {
+    const POWER_CONDITION_MODE_PAGE: u8 = 0x1a;
+    if is_ata_disk(dev, verbosity) {
+        return Err("The standby timer of an ATA disk can't be read back".into());
+    }
+    let page = mode_sense(dev, POWER_CONDITION_MODE_PAGE, verbosity)?;
+    parse_standby_timer(&page)
+}
+
+/// Parses the STANDBY_Z condition timer (in 100ms units) of the power
+/// condition mode page.
+fn parse_standby_timer(page: &[u8]) -> Result<Option<Duration>> {
+    const STANDBY_Z: u8 = 0x01;
+    let [_, _, _, conditions, _, _, _, _, t0, t1, t2, t3, ..] = *page else {
+        return Err("Truncated power condition mode page".into());
+    };
+    Ok((conditions & STANDBY_Z != 0)
+        .then(|| Duration::from_millis(u64::from(u32::from_be_bytes([t0, t1, t2, t3])) * 100)))
+}
+
+/// Programs the standby timer of a disk, after which its firmware spins it
+/// down by itself. Returns the timer in effect, rounded to the granularity of
+/// the drive.
+///
+/// ATA disks get an IDLE command with the timer in its count register (like
+/// `hdparm -S`), the other ones a MODE SELECT of their power condition mode
+/// page, not saved across power cycles.
+pub fn set_standby_timer(dev: &OsStr, timer: Duration, verbosity: u8) -> Result<Duration> {
+    if is_ata_disk(dev, verbosity) {
+        let (count, timer) = ata_standby_count(timer)
+            .ok_or_else(|| format!("Standby timer out of range: {}s", timer.as_secs()))?;
+        // ATA PASS-THROUGH(16), non-data protocol, IDLE (0xe3)
+        let mut cmd = *b"\x85\x06\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xe3\x00";
+        cmd[6] = count;
+        with_dev_fd(dev, |fd| sg_io(fd, dev, &cmd, &mut [], verbosity))?;
+        return Ok(timer);
+    }
+    const POWER_CONDITION_MODE_PAGE: u8 = 0x1a;
+    let mut page = mode_sense(dev, POWER_CONDITION_MODE_PAGE, verbosity)?;
+    let timer = set_standby_timer_field(&mut page, timer)?;
+    let mut data = vec![0u8; 8];
+    data.extend_from_slice(&page);
+    let len = (data.len() as u16).to_be_bytes();
+    // MODE SELECT(10), page format
+    let cmd = [0x55, 0x10, 0, 0, 0, 0, 0, len[0], len[1], 0];
+    with_dev_fd(dev, |fd| sg_io_write(fd, dev, &cmd, &data, verbosity))?;
+    Ok(timer)
+}
+
+/// Is the disk an ATA one, behind libata or a USB bridge?
+fn is_ata_disk(dev: &OsStr, verbosity: u8) -> bool {
+    is_usb(dev) || inquiry(dev, verbosity).is_ok_and(|id| id.is_ata())
+}
+
+/// Encodes a standby timer in the count register of the ATA IDLE command:
+/// 5s units up to 20 minutes, then 30 minutes units up to 5.5 hours. Returns
+/// the count and the timer it stands for.
+fn ata_standby_count(timer: Duration) -> Option<(u8, Duration)> {
+    let secs = timer.as_secs();
+    match secs {
+        1..=1200 => {
+            let count = secs.div_ceil(5);
+            Some((count as u8, Duration::from_secs(count * 5)))
+        }
+        1201..=19800 => {
+            let halves = secs.div_ceil(1800);
+            Some((240 + halves as u8, Duration::from_secs(halves * 1800)))
+        }
+        _ => None,
+    }
+}
+
+/// Enables the standby timer of a power condition mode page, and sets it to
+/// `timer` (100ms units). Clears the PS bit, reserved in MODE SELECT.
+fn set_standby_timer_field(page: &mut [u8], timer: Duration) -> Result<Duration> {
+    const STANDBY_Z: u8 = 0x01;
+    let units = u32::try_from(timer.as_millis() / 100)
+        .ok()
+        .filter(|units| *units > 0)
+        .ok_or_else(|| format!("Standby timer out of range: {}s", timer.as_secs()))?;
+    let [code, _, _, conditions, _, _, _, _, t0, t1, t2, t3, ..] = page else {
+        return Err("Truncated power condition mode page".into());
+    };
+    *code &= 0x3f;
+    *conditions |= STANDBY_Z;
+    [*t0, *t1, *t2, *t3] = units.to_be_bytes();
+    Ok(Duration::from_millis(u64::from(units) * 100))
+}
+
+/// Issue a SCSI MODE SENSE(10) for the current values of a mode page, returns
+/// the page (without the mode parameter header).
+pub fn mode_sense(dev: &OsStr, page: u8, verbosity: u8) -> Result<Vec<u8>> {
+    const HEADER_LEN: usize = 8;
+    let mut data = [0u8; 255];
+    // DBD: no block descriptors
+    let cmd = [0x5a, 0x08, page & 0x3f, 0, 0, 0, 0, 0, data.len() as u8, 0];
+    with_dev_fd(dev, |fd| sg_io(fd, dev, &cmd, &mut data, verbosity))?;
+
+    let mode_data_len = usize::from(u16::from_be_bytes([data[0], data[1]])) + 2;
+    let block_desc_len = usize::from(u16::from_be_bytes([data[6], data[7]]));
+    let page_data = data[..mode_data_len.min(data.len())]
+        .get(HEADER_LEN + block_desc_len..)
+        .unwrap_or_default();
+    match page_data {
+        [code, ..] if code & 0x3f == page & 0x3f => Ok(page_data.to_vec()),
+        _ => Err(format!("Mode page {:#04x} not returned", page).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn invalid_device_names() {
+        for name in ["", "sda/../..", "sd\0a"] {
+            let err = with_dev_fd(OsStr::new(name), |_| Ok(())).unwrap_err();
+            assert!(
+                err.to_string().starts_with("Invalid device name"),
+                "{}",
+                err
+            );
+        }
+        let long_name = OsStr::new("dm-0-with-a-rather-long-name-for-a-device");
+        let err = with_dev_fd(long_name, |_| Ok(())).unwrap_err();
+        assert!(
+            err.to_string().starts_with("Could not open device"),
+            "{}",
+            err
+        );
+        let err = link_to_scsi_name(OsStr::new("")).unwrap_err();
+        assert_eq!(err.to_string(), "Empty device path");
+    }
+
+    #[test]
+    fn device_numbers() {
+        assert_eq!(split_dev(0x0810), (8, 16));
+        // Extended encoding of large numbers
+        assert_eq!(split_dev(0x1231_0345), (259, 0x12345));
+        assert_eq!(kernel_name(0, 0), None);
+        // Matches the names of /sys/class/block, when sysfs is available
+        for entry in std::fs::read_dir("/sys/dev/block").into_iter().flatten() {
+            let entry = entry.unwrap();
+            let numbers = entry.file_name().into_string().unwrap();
+            let (major, minor) = numbers.split_once(':').unwrap();
+            let name = kernel_name(major.parse().unwrap(), minor.parse().unwrap()).unwrap();
+            assert!(std::path::Path::new("/sys/class/block").join(name).exists());
+        }
+    }
+
+    #[test]
+    fn bdi_stats() {
+        let clean = b"BdiWriteback:            0 kB\nBdiReclaimable:          0 kB\n\
+                      BdiDirtyThresh:     123456 kB\nb_dirty:                 0\n\
+                      b_io:                    0\nb_more_io:               0\n\
+                      b_dirty_time:            3\nstate:                   1\n";
+        assert_eq!(parse_bdi_stats(clean), Some(false));
+        let dirty = b"BdiWriteback:            0 kB\nBdiReclaimable:        128 kB\n";
+        assert_eq!(parse_bdi_stats(dirty), Some(true));
+        assert_eq!(parse_bdi_stats(b"b_dirty: 2\n"), Some(true));
+        assert_eq!(parse_bdi_stats(b""), None);
+        assert_eq!(parse_bdi_stats(b"b_io: x\n"), None);
+        // Anonymous device number, the backing device isn't the disk's
+        let mut proc = *b"/proc\0";
+        let proc = make_inplace_cstr(&mut proc).unwrap();
+        assert_eq!(has_dirty_data(proc, OsStr::new("sdz")), None);
+    }
+
+    #[test]
+    fn invalid_command_sense() {
+        // Fixed format: ILLEGAL REQUEST, INVALID COMMAND OPERATION CODE
+        let fixed = [
+            0x70, 0, 0x05, 0, 0, 0, 0, 0x0a, 0, 0, 0, 0, 0x20, 0x00, 0, 0, 0, 0,
+        ];
+        assert!(is_invalid_command(&fixed));
+        // Descriptor format: ILLEGAL REQUEST, INVALID FIELD IN CDB
+        assert!(is_invalid_command(&[0x72, 0x05, 0x24, 0x00, 0, 0, 0, 0]));
+        // NOT READY, or truncated
+        assert!(!is_invalid_command(&[0x72, 0x02, 0x04, 0x02, 0, 0, 0, 0]));
+        assert!(!is_invalid_command(&[0x70, 0, 0x05]));
+        assert!(!is_invalid_command(&[]));
+    }
+
+    #[test]
+    fn nvme_power_states() {
+        assert_eq!(std::mem::size_of::<NvmePassthruCmd>(), 72);
+        assert_eq!(check_power_state(None, 4).unwrap(), 4);
+        assert_eq!(check_power_state(Some(3), 4).unwrap(), 3);
+        assert_eq!(
+            check_power_state(Some(5), 4).unwrap_err().to_string(),
+            "power state 5 out of range, the controller supports 0 to 4"
+        );
+        assert!(is_nvme(OsStr::new("nvme0n1")));
+        assert!(!is_nvme(OsStr::new("sda")));
+    }
+
+    #[test]
+    fn power_states() {
+        assert_eq!(classify_power_state(0, 4), PowerMode::Active);
+        assert_eq!(classify_power_state(2, 4), PowerMode::Idle);
+        assert_eq!(classify_power_state(4, 4), PowerMode::Standby);
+        assert_eq!(classify_power_state(0, 0), PowerMode::Active);
+        let status = |s| parse_runtime_status(s).unwrap();
+        assert_eq!(status("suspended\n"), PowerMode::Standby);
+        assert_eq!(status("active\n"), PowerMode::Active);
+        assert!(parse_runtime_status("unsupported\n").is_err());
+    }
+
+    #[test]
+    fn usb_paths() {
+        let usb = "/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/\
+                   6:0:0:0/block/sdc";
+        assert!(is_usb_path(std::path::Path::new(usb)));
+        let sata = "/sys/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda";
+        assert!(!is_usb_path(std::path::Path::new(sata)));
+    }
+
+    #[test]
+    fn stacked_devices() {
+        use std::fs;
+        use std::os::unix::fs::symlink;
+
+        // A LUKS container (dm-1) on a logical volume (dm-0) spanning sdb1 and
+        // sdc, and a volume on a virtio disk
+        let root = TempDir::new("sys-class-block");
+        let devices = root.join("devices");
+        for (path, dev) in [
+            ("sdb", "8:16"),
+            ("sdb/sdb1", "8:17"),
+            ("sdc", "8:32"),
+            ("vda", "254:0"),
+        ] {
+            fs::create_dir_all(devices.join(path)).unwrap();
+            fs::write(devices.join(path).join("dev"), dev).unwrap();
+        }
+        fs::write(devices.join("sdb/sdb1/partition"), "1").unwrap();
+        let class = root.join("class");
+        for (name, slaves) in [
+            ("dm-0", &["sdc", "sdb1"][..]),
+            ("dm-1", &["dm-0"]),
+            ("dm-2", &["vda"]),
+        ] {
+            for slave in slaves {
+                fs::create_dir_all(class.join(name).join("slaves").join(slave)).unwrap();
+            }
+        }
+        for path in ["sdb", "sdb/sdb1", "sdc", "vda"] {
+            let name = path.rsplit('/').next().unwrap();
+            symlink(devices.join(path), class.join(name)).unwrap();
+        }
+
+        let disks = |name: &str| {
+            let mut disks = Vec::new();
+            backing_disks(&class, OsStr::new(name), &mut disks).map(|_| disks)
+        };
+        assert_eq!(disks("dm-1").unwrap(), ["sdb", "sdc"]);
+        assert_eq!(slaves(&class, OsStr::new("sdc")), Vec::<OsString>::new());
+        let err = disks("dm-2").unwrap_err();
+        assert_eq!(err.to_string(), "Not a SCSI or NVMe disk: 'vda'");
+    }
+
+    #[test]
+    fn ata_count_register() {
+        // Descriptor format, with an ATA Status Return descriptor
+        let sense = [
+            0x72, 0x01, 0x00, 0x1d, 0, 0, 0, 0x0e, 0x09, 0x0c, 0x00, 0x00, 0x00, 0xff, 0, 0, 0, 0,
+            0, 0, 0x40, 0x50,
+        ];
+        assert_eq!(parse_ata_count(&sense), Some(0xff));
+        // Fixed format
+        assert_eq!(
+            parse_ata_count(&[0x70, 0, 0x01, 0, 0x50, 0x40, 0x00]),
+            Some(0x00)
+        );
+        assert_eq!(parse_ata_count(&[0x72, 0x01, 0x00, 0x1d]), None);
+        assert_eq!(parse_ata_count(&[]), None);
+    }
+
+    #[test]
+    fn sense_text() {
+        let sense = [
+            0x70, 0, 0x02, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x04, 0x02, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            SenseText(&sense).to_string(),
+            "NOT READY, LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED"
+        );
+        let sense = [0x72, 0x05, 0x55, 0x01, 0, 0, 0, 0];
+        assert_eq!(
+            SenseText(&sense).to_string(),
+            "ILLEGAL REQUEST, ASC 0x55 ASCQ 0x01"
+        );
+        assert_eq!(SenseText(&[]).to_string(), "unknown sense data format");
+        let err = status_error(CHECK_CONDITION, &[0x72, 0x05, 0x20, 0x00]);
+        assert_eq!(
+            err.to_string(),
+            "SCSI command failed with CHECK_CONDITION: ILLEGAL REQUEST, \
+             INVALID COMMAND OPERATION CODE (sense data [72 05 20 00])"
+        );
+    }
+
+    #[test]
+    fn power_conditions() {
+        let fixed = |key, asc, ascq| {
+            let mut sense = [0u8; 18];
+            sense[0] = 0x70;
+            sense[2] = key;
+            sense[7] = 10;
+            sense[12] = asc;
+            sense[13] = ascq;
+            parse_power_condition(&sense)
+        };
+        assert_eq!(fixed(0, 0, 0), Some(PowerMode::Active));
+        assert_eq!(fixed(0, 0x5e, 0x03), Some(PowerMode::Idle));
+        assert_eq!(fixed(0, 0x5e, 0x04), Some(PowerMode::Standby));
+        assert_eq!(fixed(2, 0x04, 0x02), Some(PowerMode::Sleeping));
+        assert_eq!(fixed(2, 0x3a, 0x00), None);
+        assert_eq!(parse_power_condition(&[0x70, 0, 0]), None);
+        let descriptor = [0x72, 0x00, 0x5e, 0x02, 0, 0, 0, 0];
+        assert_eq!(parse_power_condition(&descriptor), Some(PowerMode::Standby));
+    }
+
+    #[test]
+    fn stop_commands() {
+        assert_eq!(stop_cmd(0), *b"\x1b\x00\x00\x00\x00\x00");
+        assert_eq!(stop_cmd(3), *b"\x1b\x00\x00\x00\x30\x00");
+        assert_eq!(stop_cmd(MAX_POWER_CONDITION), *b"\x1b\x00\x00\x00\xb0\x00");
+    }
+
+    #[test]
+    fn apm_levels() {
+        assert_eq!(apm_feature(1).unwrap(), (0x05, 1));
+        assert_eq!(apm_feature(128).unwrap(), (0x05, 128));
+        assert_eq!(apm_feature(255).unwrap(), (0x85, 0));
+        assert!(apm_feature(0).is_err());
+    }
+
+    #[test]
+    fn unit_serial() {
+        let page = b"\x00\x80\x00\x0a  WD-WCC4E\x00\x00";
+        assert_eq!(parse_unit_serial(page).unwrap(), "WD-WCC4E");
+        assert!(parse_unit_serial(&page[..8]).is_err());
+        assert!(parse_unit_serial(b"\x00\x83\x00\x00").is_err());
+        assert!(parse_unit_serial(b"\x00\x80\x00\x02  ").is_err());
+    }
+
+    #[test]
+    fn standby_timer_page() {
+        let mut page = [0u8; 40];
+        page[0] = 0x1a;
+        page[1] = 0x26;
+        // 20 min, in 100ms units
+        page[8..12].copy_from_slice(&12000u32.to_be_bytes());
+        assert_eq!(parse_standby_timer(&page).unwrap(), None);
+        page[3] = 0x01;
+        assert_eq!(
+            parse_standby_timer(&page).unwrap(),
+            Some(Duration::from_secs(1200))
+        );
+        assert!(parse_standby_timer(&page[..8]).is_err());
+
+        page[0] |= 0x80; // PS
+        let timer = set_standby_timer_field(&mut page, Duration::from_secs(600)).unwrap();
+        assert_eq!(timer, Duration::from_secs(600));
+        assert_eq!(page[0], 0x1a);
+        assert_eq!(parse_standby_timer(&page).unwrap(), Some(timer));
+        assert!(set_standby_timer_field(&mut page, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn ata_standby_counts() {
+        let count = |secs| ata_standby_count(Duration::from_secs(secs));
+        assert_eq!(count(5), Some((1, Duration::from_secs(5))));
+        assert_eq!(count(601), Some((121, Duration::from_secs(605))));
+        assert_eq!(count(1200), Some((240, Duration::from_secs(1200))));
+        assert_eq!(count(3600), Some((242, Duration::from_secs(3600))));
+        assert_eq!(count(19800), Some((251, Duration::from_secs(19800))));
+        assert_eq!(count(0), None);
+        assert_eq!(count(20000), None);
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Fixtures of the tests of the daemon, which never reach the disks nor the
+//! tables of the system.
+
+use std::ffi::OsStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::app::System;
+use crate::errors::Result;
+use crate::hooks;
+use crate::iomonitor::IOMonitor;
+use crate::localtime::TimeZone;
+use crate::mounts::Mounts;
+use crate::openfiles::OpenDevices;
+use crate::options::Options;
+use crate::policy::IdleTimePolicy;
+use crate::swaps::Swaps;
+use crate::sys::{PowerMode, SpindownBackend};
+use crate::tick::TickEnv;
+
+/// What the ticks of the tests borrow.
+pub(crate) struct Fixture {
+    mounts: Mounts,
+    open_devices: OpenDevices,
+    hooks: hooks::Runner,
+}
+
+impl Fixture {
+    /// Nothing mounted, and no hook running.
+    pub(crate) fn new() -> Self {
+        Self {
+            mounts: Mounts::from_snapshots(&[""]),
+            open_devices: OpenDevices::new(),
+            hooks: hooks::Runner::default(),
+        }
+    }
+
+    /// A tick at `now` of the idle time policy, spinning the disks down.
+    pub(crate) fn env(&mut self, now: Instant) -> TickEnv<'_> {
+        TickEnv {
+            now,
+            wall_time: SystemTime::now(),
+            mounts: &mut self.mounts,
+            swaps: None,
+            open_devices: &mut self.open_devices,
+            policy: &IdleTimePolicy,
+            slow_command: None,
+            audit: None,
+            dry_run: false,
+            monitor: false,
+            hooks: &mut self.hooks,
+            syncer: None,
+            time_of_day: None,
+            disks_changed: false,
+        }
+    }
+}
+
+/// Answers the commands at once, like a disk already in the state asked for.
+pub(crate) struct NullBackend;
+
+impl SpindownBackend for NullBackend {
+    fn spindown(&self, _dev: &OsStr) -> Result<Duration> {
+        Ok(Duration::ZERO)
+    }
+    fn spinup(&self, _dev: &OsStr) -> Result<Duration> {
+        Ok(Duration::ZERO)
+    }
+    fn power_state(&self, _dev: &OsStr) -> Result<PowerMode> {
+        Ok(PowerMode::Standby)
+    }
+}
+
+/// A system without any disk in /proc/diskstats, nothing mounted nor swapped
+/// on, in UTC. The disks configured have no identity and answer at once.
+pub(crate) fn system() -> System {
+    System {
+        diskstats: || Ok(IOMonitor::from_snapshots(&[""])),
+        mounts: || Ok(Mounts::from_snapshots(&[""])),
+        swaps: || Ok(Swaps::from_snapshots(&[""])),
+        wwid: |_| None,
+        backend: |_, _| Box::new(NullBackend),
+        firmware: |_, _, _| {},
+        resolve: |path, _| Err(format!("No disk at '{}'", path.to_string_lossy()).into()),
+        time_zone: || Ok(TimeZone::UTC),
+        sync_all: || Ok(()),
+    }
+}
+
+/// Options of a daemon on the fixture [`system`].
+pub(crate) fn options() -> Options {
+    Options {
+        system: system(),
+        ..Default::default()
+    }
+}
+
+/// Line of /proc/diskstats of the disk `name`, having read and written these
+/// sectors.
+pub(crate) fn diskstats(name: &str, read: usize, written: usize) -> String {
+    format!(
+        "   8       0 {} 10 0 {} 0 10 0 {} 0 0 0 0 0 0 0 0\n",
+        name, read, written
+    )
+}
@@ -0,0 +1,1886 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! State of the monitored devices, and the phases of a refresh: updating
+//! their statistics, executing the decisions of the policy, syncing and
+//! spinning them down.
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::audit::{self, Audit};
+use crate::errors::{Context, Result};
+use crate::hooks::{self, Hooks};
+use crate::iomonitor::Sectors;
+use crate::mounts::Mounts;
+use crate::openfiles::OpenDevices;
+use crate::policy::{
+    Action, Activity, DeviceConfig, DeviceState, Policy, SYNC_DIRTY_ONLY, SYNC_GLOBAL, SYNC_SPIN_UP,
+};
+use crate::swaps::Swaps;
+use crate::syncer::Syncer;
+use crate::sys::PowerMode;
+use crate::{elogln, fanotify, iomonitor, logln, sys};
+
+/// Stores disk config and retained statistics. `IOMonitor` wraps instances
+/// inside `Device<DeviceData>`s which adds statistics read from /dev/diskstats.
+pub(crate) struct DeviceData {
+    /// Stable identity of the disk (WWID), following it across kernel names.
+    pub(crate) id: Option<OsString>,
+    /// Configured by the arguments, rather than discovered.
+    pub(crate) listed: bool,
+    /// # of refreshes since the device went missing.
+    pub(crate) missing_ticks: u32,
+    pub(crate) sectors: Sectors,
+    pub(crate) state: DeviceState,
+    pub(crate) last_io: Instant,
+    /// Time of the last scheduled spin-up (or when it was found already spinning).
+    pub(crate) last_scheduled_spinup: Instant,
+    /// Don't spin down before this time, after a spin-up.
+    pub(crate) awake_until: Instant,
+    /// Time of the last update, if any.
+    pub(crate) last_tick: Option<Instant>,
+    /// Moving average of the activity, in sectors/s.
+    pub(crate) rate: f64,
+    /// Idle time computed by the last update.
+    pub(crate) idle_time: Duration,
+    /// Action decided by the last update, waiting to be executed.
+    pub(crate) pending: Action,
+    /// Control interface which requested the pending action, instead of the
+    /// policy.
+    pub(crate) request: Option<&'static str>,
+    /// The disk will be spun down by the first tick from this time (after its
+    /// sync), along with the other devices due by then.
+    pub(crate) deferred_stop: Option<Instant>,
+    /// The disk is synced in the background, to be spun down afterwards.
+    pub(crate) syncing: bool,
+    /// Remaining refreshes ignoring the activity in the `Synced` state.
+    pub(crate) grace_ticks: u8,
+    /// Last runtime error, and when it happened.
+    pub(crate) last_error: Option<(Instant, String)>,
+    /// File holding a supplementary activity signal, busy when non-zero.
+    pub(crate) busy_file: Option<PathBuf>,
+    /// Commands run when the disk spins down or up.
+    pub(crate) hooks: Hooks,
+    /// Stable path given for the disk (eg. in /dev/disk/by-id), following it
+    /// if the kernel renames it.
+    pub(crate) link: Option<PathBuf>,
+    /// # of successful spin downs.
+    pub(crate) spindowns: u64,
+    /// Times of the spin downs of the last hour, for `config.max_spindowns`.
+    pub(crate) recent_spindowns: VecDeque<Instant>,
+    /// Spin downs are held back by `config.max_spindowns`, warned about.
+    pub(crate) throttled: bool,
+    /// Spin downs are retried as the device isn't ready, warned about.
+    pub(crate) not_ready: bool,
+    /// # of spin ups, detected or scheduled.
+    pub(crate) spinups: u64,
+    /// Spins the disk down and up.
+    pub(crate) backend: Box<dyn sys::SpindownBackend>,
+    /// Watches the accesses to the files of the disk, once set up.
+    pub(crate) watcher: Option<fanotify::Watcher>,
+    pub(crate) watch_attempted: bool,
+    /// Last file accessed on the disk, from `watcher`.
+    pub(crate) last_access: Option<fanotify::Access>,
+    pub(crate) config: DeviceConfig,
+}
+
+impl From<DeviceConfig> for DeviceData {
+    fn from(config: DeviceConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            backend: Box::new(sys::ScsiBackend {
+                power_condition: config.power_condition,
+                verbosity: config.verbosity,
+            }),
+            config,
+            id: None,
+            listed: false,
+            missing_ticks: 0,
+            state: DeviceState::Spinning(),
+            sectors: Sectors::default(),
+            last_io: now,
+            last_scheduled_spinup: now,
+            awake_until: now,
+            last_tick: None,
+            rate: 0.0,
+            idle_time: Duration::ZERO,
+            pending: Action::None,
+            request: None,
+            deferred_stop: None,
+            syncing: false,
+            grace_ticks: 0,
+            last_error: None,
+            busy_file: None,
+            hooks: Hooks::default(),
+            link: None,
+            spindowns: 0,
+            recent_spindowns: VecDeque::new(),
+            throttled: false,
+            not_ready: false,
+            spinups: 0,
+            watcher: None,
+            watch_attempted: false,
+            last_access: None,
+        }
+    }
+}
+
+/// Time constant of the moving average of the activity.
+const RATE_TIME_CONSTANT: Duration = Duration::from_secs(60);
+/// Window of `DeviceConfig::max_spindowns`.
+const SPINDOWNS_WINDOW: Duration = Duration::from_secs(3600);
+/// Time given to a `--pre-spindown` hook to answer.
+const PRE_SPINDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) type IOMonitor = iomonitor::IOMonitor<DeviceData>;
+type Device = iomonitor::Device<DeviceData>;
+
+/// Resources shared by the devices during a tick.
+pub(crate) struct TickEnv<'a> {
+    /// Monotonic time of the tick, for the idle timers.
+    pub(crate) now: Instant,
+    /// Wall clock time of the tick, for the audit trail.
+    pub(crate) wall_time: SystemTime,
+    /// Utility object to read and cache the mount points.
+    pub(crate) mounts: &'a mut Mounts,
+    /// Active swap areas, if /proc/swaps is readable.
+    pub(crate) swaps: Option<&'a mut Swaps>,
+    pub(crate) open_devices: &'a mut OpenDevices,
+    pub(crate) policy: &'a dyn Policy,
+    /// Threshold above which SCSI commands are reported as slow.
+    pub(crate) slow_command: Option<Duration>,
+    pub(crate) audit: Option<&'a Audit>,
+    /// Only log the syncs and spin downs (`--dry-run`).
+    pub(crate) dry_run: bool,
+    /// Only observe the devices (`--monitor`), never spinning them up.
+    pub(crate) monitor: bool,
+    pub(crate) hooks: &'a mut hooks::Runner,
+    /// Thread running the syncs, if any.
+    pub(crate) syncer: Option<&'a Syncer<SyncRequest>>,
+    /// Local time of the tick in minutes since midnight, when spin down
+    /// windows are configured.
+    pub(crate) time_of_day: Option<u16>,
+    /// A device appeared, went missing or was replaced since the last tick:
+    /// the kernel may have renamed the disks.
+    pub(crate) disks_changed: bool,
+}
+
+impl TickEnv<'_> {
+    /// Syncs the filesystems and buffers of the device, or only logs it in a
+    /// dry run.
+    fn sync_block_device(&mut self, dev: &OsStr, config: &DeviceConfig) -> Result<()> {
+        if self.dry_run {
+            if config.verbosity >= 1 {
+                logln!("<5>Would sync {} (dry-run)", dev.to_string_lossy());
+            }
+            return Ok(());
+        }
+        sync_block_device(self.mounts, dev, config)
+    }
+
+    /// Queues the sync of the device to the background thread, to spin it
+    /// down afterwards if `spindown`. Returns false if the device must be
+    /// synced in place instead: without the thread, or in a dry run.
+    fn sync_in_background(
+        &self,
+        dev: &OsStr,
+        data: &DeviceData,
+        reason: &'static str,
+        spindown: bool,
+    ) -> bool {
+        let Some(syncer) = self.syncer.filter(|_| !self.dry_run) else {
+            return false;
+        };
+        let request = SyncRequest {
+            dev: dev.to_owned(),
+            config: data.config.clone(),
+            reason,
+            idle_time: data.idle_time,
+            spindown,
+        };
+        match syncer.request(request) {
+            Ok(()) => true,
+            Err(e) => {
+                elogln!("<4>{}, syncing {} in place", e, dev.to_string_lossy());
+                false
+            }
+        }
+    }
+
+    /// Runs the hook of the device for its spin down or up, except in a dry
+    /// run.
+    fn run_hook(&mut self, dev: &OsStr, data: &DeviceData, spinup: bool) {
+        if !self.dry_run {
+            run_hook(self.hooks, dev, data, spinup);
+        }
+    }
+}
+
+/// Logs a spin down or up of the device with its count, from `-vv`.
+fn log_cycle(dev: &OsStr, data: &DeviceData, spinup: bool) {
+    if data.config.verbosity >= 2 {
+        logln!(
+            "<6>{} spun {} (cycle #{})",
+            dev.to_string_lossy(),
+            if spinup { "up" } else { "down" },
+            if spinup { data.spinups } else { data.spindowns }
+        );
+    }
+}
+
+/// Spawns the hook of the device for its spin down or up, if it has one.
+fn run_hook(runner: &mut hooks::Runner, dev: &OsStr, data: &DeviceData, spinup: bool) {
+    let (command, state) = if spinup {
+        (&data.hooks.on_spinup, "spinning")
+    } else {
+        (&data.hooks.on_spindown, "idle")
+    };
+    let Some(command) = command else {
+        return;
+    };
+    let event = hooks::Event {
+        device: dev,
+        state,
+        idle_time: data.idle_time,
+    };
+    if let Err(e) = runner.spawn(command, &event) {
+        elogln!("<4>{}", e);
+    }
+}
+
+/// Main state transition function, first phase: updates the statistics and
+/// decides the transition with `env.policy`.
+///
+/// The resulting action is left pending until `execute()`.
+pub(crate) fn update(device: &mut Device, env: &mut TickEnv) {
+    let present = device.present();
+    let in_flight = device.in_flight();
+    let (dev_name, new_sectors, actor, device_data) = device.into();
+    let config = &device_data.config;
+    let now = env.now;
+
+    // A removed device is left alone until it comes back
+    if !present {
+        if device_data.state != DeviceState::Missing() {
+            env.disks_changed = true;
+            if config.verbosity >= 1 {
+                logln!("<5>{} is missing", dev_name.to_string_lossy());
+            }
+        }
+        device_data.state = DeviceState::Missing();
+        device_data.missing_ticks = device_data.missing_ticks.saturating_add(1);
+        device_data.pending = Action::None;
+        return;
+    }
+    if device_data.last_tick.is_none() {
+        // New device
+        env.disks_changed = true;
+    }
+    if device_data.state == DeviceState::Missing() {
+        env.disks_changed = true;
+        if config.verbosity >= 1 {
+            logln!("<5>{} is back", dev_name.to_string_lossy());
+        }
+        // Counters restart with the device, which is spinning
+        device_data.state = DeviceState::Spinning();
+        device_data.missing_ticks = 0;
+        device_data.sectors = new_sectors;
+        device_data.last_io = now;
+    }
+
+    // Difference in read/write/discarded sectors tells us if the disk was
+    // busy between two time steps.
+    let read_inc = sectors_increment(device_data.sectors.read, new_sectors.read);
+    let written_inc = sectors_increment(device_data.sectors.written, new_sectors.written);
+    let sectors_inc = match (read_inc, written_inc) {
+        // Any I/O spins up a parked disk, even in an ignored direction
+        (Some(read), Some(written)) if device_data.state == DeviceState::Idle() => {
+            read.wrapping_add(written)
+        }
+        (Some(read), Some(written)) => {
+            let read = if config.ignore_reads { 0 } else { read };
+            // The writes of a background sync are not activity
+            let written = if config.ignore_writes || device_data.syncing {
+                0
+            } else {
+                written
+            };
+            read.wrapping_add(written)
+        }
+        _ => {
+            // The disk was removed and added back
+            env.disks_changed = true;
+            if config.verbosity >= 2 {
+                logln!(
+                    "<6>Counters of {} were reset, {} => {} sectors",
+                    dev_name.to_string_lossy(),
+                    device_data.sectors.total(),
+                    new_sectors.total()
+                );
+            }
+            0
+        }
+    };
+    let sectors_inc = if sectors_inc != 0
+        && (sectors_inc as u64) < config.min_sectors
+        && device_data.state != DeviceState::Idle()
+    {
+        if config.verbosity >= 3 {
+            logln!(
+                "<7>Ignoring {} sectors of background activity on {}",
+                sectors_inc,
+                dev_name.to_string_lossy()
+            );
+        }
+        0
+    } else {
+        sectors_inc
+    };
+    // Moving average of the activity, smoothing out isolated blips
+    let dt = device_data
+        .last_tick
+        .map_or(0.0, |last_tick| now.duration_since(last_tick).as_secs_f64());
+    if dt > 0.0 {
+        let alpha = 1.0 - (-dt / RATE_TIME_CONSTANT.as_secs_f64()).exp();
+        device_data.rate += alpha * (sectors_inc as f64 / dt - device_data.rate);
+    }
+    let busy = if config.rate_threshold == 0 {
+        sectors_inc != 0
+    } else {
+        if config.verbosity >= 3 && sectors_inc != 0 {
+            logln!(
+                "<7>Average activity on {}: {:.1} sectors/s",
+                dev_name.to_string_lossy(),
+                device_data.rate
+            );
+        }
+        device_data.rate >= config.rate_threshold as f64
+    };
+    let busy_signal = match &device_data.busy_file {
+        Some(path) => iomonitor::read_busy_file(path).unwrap_or_else(|e| {
+            record_error(&mut device_data.last_error, now, Err(e));
+            false
+        }),
+        None => false,
+    };
+    if config.verbosity >= 3 && busy_signal {
+        logln!(
+            "<7>Activity signaled on {} by its busy file",
+            dev_name.to_string_lossy()
+        );
+    }
+    let busy = busy || busy_signal;
+    if let Some(watcher) = &mut device_data.watcher {
+        match watcher.drain() {
+            Ok(Some(access)) => device_data.last_access = Some(access),
+            Ok(None) => {}
+            Err(e) => record_error(&mut device_data.last_error, now, Err(e)),
+        }
+    }
+    device_data.last_tick = Some(now);
+    let mut elapsed = now.duration_since(device_data.last_io);
+
+    // A device held open by a process is not considered idle, even without I/O
+    if config.open_check && !busy && device_data.state == DeviceState::Spinning() {
+        match env.open_devices.is_open(dev_name) {
+            Ok(true) => {
+                if config.verbosity >= 3 {
+                    logln!("<7>{} is held open", dev_name.to_string_lossy());
+                }
+                device_data.last_io = now;
+                elapsed = Duration::ZERO;
+            }
+            Ok(false) => {}
+            Err(e) => record_error(&mut device_data.last_error, now, Err(e)),
+        }
+    }
+
+    let idle_time = if busy {
+        // Update retained statistics in DeviceData
+        if config.verbosity >= 3 && device_data.sectors.total() != 0 {
+            logln!(
+                "<7>Activity detected on {}, sectors: {} => {} (+{}){}{}",
+                dev_name.to_string_lossy(),
+                device_data.sectors.total(),
+                new_sectors.total(),
+                sectors_inc,
+                if actor.is_some() { ", last by " } else { "" },
+                actor.unwrap_or_default().to_string_lossy()
+            );
+        }
+
+        // Hysteresis: activity close to the idle deadline only rewinds the
+        // timer to the start of the deadband.
+        let reset_point = config.idle_time.saturating_sub(config.deadband);
+        if config.deadband > Duration::ZERO
+            && device_data.state == DeviceState::Spinning()
+            && (reset_point..config.idle_time).contains(&elapsed)
+        {
+            // Less than `reset_point` after boot, the timer is fully reset
+            device_data.last_io = now.checked_sub(reset_point).unwrap_or(now);
+            now.duration_since(device_data.last_io)
+        } else {
+            device_data.last_io = now;
+            Duration::ZERO
+        }
+    } else {
+        elapsed
+    };
+    device_data.sectors = new_sectors;
+
+    // A background sync is going on, the disk is spun down after it unless
+    // it is used meanwhile
+    if device_data.syncing {
+        if busy {
+            if config.verbosity >= 1 {
+                logln!(
+                    "<5>{} is busy again, not spinning it down after its sync",
+                    dev_name.to_string_lossy()
+                );
+            }
+            device_data.syncing = false;
+            device_data.state = DeviceState::Spinning();
+        }
+        device_data.idle_time = idle_time;
+        return;
+    }
+
+    // Writes of a slow sync may still be going on
+    if device_data.state == DeviceState::Synced() && device_data.grace_ticks > 0 {
+        device_data.grace_ticks -= 1;
+        device_data.idle_time = idle_time;
+        return;
+    }
+
+    // Scheduled spin-ups, exercising the disk
+    if !env.monitor
+        && config.wake_period > Duration::ZERO
+        && now.duration_since(device_data.last_scheduled_spinup) >= config.wake_period
+    {
+        device_data.last_scheduled_spinup = now;
+        if device_data.state == DeviceState::Idle() {
+            let awake_until = now + config.wake_duration.max(config.min_uptime);
+            wake(device_data, now, awake_until);
+            return;
+        }
+    }
+
+    // Compute state transitions
+    let activity = Activity {
+        now,
+        sectors_inc,
+        idle_time,
+        busy_signal,
+    };
+    let (mut next_state, mut action) = env.policy.decide(device_data.state, &activity, config);
+    if matches!(action, Action::Spindown { .. }) && now < device_data.awake_until {
+        (next_state, action) = (DeviceState::Spinning(), Action::None);
+    }
+    if matches!(action, Action::Spindown { .. })
+        && let Some(swaps) = env.swaps.as_deref_mut()
+    {
+        match swaps.on_dev(dev_name) {
+            Ok(false) => {}
+            Ok(true) => {
+                if config.verbosity >= 3 {
+                    logln!(
+                        "<7>{} holds an active swap area",
+                        dev_name.to_string_lossy()
+                    );
+                }
+                (next_state, action) = (DeviceState::Spinning(), Action::None);
+            }
+            Err(e) => record_error(&mut device_data.last_error, now, Err(e)),
+        }
+    }
+    // I/Os still in progress may not have moved the sectors yet
+    if matches!(action, Action::Spindown { .. }) && in_flight != 0 {
+        if config.verbosity >= 3 {
+            logln!(
+                "<7>{} has {} I/Os in flight",
+                dev_name.to_string_lossy(),
+                in_flight
+            );
+        }
+        (next_state, action) = (DeviceState::Spinning(), Action::None);
+    }
+    // Busy again before its deferred spin down: it never stopped
+    if matches!(action, Action::Spinup { .. }) && device_data.deferred_stop.take().is_some() {
+        (next_state, action) = (DeviceState::Spinning(), Action::None);
+    }
+    if matches!(action, Action::Spinup { .. }) {
+        device_data.awake_until = device_data.awake_until.max(now + config.min_uptime);
+    }
+    if matches!(action, Action::Spindown { .. })
+        && let Some(minute) = env.time_of_day
+        && !config.windows.is_empty()
+        && !config.windows.iter().any(|window| window.contains(minute))
+    {
+        if config.verbosity >= 3 {
+            logln!(
+                "<7>{} is idle outside of its spin down windows",
+                dev_name.to_string_lossy()
+            );
+        }
+        (next_state, action) = (DeviceState::Spinning(), Action::None);
+    }
+    if matches!(action, Action::Spindown { .. }) && config.max_spindowns > 0 {
+        let recent = &mut device_data.recent_spindowns;
+        while recent
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= SPINDOWNS_WINDOW)
+        {
+            recent.pop_front();
+        }
+        let throttled = recent.len() >= config.max_spindowns.into();
+        if throttled {
+            (next_state, action) = (DeviceState::Spinning(), Action::None);
+            if !device_data.throttled {
+                elogln!(
+                    "<4>{} spun down {} times within the last hour, keeping it spinning",
+                    dev_name.to_string_lossy(),
+                    recent.len()
+                );
+            }
+        }
+        device_data.throttled = throttled;
+    }
+    if next_state == DeviceState::Synced() && device_data.state != DeviceState::Synced() {
+        device_data.grace_ticks = config.grace_ticks;
+    }
+    device_data.state = next_state;
+    device_data.idle_time = idle_time;
+    device_data.pending = action;
+}
+
+/// Spins the device up at the next `execute()`, keeping it spinning until
+/// `awake_until`. A spin down in progress is cancelled.
+pub(crate) fn wake(data: &mut DeviceData, now: Instant, awake_until: Instant) {
+    data.deferred_stop = None;
+    data.syncing = false;
+    data.last_io = now;
+    data.awake_until = awake_until;
+    data.state = DeviceState::Spinning();
+    data.idle_time = Duration::ZERO;
+    data.pending = Action::Wake;
+}
+
+/// Number of sectors moved between two readings of the counters, through a
+/// wrap around. `None` if the counters were reset (eg. the disk was removed
+/// and added back), which a decrease of more than half their range stands for.
+fn sectors_increment(sectors: usize, new_sectors: usize) -> Option<usize> {
+    let inc = new_sectors.wrapping_sub(sectors);
+    (inc <= usize::MAX / 2).then_some(inc)
+}
+
+/// Main state transition function, second phase: executes the pending action.
+///
+/// Runtime errors are handled here and recovered from after writing to stderr.
+pub(crate) fn execute(device: &mut Device, env: &mut TickEnv) -> DeviceState {
+    let (dev_name, _, _, device_data) = device.into();
+    let config = &device_data.config;
+    let idle_time = device_data.idle_time;
+    let request = device_data.request.take();
+    let entry = |action, reason| audit::Entry {
+        device: dev_name,
+        action,
+        reason,
+        idle_time,
+    };
+
+    match std::mem::replace(&mut device_data.pending, Action::None) {
+        Action::None => {}
+        Action::Sync => {
+            if !env.sync_in_background(dev_name, device_data, "policy", false) {
+                let synced = env.sync_block_device(dev_name, config);
+                let entry = entry("sync", "policy");
+                record_action(env, &entry, &mut device_data.last_error, synced);
+            }
+        }
+        Action::Spindown { sync } => {
+            let reason = request.unwrap_or("idle");
+            if config.verbosity >= 1 {
+                match request {
+                    Some(request) => logln!(
+                        "<5>Spinning down {} ({} request)",
+                        dev_name.to_string_lossy(),
+                        request
+                    ),
+                    None => logln!(
+                        "<5>{} has gone idle. (idle_time: {}s >= {}s)",
+                        dev_name.to_string_lossy(),
+                        idle_time.as_secs(),
+                        config.idle_time.as_secs()
+                    ),
+                }
+            }
+            // Audited by `stopped()`
+            device_data.request = request;
+            let mut delay = Duration::ZERO;
+            if sync && env.sync_in_background(dev_name, device_data, reason, true) {
+                // Spun down once synced, see `finish_sync()`
+                device_data.syncing = true;
+                return device_data.state;
+            }
+            if sync {
+                let synced = env.sync_block_device(dev_name, config);
+                let entry = entry("sync", reason);
+                record_action(env, &entry, &mut device_data.last_error, synced);
+                delay = config.spindown_delay;
+            }
+            // Spun down by `stop()`, with the other devices due at that refresh
+            device_data.deferred_stop = Some(env.now + delay);
+            return device_data.state;
+        }
+        Action::Spinup { sync } => {
+            if config.verbosity >= 1 {
+                logln!(
+                    "<5>{} has spun up. (idle_time: {}s)",
+                    dev_name.to_string_lossy(),
+                    idle_time.as_secs()
+                );
+                if let Some(access) = &device_data.last_access {
+                    logln!(
+                        "<5>Last file access on {}: {}",
+                        dev_name.to_string_lossy(),
+                        access
+                    );
+                }
+            }
+            device_data.spinups += 1;
+            log_cycle(dev_name, device_data, true);
+            env.run_hook(dev_name, device_data, true);
+            let entry = entry("spinup", "activity");
+            record_action(env, &entry, &mut device_data.last_error, Ok(()));
+            if sync && !env.sync_in_background(dev_name, device_data, "activity", false) {
+                let synced = env.sync_block_device(dev_name, config);
+                let entry = audit::Entry {
+                    action: "sync",
+                    ..entry
+                };
+                record_action(env, &entry, &mut device_data.last_error, synced);
+            }
+        }
+        Action::SyncBeforePark => {
+            if config.verbosity >= 1 {
+                logln!(
+                    "<5>{} has gone idle, leaving the spin down to its firmware. \
+                     (idle_time: {}s >= {}s)",
+                    dev_name.to_string_lossy(),
+                    idle_time.as_secs(),
+                    config.idle_time.as_secs()
+                );
+            }
+            if !env.sync_in_background(dev_name, device_data, "firmware_timer", false) {
+                let synced = env.sync_block_device(dev_name, config);
+                let entry = entry("sync", "firmware_timer");
+                record_action(env, &entry, &mut device_data.last_error, synced);
+            }
+        }
+        Action::Wake if env.dry_run => {
+            if config.verbosity >= 1 {
+                match request {
+                    Some(request) => logln!(
+                        "<5>Would spin up {} ({} request, dry-run)",
+                        dev_name.to_string_lossy(),
+                        request
+                    ),
+                    None => logln!(
+                        "<5>Would spin up {} (scheduled, dry-run)",
+                        dev_name.to_string_lossy()
+                    ),
+                }
+            }
+        }
+        Action::Wake => {
+            let reason = request.unwrap_or("scheduled");
+            if config.verbosity >= 1 {
+                match request {
+                    Some(request) => logln!(
+                        "<5>Spinning up {} ({} request)",
+                        dev_name.to_string_lossy(),
+                        request
+                    ),
+                    None => logln!("<5>Spinning up {} (scheduled)", dev_name.to_string_lossy()),
+                }
+            }
+            let spun_up = device_data
+                .backend
+                .spinup(dev_name)
+                .map(|duration| {
+                    device_data.spinups += 1;
+                    check_command_duration(dev_name, "Spin up", duration, env)
+                })
+                .with_context(|| format!("Failed to spin up {}", dev_name.to_string_lossy()));
+            let sync = spun_up.is_ok() && config.sync_flags & SYNC_SPIN_UP != 0;
+            if spun_up.is_ok() {
+                log_cycle(dev_name, device_data, true);
+                env.run_hook(dev_name, device_data, true);
+            }
+            let entry = entry("spinup", reason);
+            record_action(env, &entry, &mut device_data.last_error, spun_up);
+            if sync && !env.sync_in_background(dev_name, device_data, reason, false) {
+                let synced = env.sync_block_device(dev_name, config);
+                let entry = audit::Entry {
+                    action: "sync",
+                    ..entry
+                };
+                record_action(env, &entry, &mut device_data.last_error, synced);
+            }
+        }
+    }
+    device_data.state
+}
+
+/// Asks the `--pre-spindown` hook of the device whether it may spin down. A
+/// hook failing to run or to answer in time vetoes the spin down.
+fn pre_spindown_allows(dev: &OsStr, command: &Path, idle_time: Duration, verbosity: u8) -> bool {
+    let event = hooks::Event {
+        device: dev,
+        state: "idle",
+        idle_time,
+    };
+    match hooks::run_with_timeout(command, &event, PRE_SPINDOWN_TIMEOUT) {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            if verbosity >= 1 {
+                logln!(
+                    "<5>Spin down of {} vetoed by its pre-spindown hook ({})",
+                    dev.to_string_lossy(),
+                    status
+                );
+            }
+            false
+        }
+        Err(e) => {
+            elogln!("<4>{}, keeping {} spinning", e, dev.to_string_lossy());
+            false
+        }
+    }
+}
+
+/// Asks the pre-spindown hooks of the devices, from a thread per hook when
+/// there are several: the refresh then waits at most one hook timeout, however
+/// many disks go idle together.
+fn pre_spindown_all(devices: &[&mut Device]) -> Vec<bool> {
+    let hooks: Vec<_> = (devices.iter())
+        .map(|device| {
+            let data = &device.data;
+            (
+                device.name(),
+                data.hooks.pre_spindown.as_deref(),
+                data.idle_time,
+                data.config.verbosity,
+            )
+        })
+        .collect();
+    let ask =
+        |&(dev_name, command, idle_time, verbosity): &(&OsStr, Option<&Path>, Duration, u8)| {
+            command
+                .is_none_or(|command| pre_spindown_allows(dev_name, command, idle_time, verbosity))
+        };
+    if let [hook] = &hooks[..] {
+        return vec![ask(hook)];
+    }
+    thread::scope(|scope| {
+        let threads: Vec<_> = (hooks.iter())
+            .map(|hook| scope.spawn(|| ask(hook)))
+            .collect();
+        (threads.into_iter())
+            .map(|thread| thread.join().unwrap_or(false))
+            .collect()
+    })
+}
+
+/// Spins down the disks, last step of `Action::Spindown`. The commands are
+/// sent concurrently: each can take seconds, adding up when many disks go idle
+/// at the same refresh.
+pub(crate) fn stop(devices: &mut [&mut Device], env: &mut TickEnv) {
+    for device in devices.iter_mut() {
+        device.data.deferred_stop = None;
+    }
+    if env.dry_run {
+        for device in devices.iter_mut() {
+            device.data.request = None;
+            if device.data.config.verbosity >= 1 {
+                logln!(
+                    "<5>Would spin down {} (dry-run)",
+                    device.name().to_string_lossy()
+                );
+            }
+        }
+        return;
+    }
+    for device in devices.iter() {
+        let (dev_name, data) = (device.name(), &device.data);
+        if data.config.verbosity >= 2 {
+            match data.backend.power_state(dev_name) {
+                Ok(mode) => logln!(
+                    "<6>Spinning down {} (power mode: {})",
+                    dev_name.to_string_lossy(),
+                    mode
+                ),
+                Err(_) => logln!("<6>Spinning down {}", dev_name.to_string_lossy()),
+            }
+        }
+    }
+    let results = spindown_all(devices);
+    for (device, spun_down) in devices.iter_mut().zip(results) {
+        stopped(device, spun_down, env);
+    }
+}
+
+/// Sends the spin down commands, from a thread per disk when there are
+/// several.
+fn spindown_all(devices: &[&mut Device]) -> Vec<Result<Duration>> {
+    let commands: Vec<_> = (devices.iter())
+        .map(|device| (device.name(), &*device.data.backend))
+        .collect();
+    if let [(dev_name, backend)] = commands[..] {
+        return vec![backend.spindown(dev_name)];
+    }
+    thread::scope(|scope| {
+        let threads: Vec<_> = (commands.iter())
+            .map(|(dev_name, backend)| scope.spawn(|| backend.spindown(dev_name)))
+            .collect();
+        (threads.into_iter())
+            .map(|thread| {
+                (thread.join()).unwrap_or_else(|_| Err("The spin down command panicked".into()))
+            })
+            .collect()
+    })
+}
+
+/// Accounts for the outcome of the spin down of a disk.
+fn stopped(device: &mut Device, spun_down: Result<Duration>, env: &mut TickEnv) {
+    let (dev_name, _, _, device_data) = device.into();
+    let verbosity = device_data.config.verbosity;
+    let reason = device_data.request.take().unwrap_or("idle");
+    let spun_down = spun_down
+        .map(|duration| {
+            device_data.spindowns += 1;
+            device_data.recent_spindowns.push_back(env.now);
+            check_command_duration(dev_name, "Spin down", duration, env)
+        })
+        .with_context(|| format!("Failed to spin down {}", dev_name.to_string_lossy()));
+    if let Err(e) = &spun_down
+        && e.not_ready()
+    {
+        // Not ready: don't hold the other devices, retry at the next refresh
+        if !device_data.not_ready {
+            elogln!("<4>{}, retrying at the next refresh", e);
+        }
+        device_data.not_ready = true;
+        device_data.state = DeviceState::Spinning();
+        return;
+    }
+    device_data.not_ready = false;
+    if spun_down.is_ok() {
+        if verbosity >= 2 {
+            check_stopped(dev_name, device_data.backend.as_ref(), verbosity);
+        }
+        log_cycle(dev_name, device_data, false);
+        env.run_hook(dev_name, device_data, false);
+        // The next spin up reports the access which woke the disk, if any
+        device_data.last_access = None;
+    }
+    let entry = audit::Entry {
+        device: dev_name,
+        action: "spindown",
+        reason,
+        idle_time: device_data.idle_time,
+    };
+    record_action(env, &entry, &mut device_data.last_error, spun_down);
+}
+
+/// Warns if a disk didn't reach standby after a spin down: some drives accept
+/// the command but ignore it.
+fn check_stopped(dev: &OsStr, backend: &dyn sys::SpindownBackend, verbosity: u8) {
+    match backend.power_state(dev) {
+        Ok(PowerMode::Standby | PowerMode::Sleeping) => {}
+        Ok(mode) => elogln!(
+            "<4>{} is still {} after the spin down, the drive may ignore the command",
+            dev.to_string_lossy(),
+            mode
+        ),
+        Err(e) if verbosity >= 3 => logln!(
+            "<7>Could not check the power mode of {}: {}",
+            dev.to_string_lossy(),
+            e
+        ),
+        Err(_) => {}
+    }
+}
+
+/// Records the outcome of an action in the audit trail, and as the last error
+/// of the device if it failed.
+fn record_action(
+    env: &TickEnv,
+    entry: &audit::Entry,
+    last_error: &mut Option<(Instant, String)>,
+    res: Result<()>,
+) {
+    if let Some(audit) = env.audit.filter(|_| !env.dry_run)
+        && let Err(e) = audit.record(env.wall_time, entry, &res)
+    {
+        elogln!("<4>{}", e);
+    }
+    record_error(last_error, env.now, res);
+}
+
+/// Hands over the state and configuration of vanished disks to the new devices
+/// sharing their identity, when a disk comes back under another name.
+pub(crate) fn reconcile_renamed(monitor: &mut IOMonitor) {
+    loop {
+        let renamed = monitor.devices().find_map(|old| {
+            let id = old.data.id.as_ref().filter(|_| !old.present())?;
+            monitor
+                .devices()
+                .find(|new| new.present() && new.data.id.as_ref() == Some(id))
+                .map(|new| (old.name().to_owned(), new.name().to_owned()))
+        });
+        let Some((old_name, new_name)) = renamed else {
+            return;
+        };
+        let (Some(old), Some(new)) = (monitor.remove(&old_name), monitor.get_mut(&new_name)) else {
+            return;
+        };
+        if old.data.config.verbosity >= 1 {
+            logln!(
+                "<5>{} is back as {}",
+                old_name.to_string_lossy(),
+                new_name.to_string_lossy()
+            );
+        }
+        hand_over(old.data, &mut new.data);
+    }
+}
+
+/// Follows the stable links of the devices (see `DeviceData::link`): when a
+/// link resolves to another kernel name, its device hands over its state and
+/// configuration to the new name. The drive left under the old name, if any,
+/// is configured by `create` as a new device.
+pub(crate) fn follow_links<F, C>(monitor: &mut IOMonitor, resolve: F, create: C)
+where
+    F: Fn(&Path) -> Option<OsString>,
+    C: Fn(&OsStr) -> DeviceData,
+{
+    let moved: Vec<_> = monitor
+        .devices()
+        .filter_map(|device| {
+            let link = device.data.link.as_deref()?;
+            let name = resolve(link).filter(|name| name != device.name())?;
+            Some((device.name().to_owned(), name, device.present()))
+        })
+        .collect();
+    // Taken out of all the moved devices first: disks may swap their names
+    let mut handed = Vec::with_capacity(moved.len());
+    for (old_name, new_name, present) in moved {
+        // Waits for the new device to show up in /proc/diskstats
+        if monitor.get_mut(&new_name).is_none() {
+            continue;
+        }
+        let Some(old) = monitor.get_mut(&old_name) else {
+            continue;
+        };
+        // The counters stay with the name, for the disk taking it
+        let left = DeviceData {
+            sectors: old.data.sectors,
+            state: old.data.state,
+            ..create(&old_name)
+        };
+        let data = std::mem::replace(&mut old.data, left);
+        if let Some(link) = &data.link {
+            elogln!(
+                "<4>{} ({}) is now {}",
+                old_name.to_string_lossy(),
+                link.display(),
+                new_name.to_string_lossy()
+            );
+        }
+        handed.push((old_name, new_name, present, data));
+    }
+    let taken: Vec<_> = handed
+        .iter()
+        .map(|(_, new_name, _, _)| new_name.clone())
+        .collect();
+    for (old_name, new_name, present, data) in handed {
+        if let Some(new) = monitor.get_mut(&new_name) {
+            hand_over(data, &mut new.data);
+        }
+        // Unless another drive took the old name
+        if !present && !taken.contains(&old_name) {
+            monitor.remove(&old_name);
+        }
+    }
+}
+
+/// Moves the state and configuration of a disk to its new device.
+fn hand_over(old: DeviceData, new: &mut DeviceData) {
+    // Counters restart with the new device
+    let (sectors, state) = (new.sectors, new.state);
+    *new = old;
+    new.sectors = sectors;
+    new.pending = Action::None;
+    if new.state == DeviceState::Missing() {
+        new.state = state;
+        new.missing_ticks = 0;
+    }
+}
+
+/// Logs the error of a failed operation on a device, and retains it for the
+/// status.
+fn record_error(last_error: &mut Option<(Instant, String)>, now: Instant, res: Result<()>) {
+    if let Err(e) = res {
+        elogln!("<4>{}", e);
+        *last_error = Some((now, e.to_string()));
+    }
+}
+
+/// Warns about a SCSI command slower than `env.slow_command`, which may reveal
+/// a struggling drive or USB bridge.
+fn check_command_duration(dev: &OsStr, command: &str, duration: Duration, env: &TickEnv) {
+    if env
+        .slow_command
+        .is_some_and(|threshold| duration > threshold)
+    {
+        elogln!(
+            "<4>{} of {} took {}ms",
+            command,
+            dev.to_string_lossy(),
+            duration.as_millis()
+        );
+    }
+}
+
+/// Watches the accesses to the files of the filesystems mounted from the
+/// device. Failures are reported, the watch being only a diagnostic.
+pub(crate) fn watch_files(device: &mut Device, mounts: &mut Mounts) {
+    let (dev_name, _, _, data) = device.into();
+    let watched = fanotify::Watcher::new().and_then(|watcher| {
+        mounts.for_dev(dev_name, |entry| {
+            watcher.watch_mount(OsStr::from_bytes(entry.mount_point.to_bytes()))
+        })?;
+        Ok(watcher)
+    });
+    match watched {
+        Ok(watcher) => data.watcher = Some(watcher),
+        Err(e) => elogln!(
+            "<4>Failed to watch the files of {}: {}",
+            dev_name.to_string_lossy(),
+            e
+        ),
+    }
+}
+
+/// Cancels the pending spin downs vetoed by errors on the filesystems of the
+/// devices or by their pre-spindown hooks, keeping the disks spinning. Checked
+/// before the global sync, which only the remaining spin downs request.
+pub(crate) fn check_vetoes(devices: &mut [&mut Device], env: &mut TickEnv) {
+    let mut asked = Vec::new();
+    for device in devices.iter_mut() {
+        let (dev_name, _, _, device_data) = (&mut **device).into();
+        if !matches!(device_data.pending, Action::Spindown { .. }) {
+            continue;
+        }
+        if has_fs_errors(env.mounts, dev_name) {
+            // Retry after another idle period
+            device_data.last_io = env.now;
+            veto(device_data);
+        } else if !env.dry_run && device_data.hooks.pre_spindown.is_some() {
+            asked.push(&mut **device);
+        }
+    }
+    let allowed = pre_spindown_all(&asked);
+    for (device, allowed) in asked.into_iter().zip(allowed) {
+        if !allowed {
+            veto(&mut device.data);
+        }
+    }
+}
+
+/// Keeps the disk spinning, the spin down being retried once idle again.
+fn veto(device_data: &mut DeviceData) {
+    device_data.state = DeviceState::Spinning();
+    device_data.pending = Action::None;
+}
+
+/// Checks for filesystems in an error state on the device, which shouldn't
+/// be parked to ease their recovery.
+fn has_fs_errors(mounts: &mut Mounts, dev: &OsStr) -> bool {
+    let mut errors = false;
+    if let Err(e) = mounts.for_dev_errors(dev, |mount_point| {
+        elogln!(
+            "<3>Filesystem on {} ({}) has errors, not spinning down",
+            dev.to_string_lossy(),
+            String::from_utf8_lossy(mount_point.to_bytes())
+        );
+        errors = true;
+    }) {
+        elogln!(
+            "<4>Failed to check filesystems of {}: {}",
+            dev.to_string_lossy(),
+            e
+        );
+    }
+    errors
+}
+
+/// Does any device about to spin down request a global sync?
+pub(crate) fn wants_global_sync<'a, I>(pending: I) -> bool
+where
+    I: IntoIterator<Item = (&'a DeviceConfig, Action)>,
+{
+    pending.into_iter().any(|(config, action)| {
+        matches!(action, Action::Spindown { .. } | Action::SyncBeforePark)
+            && config.sync_flags & SYNC_GLOBAL != 0
+    })
+}
+
+/// A sync run by the background thread.
+pub(crate) struct SyncRequest {
+    pub(crate) dev: OsString,
+    pub(crate) config: DeviceConfig,
+    /// Why the device is synced, for the audit trail.
+    pub(crate) reason: &'static str,
+    pub(crate) idle_time: Duration,
+    /// Spin the device down once synced.
+    pub(crate) spindown: bool,
+}
+
+/// Records the outcome of a background sync, and schedules the spin down of
+/// the disk if it was synced for it. Returns whether it will be spun down.
+pub(crate) fn finish_sync(
+    monitor: &mut IOMonitor,
+    request: SyncRequest,
+    synced: Result<()>,
+    env: &mut TickEnv,
+) -> bool {
+    let Some(device) = monitor.get_mut(&request.dev) else {
+        return false;
+    };
+    let (dev_name, _, _, data) = device.into();
+    let entry = audit::Entry {
+        device: dev_name,
+        action: "sync",
+        reason: request.reason,
+        idle_time: request.idle_time,
+    };
+    record_action(env, &entry, &mut data.last_error, synced);
+    if !request.spindown || !std::mem::take(&mut data.syncing) {
+        return false;
+    }
+    // Unless it was spun up meanwhile
+    if data.state != DeviceState::Synced() {
+        return false;
+    }
+    data.deferred_stop = Some(env.now + data.config.spindown_delay);
+    true
+}
+
+/// Syncfs all filesystems associated with the given device, then sync the
+/// device buffers.
+///
+/// mounts: utility object to read and cache the mount points.
+pub(crate) fn sync_block_device(
+    mounts: &mut Mounts,
+    dev: &OsStr,
+    config: &DeviceConfig,
+) -> Result<()> {
+    let verbosity = config.verbosity;
+    let dirty_only = config.sync_flags & SYNC_DIRTY_ONLY != 0 && has_writeback_stats();
+    if verbosity >= 2 {
+        logln!("<6>Syncing {}", dev.to_string_lossy());
+    }
+
+    mounts
+        .for_dev(dev, |entry| {
+            // Nothing to write back, and syncfs may fail
+            if entry.read_only {
+                return Ok(());
+            }
+            let mount_point = String::from_utf8_lossy(entry.mount_point.to_bytes());
+            if dirty_only && sys::has_dirty_data(entry.mount_point, dev) == Some(false) {
+                if verbosity >= 3 {
+                    logln!("<7>{} holds no dirty data, not syncing", mount_point);
+                }
+                return Ok(());
+            }
+            if verbosity >= 3 {
+                logln!("<7>syncfs({})", mount_point);
+            }
+            sys::syncfs(entry.mount_point)
+        })
+        .and_then(|_| {
+            // The writes to the device node itself
+            if dirty_only && sys::disk_has_dirty_data(dev) == Some(false) {
+                return Ok(());
+            }
+            //FIXME: is this redundant?
+            sys::sync_blockdev(dev).map(drop)
+        })
+        .with_context(|| format!("Failed to sync {}", dev.to_string_lossy()))
+}
+
+/// Are the writeback statistics telling the dirty filesystems available? Logs
+/// once that all the filesystems are synced otherwise.
+fn has_writeback_stats() -> bool {
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+    if sys::has_writeback_stats() {
+        return true;
+    }
+    if !REPORTED.swap(true, Ordering::Relaxed) {
+        logln!("<5>No writeback statistics (is debugfs mounted?), syncing all the filesystems");
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{MonitorPolicy, SYNC_SPIN_DOWN};
+    use crate::schedule::Window;
+    use crate::testing::{self, Fixture};
+    use crate::utils::TempDir;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    #[test]
+    fn counter_resets() {
+        assert_eq!(sectors_increment(100, 150), Some(50));
+        assert_eq!(sectors_increment(100, 100), Some(0));
+        assert_eq!(sectors_increment(usize::MAX - 9, 5), Some(15));
+        assert_eq!(sectors_increment(1_000_000_000, 1000), None);
+        assert_eq!(sectors_increment(0, 1000), Some(1000));
+    }
+
+    /// A device "sdz" through the updates of successive ticks.
+    struct Case {
+        name: &'static str,
+        data: DeviceData,
+        /// Sectors read and written, in the /proc/diskstats of each tick (or
+        /// of all the ticks).
+        sectors: Vec<(usize, usize)>,
+        /// Seconds since the first tick, and local time of day in minutes.
+        ticks: Vec<(u64, Option<u16>)>,
+        /// State, pending action and idle seconds after each tick.
+        expected: Vec<(DeviceState, Action, u64)>,
+    }
+
+    #[test]
+    fn updates() {
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        let (spinning, synced, idle) = (
+            DeviceState::Spinning(),
+            DeviceState::Synced(),
+            DeviceState::Idle(),
+        );
+        let spindown = Action::Spindown { sync: false };
+        let idle_time = |secs| DeviceConfig {
+            idle_time: Duration::from_secs(secs),
+            ..Default::default()
+        };
+        let cases = [
+            Case {
+                name: "writes of a slow sync",
+                data: DeviceData {
+                    state: synced,
+                    grace_ticks: 2,
+                    ..idle_time(600).into()
+                },
+                sectors: vec![(0, 0)],
+                ticks: vec![(0, None); 3],
+                expected: vec![
+                    (synced, Action::None, 0),
+                    (synced, Action::None, 0),
+                    (idle, Action::None, 0),
+                ],
+            },
+            Case {
+                name: "reads during a background sync",
+                data: DeviceData {
+                    state: synced,
+                    syncing: true,
+                    last_io: now - secs(600),
+                    sectors: Sectors {
+                        read: 100,
+                        written: 100,
+                    },
+                    ..DeviceConfig {
+                        sync_flags: SYNC_SPIN_DOWN,
+                        ..idle_time(600)
+                    }
+                    .into()
+                },
+                sectors: vec![(100, 900), (200, 900)],
+                ticks: vec![(0, None); 2],
+                expected: vec![(synced, Action::None, 600), (spinning, Action::None, 0)],
+            },
+            Case {
+                name: "minimal uptime after a spin up",
+                data: DeviceData {
+                    state: idle,
+                    last_io: now - secs(600),
+                    ..DeviceConfig {
+                        min_uptime: secs(120),
+                        ..idle_time(10)
+                    }
+                    .into()
+                },
+                sectors: vec![(0, 64)],
+                ticks: vec![(0, None), (60, None), (130, None)],
+                expected: vec![
+                    (spinning, Action::Spinup { sync: false }, 0),
+                    (spinning, Action::None, 60),
+                    (idle, spindown, 130),
+                ],
+            },
+            Case {
+                name: "ignored reads",
+                data: DeviceData {
+                    last_io: now - secs(60),
+                    sectors: Sectors {
+                        read: 100,
+                        written: 100,
+                    },
+                    ..DeviceConfig {
+                        ignore_reads: true,
+                        ..idle_time(600)
+                    }
+                    .into()
+                },
+                sectors: vec![(200, 100), (200, 200)],
+                ticks: vec![(0, None), (10, None)],
+                expected: vec![(spinning, Action::None, 60), (spinning, Action::None, 0)],
+            },
+            Case {
+                name: "ignored writes",
+                data: DeviceData {
+                    last_io: now - secs(60),
+                    sectors: Sectors {
+                        read: 100,
+                        written: 100,
+                    },
+                    ..DeviceConfig {
+                        ignore_writes: true,
+                        ..idle_time(600)
+                    }
+                    .into()
+                },
+                sectors: vec![(200, 100), (200, 200)],
+                ticks: vec![(0, None), (10, None)],
+                expected: vec![(spinning, Action::None, 0), (spinning, Action::None, 10)],
+            },
+            Case {
+                // 24 sectors of metadata, then a 128 sectors write
+                name: "background activity",
+                data: DeviceData {
+                    last_io: now - secs(60),
+                    ..DeviceConfig {
+                        min_sectors: 64,
+                        ..idle_time(600)
+                    }
+                    .into()
+                },
+                sectors: vec![(0, 24), (0, 152)],
+                ticks: vec![(0, None); 2],
+                expected: vec![(spinning, Action::None, 60), (spinning, Action::None, 0)],
+            },
+            Case {
+                // Two spin downs within the hour, then one
+                name: "spin downs limit",
+                data: DeviceData {
+                    last_io: now - secs(1200),
+                    awake_until: now,
+                    recent_spindowns: [now - secs(4200), now - secs(3000), now - secs(300)].into(),
+                    ..DeviceConfig {
+                        max_spindowns: 2,
+                        ..idle_time(600)
+                    }
+                    .into()
+                },
+                sectors: vec![(0, 0)],
+                ticks: vec![(0, None), (900, None)],
+                expected: vec![(spinning, Action::None, 1200), (idle, spindown, 2100)],
+            },
+            Case {
+                // At noon, then at midnight
+                name: "spin down windows",
+                data: DeviceData {
+                    last_io: now - secs(1200),
+                    awake_until: now,
+                    ..DeviceConfig {
+                        windows: vec![Window::parse(b"22:00-07:00").unwrap()],
+                        ..idle_time(600)
+                    }
+                    .into()
+                },
+                sectors: vec![(0, 0)],
+                ticks: vec![(0, Some(12 * 60)), (0, Some(0))],
+                expected: vec![(spinning, Action::None, 1200), (idle, spindown, 1200)],
+            },
+        ];
+        for case in cases {
+            let snapshots: Vec<_> = (case.sectors.iter())
+                .map(|&(read, written)| testing::diskstats("sdz", read, written))
+                .collect();
+            let snapshots: Vec<_> = snapshots.iter().map(String::as_str).collect();
+            let mut monitor = IOMonitor::from_snapshots(&snapshots);
+            monitor.push("sdz".into(), case.data);
+            let mut fixture = Fixture::new();
+            let mut outcomes = Vec::new();
+            for (secs, time_of_day) in case.ticks {
+                let mut env = TickEnv {
+                    time_of_day,
+                    ..fixture.env(now + Duration::from_secs(secs))
+                };
+                monitor
+                    .check_activity(
+                        |device| update(device, &mut env),
+                        |_| DeviceConfig::default().into(),
+                    )
+                    .unwrap();
+                let data = &mut monitor.get_mut(OsStr::new("sdz")).unwrap().data;
+                let action = std::mem::replace(&mut data.pending, Action::None);
+                outcomes.push((data.state, action, data.idle_time.as_secs()));
+            }
+            assert_eq!(outcomes, case.expected, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn renamed_disk() {
+        let mut monitor = IOMonitor::from_snapshots(&[""]);
+        let id = Some(OsString::from("naa.5000c500a1b2c3d4"));
+        let old = DeviceData {
+            id: id.clone(),
+            state: DeviceState::Idle(),
+            ..DeviceConfig::default().into()
+        };
+        monitor.push("sdzz".into(), old);
+        // Not listed in /proc/diskstats
+        monitor
+            .check_activity(|_| {}, |_| DeviceConfig::default().into())
+            .unwrap();
+        let new = DeviceData {
+            id,
+            ..DeviceConfig::default().into()
+        };
+        monitor.push("sdzy".into(), new);
+
+        reconcile_renamed(&mut monitor);
+        assert!(monitor.get_mut(OsStr::new("sdzz")).is_none());
+        let device = monitor.get_mut(OsStr::new("sdzy")).unwrap();
+        assert_eq!(device.data.state, DeviceState::Idle());
+    }
+
+    #[test]
+    fn followed_links() {
+        let link = PathBuf::from("/dev/disk/by-id/ata-DISK_1");
+        let old = || DeviceData {
+            link: Some(link.clone()),
+            state: DeviceState::Idle(),
+            config: DeviceConfig {
+                idle_time: Duration::from_secs(600),
+                ..Default::default()
+            },
+            ..DeviceConfig::default().into()
+        };
+        let resolve = |path: &Path| (path == link).then(|| OsString::from("sdzy"));
+        // Configuration of the new devices
+        let create = |_: &OsStr| -> DeviceData {
+            DeviceConfig {
+                idle_time: Duration::from_secs(300),
+                ..Default::default()
+            }
+            .into()
+        };
+        let (sdzx, sdzy) = (
+            testing::diskstats("sdzx", 0, 0),
+            testing::diskstats("sdzy", 0, 0),
+        );
+        let idle_time = |monitor: &mut IOMonitor, name: &str| {
+            let device = monitor.get_mut(OsStr::new(name))?;
+            Some(device.data.config.idle_time.as_secs())
+        };
+
+        let mut monitor = IOMonitor::from_snapshots(&[&sdzx, &sdzy, &sdzy]);
+        monitor.push("sdzx".into(), old());
+        // Not detected yet
+        monitor.check_activity(|_| {}, create).unwrap();
+        follow_links(&mut monitor, resolve, create);
+        assert_eq!(idle_time(&mut monitor, "sdzx"), Some(600));
+
+        monitor.check_activity(|_| {}, create).unwrap();
+        follow_links(&mut monitor, resolve, create);
+        assert_eq!(idle_time(&mut monitor, "sdzx"), None);
+        let device = monitor.get_mut(OsStr::new("sdzy")).unwrap();
+        assert_eq!(device.data.state, DeviceState::Idle());
+        assert_eq!(device.data.config.idle_time, Duration::from_secs(600));
+        assert_eq!(device.data.link, Some(link.clone()));
+        // Stable from then on
+        monitor.check_activity(|_| {}, create).unwrap();
+        follow_links(&mut monitor, resolve, create);
+        assert_eq!(idle_time(&mut monitor, "sdzy"), Some(600));
+
+        // Another drive took the old name, as a new device
+        let both = format!("{}{}", sdzx, sdzy);
+        let mut monitor = IOMonitor::from_snapshots(&[&both]);
+        monitor.push("sdzx".into(), old());
+        monitor.check_activity(|_| {}, create).unwrap();
+        follow_links(&mut monitor, resolve, create);
+        assert_eq!(idle_time(&mut monitor, "sdzx"), Some(300));
+        assert_eq!(idle_time(&mut monitor, "sdzy"), Some(600));
+    }
+
+    #[test]
+    fn swapped_links() {
+        let mut monitor = IOMonitor::from_snapshots(&[""]);
+        let links = ["/dev/disk/by-id/ata-DISK_1", "/dev/disk/by-id/ata-DISK_2"];
+        for (name, link, idle_time) in [("sdzb", links[0], 600), ("sdzc", links[1], 1200)] {
+            let data = DeviceData {
+                link: Some(link.into()),
+                config: DeviceConfig {
+                    idle_time: Duration::from_secs(idle_time),
+                    ..Default::default()
+                },
+                ..DeviceConfig::default().into()
+            };
+            monitor.push(name.into(), data);
+        }
+        // The kernel swapped their names
+        let resolve = |path: &Path| match path.to_str() {
+            Some(link) if link == links[0] => Some(OsString::from("sdzc")),
+            Some(link) if link == links[1] => Some(OsString::from("sdzb")),
+            _ => None,
+        };
+        follow_links(&mut monitor, resolve, |_| DeviceConfig::default().into());
+        let mut devices = Vec::new();
+        for device in monitor.devices() {
+            let link = device.data.link.as_deref().and_then(Path::to_str);
+            let idle_time = device.data.config.idle_time.as_secs();
+            devices.push((device.name().to_str().unwrap(), link.unwrap(), idle_time));
+        }
+        assert_eq!(devices, [("sdzb", links[1], 1200), ("sdzc", links[0], 600)]);
+    }
+
+    #[test]
+    fn dry_run() {
+        let mut monitor = IOMonitor::from_snapshots(&[""]);
+        let idle = DeviceData {
+            state: DeviceState::Idle(),
+            pending: Action::Spindown { sync: true },
+            hooks: Hooks {
+                pre_spindown: Some("/bin/false".into()),
+                ..Default::default()
+            },
+            ..DeviceConfig::default().into()
+        };
+        monitor.push("sdzw".into(), idle);
+        let mut fixture = Fixture::new();
+        let mut env = TickEnv {
+            dry_run: true,
+            ..fixture.env(Instant::now())
+        };
+        let device = monitor.get_mut(OsStr::new("sdzw")).unwrap();
+        // The hook is not asked
+        check_vetoes(&mut [&mut *device], &mut env);
+        assert_eq!(device.data.pending, Action::Spindown { sync: true });
+        // The missing disk would fail the spin down if it were attempted
+        assert_eq!(execute(device, &mut env), DeviceState::Idle());
+        assert_eq!(device.data.deferred_stop, Some(env.now));
+        stop(&mut [&mut *device], &mut env);
+        assert!(device.data.last_error.is_none());
+        assert_eq!(device.data.spindowns, 0);
+        assert_eq!(device.data.pending, Action::None);
+    }
+
+    #[test]
+    fn concurrent_hooks() {
+        let dir = TempDir::new("rust-idle-vetoes");
+        let hook = dir.write("hook", "#!/bin/sh\nsleep 1\n[ \"$IDLE_DEVICE\" != sdzb ]\n");
+        std::fs::set_permissions(&hook, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let mut monitor = IOMonitor::from_snapshots(&[""]);
+        for name in ["sdza", "sdzb"] {
+            let idle = DeviceData {
+                state: DeviceState::Idle(),
+                pending: Action::Spindown { sync: false },
+                hooks: Hooks {
+                    pre_spindown: Some(hook.clone()),
+                    ..Default::default()
+                },
+                ..DeviceConfig::default().into()
+            };
+            monitor.push(name.into(), idle);
+        }
+        let mut fixture = Fixture::new();
+        let mut env = fixture.env(Instant::now());
+        let mut devices: Vec<_> = monitor.devices_mut().collect();
+        let started = Instant::now();
+        check_vetoes(&mut devices, &mut env);
+        // The hooks ran together
+        assert!(started.elapsed() < Duration::from_millis(1900));
+        let pending: Vec<_> = (devices.iter())
+            .map(|device| (device.name().to_str().unwrap(), device.data.pending))
+            .collect();
+        assert_eq!(
+            pending,
+            [
+                ("sdza", Action::Spindown { sync: false }),
+                ("sdzb", Action::None)
+            ]
+        );
+    }
+
+    /// Counts the spin ups it is asked for.
+    struct CountingBackend(Arc<AtomicUsize>);
+
+    impl sys::SpindownBackend for CountingBackend {
+        fn spindown(&self, _dev: &OsStr) -> Result<Duration> {
+            Ok(Duration::ZERO)
+        }
+        fn spinup(&self, _dev: &OsStr) -> Result<Duration> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(Duration::ZERO)
+        }
+        fn power_state(&self, _dev: &OsStr) -> Result<PowerMode> {
+            Ok(PowerMode::Standby)
+        }
+    }
+
+    #[test]
+    fn monitor_never_wakes() {
+        let mut monitor = IOMonitor::from_snapshots(&[&testing::diskstats("sdzv", 0, 0)]);
+        let spinups = Arc::default();
+        let now = Instant::now();
+        let config = DeviceConfig {
+            wake_period: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        // Found spun down, due for a scheduled spin-up
+        let idle = DeviceData {
+            state: DeviceState::Idle(),
+            last_scheduled_spinup: now.checked_sub(config.wake_period).unwrap_or(now),
+            backend: Box::new(CountingBackend(Arc::clone(&spinups))),
+            ..config.into()
+        };
+        monitor.push("sdzv".into(), idle);
+        let mut fixture = Fixture::new();
+        let mut env = TickEnv {
+            policy: &MonitorPolicy,
+            monitor: true,
+            ..fixture.env(now)
+        };
+        monitor
+            .check_activity(
+                |device| update(device, &mut env),
+                |_| DeviceConfig::default().into(),
+            )
+            .unwrap();
+        let device = monitor.get_mut(OsStr::new("sdzv")).unwrap();
+        assert_eq!(device.data.pending, Action::None);
+        assert_eq!(execute(device, &mut env), DeviceState::Spinning());
+        assert_eq!(spinups.load(Ordering::Relaxed), 0);
+        assert_eq!(device.data.spinups, 0);
+    }
+
+    /// Spin downs in progress, and the most at once.
+    #[derive(Default)]
+    struct Overlap {
+        running: AtomicU32,
+        peak: AtomicU32,
+    }
+
+    /// Takes its time to spin down, like a real disk, counting the spin downs
+    /// overlapping.
+    struct SlowBackend(Arc<Overlap>);
+
+    impl sys::SpindownBackend for SlowBackend {
+        fn spindown(&self, _dev: &OsStr) -> Result<Duration> {
+            let running = self.0.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.0.peak.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(200));
+            self.0.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(Duration::from_millis(200))
+        }
+        fn spinup(&self, _dev: &OsStr) -> Result<Duration> {
+            Ok(Duration::ZERO)
+        }
+        fn power_state(&self, _dev: &OsStr) -> Result<PowerMode> {
+            Ok(PowerMode::Standby)
+        }
+    }
+
+    #[test]
+    fn concurrent_spindowns() {
+        let mut monitor = IOMonitor::from_snapshots(&[""]);
+        let overlap = Arc::new(Overlap::default());
+        for name in ["sdzp", "sdzq", "sdzr"] {
+            let idle = DeviceData {
+                state: DeviceState::Idle(),
+                backend: Box::new(SlowBackend(overlap.clone())),
+                deferred_stop: Some(Instant::now()),
+                last_access: Some(fanotify::Access {
+                    path: "/media/movies/x.mkv".into(),
+                    pid: 1,
+                    process: None,
+                }),
+                ..DeviceConfig::default().into()
+            };
+            monitor.push(name.into(), idle);
+        }
+        let mut fixture = Fixture::new();
+        let mut env = fixture.env(Instant::now());
+        let mut devices: Vec<_> = monitor.devices_mut().collect();
+        stop(&mut devices, &mut env);
+        // Sent from a thread per disk, at once
+        assert_eq!(overlap.peak.load(Ordering::SeqCst), 3);
+        for device in monitor.devices() {
+            assert_eq!(
+                (device.data.spindowns, device.data.deferred_stop),
+                (1, None)
+            );
+            assert_eq!(device.data.state, DeviceState::Idle());
+            assert!(device.data.last_access.is_none());
+        }
+    }
+
+    /// Not ready for a number of spin downs (eg. without media), then spins
+    /// the disk down.
+    struct NotReadyBackend(AtomicU32);
+
+    impl sys::SpindownBackend for NotReadyBackend {
+        fn spindown(&self, _dev: &OsStr) -> Result<Duration> {
+            if self
+                .0
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+            }
+            Ok(Duration::ZERO)
+        }
+        fn spinup(&self, _dev: &OsStr) -> Result<Duration> {
+            Ok(Duration::ZERO)
+        }
+        fn power_state(&self, _dev: &OsStr) -> Result<PowerMode> {
+            Ok(PowerMode::Standby)
+        }
+    }
+
+    #[test]
+    fn not_ready() {
+        let mut monitor = IOMonitor::from_snapshots(&[""]);
+        let idle = DeviceData {
+            state: DeviceState::Idle(),
+            backend: Box::new(NotReadyBackend(2.into())),
+            ..DeviceConfig::default().into()
+        };
+        monitor.push("sdzo".into(), idle);
+        let mut fixture = Fixture::new();
+        let mut env = fixture.env(Instant::now());
+        let mut outcomes = Vec::new();
+        for _ in 0..3 {
+            let device = monitor.get_mut(OsStr::new("sdzo")).unwrap();
+            device.data.state = DeviceState::Idle();
+            stop(&mut [device], &mut env);
+            let data = &monitor.get_mut(OsStr::new("sdzo")).unwrap().data;
+            outcomes.push((data.state, data.not_ready, data.spindowns));
+            assert!(data.last_error.is_none());
+        }
+        // Retried while the device isn't ready, warning once
+        let (spinning, idle) = (DeviceState::Spinning(), DeviceState::Idle());
+        assert_eq!(
+            outcomes,
+            [(spinning, true, 0), (spinning, true, 0), (idle, false, 1)]
+        );
+    }
+
+    #[test]
+    fn background_sync() {
+        // The sync writes to sdzs
+        let diskstats = testing::diskstats("sdzs", 0, 800) + &testing::diskstats("sdzt", 0, 0);
+        let mut monitor = IOMonitor::from_snapshots(&[&diskstats]);
+        let config = DeviceConfig {
+            spindown_delay: Duration::from_secs(2),
+            ..Default::default()
+        };
+        for (name, state) in [
+            ("sdzs", DeviceState::Synced()),
+            ("sdzt", DeviceState::Spinning()),
+        ] {
+            let syncing = DeviceData {
+                state,
+                syncing: true,
+                ..config.clone().into()
+            };
+            monitor.push(name.into(), syncing);
+        }
+        let mut fixture = Fixture::new();
+        let mut env = fixture.env(Instant::now());
+        // The writes of the sync are not activity
+        monitor
+            .check_activity(|device| update(device, &mut env), |_| config.clone().into())
+            .unwrap();
+        let device = monitor.get_mut(OsStr::new("sdzs")).unwrap();
+        assert_eq!(device.data.state, DeviceState::Synced());
+        assert_eq!(device.data.pending, Action::None);
+
+        let due = env.now + config.spindown_delay;
+        let mut finish = |dev: &str| {
+            let request = SyncRequest {
+                dev: dev.into(),
+                config: config.clone(),
+                reason: "idle",
+                idle_time: Duration::ZERO,
+                spindown: true,
+            };
+            let stopping = finish_sync(&mut monitor, request, Ok(()), &mut env);
+            let device = monitor.get_mut(OsStr::new(dev)).unwrap();
+            assert!(!device.data.syncing);
+            (stopping, device.data.deferred_stop)
+        };
+        assert_eq!(finish("sdzs"), (true, Some(due)));
+        // Spun up meanwhile
+        assert_eq!(finish("sdzt"), (false, None));
+    }
+
+    #[test]
+    fn global_sync_targeting() {
+        let global = DeviceConfig {
+            sync_flags: SYNC_GLOBAL,
+            ..Default::default()
+        };
+        let local = DeviceConfig {
+            sync_flags: SYNC_SPIN_DOWN,
+            ..Default::default()
+        };
+        let spindown = Action::Spindown { sync: true };
+
+        // No device wants it
+        assert!(!wants_global_sync([
+            (&local, spindown),
+            (&local, Action::None)
+        ]));
+        // A device wants it, but is not transitioning
+        assert!(!wants_global_sync([
+            (&local, spindown),
+            (&global, Action::None)
+        ]));
+        assert!(!wants_global_sync([(
+            &global,
+            Action::Spinup { sync: true }
+        )]));
+        // A transitioning device wants it
+        assert!(wants_global_sync([(&local, spindown), (&global, spindown)]));
+    }
+}
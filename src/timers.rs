@@ -0,0 +1,166 @@
+// Copyright (c) 2022 Maël Kerbiriou <m431.kerbiriou@gmail.com>. All rights
+// reserved. Use of this source is governed by MIT License that can be found in
+// the LICENSE file.
+
+//! Idle timers of the disks, saved across restarts of the daemon
+//! (`--state-file`): a disk 9 minutes into a 10 minutes idle time doesn't wait
+//! 10 more after a restart. One line per disk:
+//!
+//! ```text
+//! <idle|spinning> <idle secs> <saved at, unix secs> <read> <written> <disk>
+//! ```
+//!
+//! where `<disk>` is its WWID, or else its name. The sector counters tell the
+//! accesses made while the daemon was stopped.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::{Context, Result};
+use crate::iomonitor::Sectors;
+use crate::utils::parse_integer;
+
+/// Default path of the file, in the runtime directory cleared at boot.
+pub const DEFAULT_PATH: &str = "/run/rust-idle.timers";
+
+/// Idle timer of a disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timer {
+    /// Was the disk spun down?
+    pub idle: bool,
+    pub idle_time: Duration,
+    pub saved_at: SystemTime,
+    pub sectors: Sectors,
+}
+
+impl Timer {
+    /// Idle time at `now`, counting the time since it was saved.
+    pub fn idle_time_at(&self, now: SystemTime) -> Duration {
+        (self.idle_time).saturating_add(now.duration_since(self.saved_at).unwrap_or_default())
+    }
+}
+
+/// Writes the timers, one per line.
+pub fn write<'a, W, I>(out: &mut W, timers: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (&'a OsStr, Timer)>,
+{
+    for (disk, timer) in timers {
+        let saved_at = timer
+            .saved_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            out,
+            "{} {} {} {} {} ",
+            if timer.idle { "idle" } else { "spinning" },
+            timer.idle_time.as_secs(),
+            saved_at.as_secs(),
+            timer.sectors.read,
+            timer.sectors.written
+        )?;
+        out.write_all(disk.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Replaces the file of the timers, through a temporary file.
+pub fn save<'a, I>(path: &Path, timers: I) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a OsStr, Timer)>,
+{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut content = Vec::new();
+    write(&mut content, timers)?;
+    fs::write(&tmp_path, content)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .with_context(|| format!("Saving the idle timers to '{}'", path.display()))
+}
+
+/// Loads the timers saved since `since`, none if the file doesn't exist.
+pub fn load(path: &Path, since: SystemTime) -> Result<Vec<(OsString, Timer)>> {
+    match fs::read(path) {
+        Ok(content) => Ok(parse(&content, since)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Reading '{}'", path.display())),
+    }
+}
+
+/// Parses the timers saved since `since`, skipping the malformed lines.
+fn parse(content: &[u8], since: SystemTime) -> Vec<(OsString, Timer)> {
+    content
+        .split(|c| *c == b'\n')
+        .filter_map(parse_line)
+        .filter(|(_, timer)| timer.saved_at >= since)
+        .collect()
+}
+
+fn parse_line(line: &[u8]) -> Option<(OsString, Timer)> {
+    let mut it = line.splitn(6, |c| *c == b' ');
+    let idle = match it.next()? {
+        b"idle" => true,
+        b"spinning" => false,
+        _ => return None,
+    };
+    let mut number = || parse_integer(it.next()?).ok();
+    let idle_time = Duration::from_secs(number()? as u64);
+    let saved_at = UNIX_EPOCH + Duration::from_secs(number()? as u64);
+    let sectors = Sectors {
+        read: number()?,
+        written: number()?,
+    };
+    let disk = it.next().filter(|disk| !disk.is_empty())?;
+    let timer = Timer {
+        idle,
+        idle_time,
+        saved_at,
+        sectors,
+    };
+    Some((OsStr::from_bytes(disk).to_owned(), timer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timers_roundtrip() {
+        let saved_at = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+        let timer = Timer {
+            idle: true,
+            idle_time: Duration::from_secs(540),
+            saved_at,
+            sectors: Sectors {
+                read: 100,
+                written: 80,
+            },
+        };
+        let wwid = OsStr::new("t10.ATA     ST4000DM004-2CV104");
+        let spinning = Timer {
+            idle: false,
+            ..timer
+        };
+        let mut content = Vec::new();
+        write(&mut content, [(wwid, timer), (OsStr::new("sdb"), spinning)]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&content),
+            "idle 540 1760000000 100 80 t10.ATA     ST4000DM004-2CV104\n\
+             spinning 540 1760000000 100 80 sdb\n"
+        );
+        content.extend_from_slice(b"idle 1 2 3\nbusy 1 2 3 4 sdc\n");
+        let timers = parse(&content, saved_at);
+        assert_eq!(timers, [(wwid.into(), timer), ("sdb".into(), spinning)]);
+        // Stale timers are discarded
+        assert!(parse(&content, saved_at + Duration::from_secs(1)).is_empty());
+
+        let later = saved_at + Duration::from_secs(60);
+        assert_eq!(timer.idle_time_at(later), Duration::from_secs(600));
+    }
+}
@@ -2,25 +2,64 @@
 // reserved. Use of this source is governed by MIT License that can be found in
 // the LICENSE file.
 
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 
 use crate::errors::{Context, Result};
 
+/// What a `BulkReader` reads.
+enum Input {
+    File(File),
+    /// Scripted contents, one per read. The last one is read again once they
+    /// are exhausted.
+    #[cfg(test)]
+    Snapshots(std::collections::VecDeque<Vec<u8>>),
+}
+
 /// An utility to repeatedly read a file into a buffer, minimizing allocations.
 pub struct BulkReader {
-    file: File,
+    input: Input,
     buf: Vec<u8>,
+    /// Initial capacity, below which the buffer is never shrunk.
+    floor: usize,
+    /// Shrink the buffer after this many consecutive reads filling less than
+    /// half of it (0: never).
+    shrink_after: u32,
+    small_reads: u32,
+    /// Largest content of these reads.
+    high_water: usize,
 }
 
 impl BulkReader {
     pub fn open_with_capacity<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
-        Ok(Self {
-            file: File::open(path.as_ref())
-                .with_context(|| format!("Opening '{}' for reading", path.as_ref().display()))?,
+        let file = File::open(path.as_ref())
+            .with_context(|| format!("Opening '{}' for reading", path.as_ref().display()))?;
+        Ok(Self::with_input(Input::File(file), capacity))
+    }
+
+    /// Reads the given contents in turn instead of a file, to simulate
+    /// successive refreshes in tests.
+    #[cfg(test)]
+    pub fn from_snapshots<I, S>(snapshots: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<Vec<u8>>,
+    {
+        let snapshots = snapshots.into_iter().map(Into::into).collect();
+        Self::with_input(Input::Snapshots(snapshots), 4096)
+    }
+
+    fn with_input(input: Input, capacity: usize) -> Self {
+        Self {
+            input,
             buf: Vec::with_capacity(capacity),
-        })
+            floor: capacity,
+            shrink_after: 0,
+            small_reads: 0,
+            high_water: 0,
+        }
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
@@ -31,6 +70,19 @@ impl BulkReader {
         self.buf.is_empty()
     }
 
+    /// Size of the buffer, which is kept between reads.
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Shrinks the buffer, down to its initial capacity, once `reads`
+    /// consecutive reads filled less than half of it: the file has shrunk (eg.
+    /// after many devices were detached). The buffer then keeps a 50% margin
+    /// over the largest of these reads.
+    pub fn shrink_after(&mut self, reads: u32) {
+        self.shrink_after = reads;
+    }
+
     pub fn get(&self) -> &[u8] {
         &self.buf
     }
@@ -40,12 +92,47 @@ impl BulkReader {
     }
 
     pub fn read(&mut self) -> Result<&mut [u8]> {
-        self.file.seek(SeekFrom::Start(0))?;
         self.clear();
-        self.file.read_to_end(&mut self.buf)?;
+        match &mut self.input {
+            Input::File(file) => {
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut self.buf)?;
+            }
+            #[cfg(test)]
+            Input::Snapshots(snapshots) => {
+                let snapshot = match snapshots.len() {
+                    0 => return Err("No snapshot to read".into()),
+                    1 => snapshots[0].clone(),
+                    _ => snapshots.pop_front().unwrap_or_default(),
+                };
+                self.buf.extend_from_slice(&snapshot);
+            }
+        }
+        self.check_shrink();
         Ok(&mut self.buf)
     }
 
+    fn check_shrink(&mut self) {
+        let len = self.buf.len();
+        if self.shrink_after == 0
+            || len * 2 >= self.buf.capacity()
+            || self.buf.capacity() <= self.floor
+        {
+            self.small_reads = 0;
+            self.high_water = 0;
+            return;
+        }
+        self.high_water = self.high_water.max(len);
+        self.small_reads += 1;
+        if self.small_reads >= self.shrink_after {
+            let margin = self.high_water / 2;
+            self.buf
+                .shrink_to((self.high_water + margin).max(self.floor));
+            self.small_reads = 0;
+            self.high_water = 0;
+        }
+    }
+
     pub fn read_lines(&mut self) -> Result<impl Iterator<Item = &mut [u8]>> {
         Ok(self
             .read()?
@@ -58,10 +145,92 @@ impl BulkReader {
         self.get().split(|c| *c == b'\n').filter(|l| !l.is_empty())
     }
 
+    /// Lines keep their terminating '\n' (if any), which in-place tokenizers can
+    /// overwrite.
     pub fn parse_lines_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
         self.buf
-            .split_mut(|c| *c == b'\n')
-            .filter(|l| !l.is_empty())
+            .split_inclusive_mut(|c| *c == b'\n')
+            .filter(|l| *l != b"\n")
+    }
+}
+
+/// An utility to incrementally read lines from a non-blocking stream (eg. a
+/// FIFO), keeping incomplete lines for the next read.
+pub struct StreamReader {
+    file: File,
+    buf: Vec<u8>,
+    consumed: usize,
+}
+
+impl StreamReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            file: OpenOptions::new()
+                .read(true)
+                .custom_flags(nc::O_NONBLOCK)
+                .open(path.as_ref())
+                .with_context(|| format!("Opening '{}' for reading", path.as_ref().display()))?,
+            buf: Vec::with_capacity(4096),
+            consumed: 0,
+        })
+    }
+
+    /// Reads all the data currently available and returns the complete lines.
+    pub fn read_lines(&mut self) -> Result<impl Iterator<Item = &[u8]>> {
+        self.buf.drain(..self.consumed);
+        match self.file.read_to_end(&mut self.buf) {
+            // EOF: no writer on the other end of the FIFO, try again later
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.consumed = self
+            .buf
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map_or(0, |pos| pos + 1);
+        Ok(self.buf[..self.consumed]
+            .split(|c| *c == b'\n')
+            .filter(|l| !l.is_empty()))
+    }
+}
+
+/// A temporary directory for the fixtures of the tests, removed with its
+/// contents when dropped, even when an assertion fails.
+#[doc(hidden)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `<tmp>/<name>-<pid>`, clearing any leftover of a killed run.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+
+    /// Writes a file, and the directories leading to it.
+    pub fn write<C: AsRef<[u8]>>(&self, name: &str, content: C) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
     }
 }
 
@@ -77,3 +246,24 @@ pub fn parse_integer(txt: &[u8]) -> Result<usize> {
     }
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrinking_buffer() {
+        let mut reader = BulkReader::from_snapshots([vec![b'x'; 64 * 1024], vec![b'x'; 8 * 1024]]);
+        reader.shrink_after(3);
+        reader.read().unwrap();
+        let grown = reader.capacity();
+        assert!(grown >= 64 * 1024);
+
+        reader.read().unwrap();
+        reader.read().unwrap();
+        assert_eq!(reader.capacity(), grown);
+        reader.read().unwrap();
+        assert_eq!(reader.capacity(), 12 * 1024);
+        assert_eq!(reader.get().len(), 8 * 1024);
+    }
+}